@@ -0,0 +1,11 @@
+// Writing through the parent invalidates a raw pointer derived from it.
+fn main() {
+    let mut local = 0;
+    let x = &mut local;
+    let p = x as *mut i32;
+    unsafe {
+        *p = 1;
+        *x = 2;
+        *p = 3; //~ ERROR: Undefined Behavior
+    }
+}
@@ -0,0 +1,12 @@
+// Copies of a raw pointer are the same pointer.
+// expect: ok
+fn main() {
+    let mut local = 0;
+    let p = &mut local as *mut i32;
+    let q = p;
+    unsafe {
+        *p = 1;
+        *q = 2;
+        *p += 3;
+    }
+}
@@ -0,0 +1,13 @@
+// Two raw pointers derived from the same reference can be used interleaved.
+// expect: ok
+fn main() {
+    let mut local = 0;
+    let x = &mut local;
+    let p = &raw mut *x;
+    let q = &raw mut *x;
+    unsafe {
+        *p = 1;
+        *q = 2;
+        *p = 3;
+    }
+}
@@ -0,0 +1,13 @@
+// Shared references derived from the same parent can be read in any order,
+// and so can the parent.
+// expect: ok
+fn main() {
+    let mut local = 0;
+    let x = &mut local;
+    let a = &*x;
+    let b = &*x;
+    let _ = *a;
+    let _ = *b;
+    let _ = *a;
+    let _ = *x;
+}
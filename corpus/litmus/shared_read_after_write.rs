@@ -0,0 +1,9 @@
+// A shared reference cannot be read after its parent wrote.
+fn main() {
+    let mut local = 0;
+    let x = &mut local;
+    let s = &*x;
+    let _ = *s;
+    *x = 1;
+    let _ = *s; //~ ERROR: Undefined Behavior
+}
@@ -0,0 +1,11 @@
+// A reborrow can be used, then its parent, as long as the reborrow is not
+// used again afterwards.
+// expect: ok
+fn main() {
+    let mut local = 0;
+    let x = &mut local;
+    let y = &mut *x;
+    *y = 1;
+    *x = 2;
+    let _ = local;
+}
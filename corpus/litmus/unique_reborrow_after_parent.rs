@@ -0,0 +1,9 @@
+// Writing through the parent invalidates the reborrow.
+fn main() {
+    let mut local = 0;
+    let x = &mut local;
+    let y = &mut *x;
+    *y = 1;
+    *x = 2;
+    *y = 3; //~ ERROR: Undefined Behavior
+}
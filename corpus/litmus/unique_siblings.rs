@@ -0,0 +1,9 @@
+// Using a mutable reborrow invalidates its sibling.
+fn main() {
+    let mut local = 0;
+    let x = &mut local;
+    let a = &mut *x;
+    let b = &mut *x;
+    *a = 1; //~ ERROR: Undefined Behavior
+    *b = 2;
+}
@@ -0,0 +1,9 @@
+// Writing through a pointer derived from a shared reference.
+fn main() {
+    let local = 0;
+    let s = &local;
+    let p = s as *const i32 as *mut i32;
+    unsafe {
+        *p = 1; //~ ERROR: Undefined Behavior
+    }
+}
@@ -0,0 +1,151 @@
+// Execution over sets of machine states. When a frontend does not know exactly
+// which operations a program performs (e.g. because two threads may interleave
+// in any order, or because an implicit read may or may not happen), it can
+// describe the possibilities with an AbstractOp and run them all at once. The
+// machine then tracks the set of states that some resolution of the choices
+// made so far leads to. Since different resolutions often lead to the same
+// state, the set tends to stay much smaller than the number of resolutions.
+
+use std::collections::HashSet;
+
+use crate::machine2::{MachineError, Operation, TokenMachine};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AbstractOp {
+    Exact(Operation),
+    // The operation may or may not be performed.
+    Optional(Operation),
+    // Exactly one of the sequences is performed.
+    OneOf(Vec<Vec<Operation>>),
+    // Both sequences are performed, each in order, but interleaved in some
+    // unknown way.
+    Interleave(Vec<Operation>, Vec<Operation>),
+}
+
+impl AbstractOp {
+    // Every sequence of operations this may stand for.
+    pub fn resolutions(&self) -> Vec<Vec<Operation>> {
+        match self {
+            AbstractOp::Exact(op) => vec![vec![*op]],
+            AbstractOp::Optional(op) => vec![vec![], vec![*op]],
+            AbstractOp::OneOf(choices) => choices.clone(),
+            AbstractOp::Interleave(left, right) => interleavings(left, right),
+        }
+    }
+}
+
+fn interleavings(left: &[Operation], right: &[Operation]) -> Vec<Vec<Operation>> {
+    match (left.split_first(), right.split_first()) {
+        (None, _) => vec![right.to_vec()],
+        (_, None) => vec![left.to_vec()],
+        (Some((&l, left_rest)), Some((&r, right_rest))) => {
+            let mut result = Vec::new();
+            for mut rest in interleavings(left_rest, right) {
+                rest.insert(0, l);
+                result.push(rest);
+            }
+            for mut rest in interleavings(left, right_rest) {
+                rest.insert(0, r);
+                result.push(rest);
+            }
+            result
+        }
+    }
+}
+
+// What happened to the resolutions of one abstract operation, over all
+// states in the set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StepReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub errors: HashSet<MachineError>,
+}
+
+impl StepReport {
+    // Some resolution, from some state, is rejected.
+    pub fn may_fail(&self) -> bool {
+        self.rejected > 0
+    }
+
+    // Every resolution, from every state, is rejected.
+    pub fn must_fail(&self) -> bool {
+        self.accepted == 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AbstractMachine {
+    states: HashSet<TokenMachine>,
+}
+
+impl AbstractMachine {
+    pub fn new(machine: TokenMachine) -> Self {
+        AbstractMachine::from_states(vec![machine])
+    }
+
+    pub fn from_states(states: Vec<TokenMachine>) -> Self {
+        AbstractMachine {
+            states: states.into_iter().collect(),
+        }
+    }
+
+    pub fn states(&self) -> &HashSet<TokenMachine> {
+        &self.states
+    }
+
+    // Replace the set of states by the states reached by applying every
+    // resolution of [op] to every state in it. Resolutions that are rejected
+    // are dropped, so afterwards the set describes the executions that are
+    // still legal. If every resolution is rejected, the set becomes empty.
+    pub fn apply(&mut self, op: &AbstractOp) -> StepReport {
+        let resolutions = op.resolutions();
+        let mut report = StepReport::default();
+        let mut next = HashSet::new();
+
+        for state in &self.states {
+            for ops in &resolutions {
+                match state.step_all(ops) {
+                    Ok(after) => {
+                        report.accepted += 1;
+                        next.insert(after);
+                    }
+                    Err((err, _)) => {
+                        report.rejected += 1;
+                        report.errors.insert(err);
+                    }
+                }
+            }
+        }
+
+        self.states = next;
+        report
+    }
+
+    // Apply every operation in turn, stopping once no state is left.
+    pub fn run(&mut self, ops: &[AbstractOp]) -> Vec<StepReport> {
+        let mut reports = Vec::new();
+        for op in ops {
+            if self.states.is_empty() {
+                break;
+            }
+            reports.push(self.apply(op));
+        }
+        reports
+    }
+
+    // A state in the set for which [property] does not hold, if any.
+    pub fn find_violation<F>(&self, mut property: F) -> Option<&TokenMachine>
+    where
+        F: FnMut(&TokenMachine) -> bool,
+    {
+        self.states.iter().find(|state| !property(state))
+    }
+
+    pub fn holds_for_all<F>(&self, property: F) -> bool
+    where
+        F: FnMut(&TokenMachine) -> bool,
+    {
+        self.find_violation(property).is_none()
+    }
+}
@@ -0,0 +1,203 @@
+// Replaying every trace of a directory, e.g. the whole corpus after changing
+// a rule, on a number of worker threads. Every trace is replayed under its own
+// configuration with the overrides of the command line on top, and checked
+// against its expect header if it has one. The results are kept in the order
+// of the file names, whatever order the workers finish them in.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::json;
+use crate::machine2::MachineConfig;
+use crate::trace::{self, Trace, Verdict};
+
+#[derive(Debug, Clone)]
+pub struct TraceResult {
+    pub path: PathBuf,
+    // The verdict and the number of operations, or why the trace could not
+    // be replayed.
+    pub outcome: Result<(Verdict, usize), String>,
+    // Whether the verdict is the one the expect header gives, for traces that
+    // have one.
+    pub expected: Option<Result<(), String>>,
+    pub elapsed: Duration,
+}
+
+impl TraceResult {
+    // The name of the error the trace is rejected with, "accepted", or
+    // "invalid" for traces that could not be replayed.
+    pub fn kind(&self) -> &'static str {
+        match &self.outcome {
+            Ok((Verdict::Accepted, _)) => "accepted",
+            Ok((Verdict::Rejected(_, err), _)) => err.name(),
+            Err(_) => "invalid",
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.outcome.is_err() || matches!(self.expected, Some(Err(_)))
+    }
+}
+
+// Compare [verdict] with an expect header of the form "<error> at <step>".
+fn check_expected(expect: &str, verdict: Verdict) -> Result<(), String> {
+    let found = match verdict {
+        Verdict::Accepted => "accepted".to_string(),
+        Verdict::Rejected(step, err) => format!("{} at {}", err.name(), step),
+    };
+    if found == expect.trim() {
+        Ok(())
+    } else {
+        Err(format!("expected {}, but found {}", expect.trim(), found))
+    }
+}
+
+fn run_one<F>(path: &Path, configure: &F) -> TraceResult
+where
+    F: Fn(MachineConfig) -> Result<MachineConfig, String>,
+{
+    let start = Instant::now();
+    let replayed = Trace::load(path).and_then(|trace| {
+        let config = configure(trace.config()?.unwrap_or_default())?;
+        let (verdict, _) = trace::replay(config, &trace);
+        let expected = trace
+            .header("expect")
+            .map(|expect| check_expected(expect, verdict));
+        Ok((verdict, trace.ops.len(), expected))
+    });
+
+    let (outcome, expected) = match replayed {
+        Ok((verdict, len, expected)) => (Ok((verdict, len)), expected),
+        Err(msg) => (Err(msg), None),
+    };
+    TraceResult {
+        path: path.to_path_buf(),
+        outcome,
+        expected,
+        elapsed: start.elapsed(),
+    }
+}
+
+// Replay every .tbm file in [dir] on [jobs] threads, with [configure] turning
+// the configuration of a trace into the one to replay it under.
+pub fn run_all<F>(dir: &Path, jobs: usize, configure: F) -> Result<Vec<TraceResult>, String>
+where
+    F: Fn(MachineConfig) -> Result<MachineConfig, String> + Sync,
+{
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "tbm"))
+        .collect();
+    paths.sort();
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(paths.len()));
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                let result = run_one(path, &configure);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+// How many traces ended in each way, and how long they took together.
+pub fn by_kind(results: &[TraceResult]) -> BTreeMap<&'static str, (usize, Duration)> {
+    let mut kinds: BTreeMap<_, (usize, Duration)> = BTreeMap::new();
+    for result in results {
+        let entry = kinds.entry(result.kind()).or_default();
+        entry.0 += 1;
+        entry.1 += result.elapsed;
+    }
+    kinds
+}
+
+pub fn render_table(results: &[TraceResult], wall: Duration) -> String {
+    let mut out = String::new();
+    writeln!(out, "{:<36} {:>7} {:>12}", "verdict", "traces", "time (ms)").unwrap();
+    for (kind, (count, elapsed)) in by_kind(results) {
+        writeln!(
+            out,
+            "{:<36} {:>7} {:>12.2}",
+            kind,
+            count,
+            elapsed.as_secs_f64() * 1000.0
+        )
+        .unwrap();
+    }
+
+    let checked = results.iter().filter(|r| r.expected.is_some()).count();
+    let mismatched: Vec<_> = results
+        .iter()
+        .filter(|r| matches!(r.expected, Some(Err(_))))
+        .collect();
+    writeln!(
+        out,
+        "{} traces in {:.2}s, {} of {} expectations met",
+        results.len(),
+        wall.as_secs_f64(),
+        checked - mismatched.len(),
+        checked
+    )
+    .unwrap();
+
+    for result in results {
+        let problem = match (&result.outcome, &result.expected) {
+            (Err(msg), _) | (_, Some(Err(msg))) => msg,
+            _ => continue,
+        };
+        writeln!(out, "FAIL  {}: {}", result.path.display(), problem).unwrap();
+    }
+    out
+}
+
+pub fn to_json(results: &[TraceResult]) -> String {
+    let entries: Vec<_> = results
+        .iter()
+        .map(|result| {
+            let path = json::string(&result.path.display().to_string());
+            let (ops, step) = match &result.outcome {
+                Ok((Verdict::Rejected(step, _), len)) => (len.to_string(), step.to_string()),
+                Ok((Verdict::Accepted, len)) => (len.to_string(), "null".to_string()),
+                Err(_) => ("null".to_string(), "null".to_string()),
+            };
+            let message = match &result.outcome {
+                Ok((verdict, _)) => verdict.to_string(),
+                Err(msg) => msg.clone(),
+            };
+            let expected = match &result.expected {
+                None => "null".to_string(),
+                Some(Ok(())) => "true".to_string(),
+                Some(Err(_)) => "false".to_string(),
+            };
+            format!(
+                "  {{ \"path\": {}, \"verdict\": \"{}\", \"step\": {}, \"operations\": {}, \
+                 \"message\": {}, \"expectation_met\": {}, \"micros\": {} }}",
+                path,
+                result.kind(),
+                step,
+                ops,
+                json::string(&message),
+                expected,
+                result.elapsed.as_micros()
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
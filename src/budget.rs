@@ -0,0 +1,129 @@
+// Limits on how much work a search may do. Searches that run out of budget
+// stop early and report what they found so far together with the reason they
+// stopped, instead of running for as long as the state space takes.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Budget {
+    // Maximum number of operations in a trace.
+    pub max_depth: usize,
+    // Maximum number of states to visit.
+    pub max_states: Option<usize>,
+    pub max_time: Option<Duration>,
+}
+
+impl Budget {
+    // A budget that only bounds the depth.
+    pub fn depth(max_depth: usize) -> Self {
+        Budget {
+            max_depth,
+            max_states: None,
+            max_time: None,
+        }
+    }
+
+    pub fn with_states(self, max_states: usize) -> Self {
+        Budget {
+            max_states: Some(max_states),
+            ..self
+        }
+    }
+
+    pub fn with_time(self, max_time: Duration) -> Self {
+        Budget {
+            max_time: Some(max_time),
+            ..self
+        }
+    }
+}
+
+// Why a search stopped before it was done. (Reaching the maximum depth does
+// not count: that is part of what the search was asked to do.)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Exhausted {
+    States,
+    Time,
+}
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exhausted::States => write!(f, "state budget exhausted"),
+            Exhausted::Time => write!(f, "time budget exhausted"),
+        }
+    }
+}
+
+// How much of the budget a search has spent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SearchReport {
+    pub states: usize,
+    // None if the search ran to completion.
+    pub exhausted: Option<Exhausted>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Meter {
+    budget: Budget,
+    started: Instant,
+    states: usize,
+    exhausted: Option<Exhausted>,
+}
+
+impl Meter {
+    pub fn start(budget: Budget) -> Self {
+        Meter::resume(budget, Instant::now())
+    }
+
+    // A meter whose time budget started running at [started], for searches
+    // that are split into parts sharing a single deadline.
+    pub fn resume(budget: Budget, started: Instant) -> Self {
+        Meter {
+            budget,
+            started,
+            states: 0,
+            exhausted: None,
+        }
+    }
+
+    pub fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    // Account for visiting one more state. Returns false if the budget does
+    // not allow it, and keeps returning false from then on.
+    pub fn visit(&mut self) -> bool {
+        if self.exhausted.is_some() {
+            return false;
+        }
+
+        if self.budget.max_states.is_some_and(|max| self.states >= max) {
+            self.exhausted = Some(Exhausted::States);
+            return false;
+        }
+        if self
+            .budget
+            .max_time
+            .is_some_and(|max| self.started.elapsed() >= max)
+        {
+            self.exhausted = Some(Exhausted::Time);
+            return false;
+        }
+
+        self.states += 1;
+        true
+    }
+
+    pub fn exhausted(&self) -> Option<Exhausted> {
+        self.exhausted
+    }
+
+    pub fn report(&self) -> SearchReport {
+        SearchReport {
+            states: self.states,
+            exhausted: self.exhausted,
+        }
+    }
+}
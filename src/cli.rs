@@ -0,0 +1,1149 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::batch;
+use crate::budget::Budget;
+use crate::cost::{self, CostModel};
+use crate::coverage::{self, SearchOptions};
+use crate::diff::{self, Edit};
+use crate::fuzz::{self, FuzzOptions};
+use crate::grammar;
+use crate::json;
+use crate::litmus;
+use crate::lockstep;
+use crate::machine2::{MachineConfig, TokenMachine};
+use crate::memory;
+use crate::metrics;
+use crate::miri;
+use crate::orchestrate::{self, Job, OrchestratorOptions};
+use crate::projection;
+use crate::query::Query;
+use crate::repl;
+use crate::report;
+use crate::repro::Bundle;
+use crate::script::{self, Script};
+use crate::serve;
+use crate::session::{self, SessionManager};
+use crate::simulate::{self, SimulateOptions};
+use crate::slice;
+use crate::trace::{self, Trace, Verdict};
+use crate::triage::{self, Index, Status};
+
+const USAGE: &str = "\
+usage: tbm <command> [arguments]
+
+commands:
+    replay <trace> [--model simple] [--on-error stop|continue]
+                                    replay a trace file and print the verdict,
+                                    optionally on the simple model of
+                                    machine.rs instead; with --on-error
+                                    continue, rejected operations are skipped
+                                    and every violation is listed
+    lint <trace>                    list every syntax error of a trace file,
+                                    showing where on its line it is
+    repl                            perform operations interactively; :record
+                                    FILE and :stop save them as a trace, and
+                                    :lockstep FILE steps through a trace on
+                                    both models as lockstep does
+    metrics <trace> [--out FILE]    write per-step metrics of a trace as CSV
+    cost <trace> ... [--cost name=N]
+                                    compare the total and critical-path cost
+                                    of traces (e.g. alternative lowerings of a
+                                    program) under the cost model of cost.rs,
+                                    changing the cost of an operation with
+                                    --cost, e.g. --cost borrow=2
+    reads-from <trace>              list which write every read of a trace
+                                    observes
+    export-json <trace> [--out FILE]
+                                    write the reference trees after every step
+                                    of a trace as JSON, for d3.js
+    project [<trace>] [--depth N] [--refs N]
+                                    check where the piece accounting of a
+                                    trace stops corresponding to the single
+                                    owner of machine.rs, or without a trace,
+                                    count which features break it among all
+                                    short traces
+    diff <a> <b>                    align two traces up to renaming of
+                                    references, list the operations that were
+                                    deleted, inserted or changed, and report
+                                    where the states of corresponding
+                                    references first differ
+    explain <trace> [<step>] [--out FILE]
+                                    list the earlier operations the verdict
+                                    of a step (the rejected one by default)
+                                    depends on, optionally writing them to
+                                    FILE as a trace of their own
+    query <query> <trace or dir> ...
+                                    list the steps or references of the traces
+                                    (every .tbm file of a directory) matching
+                                    a query such as 'refs where state = dead
+                                    and uses = 0' (see query.rs)
+    compare <trace> --with key=value ...
+                                    replay a trace under its configuration and
+                                    under the same one with the given settings
+                                    changed, up to the first step on which the
+                                    two disagree
+    lockstep <trace>                replay a trace on the token machine and a
+                                    Stacked Borrows model side by side, up to
+                                    the first step where they disagree (see
+                                    also :lockstep in the repl)
+    fuzz [--seed N] [--runs N] [--len N] [--refs N] [--mutations N] [--out DIR]
+         [--index FILE]
+                                    fuzz random traces, writing a reproducer
+                                    bundle to DIR for every failure that is
+                                    not marked as known in the triage index
+                                    (DIR/triage.idx by default)
+    fuzz-parser [--seed N] [--runs N] [--len N] [--refs N] [--out DIR]
+                                    feed mangled random traces to the trace
+                                    parser, checking that it never panics and
+                                    reports errors inside the input; failing
+                                    inputs are written to DIR if given
+    orchestrate [--pair sb|simple] [--seconds N] [--runs N] [--interval SECONDS]
+                [--len N] [--refs N] [--seed N] [--out DIR]
+                                    fuzz every configuration (or the one
+                                    given with --config) against each model
+                                    (both by default) on a thread per
+                                    combination, writing distinct divergences
+                                    and a periodic summary.txt to DIR
+    simulate [--steps N] [--walk N] [--refs N] [--sample N] [--seed N]
+             [--time SECONDS] [--out FILE]
+                                    take long random walks over accepted
+                                    operations, checking the invariants and
+                                    sampling the size of the state every N
+                                    steps; a failing walk is minimized and
+                                    written to FILE
+    script <file.rhai> [<trace>] [--depth N] [--refs N] [--steps N] [--seed N]
+           [--out FILE]
+                                    run a Rhai script (needs the scripting
+                                    feature): check its property over all
+                                    traces of up to N operations and take a
+                                    walk steered by its priority function, or
+                                    with a trace, rewrite it with its process
+                                    function; traces go to FILE or stdout
+    serve [--address HOST:PORT] [--sessions N] [--ops M]
+                                    drive machines over HTTP with JSON
+                                    requests (see serve.rs), on
+                                    127.0.0.1:7878 by default, keeping at
+                                    most N sessions (256) of at most M
+                                    operations (100000) each
+    triage list [--index FILE]      list the failures in a triage index
+    triage mark <hash|bundle> [--note TEXT] [--index FILE]
+                                    mark a failure as known, so that fuzzing
+                                    no longer reports it
+    repro <bundle>                  replay a reproducer bundle
+    find-errors [--depth N] [--refs N] [--states N] [--time SECONDS] [--out DIR]
+                                    find the shortest trace triggering each
+                                    kind of error, under any configuration,
+                                    and write them to DIR as a corpus
+    check-corpus <dir>              check that every trace in a corpus is
+                                    rejected as its expect header says
+    run-all <dir> [--jobs N] [--out FILE]
+                                    replay every trace in a directory on N
+                                    threads (4 by default), checking expect
+                                    headers, and print how many ended with
+                                    each verdict; the result of every trace is
+                                    written to FILE (results.json) as JSON
+    miri-compare --programs DIR --results FILE --traces DIR [--report FILE]
+                                    compare the verdicts of the machine with
+                                    Miri's and print a confusion matrix
+    report [--depth N] [--refs N] [--litmus DIR] [--json FILE]
+                                    check which guarantees hold and which
+                                    litmus tests pass under a configuration,
+                                    optionally writing the results as JSON
+    import-litmus <dir> --out DIR   import Stacked/Tree Borrows style litmus
+                                    tests (<name>.rs, below dir) as traces and
+                                    compare them with their expected outcome;
+                                    tests that can't be imported are listed in
+                                    DIR/skipped
+    import-litmus --miri CHECKOUT --out DIR
+                                    the same for the Stacked and Tree Borrows
+                                    tests of a checkout of Miri
+
+Commands that run the machine accept --config key=value (repeatable) to
+change its rules, e.g. --config dup_rule=cap_to_read_only.";
+
+// Positional arguments and "--name value" options.
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = iter
+                        .next()
+                        .ok_or_else(|| format!("missing value for --{}", name))?;
+                    options.push((name.to_string(), value.clone()));
+                }
+                None => positional.push(arg.clone()),
+            }
+        }
+
+        Ok(Args {
+            positional,
+            options,
+        })
+    }
+
+    fn positional(&self, index: usize, what: &str) -> Result<&str, String> {
+        self.positional
+            .get(index)
+            .map(|arg| arg.as_str())
+            .ok_or_else(|| format!("missing {}\n\n{}", what, USAGE))
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, String> {
+        self.get(name)
+            .ok_or_else(|| format!("missing --{}\n\n{}", name, USAGE))
+    }
+
+    fn get_all(&self, name: &str) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn parse_or<T: FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("invalid value for --{}: '{}'", name, value)),
+            None => Ok(default),
+        }
+    }
+
+    fn config(&self) -> Result<MachineConfig, String> {
+        self.config_from(MachineConfig::default())
+    }
+
+    // [base] with the settings given using --config applied on top.
+    fn config_from(&self, base: MachineConfig) -> Result<MachineConfig, String> {
+        let mut config = base;
+        for setting in self.get_all("config") {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected --config key=value, found '{}'", setting))?;
+            trace::apply_config_setting(&mut config, key.trim(), value.trim())?;
+        }
+        Ok(config)
+    }
+}
+
+// Run the command given by [args] (without the program name). Returns the exit
+// code: 0 for success and 1 if the command found a problem.
+pub fn run(args: &[String]) -> Result<i32, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), Args::parse(rest)?),
+        None => return Err(USAGE.to_string()),
+    };
+
+    match command {
+        "replay" => replay(&rest),
+        "repl" => repl(&rest),
+        "metrics" => metrics(&rest),
+        "cost" => trace_cost(&rest),
+        "reads-from" => reads_from(&rest),
+        "lockstep" => lockstep(&rest),
+        "export-json" => export_json(&rest),
+        "fuzz" => fuzz(&rest),
+        "fuzz-parser" => fuzz_parser(&rest),
+        "lint" => lint(&rest),
+        "repro" => repro(&rest),
+        "triage" => triage(&rest),
+        "find-errors" => find_errors(&rest),
+        "check-corpus" => check_corpus(&rest),
+        "run-all" => run_all(&rest),
+        "miri-compare" => miri_compare(&rest),
+        "import-litmus" => import_litmus(&rest),
+        "report" => semantics_report(&rest),
+        "project" => project(&rest),
+        "diff" => diff_traces(&rest),
+        "compare" => compare(&rest),
+        "query" => query(&rest),
+        "explain" => explain(&rest),
+        "simulate" => simulate(&rest),
+        "script" => script(&rest),
+        "serve" => serve(&rest),
+        "orchestrate" => orchestrate(&rest),
+        "help" | "--help" => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        _ => Err(format!("unknown command '{}'\n\n{}", command, USAGE)),
+    }
+}
+
+fn replay(args: &Args) -> Result<i32, String> {
+    let trace = Trace::load(Path::new(args.positional(0, "trace file")?))?;
+
+    match args.get("model") {
+        None | Some("machine2") => {}
+        Some("simple") => {
+            let (verdict, machine) = trace::replay_simple(&trace);
+            if let Some(machine) = machine {
+                println!("{:?}", machine);
+            }
+            println!("{}", verdict);
+            return Ok(0);
+        }
+        Some(model) => {
+            return Err(format!(
+                "unknown model '{}', expected simple or machine2",
+                model
+            ))
+        }
+    }
+
+    let config = args.config_from(trace.config()?.unwrap_or_default())?;
+
+    match args.get("on-error").unwrap_or("stop") {
+        "stop" => {
+            let (verdict, machine) = trace::replay(config, &trace);
+            println!("{:?}", machine);
+            println!("{}", verdict);
+            if let Verdict::Rejected(step, err) = verdict {
+                for change in machine
+                    .kind_diagnostic(trace.ops[step], err)
+                    .unwrap_or_default()
+                {
+                    println!("  {}", change);
+                }
+            }
+        }
+        "continue" => {
+            let (violations, machine) = trace::replay_all(config, &trace);
+            println!("{:?}", machine);
+            for violation in &violations {
+                println!("{}", violation);
+            }
+            println!("{} violations", violations.len());
+        }
+        other => {
+            return Err(format!(
+                "invalid value '{}' for --on-error, expected stop or continue",
+                other
+            ))
+        }
+    }
+
+    Ok(0)
+}
+
+fn repl(args: &Args) -> Result<i32, String> {
+    let stdin = std::io::stdin();
+    repl::run(args.config()?, stdin.lock(), std::io::stdout()).map_err(|e| e.to_string())?;
+    Ok(0)
+}
+
+fn metrics(args: &Args) -> Result<i32, String> {
+    let trace = Trace::load(Path::new(args.positional(0, "trace file")?))?;
+    let config = args.config_from(trace.config()?.unwrap_or_default())?;
+    let (rows, verdict) = metrics::series(config, &trace);
+    let csv = metrics::to_csv(&rows);
+
+    match args.get("out") {
+        Some(out) => fs::write(out, csv).map_err(|e| format!("{}: {}", out, e))?,
+        None => print!("{}", csv),
+    }
+    eprintln!("{}", verdict);
+
+    Ok(0)
+}
+
+fn reads_from(args: &Args) -> Result<i32, String> {
+    let trace = Trace::load(Path::new(args.positional(0, "trace file")?))?;
+    let config = args.config_from(trace.config()?.unwrap_or_default())?;
+    let (reads, verdict) = memory::reads_from(config, &trace);
+
+    for read in &reads {
+        println!("{}", read);
+    }
+    println!("{}", verdict);
+
+    Ok(0)
+}
+
+fn export_json(args: &Args) -> Result<i32, String> {
+    let trace = Trace::load(Path::new(args.positional(0, "trace file")?))?;
+    let config = args.config_from(trace.config()?.unwrap_or_default())?;
+    let json = json::export_trace(config, &trace);
+
+    match args.get("out") {
+        Some(out) => fs::write(out, json).map_err(|e| format!("{}: {}", out, e))?,
+        None => print!("{}", json),
+    }
+
+    Ok(0)
+}
+
+fn diff_traces(args: &Args) -> Result<i32, String> {
+    let a = Trace::load(Path::new(args.positional(0, "first trace file")?))?;
+    let b = Trace::load(Path::new(args.positional(1, "second trace file")?))?;
+    let config_a = args.config_from(a.config()?.unwrap_or_default())?;
+    let config_b = args.config_from(b.config()?.unwrap_or_default())?;
+
+    let diff = diff::diff(&a, &b);
+    let divergence = diff::first_divergence(config_a, &a, config_b, &b, &diff);
+    let lines: Vec<_> = diff::render(&a, &b, &diff)
+        .lines()
+        .map(str::to_string)
+        .collect();
+
+    for (index, line) in lines.iter().enumerate() {
+        println!("{}", line);
+        if let Some(divergence) = divergence.as_ref().filter(|d| d.edit == index) {
+            println!("! the states diverge here: {}", divergence);
+        }
+    }
+
+    let same = diff.edits.iter().all(|edit| matches!(edit, Edit::Same(..)));
+    if divergence.is_none() {
+        println!("the states of corresponding references agree throughout");
+    }
+
+    Ok(if same && divergence.is_none() { 0 } else { 1 })
+}
+
+fn trace_cost(args: &Args) -> Result<i32, String> {
+    args.positional(0, "trace file")?;
+    let mut model = CostModel::default();
+    for setting in args.get_all("cost") {
+        let (name, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("expected --cost name=N, found '{}'", setting))?;
+        let value = value
+            .parse()
+            .map_err(|_| format!("expected a cost, found '{}'", value))?;
+        model.set(name, value)?;
+    }
+
+    let mut rejected = false;
+    for path in &args.positional {
+        let trace = Trace::load(Path::new(path))?;
+        let config = args.config_from(trace.config()?.unwrap_or_default())?;
+        let machine = TokenMachine::init_empty_with(config);
+        let metrics = cost::measure(&model, &machine, &trace.ops);
+        let mut counts: Vec<_> = metrics.counts.iter().collect();
+        counts.sort();
+        let counts: Vec<_> = counts
+            .into_iter()
+            .map(|(name, count)| format!("{} {}", count, name))
+            .collect();
+        println!(
+            "{}: total {}, critical path {} ({})",
+            path,
+            metrics.total,
+            metrics.critical_path,
+            counts.join(", ")
+        );
+        if let Some((step, err)) = metrics.rejected_at {
+            println!("    only up to step {}, which is rejected: {}", step, err);
+            rejected = true;
+        }
+    }
+
+    Ok(if rejected { 1 } else { 0 })
+}
+
+fn query(args: &Args) -> Result<i32, String> {
+    let query = Query::parse(args.positional(0, "query")?)?;
+    args.positional(1, "trace file or directory")?;
+
+    let mut paths = Vec::new();
+    for arg in &args.positional[1..] {
+        let path = Path::new(arg);
+        if !path.is_dir() {
+            paths.push(path.to_path_buf());
+            continue;
+        }
+        let mut traces: Vec<_> = fs::read_dir(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "tbm"))
+            .collect();
+        traces.sort();
+        paths.extend(traces);
+    }
+
+    let (mut total, mut matching) = (0, 0);
+    for path in &paths {
+        let trace = Trace::load(path)?;
+        let config = args.config_from(trace.config()?.unwrap_or_default())?;
+        let matches = query.run(config, &trace);
+        for m in &matches {
+            println!("{}: {}", path.display(), m);
+        }
+        total += matches.len();
+        matching += usize::from(!matches.is_empty());
+    }
+    println!(
+        "{} matches in {} of {} traces",
+        total,
+        matching,
+        paths.len()
+    );
+    Ok(0)
+}
+
+fn explain(args: &Args) -> Result<i32, String> {
+    let trace = Trace::load(Path::new(args.positional(0, "trace file")?))?;
+    let config = args.config_from(trace.config()?.unwrap_or_default())?;
+    let recorded = slice::record(config, &trace);
+
+    let step = match args.positional.get(1) {
+        Some(step) => step
+            .parse()
+            .map_err(|_| format!("invalid step '{}'", step))?,
+        None => recorded.log.events().len().saturating_sub(1),
+    };
+    let explanation = recorded.explain_minimal(step)?;
+    println!("{}", explanation);
+
+    if let Some(out) = args.get("out") {
+        let mut slice = explanation.trace.clone();
+        slice.set_config(&config);
+        slice.save(Path::new(out))?;
+    }
+
+    Ok(0)
+}
+
+fn compare(args: &Args) -> Result<i32, String> {
+    let trace = Trace::load(Path::new(args.positional(0, "trace file")?))?;
+    let config = args.config_from(trace.config()?.unwrap_or_default())?;
+    let settings = args.get_all("with");
+    if settings.is_empty() {
+        return Err("expected at least one --with key=value".to_string());
+    }
+    let mut other = config;
+    for setting in settings {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("expected --with key=value, found '{}'", setting))?;
+        trace::apply_config_setting(&mut other, key.trim(), value.trim())?;
+    }
+
+    let mut sessions = SessionManager::new();
+    for (name, config) in [("base", config), ("with", other)] {
+        println!("{:<5} {}", name, trace::format_config_inline(&config));
+        sessions.create(name, config).map_err(|e| e.to_string())?;
+    }
+
+    match session::first_disagreement(&mut sessions, &trace) {
+        None => {
+            println!("both configurations agree on every step");
+            Ok(0)
+        }
+        Some((step, outcomes)) => {
+            println!(
+                "step {} ('{}') is where they disagree:",
+                step, trace.ops[step]
+            );
+            for (name, outcome) in outcomes {
+                println!("  {:<5} {}", name, session::describe_outcome(&outcome));
+            }
+            Ok(1)
+        }
+    }
+}
+
+fn lockstep(args: &Args) -> Result<i32, String> {
+    let trace = Trace::load(Path::new(args.positional(0, "trace file")?))?;
+    let config = args.config_from(trace.config()?.unwrap_or_default())?;
+    let result = lockstep::lockstep(config, &trace);
+
+    for step in &result.steps {
+        println!("{}", step);
+    }
+
+    match result.divergence {
+        Some(index) => {
+            println!("diverged at step {}", index);
+            Ok(1)
+        }
+        None => {
+            println!("no divergence");
+            Ok(0)
+        }
+    }
+}
+
+fn fuzz(args: &Args) -> Result<i32, String> {
+    let defaults = FuzzOptions::default();
+    let options = FuzzOptions {
+        config: args.config()?,
+        max_len: args.parse_or("len", defaults.max_len)?,
+        max_refs: args.parse_or("refs", defaults.max_refs)?,
+        mutations: args.parse_or("mutations", defaults.mutations)?,
+    };
+    let seed = args.parse_or("seed", 0)?;
+    let runs = args.parse_or("runs", 1000)?;
+    let out = Path::new(args.get("out").unwrap_or("fuzz-failures"));
+    let mut index = Index::load(&triage_index(args, out))?;
+
+    let failures = fuzz::fuzz(&options, seed, runs);
+
+    let mut known = 0;
+    for failure in &failures {
+        let hash = triage::canonical_hash(&options.config, &failure.minimized);
+        if index.get(hash).map(|entry| entry.status) == Some(Status::Known) {
+            known += 1;
+            continue;
+        }
+        let seen = !index.record(hash, &failure.failure.actual);
+
+        let dir = out.join(failure.seed.to_string());
+        Bundle::from_failure(options, failure).write(&dir)?;
+        println!(
+            "seed {}: {} (minimized to {} operations, hash {}{}, bundle in {})",
+            failure.seed,
+            failure.failure.actual,
+            failure.minimized.ops.len(),
+            triage::format_hash(hash),
+            if seen { ", seen before" } else { "" },
+            dir.display()
+        );
+    }
+    if !failures.is_empty() {
+        index.save()?;
+    }
+    println!("{} runs, {} failures", runs, failures.len());
+    if known > 0 {
+        println!("{} of them already known (see tbm triage list)", known);
+    }
+
+    Ok(if failures.len() == known { 0 } else { 1 })
+}
+
+fn fuzz_parser(args: &Args) -> Result<i32, String> {
+    let defaults = FuzzOptions::default();
+    let options = FuzzOptions {
+        config: args.config()?,
+        max_len: args.parse_or("len", defaults.max_len)?,
+        max_refs: args.parse_or("refs", defaults.max_refs)?,
+        mutations: 0,
+    };
+    let seed = args.parse_or("seed", 0)?;
+    let runs = args.parse_or("runs", 1000)?;
+
+    let failures = fuzz::fuzz_parser(&options, seed, runs);
+    if let (Some(out), false) = (args.get("out"), failures.is_empty()) {
+        fs::create_dir_all(out).map_err(|e| format!("{}: {}", out, e))?;
+    }
+    for failure in &failures {
+        println!("seed {}: {}", failure.seed, failure.problem);
+        if let Some(out) = args.get("out") {
+            let path = Path::new(out).join(format!("parser-{}.txt", failure.seed));
+            fs::write(&path, &failure.input).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+    }
+    println!("{} runs, {} failures", runs, failures.len());
+
+    Ok(if failures.is_empty() { 0 } else { 1 })
+}
+
+fn lint(args: &Args) -> Result<i32, String> {
+    let path = args.positional(0, "trace file")?;
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let (trace, errors) = grammar::parse_recovering(&text);
+    for err in &errors {
+        println!("{}: {}", path, grammar::render_error(&text, err));
+    }
+    if errors.len() >= grammar::MAX_ERRORS {
+        println!("(stopped after {} errors)", errors.len());
+    }
+    println!("{} operations, {} errors", trace.ops.len(), errors.len());
+
+    Ok(if errors.is_empty() { 0 } else { 1 })
+}
+
+fn orchestrate(args: &Args) -> Result<i32, String> {
+    let configs = if args.get_all("config").is_empty() {
+        coverage::all_configs()
+    } else {
+        vec![args.config()?]
+    };
+    let pairs = match args.get_all("pair") {
+        pairs if pairs.is_empty() => vec!["sb", "simple"],
+        pairs => pairs,
+    };
+
+    let mut jobs = Vec::new();
+    for pair in pairs {
+        let pair = orchestrate::parse_model_pair(pair)?;
+        jobs.extend(configs.iter().map(|&config| Job { config, pair }));
+    }
+
+    let defaults = FuzzOptions::default();
+    let options = OrchestratorOptions {
+        jobs,
+        max_len: args.parse_or("len", defaults.max_len)?,
+        max_refs: args.parse_or("refs", defaults.max_refs)?,
+        seed: args.parse_or("seed", 0)?,
+        duration: Duration::from_secs(args.parse_or("seconds", 60)?),
+        runs: args
+            .get("runs")
+            .map(|_| args.parse_or("runs", 0))
+            .transpose()?,
+        interval: Duration::from_secs(args.parse_or("interval", 10)?),
+        out: Path::new(args.get("out").unwrap_or("divergences")).to_path_buf(),
+    };
+
+    let stats = orchestrate::orchestrate(&options, |divergences| {
+        for divergence in divergences {
+            println!(
+                "{}: {} ({} operations, seed {})",
+                divergence.job,
+                divergence.description,
+                divergence.minimized.ops.len(),
+                divergence.seed
+            );
+        }
+    })?;
+
+    let runs: u64 = stats.iter().map(|stats| stats.runs).sum();
+    let divergences: u64 = stats.iter().map(|stats| stats.divergences).sum();
+    println!(
+        "{} runs, {} distinct divergences (summary in {})",
+        runs,
+        divergences,
+        orchestrate::summary_path(&options.out).display()
+    );
+
+    Ok(if divergences == 0 { 0 } else { 1 })
+}
+
+// Print [trace], or write it to the file given with --out.
+fn output_trace(args: &Args, trace: &Trace) -> Result<(), String> {
+    match args.get("out") {
+        Some(out) => trace.save(Path::new(out)),
+        None => {
+            print!("{}", trace);
+            Ok(())
+        }
+    }
+}
+
+fn script(args: &Args) -> Result<i32, String> {
+    let script = Script::load(Path::new(args.positional(0, "script file")?))?;
+
+    if let Some(path) = args.positional.get(1) {
+        let trace = Trace::load(Path::new(path))?;
+        if !script.defines("process") {
+            return Err("the script has to define process(ops) to rewrite a trace".to_string());
+        }
+        output_trace(args, &script::post_process(&script, &trace)?)?;
+        return Ok(0);
+    }
+    if !script.defines("property") && !script.defines("priority") {
+        return Err("the script defines neither property nor priority".to_string());
+    }
+
+    let config = args.config()?;
+    let max_refs = args.parse_or("refs", 3)?;
+    let mut status = 0;
+
+    if script.defines("property") {
+        let budget = Budget::depth(args.parse_or("depth", 4)?);
+        match script::check_property(&script, config, budget, max_refs)? {
+            Ok(report) => println!("the property holds after {} traces", report.states),
+            Err((ops, msg)) => {
+                status = 1;
+                println!("{}:", msg);
+                for op in ops {
+                    println!("  {}", op);
+                }
+            }
+        }
+    }
+
+    if script.defines("priority") {
+        let trace = script::guided_walk(
+            &script,
+            config,
+            args.parse_or("steps", 100)?,
+            max_refs,
+            args.parse_or("seed", 0)?,
+        )?;
+        eprintln!("walked {} operations", trace.ops.len());
+        output_trace(args, &trace)?;
+    }
+
+    Ok(status)
+}
+
+fn serve(args: &Args) -> Result<i32, String> {
+    let address = args.get("address").unwrap_or("127.0.0.1:7878");
+    let defaults = serve::Limits::default();
+    let limits = serve::Limits {
+        max_sessions: args.parse_or("sessions", defaults.max_sessions)?,
+        max_ops: args.parse_or("ops", defaults.max_ops)?,
+    };
+    eprintln!("listening on {}", address);
+    serve::serve(address, limits)?;
+    Ok(0)
+}
+
+fn triage_index(args: &Args, out: &Path) -> std::path::PathBuf {
+    match args.get("index") {
+        Some(index) => Path::new(index).to_path_buf(),
+        None => out.join("triage.idx"),
+    }
+}
+
+fn triage(args: &Args) -> Result<i32, String> {
+    let path = triage_index(args, Path::new("fuzz-failures"));
+    let mut index = Index::load(&path)?;
+
+    match args.positional(0, "triage command (list or mark)")? {
+        "list" => {
+            for (hash, entry) in index.entries() {
+                println!(
+                    "{} {:<5} {}",
+                    triage::format_hash(hash),
+                    entry.status,
+                    entry.note
+                );
+            }
+            Ok(0)
+        }
+        "mark" => {
+            let target = args.positional(1, "trace hash or bundle directory")?;
+            let hash = if Path::new(target).is_dir() {
+                let bundle = Bundle::read(Path::new(target))?;
+                triage::canonical_hash(&bundle.options.config, &bundle.minimized)
+            } else {
+                triage::parse_hash(target)?
+            };
+            index.mark(hash, args.get("note"));
+            index.save()?;
+            println!("marked {} as known", triage::format_hash(hash));
+            Ok(0)
+        }
+        command => Err(format!("unknown triage command '{}'\n\n{}", command, USAGE)),
+    }
+}
+
+fn repro(args: &Args) -> Result<i32, String> {
+    let dir = Path::new(args.positional(0, "bundle directory")?);
+    let bundle = Bundle::read(dir)?;
+
+    println!("seed: {}", bundle.seed);
+    println!("expected: {}", bundle.expected);
+    println!("recorded: {}", bundle.actual);
+    if !bundle.seed_regenerates_trace() {
+        println!("note: the seed no longer generates the recorded trace");
+    }
+
+    let mut reproduced = true;
+    for (file, failure) in bundle.reproduce() {
+        match &failure {
+            Some(failure) => println!("{}: {}", file, failure.actual),
+            None => println!("{}: no failure", file),
+        }
+        if file == "trace.tbm" {
+            reproduced = failure.map(|f| f.actual).as_ref() == Some(&bundle.actual);
+        }
+    }
+
+    if reproduced {
+        println!("reproduced");
+        Ok(1)
+    } else {
+        println!("not reproduced");
+        Ok(0)
+    }
+}
+
+fn find_errors(args: &Args) -> Result<i32, String> {
+    let mut budget =
+        Budget::depth(args.parse_or("depth", 8)?).with_states(args.parse_or("states", 20_000)?);
+    if let Some(seconds) = args.get("time") {
+        let seconds = seconds
+            .parse()
+            .map_err(|_| format!("invalid value '{}' for --time", seconds))?;
+        budget = budget.with_time(Duration::from_secs(seconds));
+    }
+    let options = SearchOptions {
+        budget,
+        max_refs: args.parse_or("refs", 4)?,
+    };
+    let out = Path::new(args.get("out").unwrap_or("corpus/errors"));
+
+    let (witnesses, exhausted) = coverage::find_witnesses(&coverage::all_configs(), &options);
+    if let Some(exhausted) = exhausted {
+        eprintln!("search stopped early: {}", exhausted);
+    }
+    coverage::write_corpus(out, &witnesses)?;
+
+    for (name, witness) in &witnesses {
+        println!(
+            "{}: {} operations ({})",
+            name,
+            witness.trace.ops.len(),
+            trace::format_config_inline(&witness.config)
+        );
+    }
+    let missing = coverage::missing(&witnesses);
+    for name in &missing {
+        println!("{}: not found", name);
+    }
+
+    Ok(if missing.is_empty() { 0 } else { 1 })
+}
+
+fn check_corpus(args: &Args) -> Result<i32, String> {
+    let dir = Path::new(args.positional(0, "corpus directory")?);
+
+    let mut failures = 0;
+    for (path, result) in coverage::check_corpus(dir)? {
+        match result {
+            Ok(()) => println!("ok    {}", path.display()),
+            Err(msg) => {
+                failures += 1;
+                println!("FAIL  {}: {}", path.display(), msg);
+            }
+        }
+    }
+
+    Ok(if failures == 0 { 0 } else { 1 })
+}
+
+fn run_all(args: &Args) -> Result<i32, String> {
+    let dir = Path::new(args.positional(0, "trace directory")?);
+    let out = args.get("out").unwrap_or("results.json");
+
+    let start = std::time::Instant::now();
+    let results = batch::run_all(dir, args.parse_or("jobs", 4)?, |base| {
+        args.config_from(base)
+    })?;
+    print!("{}", batch::render_table(&results, start.elapsed()));
+    fs::write(out, batch::to_json(&results)).map_err(|e| format!("{}: {}", out, e))?;
+
+    Ok(if results.iter().any(|result| result.failed()) {
+        1
+    } else {
+        0
+    })
+}
+
+fn miri_compare(args: &Args) -> Result<i32, String> {
+    let comparison = miri::compare(
+        Path::new(args.required("programs")?),
+        Path::new(args.required("results")?),
+        Path::new(args.required("traces")?),
+        args.config()?,
+    )?;
+
+    print_comparison(&comparison);
+
+    if let Some(report) = args.get("report") {
+        let report = Path::new(report);
+        let base = report.parent().unwrap_or_else(|| Path::new(""));
+        fs::write(report, comparison.render_markdown(base))
+            .map_err(|e| format!("{}: {}", report.display(), e))?;
+    }
+
+    Ok(if comparison.divergent().is_empty() {
+        0
+    } else {
+        1
+    })
+}
+
+fn print_comparison(comparison: &miri::Comparison) {
+    println!("                 Miri ok   Miri ub");
+    println!(
+        "model accepts  {:>9} {:>9}",
+        comparison.count(true, true),
+        comparison.count(true, false)
+    );
+    println!(
+        "model rejects  {:>9} {:>9}",
+        comparison.count(false, true),
+        comparison.count(false, false)
+    );
+
+    for case in comparison.divergent() {
+        println!(
+            "divergent: {} (model: {}; {} {})",
+            case.name,
+            case.model,
+            case.program.display(),
+            case.trace.display()
+        );
+    }
+    for (name, reason) in &comparison.skipped {
+        println!("skipped: {}: {}", name, reason);
+    }
+}
+
+fn simulate(args: &Args) -> Result<i32, String> {
+    let mut budget = Budget::depth(args.parse_or("walk", 10_000)?)
+        .with_states(args.parse_or("steps", 1_000_000)?);
+    if let Some(seconds) = args.get("time") {
+        let seconds = seconds
+            .parse()
+            .map_err(|_| format!("invalid value for --time: '{}'", seconds))?;
+        budget = budget.with_time(Duration::from_secs(seconds));
+    }
+    let options = SimulateOptions {
+        config: args.config()?,
+        budget,
+        max_refs: args.parse_or("refs", FuzzOptions::default().max_refs)?,
+        sample_every: args.parse_or("sample", 1000)?.max(1),
+    };
+
+    let simulation = simulate::simulate(&options, args.parse_or("seed", 0)?);
+
+    println!(
+        "{} steps in {} walks ({} stuck), {} samples",
+        simulation.steps,
+        simulation.walks,
+        simulation.stuck,
+        simulation.samples.len()
+    );
+    if let Some(exhausted) = simulation.report.exhausted {
+        println!("stopped: {}", exhausted);
+    }
+    println!("{:<14} {:>7} {:>9} {:>7}", "", "min", "mean", "max");
+    for (name, min, mean, max, growing) in
+        simulate::summarize(&simulation.samples, budget.max_depth)
+    {
+        println!(
+            "{:<14} {:>7} {:>9.1} {:>7}{}",
+            name,
+            min,
+            mean,
+            max,
+            if growing {
+                "  (grows with walk length)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    match simulation.failure {
+        None => Ok(0),
+        Some(failure) => {
+            println!("failure {}", failure);
+            if let Some(out) = args.get("out") {
+                let mut trace = failure.minimized.unwrap_or(failure.trace);
+                trace.set_config(&options.config);
+                trace.save(Path::new(out))?;
+                println!("written to {}", out);
+            }
+            Ok(1)
+        }
+    }
+}
+
+fn project(args: &Args) -> Result<i32, String> {
+    if let Ok(path) = args.positional(0, "trace file") {
+        let trace = Trace::load(Path::new(path))?;
+        let config = args.config_from(trace.config()?.unwrap_or_default())?;
+        let projection = projection::project(config, &trace)?;
+        println!("{}", projection);
+        return Ok(match projection {
+            projection::Projection::Holds { .. } => 0,
+            projection::Projection::Broken(_) => 1,
+        });
+    }
+
+    let census = projection::census(
+        args.config()?,
+        args.parse_or("depth", 4)?,
+        args.parse_or("refs", 3)?,
+    );
+    for (feature, (count, example)) in &census {
+        let example: Vec<_> = example.iter().map(|op| op.to_string()).collect();
+        println!(
+            "{:<18} {:>7} traces, e.g. {}",
+            feature.to_string(),
+            count,
+            if example.is_empty() {
+                "the initial state".to_string()
+            } else {
+                example.join("; ")
+            }
+        );
+    }
+    if census.is_empty() {
+        println!("the projection holds along every trace");
+    }
+
+    Ok(0)
+}
+
+fn semantics_report(args: &Args) -> Result<i32, String> {
+    let report = report::generate(
+        args.config()?,
+        args.parse_or("depth", 4)?,
+        args.parse_or("refs", 3)?,
+        Path::new(args.get("litmus").unwrap_or("corpus/litmus")),
+    )?;
+
+    print!("{}", report.render_table());
+    if let Some(out) = args.get("json") {
+        fs::write(out, report.to_json()).map_err(|e| format!("{}: {}", out, e))?;
+    }
+
+    Ok(0)
+}
+
+// The tests are compared in the same way as programs run through Miri, with
+// their expected outcome standing in for Miri's verdict.
+fn import_litmus(args: &Args) -> Result<i32, String> {
+    let out = Path::new(args.required("out")?);
+    let config = args.config()?;
+
+    let (programs, tests) = match args.get("miri") {
+        Some(checkout) => litmus::import_miri(config, Path::new(checkout))?,
+        None => {
+            let dir = Path::new(args.positional(0, "litmus test directory")?);
+            (dir.to_path_buf(), litmus::import_all(config, dir)?)
+        }
+    };
+    let (imported, unsupported) = litmus::write_imported(tests, out)?;
+    println!("imported {} tests to {}", imported.len(), out.display());
+    for (name, reason) in &unsupported {
+        println!("unsupported: {}: {}", name, reason);
+    }
+    if !unsupported.is_empty() {
+        println!(
+            "skipped {} tests, listed in {}",
+            unsupported.len(),
+            out.join("skipped").display()
+        );
+    }
+
+    let comparison = miri::compare(&programs, &out.join("expected"), out, config)?;
+    print_comparison(&comparison);
+
+    Ok(if comparison.divergent().is_empty() {
+        0
+    } else {
+        1
+    })
+}
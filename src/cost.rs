@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use crate::machine2::{MachineError, Operation, TokenMachine};
+use crate::slice::{self, Footprint, Resource};
+
+// Abstract costs of the operations, for comparing how much "borrow-checking
+// work" different lowerings of the same program need. Accesses are free by
+// default, since every lowering has to perform the same accesses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CostModel {
+    pub new_root: u64,
+    pub create_ref: u64,
+    pub borrow: u64,
+    pub return_token: u64,
+    pub dup: u64,
+    pub merge: u64,
+    pub set_perms: u64,
+    pub access: u64,
+    pub reclaim: u64,
+    // Entering or returning from a function, which is free for the same
+    // reason as accesses.
+    pub call: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            new_root: 0,
+            create_ref: 1,
+            borrow: 1,
+            return_token: 1,
+            dup: 1,
+            merge: 1,
+            set_perms: 1,
+            access: 0,
+            reclaim: 1,
+            call: 0,
+        }
+    }
+}
+
+impl CostModel {
+    // Change the cost of the operations named by [name], one of the fields
+    // above (e.g. "borrow").
+    pub fn set(&mut self, name: &str, cost: u64) -> Result<(), String> {
+        let field = match name {
+            "new_root" => &mut self.new_root,
+            "create_ref" => &mut self.create_ref,
+            "borrow" => &mut self.borrow,
+            "return_token" => &mut self.return_token,
+            "dup" => &mut self.dup,
+            "merge" => &mut self.merge,
+            "set_perms" => &mut self.set_perms,
+            "access" => &mut self.access,
+            "reclaim" => &mut self.reclaim,
+            "call" => &mut self.call,
+            _ => return Err(format!("unknown operation cost '{}'", name)),
+        };
+        *field = cost;
+        Ok(())
+    }
+
+    pub fn cost(&self, op: Operation) -> u64 {
+        match op {
+            Operation::NewRoot | Operation::NewConstRoot => self.new_root,
+            Operation::CreateRef(..) => self.create_ref,
+            Operation::Borrow(_) => self.borrow,
+            Operation::Return(_) => self.return_token,
+            Operation::Dup(_) => self.dup,
+            Operation::Merge(_) => self.merge,
+            Operation::SetPerms(..) => self.set_perms,
+            Operation::Use(..) => self.access,
+            Operation::ReclaimExclusive(_) => self.reclaim,
+            Operation::Call(_) | Operation::Arg(_) | Operation::Ret => self.call,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceMetrics {
+    // Sum of the costs of all accepted operations.
+    pub total: u64,
+    // Cost of the most expensive chain of operations that depend on each
+    // other. Operations that only involve unrelated references (for instance
+    // in different trees) could be performed independently, so this is the
+    // cost that remains if the independent parts are done side by side. An
+    // operation depends on the last earlier one that changed something it
+    // uses (by the footprints of slice.rs), and an operation that changes
+    // something also depends on every earlier one that read it since.
+    // Operations inside a call read the calls in progress, which the call,
+    // its arguments and its return change, so they all happen between those.
+    pub critical_path: u64,
+    // How many accepted operations there were of each kind, e.g. "Borrow".
+    pub counts: HashMap<&'static str, usize>,
+    // The first operation that was rejected, if any. Metrics only cover the
+    // operations before it.
+    pub rejected_at: Option<(usize, MachineError)>,
+}
+
+fn op_name(op: Operation) -> &'static str {
+    match op {
+        Operation::NewRoot => "NewRoot",
+        Operation::NewConstRoot => "NewConstRoot",
+        Operation::CreateRef(..) => "CreateRef",
+        Operation::Borrow(_) => "Borrow",
+        Operation::Return(_) => "Return",
+        Operation::Dup(_) => "Dup",
+        Operation::Merge(_) => "Merge",
+        Operation::SetPerms(..) => "SetPerms",
+        Operation::Use(..) => "Use",
+        Operation::ReclaimExclusive(_) => "ReclaimExclusive",
+        Operation::Call(_) => "Call",
+        Operation::Arg(_) => "Arg",
+        Operation::Ret => "Ret",
+    }
+}
+
+// Replay [trace] from [machine] and compute its cost under [model].
+pub fn measure(model: &CostModel, machine: &TokenMachine, trace: &[Operation]) -> TraceMetrics {
+    let mut metrics = TraceMetrics {
+        total: 0,
+        critical_path: 0,
+        counts: HashMap::new(),
+        rejected_at: None,
+    };
+
+    // For every resource, when the last operation that changed it finishes,
+    // and when the last of the operations that read it since finishes.
+    let mut written: HashMap<Resource, u64> = HashMap::new();
+    let mut read: HashMap<Resource, u64> = HashMap::new();
+    let mut state = machine.clone();
+
+    for (i, &op) in trace.iter().enumerate() {
+        let (outcome, Footprint { reads, writes }) = slice::apply_observed(&mut state, op);
+        if let Err(err) = outcome {
+            metrics.rejected_at = Some((i, err));
+            break;
+        }
+
+        let cost = model.cost(op);
+        let after =
+            |times: &HashMap<Resource, u64>, res: &Resource| times.get(res).copied().unwrap_or(0);
+        let start = reads
+            .iter()
+            .chain(&writes)
+            .map(|res| after(&written, res))
+            .chain(writes.iter().map(|res| after(&read, res)))
+            .max()
+            .unwrap_or(0);
+        let finish = start + cost;
+        for res in reads {
+            let last = read.entry(res).or_insert(0);
+            *last = (*last).max(finish);
+        }
+        for res in writes {
+            written.insert(res, finish);
+            read.remove(&res);
+        }
+
+        metrics.total += cost;
+        metrics.critical_path = metrics.critical_path.max(finish);
+        *metrics.counts.entry(op_name(op)).or_insert(0) += 1;
+    }
+
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine2::MachineConfig;
+    use crate::trace::Trace;
+
+    fn measure_text(model: &CostModel, text: &str) -> TraceMetrics {
+        let trace = Trace::parse(text).unwrap();
+        let machine = TokenMachine::init_empty_with(MachineConfig::default());
+        measure(model, &machine, &trace.ops)
+    }
+
+    #[test]
+    fn independent_trees_overlap() {
+        let metrics = measure_text(
+            &CostModel::default(),
+            "r0 = root\nr1 = root\nr2 = create r0 unique\nr3 = create r1 unique\nborrow r2\nborrow r3\n",
+        );
+        assert_eq!((metrics.total, metrics.critical_path), (4, 2));
+        assert_eq!(metrics.counts["Borrow"], 2);
+        assert_eq!(metrics.rejected_at, None);
+    }
+
+    #[test]
+    fn reclaiming_waits_for_the_descendants() {
+        // The reclaim comes after creating r3, which only touches r2 and r3.
+        let metrics = measure_text(
+            &CostModel::default(),
+            "r0 = root\nr1 = create r0 shared_ro\ndup r0\nborrow r1\n\
+             r2 = create r1 shared_ro\nr3 = create r2 shared_ro\nreclaim r0\n",
+        );
+        assert_eq!((metrics.total, metrics.critical_path), (6, 6));
+    }
+
+    #[test]
+    fn operations_in_a_call_happen_between_the_call_and_its_return() {
+        let mut model = CostModel::default();
+        model.set("call", 1).unwrap();
+        // The creation of r4 in another tree can overlap with the whole call,
+        // but the return has to wait for the creation of r3 inside it.
+        let metrics = measure_text(
+            &model,
+            "r0 = root\nr1 = root\nr2 = create r1 unique\ncall r0\n\
+             r3 = create r0 unique\nret\nr4 = create r2 unique\n",
+        );
+        assert_eq!((metrics.total, metrics.critical_path), (5, 3));
+        assert!(model.set("frobnicate", 1).is_err());
+    }
+
+    #[test]
+    fn borrows_wait_for_accesses_to_the_same_tree() {
+        let mut model = CostModel::default();
+        model.set("access", 1).unwrap();
+        // Borrowing r3 only touches r1 and r3, but moves pieces away from a
+        // writer, which the read through r2 checks.
+        let metrics = measure_text(
+            &model,
+            "r0 = root\ndup r0\nr1 = create r0 shared_rw\nr2 = create r0 shared_ro\n\
+             borrow r1\nborrow r2\nr3 = create r1 shared_ro\nborrow r3\nuse r2 read\n",
+        );
+        assert_eq!((metrics.total, metrics.critical_path), (8, 6));
+    }
+}
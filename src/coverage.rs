@@ -0,0 +1,237 @@
+// Search for the shortest trace that triggers each kind of MachineError, to
+// keep the error taxonomy honest: every variant should be reachable, and the
+// traces found serve as a corpus of negative tests.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::budget::{Budget, Exhausted, Meter};
+use crate::explore;
+use crate::machine2::{
+    AccessKind, DupRule, MachineConfig, MachineError, Operation, Reference, ReturnRule,
+    TokenMachine, ERROR_NAMES,
+};
+use crate::trace::{self, Trace, Verdict};
+
+#[derive(Debug, Clone)]
+pub struct Witness {
+    pub config: MachineConfig,
+    // Accepted operations followed by the one that is rejected.
+    pub trace: Trace,
+    pub error: MachineError,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SearchOptions {
+    // The depth is the maximum number of accepted operations before the
+    // rejected one, and the maximum number of states applies to each
+    // configuration separately. The time budget is shared.
+    pub budget: Budget,
+    pub max_refs: usize,
+}
+
+// Every combination of rule variants, starting from the default root.
+pub fn all_configs() -> Vec<MachineConfig> {
+    let mut configs = Vec::new();
+    for &dup_rule in &[
+        DupRule::Unrestricted,
+        DupRule::CapToReadOnly,
+        DupRule::RequireReadOnly,
+    ] {
+        for &return_rule in &[ReturnRule::Strict, ReturnRule::Partial] {
+            configs.push(MachineConfig {
+                dup_rule,
+                return_rule,
+                ..MachineConfig::default()
+            });
+        }
+    }
+    configs
+}
+
+fn candidates(machine: &TokenMachine, max_refs: usize) -> Vec<Operation> {
+    let mut ops = explore::candidate_operations(machine, max_refs);
+    // Also refer to a reference that does not exist (yet).
+    let refs = machine.references();
+    let dangling = (0..)
+        .map(Reference::from_id)
+        .find(|r| !refs.contains(r))
+        .unwrap();
+    ops.push(Operation::Use(dangling, AccessKind::Read));
+    ops
+}
+
+// Breadth-first search over the accepted traces of every configuration in
+// [configs]. The first time an error is encountered in a configuration, the
+// trace leading to it is as short as possible for that configuration; among
+// configurations, the shortest witness (or the earliest configuration, in
+// case of a tie) is kept. The search for a configuration stops early once
+// every kind of error has a witness.
+//
+// Also returns why the search was cut short, if it was. Running out of states
+// in one configuration only stops the search from going deeper in that
+// configuration; running out of time stops it altogether.
+pub fn find_witnesses(
+    configs: &[MachineConfig],
+    options: &SearchOptions,
+) -> (BTreeMap<&'static str, Witness>, Option<Exhausted>) {
+    let mut witnesses: BTreeMap<&'static str, Witness> = BTreeMap::new();
+    let mut exhausted = None;
+    let started = Instant::now();
+
+    for &config in configs {
+        let mut meter = Meter::resume(options.budget, started);
+        let mut machine = TokenMachine::init_empty_with(config);
+        machine.apply(Operation::NewRoot).unwrap();
+
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        if !meter.visit() {
+            exhausted = meter.exhausted();
+            break;
+        }
+        visited.insert(machine.clone());
+        queue.push_back((machine, vec![Operation::NewRoot]));
+
+        while let Some((state, ops)) = queue.pop_front() {
+            if witnesses.len() == ERROR_NAMES.len() {
+                break;
+            }
+            if meter.exhausted() == Some(Exhausted::Time) {
+                break;
+            }
+
+            for op in candidates(&state, options.max_refs) {
+                match state.step(op) {
+                    Err((error, _)) => {
+                        let shorter = witnesses
+                            .get(error.name())
+                            .is_none_or(|w| w.trace.ops.len() > ops.len() + 1);
+                        if shorter {
+                            let mut trace_ops = ops.clone();
+                            trace_ops.push(op);
+                            witnesses.insert(
+                                error.name(),
+                                Witness {
+                                    config,
+                                    trace: Trace::new(trace_ops),
+                                    error,
+                                },
+                            );
+                        }
+                    }
+                    Ok((next, _)) => {
+                        if ops.len() < options.budget.max_depth
+                            && !visited.contains(&next)
+                            && meter.visit()
+                        {
+                            let mut next_ops = ops.clone();
+                            next_ops.push(op);
+                            visited.insert(next.clone());
+                            queue.push_back((next, next_ops));
+                        }
+                    }
+                }
+            }
+        }
+
+        if meter.exhausted().is_some() {
+            exhausted = meter.exhausted();
+        }
+        if exhausted == Some(Exhausted::Time) {
+            break;
+        }
+    }
+
+    (witnesses, exhausted)
+}
+
+// The kinds of errors for which no witness was found.
+pub fn missing(witnesses: &BTreeMap<&'static str, Witness>) -> Vec<&'static str> {
+    ERROR_NAMES
+        .iter()
+        .copied()
+        .filter(|name| !witnesses.contains_key(name))
+        .collect()
+}
+
+// Write every witness to [dir] as <ErrorName>.tbm, with headers giving the
+// configuration and the expected rejection ("#! expect ErrorName at 3").
+pub fn write_corpus(dir: &Path, witnesses: &BTreeMap<&'static str, Witness>) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    for (name, witness) in witnesses {
+        let mut trace = witness.trace.clone();
+        trace.set_config(&witness.config);
+        trace.set_header(
+            "expect",
+            format!("{} at {}", name, witness.trace.ops.len() - 1),
+        );
+        trace.save(&dir.join(format!("{}.tbm", name)))?;
+    }
+
+    Ok(())
+}
+
+// Replay a trace with an "expect" header and check that it is rejected in
+// the expected way.
+pub fn check_expectation(trace: &Trace) -> Result<(), String> {
+    let expect = trace.header("expect").ok_or("missing expect header")?;
+    let (name, step) = expect
+        .split_once(" at ")
+        .and_then(|(name, step)| Some((name.trim(), step.trim().parse::<usize>().ok()?)))
+        .ok_or_else(|| format!("cannot parse expect header '{}'", expect))?;
+
+    let config = trace.config()?.unwrap_or_default();
+    match trace::replay(config, trace).0 {
+        Verdict::Rejected(s, err) if s == step && err.name() == name => Ok(()),
+        verdict => Err(format!(
+            "expected {} at step {}, but trace was {}",
+            name, step, verdict
+        )),
+    }
+}
+
+pub type CorpusResults = Vec<(PathBuf, Result<(), String>)>;
+
+// Check every .tbm file in [dir] against its expectation.
+pub fn check_corpus(dir: &Path) -> Result<CorpusResults, String> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "tbm"))
+        .collect();
+    paths.sort();
+
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let result = Trace::load(&path).and_then(|trace| check_expectation(&trace));
+            (path, result)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::ContentHash;
+
+    #[test]
+    fn the_dangling_reference_does_not_exist() {
+        let mut machine = TokenMachine::init_empty_with(MachineConfig::default());
+        machine.set_id_allocator(ContentHash { namespace: 0 });
+        for _ in 0..3 {
+            machine.new_root();
+        }
+        let refs = machine.references();
+        let dangling: Vec<_> = candidates(&machine, 4)
+            .into_iter()
+            .flat_map(Operation::references)
+            .filter(|r| !refs.contains(r))
+            .collect();
+        assert_eq!(dangling.len(), 1);
+    }
+}
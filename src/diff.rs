@@ -0,0 +1,243 @@
+// Comparing two traces, e.g. those generated for the same program by two
+// versions of a frontend. The traces are aligned operation by operation, like
+// lines in a textual diff, except that references are compared up to
+// renaming: two creating operations that are aligned make the references they
+// create correspond, and two operations are the same if they do the same to
+// corresponding references. Both traces are then replayed along the
+// alignment, to find the first point at which corresponding references are
+// no longer in the same state.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::machine2::{MachineConfig, Operation, Reference, TokenMachine};
+use crate::trace::{self, Trace};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Edit {
+    // Operation i of the first trace is operation j of the second.
+    Same(usize, usize),
+    // Operation i of the first trace became operation j of the second.
+    Changed(usize, usize),
+    // Operation i of the first trace is not in the second.
+    Deleted(usize),
+    // Operation j of the second trace is not in the first.
+    Inserted(usize),
+}
+
+// [op] with every reference replaced by the same placeholder, so that
+// operations can be aligned before it is known which references correspond.
+fn shape(op: Operation) -> Operation {
+    op.map_references(|_| Reference::from_id(0))
+}
+
+// The alignment with the fewest deletions, insertions and changes, where
+// operations of the same shape can be aligned for free.
+fn edit_script(a: &[Operation], b: &[Operation]) -> Vec<Edit> {
+    let (n, m) = (a.len(), b.len());
+    // cost[i][j] is the cost of aligning a[i..] with b[j..].
+    let mut cost = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            cost[i][j] = if i == n {
+                m - j
+            } else if j == m {
+                n - i
+            } else {
+                let align = if shape(a[i]) == shape(b[j]) { 0 } else { 1 };
+                (cost[i + 1][j + 1] + align)
+                    .min(cost[i + 1][j] + 1)
+                    .min(cost[i][j + 1] + 1)
+            };
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m {
+            let align = if shape(a[i]) == shape(b[j]) { 0 } else { 1 };
+            if cost[i][j] == cost[i + 1][j + 1] + align {
+                edits.push(Edit::Changed(i, j));
+                i += 1;
+                j += 1;
+                continue;
+            }
+        }
+        if i < n && cost[i][j] == cost[i + 1][j] + 1 {
+            edits.push(Edit::Deleted(i));
+            i += 1;
+        } else {
+            edits.push(Edit::Inserted(j));
+            j += 1;
+        }
+    }
+    edits
+}
+
+// The references created by every operation of [ops], as replay would number
+// them.
+fn created_refs(ops: &[Operation]) -> Vec<Option<Reference>> {
+    let mut count = 0;
+    ops.iter()
+        .map(|&op| {
+            if trace::creates_reference(op) {
+                count += 1;
+                Some(Reference::from_id(count - 1))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Diff {
+    pub edits: Vec<Edit>,
+    // The reference of the second trace that corresponds to each reference of
+    // the first.
+    pub renaming: HashMap<Reference, Reference>,
+}
+
+// Align [a] with [b], telling apart the aligned operations that are the same
+// up to renaming from those that changed.
+pub fn diff(a: &Trace, b: &Trace) -> Diff {
+    let (created_a, created_b) = (created_refs(&a.ops), created_refs(&b.ops));
+    let mut renaming = HashMap::new();
+
+    let edits = edit_script(&a.ops, &b.ops)
+        .into_iter()
+        .map(|edit| match edit {
+            Edit::Changed(i, j) => {
+                let (op_a, op_b) = (a.ops[i], b.ops[j]);
+                let same = shape(op_a) == shape(op_b)
+                    && op_a
+                        .references()
+                        .iter()
+                        .zip(op_b.references())
+                        .all(|(r, s)| renaming.get(r) == Some(&s));
+                if let (Some(r), Some(s)) = (created_a[i], created_b[j]) {
+                    renaming.insert(r, s);
+                }
+                if same {
+                    Edit::Same(i, j)
+                } else {
+                    Edit::Changed(i, j)
+                }
+            }
+            edit => edit,
+        })
+        .collect();
+
+    Diff { edits, renaming }
+}
+
+// Where replaying both traces along the alignment first goes differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDivergence {
+    // The index of the edit after which the states differ.
+    pub edit: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for StateDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+// What can be compared about a reference across machines.
+fn describe(machine: &TokenMachine, r: Reference) -> String {
+    let pieces = machine.get_token_info(r).map_or(0, |info| info.pieces_held);
+    format!(
+        "{:?} {:?}, {} pieces, {:?} token",
+        machine.state_of(r),
+        machine.kind_of(r),
+        pieces,
+        machine.token_perms(r)
+    )
+}
+
+// Replay both traces along [diff] and compare the states of corresponding
+// references after every edit. Replay stops at the first rejected operation
+// of either trace, which counts as a divergence unless the other trace
+// rejects its counterpart as well.
+pub fn first_divergence(
+    config_a: MachineConfig,
+    a: &Trace,
+    config_b: MachineConfig,
+    b: &Trace,
+    diff: &Diff,
+) -> Option<StateDivergence> {
+    let mut machine_a = TokenMachine::init_empty_with(config_a);
+    let mut machine_b = TokenMachine::init_empty_with(config_b);
+    let mut renaming: Vec<(Reference, Reference)> = Vec::new();
+    let (created_a, created_b) = (created_refs(&a.ops), created_refs(&b.ops));
+
+    for (index, &edit) in diff.edits.iter().enumerate() {
+        let diverged = |reason: String| {
+            Some(StateDivergence {
+                edit: index,
+                reason,
+            })
+        };
+        let (step_a, step_b) = match edit {
+            Edit::Same(i, j) | Edit::Changed(i, j) => (Some(i), Some(j)),
+            Edit::Deleted(i) => (Some(i), None),
+            Edit::Inserted(j) => (None, Some(j)),
+        };
+
+        let result_a = step_a.map(|i| machine_a.apply(a.ops[i]));
+        let result_b = step_b.map(|j| machine_b.apply(b.ops[j]));
+        match (result_a, result_b) {
+            (Some(Err(_)), Some(Err(_))) => return None,
+            (Some(Err(err)), _) => {
+                return diverged(format!(
+                    "the first trace is rejected at step {}: {}",
+                    step_a.unwrap(),
+                    err
+                ))
+            }
+            (_, Some(Err(err))) => {
+                return diverged(format!(
+                    "the second trace is rejected at step {}: {}",
+                    step_b.unwrap(),
+                    err
+                ))
+            }
+            _ => {}
+        }
+
+        if let (Some(i), Some(j)) = (step_a, step_b) {
+            if let (Some(r), Some(s)) = (created_a[i], created_b[j]) {
+                renaming.push((r, s));
+            }
+        }
+        for &(r, s) in &renaming {
+            let (state_a, state_b) = (describe(&machine_a, r), describe(&machine_b, s));
+            if state_a != state_b {
+                return diverged(format!(
+                    "{} of the first trace is {}, but {} of the second is {}",
+                    r, state_a, s, state_b
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+// The alignment as a textual diff: " " for operations that are the same, "-"
+// and "+" for deleted and inserted ones, and "~" for changed ones.
+pub fn render(a: &Trace, b: &Trace, diff: &Diff) -> String {
+    let mut lines = Vec::new();
+    for edit in &diff.edits {
+        lines.push(match *edit {
+            Edit::Same(i, _) => format!("  {}", a.ops[i]),
+            Edit::Changed(i, j) => format!("~ {} => {}", a.ops[i], b.ops[j]),
+            Edit::Deleted(i) => format!("- {}", a.ops[i]),
+            Edit::Inserted(j) => format!("+ {}", b.ops[j]),
+        });
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,49 @@
+use crate::machine2::{MachineError, Operation, PermChange, Reference};
+
+// The outcome of an operation, as returned by TokenMachine::apply.
+pub type Outcome = Result<Option<Reference>, MachineError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    // Position of the event in the log. Events are numbered in the order in
+    // which the operations were applied to the machine.
+    pub seq: u64,
+    pub op: Operation,
+    pub outcome: Outcome,
+    // Set if the operation changed the permissions of a token, either
+    // explicitly or as a side effect of duplicating it.
+    pub perm_change: Option<PermChange>,
+}
+
+// A record of every operation that was attempted on a machine, including the
+// rejected ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventLog {
+    events: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        EventLog { events: Vec::new() }
+    }
+
+    pub fn record(
+        &mut self,
+        op: Operation,
+        outcome: Outcome,
+        perm_change: Option<PermChange>,
+    ) -> u64 {
+        let seq = self.events.len() as u64;
+        self.events.push(Event {
+            seq,
+            op,
+            outcome,
+            perm_change,
+        });
+        seq
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+}
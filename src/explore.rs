@@ -0,0 +1,122 @@
+use crate::budget::{Budget, Meter, SearchReport};
+use crate::machine2::{AccessKind, Operation, RefKind, TokenMachine, TokenPermissions};
+use crate::profiling::{self, Counter};
+
+const REF_KINDS: [RefKind; 3] = [
+    RefKind::SharedReadOnly,
+    RefKind::SharedReadWrite,
+    RefKind::Unique,
+];
+
+const ACCESS_KINDS: [AccessKind; 2] = [AccessKind::Read, AccessKind::Write];
+
+const PERMISSIONS: [TokenPermissions; 2] =
+    [TokenPermissions::ReadOnly, TokenPermissions::ReadWrite];
+
+// Every operation that could be attempted in the given state. Most of them
+// will be rejected by the machine. New references (including new roots) are
+// only proposed while fewer than [max_refs] references exist, since otherwise
+// the number of traces of a given length is unbounded.
+pub fn candidate_operations(machine: &TokenMachine, max_refs: usize) -> Vec<Operation> {
+    let refs = machine.references();
+    let mut ops = Vec::new();
+
+    if refs.len() < max_refs {
+        ops.push(Operation::NewRoot);
+        ops.push(Operation::NewConstRoot);
+    }
+
+    for &r in &refs {
+        if refs.len() < max_refs {
+            for &kind in &REF_KINDS {
+                ops.push(Operation::CreateRef(r, kind));
+            }
+        }
+
+        ops.push(Operation::Borrow(r));
+        ops.push(Operation::Return(r));
+        ops.push(Operation::Dup(r));
+        ops.push(Operation::Merge(r));
+
+        for &perms in &PERMISSIONS {
+            ops.push(Operation::SetPerms(r, perms));
+        }
+
+        for &access_kind in &ACCESS_KINDS {
+            ops.push(Operation::Use(r, access_kind));
+        }
+
+        ops.push(Operation::ReclaimExclusive(r));
+        ops.push(Operation::Call(r));
+        ops.push(Operation::Arg(r));
+    }
+    ops.push(Operation::Ret);
+
+    ops
+}
+
+// Call [visit] on every trace of at most [depth] accepted operations starting
+// from [machine] (including the empty trace), together with the state the
+// trace ends in.
+pub fn for_each_trace<F>(machine: &TokenMachine, depth: usize, max_refs: usize, visit: &mut F)
+where
+    F: FnMut(&[Operation], &TokenMachine),
+{
+    for_each_trace_within(machine, Budget::depth(depth), max_refs, visit);
+}
+
+// Like for_each_trace, but stops once [budget] runs out. Every state visited
+// before that has been passed to [visit].
+pub fn for_each_trace_within<F>(
+    machine: &TokenMachine,
+    budget: Budget,
+    max_refs: usize,
+    visit: &mut F,
+) -> SearchReport
+where
+    F: FnMut(&[Operation], &TokenMachine),
+{
+    let mut meter = Meter::start(budget);
+    let mut trace = Vec::new();
+    for_each_trace_from(
+        machine,
+        &mut trace,
+        budget.max_depth,
+        max_refs,
+        &mut meter,
+        visit,
+    );
+    meter.report()
+}
+
+fn for_each_trace_from<F>(
+    machine: &TokenMachine,
+    trace: &mut Vec<Operation>,
+    depth: usize,
+    max_refs: usize,
+    meter: &mut Meter,
+    visit: &mut F,
+) where
+    F: FnMut(&[Operation], &TokenMachine),
+{
+    if !meter.visit() {
+        return;
+    }
+    profiling::count(Counter::StatesExpanded);
+    visit(trace, machine);
+
+    if depth == 0 {
+        return;
+    }
+
+    for op in candidate_operations(machine, max_refs) {
+        let next = match machine.step(op) {
+            Ok((next, _)) => next,
+            Err(_) => continue,
+        };
+
+        trace.push(op);
+        for_each_trace_from(&next, trace, depth - 1, max_refs, meter, visit);
+        trace.pop();
+    }
+}
@@ -0,0 +1,177 @@
+// Reusable trace fragments for patterns that come up in many experiments. The
+// fragments are written against a TraceBuilder, which keeps track of the IDs
+// that new references will get, and can also be expanded in trace files with
+// "expand <fragment> <arguments>" (see trace.rs).
+
+use crate::machine2::{AccessKind, Operation, RefKind, Reference, TokenPermissions};
+use crate::trace::{self, Trace};
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceBuilder {
+    ops: Vec<Operation>,
+    next_id: u32,
+}
+
+impl TraceBuilder {
+    pub fn new() -> Self {
+        TraceBuilder::default()
+    }
+
+    // A builder for operations that will follow a trace that has already
+    // created [created] references.
+    pub fn continuing(created: u32) -> Self {
+        TraceBuilder {
+            ops: Vec::new(),
+            next_id: created,
+        }
+    }
+
+    pub fn root(&mut self) -> Reference {
+        self.op(Operation::NewRoot);
+        Reference::from_id(self.next_id - 1)
+    }
+
+    pub fn create(&mut self, parent: Reference, kind: RefKind) -> Reference {
+        self.op(Operation::CreateRef(parent, kind));
+        Reference::from_id(self.next_id - 1)
+    }
+
+    pub fn op(&mut self, op: Operation) -> &mut Self {
+        if let Operation::NewRoot | Operation::NewConstRoot | Operation::CreateRef(..) = op {
+            self.next_id += 1;
+        }
+        self.ops.push(op);
+        self
+    }
+
+    pub fn ops(&self) -> &[Operation] {
+        &self.ops
+    }
+
+    pub fn build(self) -> Trace {
+        Trace::new(self.ops)
+    }
+}
+
+// Create a reference of [kind] from [parent], borrow the token for it, access
+// it once and hand the token back.
+pub fn reborrow_and_return(
+    b: &mut TraceBuilder,
+    parent: Reference,
+    kind: RefKind,
+    access: AccessKind,
+) -> Reference {
+    let child = b.create(parent, kind);
+    b.op(Operation::Borrow(child))
+        .op(Operation::Use(child, access))
+        .op(Operation::Return(child));
+    child
+}
+
+// Make the token of [parent] read-only and split it so that [readers] shared
+// read-only references derived from it can all hold a piece at the same time,
+// let each of them read, and then collect and merge the pieces again and make
+// the token writable again. [parent] must hold its token exclusively.
+pub fn shared_fan_out(b: &mut TraceBuilder, parent: Reference, readers: usize) -> Vec<Reference> {
+    let children: Vec<_> = (0..readers)
+        .map(|_| b.create(parent, RefKind::SharedReadOnly))
+        .collect();
+
+    b.op(Operation::SetPerms(parent, TokenPermissions::ReadOnly));
+    for _ in 1..readers {
+        b.op(Operation::Dup(parent));
+    }
+    for &child in &children {
+        b.op(Operation::Borrow(child));
+    }
+    for &child in &children {
+        b.op(Operation::Use(child, AccessKind::Read));
+    }
+    for &child in &children {
+        b.op(Operation::Return(child));
+    }
+    for _ in 1..readers {
+        b.op(Operation::Merge(parent));
+    }
+    b.op(Operation::SetPerms(parent, TokenPermissions::ReadWrite));
+
+    children
+}
+
+// Cast [parent] to a raw pointer (a shared read-write reference), turn the raw
+// pointer back into a unique reference and write through it, then return the
+// token all the way back. Returns the raw pointer and the new reference.
+pub fn raw_round_trip(b: &mut TraceBuilder, parent: Reference) -> (Reference, Reference) {
+    let raw = b.create(parent, RefKind::SharedReadWrite);
+    b.op(Operation::Borrow(raw));
+    let back = reborrow_and_return(b, raw, RefKind::Unique, AccessKind::Write);
+    b.op(Operation::Return(raw));
+    (raw, back)
+}
+
+// A two-phase borrow, as in v.push(v.len()): the unique reference for the
+// call is created first, but only activated after a shared reference has read
+// [owner] to evaluate the arguments. Returns the unique reference and the
+// shared one.
+pub fn two_phase_call(b: &mut TraceBuilder, owner: Reference) -> (Reference, Reference) {
+    let call = b.create(owner, RefKind::Unique);
+    let argument = reborrow_and_return(b, owner, RefKind::SharedReadOnly, AccessKind::Read);
+    b.op(Operation::Borrow(call))
+        .op(Operation::Use(call, AccessKind::Write))
+        .op(Operation::Return(call));
+    (call, argument)
+}
+
+// The most readers fan_out takes, so that a short line in a trace can't ask
+// for an unbounded number of operations.
+pub const MAX_READERS: usize = 4096;
+
+pub const FRAGMENT_USAGE: &str = "\
+reborrow <parent> <kind> <access>, fan_out <parent> <readers>, \
+raw_round_trip <parent> or two_phase_call <owner>";
+
+// Expand the fragment named by the first of [words], applied to the remaining
+// words, into operations following a trace that has created [created]
+// references.
+pub fn expand(words: &[&str], created: u32) -> Result<Vec<Operation>, String> {
+    let mut b = TraceBuilder::continuing(created);
+
+    match words {
+        ["reborrow", parent, kind, access] => {
+            reborrow_and_return(
+                &mut b,
+                trace::parse_reference(parent)?,
+                trace::parse_kind(kind)?,
+                trace::parse_access(access)?,
+            );
+        }
+        ["fan_out", parent, readers] => {
+            let readers = readers
+                .parse()
+                .ok()
+                .filter(|&n| n <= MAX_READERS)
+                .ok_or_else(|| {
+                    format!(
+                        "expected a number of readers up to {}, found '{}'",
+                        MAX_READERS, readers
+                    )
+                })?;
+            shared_fan_out(&mut b, trace::parse_reference(parent)?, readers);
+        }
+        ["raw_round_trip", parent] => {
+            raw_round_trip(&mut b, trace::parse_reference(parent)?);
+        }
+        ["two_phase_call", owner] => {
+            two_phase_call(&mut b, trace::parse_reference(owner)?);
+        }
+        _ => {
+            return Err(format!(
+                "cannot expand '{}', expected {}",
+                words.join(" "),
+                FRAGMENT_USAGE
+            ))
+        }
+    }
+
+    Ok(b.ops)
+}
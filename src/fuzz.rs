@@ -0,0 +1,411 @@
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use crate::explore;
+use crate::grammar;
+use crate::machine2::{MachineConfig, Operation, TokenMachine};
+use crate::minimize;
+use crate::mutate;
+use crate::rng::Rng;
+use crate::trace::Trace;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FuzzOptions {
+    pub config: MachineConfig,
+    // Maximum number of operations in a generated trace.
+    pub max_len: usize,
+    // Maximum number of references in a generated trace.
+    pub max_refs: usize,
+    // Number of random mutations (see mutate.rs) applied to each generated
+    // trace, to get out of the states the generator keeps steering towards.
+    pub mutations: usize,
+}
+
+impl Default for FuzzOptions {
+    fn default() -> Self {
+        FuzzOptions {
+            config: MachineConfig::default(),
+            max_len: 30,
+            max_refs: 6,
+            mutations: 0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FailureKind {
+    Panic,
+    BrokenInvariant,
+    // A rejected operation changed the state, which it must not (see
+    // TokenMachine::apply).
+    ChangedOnRejection,
+}
+
+// Something going wrong while replaying a trace. Rejected operations are not
+// failures: that is just the machine doing its job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub kind: FailureKind,
+    // Index of the operation during or after which things went wrong.
+    pub step: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+// Generate a random trace. Operations are drawn from the candidates of the
+// current state; rejected ones are mostly skipped, so that traces get a
+// chance to reach interesting states, but occasionally a rejected operation
+// ends the trace. An operation that makes the machine panic always ends it.
+// Finally, the configured number of random mutations is applied.
+pub fn generate(rng: &mut Rng, options: &FuzzOptions) -> Trace {
+    let mut machine = TokenMachine::init_empty_with(options.config);
+    let mut ops = vec![Operation::NewRoot];
+    machine.apply(Operation::NewRoot).unwrap();
+
+    'outer: while ops.len() < options.max_len {
+        let candidates = explore::candidate_operations(&machine, options.max_refs);
+
+        for _ in 0..16 {
+            let op = *rng.choose(&candidates);
+            match panic::catch_unwind(AssertUnwindSafe(|| machine.apply(op))) {
+                Ok(Ok(_)) => {
+                    ops.push(op);
+                    continue 'outer;
+                }
+                Ok(Err(_)) if rng.below(32) == 0 => {
+                    ops.push(op);
+                    break 'outer;
+                }
+                Ok(Err(_)) => {}
+                // Leave it to check to report the panic.
+                Err(_) => {
+                    ops.push(op);
+                    break 'outer;
+                }
+            }
+        }
+
+        break;
+    }
+
+    let mut trace = Trace::new(ops);
+    for _ in 0..options.mutations {
+        if let Some(mutation) = mutate::random_mutation(rng, &trace) {
+            trace = mutation.apply(&trace);
+        }
+    }
+    trace
+}
+
+// The message of a panic, on a single line so that it fits in a verdict file.
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    };
+
+    msg.lines().map(str::trim).collect::<Vec<_>>().join(" ")
+}
+
+// Replay [trace] and check that the machine neither panics nor breaks its
+// own invariants along the way, and that the operation that ends the trace
+// (if it is rejected) leaves the state unchanged.
+pub fn check(config: MachineConfig, trace: &Trace) -> Option<Failure> {
+    let mut machine = TokenMachine::init_empty_with(config);
+
+    for (i, &op) in trace.ops.iter().enumerate() {
+        let before = machine.clone();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| machine.apply(op)));
+
+        match result {
+            Err(payload) => {
+                return Some(Failure {
+                    kind: FailureKind::Panic,
+                    step: i,
+                    expected: "no panic".to_string(),
+                    actual: format!("panicked at step {}: {}", i, panic_message(&*payload)),
+                });
+            }
+            Ok(Err(err)) if machine != before => {
+                return Some(Failure {
+                    kind: FailureKind::ChangedOnRejection,
+                    step: i,
+                    expected: "state unchanged".to_string(),
+                    actual: format!("state changed by rejected step {}: {}", i, err),
+                });
+            }
+            Ok(Err(_)) => return None,
+            Ok(Ok(_)) => {}
+        }
+
+        if let Err(msg) = machine.check_invariants() {
+            return Some(Failure {
+                kind: FailureKind::BrokenInvariant,
+                step: i,
+                expected: "invariants hold".to_string(),
+                actual: format!("invariant broken after step {}: {}", i, msg),
+            });
+        }
+    }
+
+    None
+}
+
+thread_local! {
+    // How many calls of quietly are running on this thread.
+    static QUIET: Cell<usize> = const { Cell::new(0) };
+}
+
+// Leaves quietly when dropped, also if [f] panics.
+struct Quiet;
+
+impl Drop for Quiet {
+    fn drop(&mut self) {
+        QUIET.with(|quiet| quiet.set(quiet.get() - 1));
+    }
+}
+
+// Run [f] without the panic hook printing every caught panic. The hook is
+// process-wide, so instead of swapping it on every call (which races with
+// other threads doing the same), it is replaced once by one that says nothing
+// about panics on threads inside quietly and leaves the others to the
+// original hook.
+pub fn quietly<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if QUIET.with(Cell::get) == 0 {
+                hook(info);
+            }
+        }));
+    });
+
+    QUIET.with(|quiet| quiet.set(quiet.get() + 1));
+    let _quiet = Quiet;
+    f()
+}
+
+#[derive(Debug, Clone)]
+pub struct FuzzFailure {
+    pub seed: u64,
+    pub trace: Trace,
+    pub minimized: Trace,
+    pub failure: Failure,
+}
+
+// Shrink a failing trace while keeping the same kind of failure.
+pub fn minimize_failure(config: MachineConfig, trace: &Trace, failure: &Failure) -> Trace {
+    minimize::shrink(trace, |candidate| {
+        check(config, candidate).map(|f| f.kind) == Some(failure.kind)
+    })
+}
+
+// Fuzz [runs] traces. Run i uses seed [seed] + i, so that every failure can
+// be regenerated from its own seed.
+pub fn fuzz(options: &FuzzOptions, seed: u64, runs: u64) -> Vec<FuzzFailure> {
+    quietly(|| {
+        let mut failures = Vec::new();
+
+        for run in 0..runs {
+            let run_seed = seed.wrapping_add(run);
+            let trace = generate(&mut Rng::new(run_seed), options);
+
+            if let Some(failure) = check(options.config, &trace) {
+                let minimized = minimize_failure(options.config, &trace, &failure);
+                failures.push(FuzzFailure {
+                    seed: run_seed,
+                    trace,
+                    minimized,
+                    failure,
+                });
+            }
+        }
+
+        failures
+    })
+}
+
+// Fuzzing the trace parser itself (see grammar.rs), which reads whatever
+// frontends and users of tbm serve send it. Every input is a rendered random
+// trace, mangled a few times over at the level of characters and lines.
+#[derive(Debug, Clone)]
+pub struct ParserFailure {
+    pub seed: u64,
+    pub input: String,
+    pub problem: String,
+}
+
+// Characters that are likely to matter to the parser, and some that aren't.
+const PARSER_ALPHABET: &[&str] = &[
+    " ",
+    "\t",
+    "\n",
+    "\r\n",
+    "=",
+    "#",
+    "#!",
+    "r",
+    "0",
+    "9",
+    "_",
+    "x",
+    "é",
+    "\u{0}",
+    "\u{2028}",
+    "4294967295",
+    "4294967296",
+    "root",
+    "expand",
+    "fan_out",
+    "unique",
+];
+
+// Apply one random change to [text].
+fn mangle(rng: &mut Rng, text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let at = rng.below(chars.len() + 1);
+    let (before, after) = chars.split_at(at);
+    let before: String = before.iter().collect();
+    let after: String = after.iter().collect();
+
+    match rng.below(5) {
+        // Insert a piece of the alphabet.
+        0 => format!("{}{}{}", before, rng.choose(PARSER_ALPHABET), after),
+        // Delete a few characters.
+        1 => {
+            let skip = rng.below(8);
+            format!("{}{}", before, after.chars().skip(skip).collect::<String>())
+        }
+        // Swap a line with a random other line.
+        2 => {
+            let mut lines: Vec<_> = text.lines().collect();
+            if lines.len() > 1 {
+                let (i, j) = (rng.below(lines.len()), rng.below(lines.len()));
+                lines.swap(i, j);
+            }
+            lines.join("\n")
+        }
+        // Repeat a stretch of the text.
+        3 => {
+            let len = rng.below(64);
+            let stretch: String = after.chars().take(len).collect();
+            format!("{}{}{}", before, stretch.repeat(1 + rng.below(4)), after)
+        }
+        // An overlong line.
+        _ => format!("{}{}{}", before, "r0 ".repeat(rng.below(3000)), after),
+    }
+}
+
+// Check that the parser doesn't panic on [input], that its errors point into
+// the input, and that parse agrees with parse_recovering.
+fn check_parser(input: &str) -> Option<String> {
+    let result = panic::catch_unwind(|| {
+        let (_, errors) = grammar::parse_recovering(input);
+        for err in &errors {
+            grammar::render_error(input, err);
+        }
+        (errors, grammar::parse(input))
+    });
+    let (errors, parsed) = match result {
+        Ok(result) => result,
+        Err(_) => return Some("the parser panicked".to_string()),
+    };
+
+    let lines: Vec<_> = input.lines().collect();
+    for err in &errors {
+        let line = match err.line.checked_sub(1).and_then(|i| lines.get(i)) {
+            Some(line) => line,
+            None => return Some(format!("error outside the input: {}", err)),
+        };
+        if let Some(column) = err.column {
+            if column == 0 || column > line.chars().count() + 1 {
+                return Some(format!("error outside its line: {}", err));
+            }
+        }
+    }
+    if errors.len() > grammar::MAX_ERRORS {
+        return Some(format!("{} errors reported", errors.len()));
+    }
+    if errors.first() != parsed.as_ref().err() {
+        return Some("parse and parse_recovering disagree".to_string());
+    }
+    None
+}
+
+// Fuzz the parser with [runs] inputs, seeded like fuzz. Unmangled renderings
+// must also parse back to the same trace.
+pub fn fuzz_parser(options: &FuzzOptions, seed: u64, runs: u64) -> Vec<ParserFailure> {
+    quietly(|| {
+        let mut failures = Vec::new();
+
+        for run in 0..runs {
+            let run_seed = seed.wrapping_add(run);
+            let mut rng = Rng::new(run_seed);
+            let trace = generate(&mut rng, options);
+            let rendered = trace.to_string();
+
+            let mut fail = |input: &str, problem: String| {
+                failures.push(ParserFailure {
+                    seed: run_seed,
+                    input: input.to_string(),
+                    problem,
+                })
+            };
+
+            match Trace::parse(&rendered) {
+                Ok(parsed) if parsed.ops == trace.ops => {}
+                Ok(_) => fail(&rendered, "the trace parses differently".to_string()),
+                Err(err) => fail(&rendered, format!("the trace doesn't parse: {}", err)),
+            }
+
+            let mut input = rendered;
+            for _ in 0..1 + rng.below(8) {
+                input = mangle(&mut rng, &input);
+            }
+            if let Some(problem) = check_parser(&input) {
+                fail(&input, problem);
+            }
+        }
+
+        failures
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn quietly_only_silences_its_own_thread() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..100 {
+                        let caught = quietly(|| panic::catch_unwind(|| panic!("expected")));
+                        assert!(caught.is_err());
+                        assert_eq!(QUIET.with(Cell::get), 0);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // A panic that leaves quietly still ends it.
+        let caught = panic::catch_unwind(|| quietly(|| panic!("expected")));
+        assert!(caught.is_err());
+        assert_eq!(QUIET.with(Cell::get), 0);
+        assert_eq!(quietly(|| quietly(|| QUIET.with(Cell::get))), 2);
+    }
+}
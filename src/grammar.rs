@@ -0,0 +1,486 @@
+// The grammar of trace files, and the parser behind Trace::parse. Trace files
+// come from frontends, fuzzers and users of tbm serve alike, so the parser is
+// written for input that may be anything: it never panics, bounds how much it
+// will produce (including through the config header, see MAX_ROOT_PIECES),
+// and says where a line goes wrong and what would have been accepted there.
+// After an error it carries on with the next line, so that every error of a
+// file can be reported at once.
+//
+//     trace      = { line } ;
+//     line       = header | [ statement ] [ comment ] ;
+//     header     = "#!" name [ value ] ;           (value: the rest of the line;
+//                                                   that of "config" must be
+//                                                   a valid configuration)
+//     comment    = "#" { any character } ;
+//     statement  = [ reference "=" ] operation
+//                | "expand" word { word } ;         (see fragments.rs)
+//     operation  = "root" | "const_root"
+//                | "create" reference kind
+//                | "borrow" reference | "return" reference
+//                | "dup" reference | "merge" reference
+//                | "perms" reference perms
+//                | "use" reference access
+//                | "reclaim" reference
+//                | "call" reference | "arg" reference | "ret" ;
+//     reference  = "r" digit { digit } ;            (at most 2^32 - 1)
+//     kind       = "shared_ro" | "shared_rw" | "unique" ;
+//     perms      = "readonly" | "readwrite" ;
+//     access     = "read" | "write" ;
+//     word       = ( letter | digit | "_" ) { letter | digit | "_" } ;
+//
+// Words are separated by whitespace; "=" needs none around it. A binding
+// "rN = ..." is only allowed on operations that create a reference and must
+// name the reference the operation will create. Columns count characters and
+// start at 1, like lines.
+
+use std::convert::TryFrom;
+
+use crate::fragments;
+use crate::machine2::{AccessKind, Operation, RefKind, Reference, TokenPermissions};
+use crate::trace::{self, creates_reference, ParseError, Trace};
+
+// Lines longer than this are rejected without looking at them.
+pub const MAX_LINE_LEN: usize = 4096;
+// The most operations a trace can have, counting expanded fragments.
+pub const MAX_OPS: usize = 1_000_000;
+// Parsing gives up after this many errors.
+pub const MAX_ERRORS: usize = 100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Word,
+    Equals,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Token<'a> {
+    kind: TokenKind,
+    text: &'a str,
+    column: usize,
+}
+
+fn error(line: usize, column: usize, expected: &[&str], message: String) -> ParseError {
+    ParseError {
+        line,
+        column: Some(column),
+        expected: expected.iter().map(|e| e.to_string()).collect(),
+        message,
+    }
+}
+
+// Split a line (without its comment) into tokens.
+fn tokenize(line: usize, text: &str) -> Result<Vec<Token<'_>>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().enumerate().peekable();
+
+    while let Some((column, (start, c))) = chars.next() {
+        let column = column + 1;
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '=' {
+            tokens.push(Token {
+                kind: TokenKind::Equals,
+                text: &text[start..start + 1],
+                column,
+            });
+            continue;
+        }
+        if !(c.is_ascii_alphanumeric() || c == '_') {
+            return Err(error(
+                line,
+                column,
+                &["a word", "'='", "'#'"],
+                format!("unexpected character '{}'", c.escape_debug()),
+            ));
+        }
+
+        let mut end = start + c.len_utf8();
+        while let Some(&(_, (at, next))) = chars.peek() {
+            if !(next.is_ascii_alphanumeric() || next == '_') {
+                break;
+            }
+            end = at + next.len_utf8();
+            chars.next();
+        }
+        tokens.push(Token {
+            kind: TokenKind::Word,
+            text: &text[start..end],
+            column,
+        });
+    }
+
+    Ok(tokens)
+}
+
+// The operations, with the kinds of arguments they take.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Arg {
+    Ref,
+    Kind,
+    Perms,
+    Access,
+}
+
+const OPERATIONS: [(&str, &[Arg]); 13] = [
+    ("root", &[]),
+    ("const_root", &[]),
+    ("create", &[Arg::Ref, Arg::Kind]),
+    ("borrow", &[Arg::Ref]),
+    ("return", &[Arg::Ref]),
+    ("dup", &[Arg::Ref]),
+    ("merge", &[Arg::Ref]),
+    ("perms", &[Arg::Ref, Arg::Perms]),
+    ("use", &[Arg::Ref, Arg::Access]),
+    ("reclaim", &[Arg::Ref]),
+    ("call", &[Arg::Ref]),
+    ("arg", &[Arg::Ref]),
+    ("ret", &[]),
+];
+
+// The words that can start a statement.
+fn statement_keywords() -> Vec<&'static str> {
+    let mut keywords: Vec<_> = OPERATIONS.iter().map(|(name, _)| *name).collect();
+    keywords.push("expand");
+    keywords
+}
+
+fn expected_words(arg: Arg) -> &'static [&'static str] {
+    match arg {
+        Arg::Ref => &["a reference like r0"],
+        Arg::Kind => &["shared_ro", "shared_rw", "unique"],
+        Arg::Perms => &["readonly", "readwrite"],
+        Arg::Access => &["read", "write"],
+    }
+}
+
+pub fn reference(word: &str) -> Option<Reference> {
+    let digits = word.strip_prefix('r')?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok().map(Reference::from_id)
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Value {
+    Ref(Reference),
+    Kind(RefKind),
+    Perms(TokenPermissions),
+    Access(AccessKind),
+}
+
+fn argument(arg: Arg, word: &str) -> Option<Value> {
+    Some(match (arg, word) {
+        (Arg::Ref, _) => Value::Ref(reference(word)?),
+        (Arg::Kind, "shared_ro") => Value::Kind(RefKind::SharedReadOnly),
+        (Arg::Kind, "shared_rw") => Value::Kind(RefKind::SharedReadWrite),
+        (Arg::Kind, "unique") => Value::Kind(RefKind::Unique),
+        (Arg::Perms, "readonly") => Value::Perms(TokenPermissions::ReadOnly),
+        (Arg::Perms, "readwrite") => Value::Perms(TokenPermissions::ReadWrite),
+        (Arg::Access, "read") => Value::Access(AccessKind::Read),
+        (Arg::Access, "write") => Value::Access(AccessKind::Write),
+        _ => return None,
+    })
+}
+
+fn build(name: &str, values: &[Value]) -> Operation {
+    use Value::*;
+    match (name, values) {
+        ("root", []) => Operation::NewRoot,
+        ("const_root", []) => Operation::NewConstRoot,
+        ("create", [Ref(r), Kind(kind)]) => Operation::CreateRef(*r, *kind),
+        ("borrow", [Ref(r)]) => Operation::Borrow(*r),
+        ("return", [Ref(r)]) => Operation::Return(*r),
+        ("dup", [Ref(r)]) => Operation::Dup(*r),
+        ("merge", [Ref(r)]) => Operation::Merge(*r),
+        ("perms", [Ref(r), Perms(perms)]) => Operation::SetPerms(*r, *perms),
+        ("use", [Ref(r), Access(access)]) => Operation::Use(*r, *access),
+        ("reclaim", [Ref(r)]) => Operation::ReclaimExclusive(*r),
+        ("call", [Ref(r)]) => Operation::Call(*r),
+        ("arg", [Ref(r)]) => Operation::Arg(*r),
+        ("ret", []) => Operation::Ret,
+        _ => unreachable!("arguments are checked against OPERATIONS"),
+    }
+}
+
+// Parse an operation from [tokens], none of which may be left over. [end] is
+// the column just past the end of the line, for errors about missing words.
+fn operation(line: usize, tokens: &[Token], end: usize) -> Result<Operation, ParseError> {
+    let keywords = statement_keywords();
+    let (first, rest) = match tokens.split_first() {
+        Some((first, rest)) if first.kind == TokenKind::Word => (first, rest),
+        Some((first, _)) => {
+            return Err(error(
+                line,
+                first.column,
+                &keywords,
+                format!("expected an operation, found '{}'", first.text),
+            ))
+        }
+        None => {
+            return Err(error(
+                line,
+                end,
+                &keywords,
+                "expected an operation, found the end of the line".to_string(),
+            ))
+        }
+    };
+
+    let args = OPERATIONS
+        .iter()
+        .find(|(name, _)| *name == first.text)
+        .map(|(_, args)| *args)
+        .ok_or_else(|| {
+            error(
+                line,
+                first.column,
+                &keywords,
+                format!("unknown operation '{}'", first.text),
+            )
+        })?;
+
+    let mut values = Vec::new();
+    for (i, &arg) in args.iter().enumerate() {
+        let expected = expected_words(arg);
+        let token = rest.get(i).ok_or_else(|| {
+            error(
+                line,
+                end,
+                expected,
+                format!(
+                    "expected {}, found the end of the line",
+                    expected.join(" or ")
+                ),
+            )
+        })?;
+        let value = match token.kind {
+            TokenKind::Word => argument(arg, token.text),
+            TokenKind::Equals => None,
+        };
+        values.push(value.ok_or_else(|| {
+            error(
+                line,
+                token.column,
+                expected,
+                format!("expected {}, found '{}'", expected.join(" or "), token.text),
+            )
+        })?);
+    }
+
+    if let Some(extra) = rest.get(args.len()) {
+        return Err(error(
+            line,
+            extra.column,
+            &["the end of the line"],
+            format!("expected the end of the line, found '{}'", extra.text),
+        ));
+    }
+
+    Ok(build(first.text, &values))
+}
+
+// Parse a single operation on a line of its own, such as a line of the REPL
+// without a binding.
+pub fn parse_operation(text: &str) -> Result<Operation, ParseError> {
+    if text.len() > MAX_LINE_LEN {
+        return Err(ParseError::at_line(
+            1,
+            "the operation is too long".to_string(),
+        ));
+    }
+    let tokens = tokenize(1, text)?;
+    operation(1, &tokens, text.chars().count() + 1)
+}
+
+// The part of [line] before its comment, if any.
+fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+// The state of parsing a trace, line by line.
+struct Parser {
+    trace: Trace,
+    // The ID the next reference created will get.
+    next_id: u32,
+}
+
+impl Parser {
+    fn push(&mut self, line: usize, ops: Vec<Operation>) -> Result<(), ParseError> {
+        if self.trace.ops.len() + ops.len() > MAX_OPS {
+            return Err(error(
+                line,
+                1,
+                &[],
+                format!("traces can have at most {} operations", MAX_OPS),
+            ));
+        }
+        let created = ops.iter().filter(|&&op| creates_reference(op)).count();
+        self.next_id = u32::try_from(created)
+            .ok()
+            .and_then(|created| self.next_id.checked_add(created))
+            .ok_or_else(|| error(line, 1, &[], "too many references".to_string()))?;
+        self.trace.ops.extend(ops);
+        Ok(())
+    }
+
+    fn line(&mut self, number: usize, text: &str) -> Result<(), ParseError> {
+        if text.len() > MAX_LINE_LEN {
+            return Err(error(
+                number,
+                1,
+                &[],
+                format!("lines can be at most {} bytes long", MAX_LINE_LEN),
+            ));
+        }
+
+        if let Some(header) = text.trim_start().strip_prefix("#!") {
+            let header = header.trim();
+            let (key, value) = header
+                .split_once(char::is_whitespace)
+                .unwrap_or((header, ""));
+            let value = value.trim();
+            if key == "config" {
+                // The configuration decides how much the machine allocates
+                // up front (see MAX_ROOT_PIECES), so it is checked here.
+                trace::parse_config_inline(value).map_err(|msg| {
+                    let at = text.find(value).unwrap_or(0);
+                    error(number, text[..at].chars().count() + 1, &[], msg)
+                })?;
+            }
+            self.trace
+                .headers
+                .push((key.to_string(), value.to_string()));
+            return Ok(());
+        }
+
+        let text = strip_comment(text);
+        let tokens = tokenize(number, text)?;
+        let end = text.chars().count() + 1;
+        let first = match tokens.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+
+        if first.text == "expand" && first.kind == TokenKind::Word {
+            let words: Vec<_> = tokens[1..].iter().map(|token| token.text).collect();
+            let ops = fragments::expand(&words, self.next_id)
+                .map_err(|msg| error(number, first.column, &[fragments::FRAGMENT_USAGE], msg))?;
+            return self.push(number, ops);
+        }
+
+        let (binding, op_tokens) = match tokens.get(1) {
+            Some(eq) if eq.kind == TokenKind::Equals => (Some(first), &tokens[2..]),
+            _ => (None, &tokens[..]),
+        };
+        let op = operation(number, op_tokens, end)?;
+
+        if let Some(binding) = binding {
+            let bound = reference(binding.text).ok_or_else(|| {
+                error(
+                    number,
+                    binding.column,
+                    &["a reference like r0"],
+                    format!("expected a reference like r0, found '{}'", binding.text),
+                )
+            })?;
+            if !creates_reference(op) {
+                return Err(error(
+                    number,
+                    binding.column,
+                    &[],
+                    format!("'{}' does not create a reference", op),
+                ));
+            }
+            if bound.id() != self.next_id {
+                return Err(error(
+                    number,
+                    binding.column,
+                    &[],
+                    format!(
+                        "new reference will be {}, not {}",
+                        Reference::from_id(self.next_id),
+                        bound
+                    ),
+                ));
+            }
+        }
+
+        self.push(number, vec![op])
+    }
+}
+
+// Parse [text] as a trace, skipping the lines that are wrong. Returns the
+// trace made of the other lines and the errors, at most MAX_ERRORS of them.
+// Running out of operations ends parsing.
+pub fn parse_recovering(text: &str) -> (Trace, Vec<ParseError>) {
+    let mut parser = Parser {
+        trace: Trace::default(),
+        next_id: 0,
+    };
+    let mut errors = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        if let Err(err) = parser.line(i + 1, line) {
+            let full = parser.trace.ops.len() >= MAX_OPS;
+            errors.push(err);
+            if full || errors.len() >= MAX_ERRORS {
+                break;
+            }
+        }
+    }
+
+    (parser.trace, errors)
+}
+
+// Parse [text] as a trace, failing on the first error.
+pub fn parse(text: &str) -> Result<Trace, ParseError> {
+    let (trace, mut errors) = parse_recovering(text);
+    if errors.is_empty() {
+        Ok(trace)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+// [err] with the line it is about and a caret under the column, for printing.
+pub fn render_error(text: &str, err: &ParseError) -> String {
+    let mut out = err.to_string();
+    if let (Some(line), Some(column)) = (
+        err.line.checked_sub(1).and_then(|i| text.lines().nth(i)),
+        err.column,
+    ) {
+        if line.len() <= MAX_LINE_LEN {
+            out.push_str(&format!("\n    {}\n    {}^", line, " ".repeat(column - 1)));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_config_header_with_too_many_pieces_is_a_parse_error() {
+        let text = "#! version 2\n#! config root_pieces=4000000000\nroot\n";
+        let err = parse(text).unwrap_err();
+        assert_eq!((err.line, err.column), (2, Some(11)));
+
+        let (trace, errors) = parse_recovering(text);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(trace.ops, vec![Operation::NewRoot]);
+        assert_eq!(trace.header("config"), None);
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_word() {
+        let err = parse("root\nuse r0 wrte\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, Some(8)));
+        assert_eq!(err.expected, vec!["read", "write"]);
+    }
+}
@@ -0,0 +1,96 @@
+// A stricter contract for embedding the machine in something that keeps
+// running after its user makes a mistake, such as the REPL. The machine itself
+// treats errors as recoverable (see TokenMachine::apply), which is what
+// searches and replays want, but an interactive user who carries on after a
+// rejected operation easily forgets that it never happened. A GuardedMachine
+// is poisoned by the first rejected operation, and refuses everything after
+// it until the embedder explicitly recovers to a snapshot or resets.
+
+use std::fmt;
+
+use crate::machine2::{MachineError, Operation, Reference, TokenMachine};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GuardError {
+    // The operation was rejected by the machine, which is now poisoned.
+    Rejected(MachineError),
+    // The machine was poisoned by an earlier operation, which was rejected
+    // with [error]; nothing was done.
+    Poisoned { by: Operation, error: MachineError },
+}
+
+impl fmt::Display for GuardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuardError::Rejected(err) => write!(f, "{}", err),
+            GuardError::Poisoned { by, error } => write!(
+                f,
+                "Machine is poisoned since '{}' was rejected ({}); recover or reset first",
+                by, error
+            ),
+        }
+    }
+}
+
+// A state of the machine to recover to. Snapshots are only ever taken of
+// machines that are not poisoned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot(TokenMachine);
+
+#[derive(Debug, Clone)]
+pub struct GuardedMachine {
+    machine: TokenMachine,
+    initial: TokenMachine,
+    poison: Option<(Operation, MachineError)>,
+}
+
+impl GuardedMachine {
+    // Guard [machine], which is also the state reset returns to.
+    pub fn new(machine: TokenMachine) -> Self {
+        GuardedMachine {
+            initial: machine.clone(),
+            machine,
+            poison: None,
+        }
+    }
+
+    pub fn machine(&self) -> &TokenMachine {
+        &self.machine
+    }
+
+    // The rejected operation that poisoned the machine and its error, if any.
+    pub fn poison(&self) -> Option<(Operation, MachineError)> {
+        self.poison
+    }
+
+    pub fn apply(&mut self, op: Operation) -> Result<Option<Reference>, GuardError> {
+        if let Some((by, error)) = self.poison {
+            return Err(GuardError::Poisoned { by, error });
+        }
+
+        self.machine.apply(op).map_err(|err| {
+            self.poison = Some((op, err));
+            GuardError::Rejected(err)
+        })
+    }
+
+    // The current state, or None while the machine is poisoned.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        match self.poison {
+            Some(_) => None,
+            None => Some(Snapshot(self.machine.clone())),
+        }
+    }
+
+    // Continue from [snapshot], clearing the poison.
+    pub fn recover_to(&mut self, snapshot: Snapshot) {
+        self.machine = snapshot.0;
+        self.poison = None;
+    }
+
+    // Continue from the state the machine was guarded in.
+    pub fn reset(&mut self) {
+        self.machine = self.initial.clone();
+        self.poison = None;
+    }
+}
@@ -0,0 +1,129 @@
+// Allocation of reference IDs. By default references are numbered in the order
+// they are created, but a frontend that wants IDs to be stable across runs
+// (e.g. to diff the traces of two slightly different versions of a program)
+// can derive them from something it knows about each reference instead, such
+// as the source location that creates it.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+pub trait IdAllocator: fmt::Debug + Send + Sync {
+    // Pick an ID for the reference created with [key] (if the frontend gave
+    // one) when [created] references already exist. If the ID is taken, the
+    // machine asks again with increasing values of [attempt], so an allocator
+    // must eventually return a free ID.
+    fn allocate(&self, key: Option<&str>, created: u32, attempt: u32) -> u32;
+
+    // Allocators are compared by their description, so it must
+    // include everything that determines which IDs they hand out.
+    fn describe(&self) -> String;
+}
+
+// The allocator of a machine, shared between its clones.
+#[derive(Debug, Clone)]
+pub struct SharedAllocator(Arc<dyn IdAllocator>);
+
+impl SharedAllocator {
+    pub fn new<A: IdAllocator + 'static>(allocator: A) -> Self {
+        SharedAllocator(Arc::new(allocator))
+    }
+}
+
+impl Deref for SharedAllocator {
+    type Target = dyn IdAllocator;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl PartialEq for SharedAllocator {
+    fn eq(&self, other: &Self) -> bool {
+        self.describe() == other.describe()
+    }
+}
+
+impl Eq for SharedAllocator {}
+
+// The default: references are numbered 0, 1, 2, ... in order of creation.
+// This is what traces assume.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Sequential;
+
+impl IdAllocator for Sequential {
+    fn allocate(&self, _key: Option<&str>, created: u32, attempt: u32) -> u32 {
+        created + attempt
+    }
+
+    fn describe(&self) -> String {
+        "sequential".to_string()
+    }
+}
+
+// IDs derived from a hash of the key, so that the same key gets the same ID
+// no matter how many references were created before it. Keys are hashed
+// together with a namespace, which lets separate frontends (or separate
+// functions of one program) share a machine without their IDs colliding by
+// construction. References created without a key fall back to their creation
+// index.
+//
+// When a key is used more than once (e.g. a borrow inside a loop), later
+// references get the next free ID along the key's probe sequence, so they are
+// still stable as long as the key's uses happen in the same order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ContentHash {
+    pub namespace: u32,
+}
+
+impl IdAllocator for ContentHash {
+    fn allocate(&self, key: Option<&str>, created: u32, attempt: u32) -> u32 {
+        let mut hash = Fnv::new();
+        hash.write(&self.namespace.to_le_bytes());
+        match key {
+            Some(key) => {
+                hash.write(&[1]);
+                hash.write(key.as_bytes());
+            }
+            None => {
+                hash.write(&[0]);
+                hash.write(&created.to_le_bytes());
+            }
+        }
+        hash.write(&attempt.to_le_bytes());
+
+        hash.finish() as u32
+    }
+
+    fn describe(&self) -> String {
+        format!("content-hash namespace={}", self.namespace)
+    }
+}
+
+// 64-bit FNV-1a. The standard library's hashers are not guaranteed to be
+// stable across releases, which would defeat the purpose. Also used for
+// hashes that are stored on disk (see triage.rs).
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv::new()
+    }
+}
+
+impl Fnv {
+    pub fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
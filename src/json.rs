@@ -0,0 +1,141 @@
+// Export of the machine state after every step of a trace as JSON, for
+// animating the reference trees in a browser. Every tree is a nested object
+// with a "children" array, which is the shape d3.hierarchy expects:
+//
+//     {
+//       "config": "dup_rule=unrestricted return_rule=strict",
+//       "steps": [
+//         {
+//           "step": 1,
+//           "operation": "root",
+//           "outcome": "ok",
+//           "trees": [
+//             {
+//               "name": "r0", "kind": "unique", "state": "borrowing",
+//               "pieces": 1, "splits": 0,
+//               "token": { "pieces": 1, "perms": "readwrite", "constant": false },
+//               "children": []
+//             }
+//           ]
+//         }
+//       ]
+//     }
+//
+// Step 0 is the empty machine. "token" is only present on roots, and
+// describes the token of the whole tree. The last step of a rejected trace has
+// the rejection as its outcome and shows the state the machine stayed in.
+
+use std::fmt::Write;
+
+use crate::machine2::{MachineConfig, Operation, RefState, Reference, TokenMachine};
+use crate::trace::{self, Trace};
+
+// Escape [s] as a JSON string, including the quotes.
+pub fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn state_name(state: RefState) -> &'static str {
+    match state {
+        RefState::Created => "created",
+        RefState::Borrowing => "borrowing",
+        RefState::Dead => "dead",
+    }
+}
+
+fn node(machine: &TokenMachine, r: Reference, refs: &[Reference]) -> String {
+    let mut out = format!(
+        "{{\"name\":{},\"kind\":{},\"state\":{},",
+        string(&r.to_string()),
+        string(trace::kind_name(machine.kind_of(r))),
+        string(state_name(machine.state_of(r)))
+    );
+    let pieces = machine.get_token_info(r).map_or(0, |info| info.pieces_held);
+    write!(
+        out,
+        "\"pieces\":{},\"splits\":{},",
+        pieces,
+        machine.outstanding_splits(r)
+    )
+    .unwrap();
+
+    if machine.root_of(r) == r {
+        write!(
+            out,
+            "\"token\":{{\"pieces\":{},\"perms\":{},\"constant\":{}}},",
+            machine.token_count(r),
+            string(trace::perms_name(machine.token_perms(r))),
+            machine.is_constant(r)
+        )
+        .unwrap();
+    }
+
+    let children: Vec<_> = refs
+        .iter()
+        .copied()
+        .filter(|&child| child != r && machine.parent_of(child) == r)
+        .map(|child| node(machine, child, refs))
+        .collect();
+    write!(out, "\"children\":[{}]}}", children.join(",")).unwrap();
+    out
+}
+
+// The trees of [machine] as a JSON array.
+pub fn trees(machine: &TokenMachine) -> String {
+    let refs = machine.references();
+    let roots: Vec<_> = machine
+        .roots()
+        .into_iter()
+        .map(|root| node(machine, root, &refs))
+        .collect();
+    format!("[{}]", roots.join(","))
+}
+
+fn step(index: usize, op: Option<Operation>, outcome: &str, machine: &TokenMachine) -> String {
+    let operation = match op {
+        Some(op) => string(&op.to_string()),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"step\":{},\"operation\":{},\"outcome\":{},\"trees\":{}}}",
+        index,
+        operation,
+        string(outcome),
+        trees(machine)
+    )
+}
+
+// Replay [trace] and describe the state after every step.
+pub fn export_trace(config: MachineConfig, trace: &Trace) -> String {
+    let mut machine = TokenMachine::init_empty_with(config);
+    let mut steps = vec![step(0, None, "ok", &machine)];
+
+    for (i, &op) in trace.ops.iter().enumerate() {
+        let outcome = match machine.apply(op) {
+            Ok(_) => "ok".to_string(),
+            Err(err) => format!("rejected: {}", err),
+        };
+        let rejected = outcome != "ok";
+        steps.push(step(i + 1, Some(op), &outcome, &machine));
+        if rejected {
+            break;
+        }
+    }
+
+    format!(
+        "{{\"config\":{},\"steps\":[\n{}\n]}}\n",
+        string(&trace::format_config_inline(&config)),
+        steps.join(",\n")
+    )
+}
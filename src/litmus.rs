@@ -0,0 +1,643 @@
+// Import of litmus tests in the style of the Stacked Borrows and Tree Borrows
+// test suites (e.g. Miri's tests/pass and tests/fail directories): small Rust
+// programs whose main function creates references to locals and uses them in
+// straight-line code. Each test is turned into a trace, and its expected
+// outcome into a Miri verdict, so that the suite can be compared with the
+// machine using miri::compare.
+//
+// Only a subset of Rust is understood:
+//
+//     let x = 0;                   a local, which becomes a new tree
+//     let y = &mut x;              a reference to a local
+//     let z = &mut *y;             a reborrow (&, &mut, &raw const, &raw mut,
+//                                  addr_of!, addr_of_mut!)
+//     let p = z as *mut i32;       a cast to a raw pointer
+//     let q = p;                   a copy of a reference or pointer
+//     *z = *p + x;                 reads of everything on the right, then a
+//                                  write through the place on the left
+//     let v = *q;                  reads (v becomes a new local)
+//
+// Lines consisting of attributes, use items, "fn main() {", "unsafe {" and
+// closing braces are skipped; anything else makes the test unsupported. The
+// expected outcome is undefined behavior if the test has a "//~ ERROR"
+// annotation or a "// expect: ub" comment, and no undefined behavior for
+// "// expect: ok". Otherwise it follows from the directory the test is in
+// ("fail" or "pass").
+//
+// The token machine needs to be told where tokens go, which the programs don't
+// say. The importer inserts the borrows, returns and merges that make each
+// access possible (see planner::plan_access), and lends every shared
+// reference a piece of its parent's token as soon as it is created, since
+// shared references are meant to be usable side by side.
+//
+// A test that gets stuck has a "stuck" header saying which statement got
+// stuck, and which statement created the reference it got stuck on.
+//
+// The tests of a directory are found recursively, and named by their path
+// below it. Miri's own suite is imported from a checkout with import_miri,
+// which only looks at the directories of the aliasing models (MIRI_DIRS),
+// since the other failing tests are undefined behavior for reasons the
+// machine has nothing to say about. Most of those tests use more Rust than the
+// importer understands; the ones it skips are listed with the reason, in a
+// file "skipped" next to the imported traces.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::machine2::{AccessKind, MachineConfig, Operation, RefKind, Reference, TokenMachine};
+use crate::meta::MetaTable;
+use crate::miri::MiriVerdict;
+use crate::planner::{self, PlanResult};
+use crate::trace::Trace;
+
+// The longest sequence of moves inserted before a single access.
+const MAX_PLAN: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct Litmus {
+    pub name: String,
+    pub expected: MiriVerdict,
+    pub trace: Trace,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Binding {
+    // A local variable, whose tree has [Reference] as its root.
+    Local(Reference),
+    // A reference or raw pointer.
+    Pointer(Reference),
+}
+
+struct Importer {
+    machine: TokenMachine,
+    ops: Vec<Operation>,
+    bindings: Vec<(String, Binding)>,
+    // Set once an access or the creation of a reference turned out to be
+    // impossible: the program has undefined behavior at that point, so the
+    // rest of it does not matter.
+    stuck: bool,
+    // Where the importer is in the test, as "`statement` on line N".
+    at: String,
+    // Where each reference was created, as given by [at] at the time.
+    origins: MetaTable<String>,
+    // Where the test got stuck, and on what.
+    stuck_at: Option<String>,
+}
+
+impl Importer {
+    fn new(config: MachineConfig) -> Self {
+        Importer {
+            machine: TokenMachine::init_empty_with(config),
+            ops: Vec::new(),
+            bindings: Vec::new(),
+            stuck: false,
+            at: String::new(),
+            origins: MetaTable::new(),
+            stuck_at: None,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Binding> {
+        self.bindings
+            .iter()
+            .rev()
+            .find(|(bound, _)| bound == name)
+            .map(|(_, binding)| *binding)
+    }
+
+    fn bind(&mut self, name: &str, binding: Binding) {
+        if name != "_" {
+            self.bindings.push((name.to_string(), binding));
+        }
+    }
+
+    // Perform [op], which the importer has made sure is accepted.
+    fn perform(&mut self, op: Operation) -> Option<Reference> {
+        self.ops.push(op);
+        self.machine
+            .apply(op)
+            .expect("importer performed a rejected operation")
+    }
+
+    // Perform [op] if the machine accepts it, and report whether it did.
+    fn try_perform(&mut self, op: Operation) -> bool {
+        self.try_create(op).is_some()
+    }
+
+    // Perform [op] if the machine accepts it, returning the reference it
+    // created, if any.
+    fn try_create(&mut self, op: Operation) -> Option<Option<Reference>> {
+        let (next, created) = self.machine.step(op).ok()?;
+        self.machine = next;
+        self.ops.push(op);
+        Some(created)
+    }
+
+    fn new_local(&mut self, name: &str) {
+        let root = self.perform(Operation::NewRoot).unwrap();
+        self.origins.set(root, self.at.clone());
+        self.bind(name, Binding::Local(root));
+    }
+
+    // Record the rejected [op] on [r], so that replaying the trace ends with
+    // it, and stop.
+    fn get_stuck(&mut self, op: Operation, r: Reference) {
+        self.ops.push(op);
+        self.stuck = true;
+        self.stuck_at = Some(format!(
+            "{}, on the reference created by {}",
+            self.at,
+            self.origins.get_or_default(r)
+        ));
+    }
+
+    // Create a reference. If the machine rejects that, the program is stuck
+    // just as with an impossible access, and [parent] is returned in place of
+    // the new reference since it will not be used anymore.
+    fn create(&mut self, parent: Reference, kind: RefKind) -> Reference {
+        if self.stuck {
+            return parent;
+        }
+        let op = Operation::CreateRef(parent, kind);
+        let r = match self.try_create(op) {
+            Some(created) => created.unwrap(),
+            None => {
+                self.get_stuck(op, parent);
+                return parent;
+            }
+        };
+        self.origins.set(r, self.at.clone());
+
+        // Lend the new shared reference a piece of the token right away, if
+        // the parent has one or can get hold of one.
+        if kind != RefKind::Unique
+            && (self.machine.get_token_info(parent).is_some()
+                || self.plan(parent, AccessKind::Read))
+            && self.try_perform(Operation::Dup(parent))
+        {
+            self.try_perform(Operation::Borrow(r));
+        }
+
+        r
+    }
+
+    // Move tokens around so that [target] can perform an access of kind
+    // [access_kind], and report whether that worked out.
+    fn plan(&mut self, target: Reference, access_kind: AccessKind) -> bool {
+        match planner::plan_access(&self.machine, target, access_kind, MAX_PLAN) {
+            PlanResult::Found(moves) => {
+                for op in moves {
+                    self.perform(op);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn access(&mut self, target: Reference, access_kind: AccessKind) {
+        if self.stuck {
+            return;
+        }
+        let op = Operation::Use(target, access_kind);
+        if !self.plan(target, access_kind) || !self.try_perform(op) {
+            self.get_stuck(op, target);
+        }
+    }
+
+    // The reference an expression evaluates to.
+    fn pointer(&mut self, expr: &str) -> Result<Reference, String> {
+        let expr = strip_parens(expr.trim());
+
+        if let Some((inner, ty)) = expr.rsplit_once(" as ") {
+            let kind = if ty.trim().starts_with("*mut") {
+                RefKind::SharedReadWrite
+            } else if ty.trim().starts_with("*const") {
+                RefKind::SharedReadOnly
+            } else {
+                return Err(format!("unsupported cast to '{}'", ty.trim()));
+            };
+            let parent = self.pointer(inner)?;
+            return Ok(self.create(parent, kind));
+        }
+
+        for (prefix, kind) in &[
+            ("&raw mut ", RefKind::SharedReadWrite),
+            ("&raw const ", RefKind::SharedReadOnly),
+            ("&mut ", RefKind::Unique),
+            ("&", RefKind::SharedReadOnly),
+        ] {
+            if let Some(place) = expr.strip_prefix(prefix) {
+                let parent = self.place(place)?;
+                return Ok(self.create(parent, *kind));
+            }
+        }
+
+        for (mac, kind) in &[
+            ("addr_of_mut!", RefKind::SharedReadWrite),
+            ("addr_of!", RefKind::SharedReadOnly),
+        ] {
+            if let Some(place) = expr
+                .rsplit("::")
+                .next()
+                .and_then(|last| last.strip_prefix(mac))
+            {
+                let parent = self.place(strip_parens(place.trim()))?;
+                return Ok(self.create(parent, *kind));
+            }
+        }
+
+        match self.lookup(expr) {
+            Some(Binding::Pointer(r)) => Ok(r),
+            Some(Binding::Local(_)) => Err(format!("'{}' is not a reference", expr)),
+            None => Err(format!("unsupported expression '{}'", expr)),
+        }
+    }
+
+    // The reference through which a place is accessed: the pointer for *E,
+    // and the root of its tree for a local.
+    fn place(&mut self, place: &str) -> Result<Reference, String> {
+        let place = strip_parens(place.trim());
+        if let Some(pointer) = place.strip_prefix('*') {
+            return self.pointer(pointer);
+        }
+        match self.lookup(place) {
+            Some(Binding::Local(root)) => Ok(root),
+            Some(Binding::Pointer(_)) => Err(format!("place '{}' is a reference", place)),
+            None => Err(format!("unsupported place '{}'", place)),
+        }
+    }
+
+    // Whether an expression evaluates to a reference rather than a value.
+    fn is_pointer(&self, expr: &str) -> bool {
+        let expr = strip_parens(expr.trim());
+        expr.starts_with('&')
+            || expr.contains(" as *")
+            || expr.contains("addr_of")
+            || matches!(self.lookup(expr), Some(Binding::Pointer(_)))
+    }
+
+    // Read every place an expression mentions, from left to right.
+    fn read_all(&mut self, expr: &str) -> Result<(), String> {
+        let words = expr
+            .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '*'))
+            .filter(|word| !word.is_empty());
+
+        for word in words {
+            let (name, deref) = match word.strip_prefix('*') {
+                Some(name) => (name.trim_start_matches('*'), true),
+                None => (word, false),
+            };
+            if name.is_empty() {
+                continue;
+            }
+            match (self.lookup(name), deref) {
+                (Some(Binding::Pointer(r)), true) => self.access(r, AccessKind::Read),
+                (Some(Binding::Local(root)), false) => self.access(root, AccessKind::Read),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &str) -> Result<(), String> {
+        // Blocks, items and control flow are beyond straight-line code.
+        let keyword = stmt
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next();
+        if stmt.contains(['{', '}']) || keyword.is_some_and(|word| UNSUPPORTED.contains(&word)) {
+            return Err(format!("unsupported statement '{}'", stmt));
+        }
+
+        if let Some(rest) = stmt.strip_prefix("let ") {
+            let (pattern, expr) = rest
+                .split_once('=')
+                .ok_or_else(|| format!("expected 'let name = expr', found '{}'", stmt))?;
+            let name = pattern
+                .split(':')
+                .next()
+                .unwrap()
+                .trim()
+                .trim_start_matches("mut ")
+                .trim();
+
+            if self.is_pointer(expr) {
+                let r = self.pointer(expr)?;
+                self.bind(name, Binding::Pointer(r));
+            } else {
+                self.read_all(expr)?;
+                if name != "_" {
+                    self.new_local(name);
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some((place, expr)) = stmt.split_once('=') {
+            let comparison = place.ends_with(['!', '<', '>']) || expr.starts_with('=');
+            if !comparison {
+                // A compound assignment such as "*x += 1" reads the place
+                // first.
+                let compound = place.trim_end_matches(['+', '-', '*', '/', '%', '&', '|', '^']);
+                if compound.len() != place.len() {
+                    self.read_all(compound)?;
+                }
+                self.read_all(expr)?;
+                let target = self.place(compound)?;
+                self.access(target, AccessKind::Write);
+                return Ok(());
+            }
+        }
+
+        self.read_all(stmt)
+    }
+}
+
+fn strip_parens(expr: &str) -> &str {
+    let mut expr = expr;
+    while expr.starts_with('(') && expr.ends_with(')') {
+        expr = expr[1..expr.len() - 1].trim();
+    }
+    expr
+}
+
+// Keywords starting statements that are not straight-line code.
+const UNSUPPORTED: [&str; 17] = [
+    "fn", "struct", "enum", "union", "impl", "trait", "mod", "static", "const", "extern", "type",
+    "if", "while", "for", "loop", "match", "return",
+];
+
+// Lines that carry no statement of the straight-line program.
+fn skipped(line: &str) -> bool {
+    line.is_empty()
+        || line.starts_with("//")
+        || line.starts_with("#")
+        || line.starts_with("use ")
+        || line.starts_with("fn main()")
+        || line == "unsafe {"
+        || line
+            .chars()
+            .all(|c| c == '}' || c == ';' || c.is_whitespace())
+}
+
+fn expectation(path: &Path, text: &str) -> Result<MiriVerdict, String> {
+    if text.contains("//~ ERROR") || text.contains("// expect: ub") {
+        return Ok(MiriVerdict::UndefinedBehavior);
+    }
+    if text.contains("// expect: ok") {
+        return Ok(MiriVerdict::Ok);
+    }
+    for dir in path.ancestors() {
+        match dir.file_name().and_then(|name| name.to_str()) {
+            Some("fail") => return Ok(MiriVerdict::UndefinedBehavior),
+            Some("pass") => return Ok(MiriVerdict::Ok),
+            _ => {}
+        }
+    }
+    Err("no expected outcome (no //~ ERROR or // expect: annotation)".to_string())
+}
+
+// Import the test [text] read from [path].
+pub fn import(config: MachineConfig, path: &Path, text: &str) -> Result<Litmus, String> {
+    let name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format!("{}: not a test file", path.display()))?
+        .to_string();
+    let expected = expectation(path, text)?;
+
+    let mut importer = Importer::new(config);
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split("//").next().unwrap().trim();
+        if skipped(line) {
+            continue;
+        }
+        for stmt in line.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            if importer.stuck {
+                break;
+            }
+            importer.at = format!("`{}` on line {}", stmt, i + 1);
+            importer
+                .statement(stmt)
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+    }
+
+    let mut trace = Trace::new(importer.ops);
+    trace.set_config(&config);
+    trace.set_header("source", path.display().to_string());
+    if let Some(stuck_at) = importer.stuck_at {
+        trace.set_header("stuck", stuck_at);
+    }
+
+    Ok(Litmus {
+        name,
+        expected,
+        trace,
+    })
+}
+
+// The directories of Miri's tests of Stacked and Tree Borrows, relative to
+// its tests directory. Not every version of Miri has all of them.
+pub const MIRI_DIRS: [&str; 6] = [
+    "pass/stacked-borrows",
+    "pass/tree_borrows",
+    "pass/both_borrows",
+    "fail/stacked_borrows",
+    "fail/tree_borrows",
+    "fail/both_borrows",
+];
+
+// Add the .rs files below [dir] to [paths].
+fn find_tests(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.is_dir() {
+            find_tests(&path, paths)?;
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Import the tests below [dirs], naming each by its path relative to [root].
+fn import_below(
+    config: MachineConfig,
+    root: &Path,
+    dirs: &[PathBuf],
+) -> Result<ImportedTests, String> {
+    let mut paths = Vec::new();
+    for dir in dirs {
+        find_tests(dir, &mut paths)?;
+    }
+    paths.sort();
+
+    let mut imported = Vec::new();
+    let mut unsupported = Vec::new();
+
+    for path in paths {
+        let name = path.strip_prefix(root).unwrap().with_extension("");
+        let name: Vec<_> = name.iter().map(|part| part.to_string_lossy()).collect();
+        let name = name.join("/");
+        let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match import(config, &path, &text) {
+            Ok(litmus) => imported.push(Litmus { name, ..litmus }),
+            Err(msg) => unsupported.push((name, msg)),
+        }
+    }
+
+    Ok((imported, unsupported))
+}
+
+// Import every .rs file below [dir]. Returns the imported tests, and the
+// tests that could not be imported with the reason.
+pub fn import_all(config: MachineConfig, dir: &Path) -> Result<ImportedTests, String> {
+    import_below(config, dir, &[dir.to_path_buf()])
+}
+
+// Import the tests of Stacked and Tree Borrows from a checkout of Miri. Their
+// names are relative to the checkout's tests directory, which is returned as
+// well, since that is where miri::compare finds the programs.
+pub fn import_miri(
+    config: MachineConfig,
+    checkout: &Path,
+) -> Result<(PathBuf, ImportedTests), String> {
+    let root = checkout.join("tests");
+    let dirs: Vec<_> = MIRI_DIRS
+        .iter()
+        .map(|dir| root.join(dir))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    if dirs.is_empty() {
+        return Err(format!(
+            "{}: not a Miri checkout (none of tests/{} exist)",
+            checkout.display(),
+            MIRI_DIRS.join(", tests/")
+        ));
+    }
+    let tests = import_below(config, &root, &dirs)?;
+    Ok((root, tests))
+}
+
+pub type ImportedTests = (Vec<Litmus>, Vec<(String, String)>);
+
+// Write the traces of [tests] as <name>.tbm to [out], a results file
+// "expected" in the format of miri::compare, and a file "skipped" listing the
+// tests that could not be imported with the reason. Returns the names of the
+// imported tests.
+pub fn write_imported(tests: ImportedTests, out: &Path) -> Result<Imported, String> {
+    let (tests, unsupported) = tests;
+
+    fs::create_dir_all(out).map_err(|e| format!("{}: {}", out.display(), e))?;
+
+    let mut imported = Vec::new();
+    let mut results = String::new();
+    for litmus in tests {
+        let path = out.join(format!("{}.tbm", litmus.name));
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        litmus.trace.save(&path)?;
+        let verdict = match litmus.expected {
+            MiriVerdict::Ok => "ok",
+            MiriVerdict::UndefinedBehavior => "ub",
+        };
+        results.push_str(&format!("{} {}\n", litmus.name, verdict));
+        imported.push(litmus.name);
+    }
+
+    let results_path = out.join("expected");
+    fs::write(&results_path, results).map_err(|e| format!("{}: {}", results_path.display(), e))?;
+
+    let skipped: String = unsupported
+        .iter()
+        .map(|(name, reason)| format!("{}: {}\n", name, reason))
+        .collect();
+    let skipped_path = out.join("skipped");
+    fs::write(&skipped_path, skipped).map_err(|e| format!("{}: {}", skipped_path.display(), e))?;
+
+    Ok((imported, unsupported))
+}
+
+pub type Imported = (Vec<String>, Vec<(String, String)>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stuck_tests_say_where() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/litmus/write_through_shared.rs");
+        let text = fs::read_to_string(&path).unwrap();
+        let litmus = import(MachineConfig::default(), &path, &text).unwrap();
+        // The cast to *mut is already rejected, on the *const pointer created
+        // by the same statement.
+        let cast = "`let p = s as *const i32 as *mut i32` on line 5";
+        assert_eq!(
+            litmus.trace.header("stuck"),
+            Some(format!("{}, on the reference created by {}", cast, cast).as_str())
+        );
+    }
+
+    #[test]
+    fn only_the_aliasing_tests_of_miri_are_imported() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/litmus");
+        let checkout = std::env::temp_dir().join(format!("tbm-miri-{}", std::process::id()));
+        let tests = checkout.join("tests");
+        let files = [
+            ("pass/stacked-borrows", "unique_reborrow.rs", None),
+            ("fail/stacked_borrows", "write_through_shared.rs", None),
+            (
+                "fail/tree_borrows/nested",
+                "helper.rs",
+                Some("fn f(x: &mut i32) {}\n"),
+            ),
+            ("fail/data_race", "unique_siblings.rs", None),
+        ];
+        for (dir, file, text) in &files {
+            fs::create_dir_all(tests.join(dir)).unwrap();
+            let text = text.map_or_else(
+                || fs::read_to_string(corpus.join(file)).unwrap(),
+                String::from,
+            );
+            fs::write(tests.join(dir).join(file), text).unwrap();
+        }
+
+        let (root, imported) = import_miri(MachineConfig::default(), &checkout).unwrap();
+        assert_eq!(root, tests);
+        let out = checkout.join("out");
+        let (names, skipped) = write_imported(imported, &out).unwrap();
+        assert_eq!(
+            names,
+            [
+                "fail/stacked_borrows/write_through_shared",
+                "pass/stacked-borrows/unique_reborrow"
+            ]
+        );
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, "fail/tree_borrows/nested/helper");
+        let listed = fs::read_to_string(out.join("skipped")).unwrap();
+        assert!(
+            listed.starts_with("fail/tree_borrows/nested/helper: "),
+            "{}",
+            listed
+        );
+        assert!(out
+            .join("pass/stacked-borrows/unique_reborrow.tbm")
+            .exists());
+        assert_eq!(
+            fs::read_to_string(out.join("expected")).unwrap(),
+            "fail/stacked_borrows/write_through_shared ub\npass/stacked-borrows/unique_reborrow ok\n"
+        );
+
+        let comparison =
+            crate::miri::compare(&root, &out.join("expected"), &out, MachineConfig::default())
+                .unwrap();
+        assert_eq!(comparison.cases.len(), 2);
+        fs::remove_dir_all(&checkout).unwrap();
+        assert!(import_miri(MachineConfig::default(), &checkout).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+// Replaying a trace on the token machine and on the Stacked Borrows model
+// (sb.rs) at the same time, to find the first step at which they disagree
+// about whether an operation is allowed. Traces can also be stepped through
+// one operation at a time, which the REPL does with :lockstep and :next.
+
+use std::fmt;
+
+use crate::machine2::{
+    MachineConfig, MachineError, Operation, TokenExclusivity, TokenMachine, TokenPermissions,
+};
+use crate::sb::{SbError, StackedBorrows};
+use crate::trace::Trace;
+
+#[derive(Debug, Clone)]
+pub struct LockstepStep {
+    pub index: usize,
+    pub op: Operation,
+    pub token: Result<(), MachineError>,
+    pub sb: Result<(), SbError>,
+    // The states of both models after the step.
+    pub token_state: String,
+    pub sb_state: String,
+}
+
+impl LockstepStep {
+    pub fn agrees(&self) -> bool {
+        self.token.is_ok() == self.sb.is_ok()
+    }
+}
+
+fn outcome<E: ToString>(result: &Result<(), E>) -> String {
+    match result {
+        Ok(()) => "ok".to_string(),
+        Err(err) => err.to_string(),
+    }
+}
+
+// The operation, marked with "!" if the models disagree on it, and the
+// outcome and state of each model on a line of its own.
+impl fmt::Display for LockstepStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = if self.agrees() { " " } else { "!" };
+        writeln!(f, "{}{:>3}  {}", marker, self.index, self.op)?;
+        writeln!(
+            f,
+            "      token machine:   {:<40} {}",
+            outcome(&self.token),
+            self.token_state
+        )?;
+        write!(
+            f,
+            "      stacked borrows: {:<40} {}",
+            outcome(&self.sb),
+            self.sb_state
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lockstep {
+    pub steps: Vec<LockstepStep>,
+    // The index of the first step the models disagree on.
+    pub divergence: Option<usize>,
+}
+
+// A one-line description of who holds the token of each tree, e.g.
+// "r0: r1 r2 (2 pieces, readonly)".
+pub fn summarize(machine: &TokenMachine) -> String {
+    let mut trees = Vec::new();
+
+    for root in machine.roots() {
+        let holders: Vec<_> = machine
+            .references()
+            .into_iter()
+            .filter(|&r| machine.root_of(r) == root)
+            .filter_map(|r| machine.get_token_info(r).map(|info| (r, info)))
+            .collect();
+
+        let mut names = Vec::new();
+        for (r, info) in &holders {
+            if info.pieces_held == 1 {
+                names.push(r.to_string());
+            } else {
+                names.push(format!("{}x{}", r, info.pieces_held));
+            }
+        }
+        let perms = match machine.token_perms(root) {
+            TokenPermissions::ReadOnly => "readonly",
+            TokenPermissions::ReadWrite => "readwrite",
+        };
+        let sharing = match holders.first() {
+            Some((_, info)) if info.exclusivity == TokenExclusivity::Exclusive => "exclusive",
+            _ => "shared",
+        };
+
+        trees.push(format!(
+            "{}: {} ({}, {})",
+            root,
+            names.join(" "),
+            sharing,
+            perms
+        ));
+    }
+
+    trees.join("; ")
+}
+
+// Both models part way through a trace.
+#[derive(Debug, Clone)]
+pub struct Stepper {
+    ops: Vec<Operation>,
+    machine: TokenMachine,
+    sb: StackedBorrows,
+    next: usize,
+    // Set once a model rejected an operation, after which neither goes on.
+    stopped: bool,
+}
+
+impl Stepper {
+    pub fn new(config: MachineConfig, trace: &Trace) -> Self {
+        Stepper {
+            ops: trace.ops.clone(),
+            machine: TokenMachine::init_empty_with(config),
+            sb: StackedBorrows::new(),
+            next: 0,
+            stopped: false,
+        }
+    }
+
+    // Perform the next operation on both models, unless the trace has ended
+    // or one of them rejected the previous operation.
+    pub fn step(&mut self) -> Option<LockstepStep> {
+        if self.stopped {
+            return None;
+        }
+        let op = *self.ops.get(self.next)?;
+        let step = LockstepStep {
+            index: self.next,
+            op,
+            token: self.machine.apply(op).map(|_| ()),
+            sb: self.sb.apply(op).map(|_| ()),
+            token_state: summarize(&self.machine),
+            sb_state: self.sb.to_string(),
+        };
+        self.next += 1;
+        self.stopped = step.token.is_err() || step.sb.is_err();
+        Some(step)
+    }
+}
+
+// Replay [trace] on both models until one of them rejects an operation. If
+// the other one accepts that operation, that is where they diverge.
+pub fn lockstep(config: MachineConfig, trace: &Trace) -> Lockstep {
+    let mut stepper = Stepper::new(config, trace);
+    let mut steps = Vec::new();
+
+    while let Some(step) = stepper.step() {
+        let agrees = step.agrees();
+        let index = step.index;
+        steps.push(step);
+
+        if !agrees {
+            return Lockstep {
+                steps,
+                divergence: Some(index),
+            };
+        }
+    }
+
+    Lockstep {
+        steps,
+        divergence: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn stepping_stops_where_the_replay_does() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/errors");
+        for entry in fs::read_dir(dir).unwrap() {
+            let trace = Trace::load(&entry.unwrap().path()).unwrap();
+            let config = trace.config().unwrap().unwrap_or_default();
+            let result = lockstep(config, &trace);
+
+            let mut stepper = Stepper::new(config, &trace);
+            let mut stepped = Vec::new();
+            while let Some(step) = stepper.step() {
+                stepped.push(step.to_string());
+            }
+            // The replay stops at a divergence, which is also where one of
+            // the models rejects an operation.
+            let replayed: Vec<_> = result.steps.iter().map(ToString::to_string).collect();
+            assert_eq!(stepped, replayed);
+        }
+    }
+}
@@ -2,10 +2,11 @@ use std::collections::HashMap;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RefState {
-    // Active means the reference either has received the token already, but not
-    // yet returned it to its parent (although it may have passed it along to a
-    // child), or it has never received the token at all.
-    Active,
+    // The reference has never received the token.
+    Created,
+    // The reference has received the token but not yet returned it to its
+    // parent (although it may have passed it along to a child).
+    Borrowing,
     // Dead means that the reference has returned the token to its parent and
     // can never receive it again.
     Dead,
@@ -15,8 +16,11 @@ pub enum RefState {
 pub struct RefInfo {
     // The reference this reference was derived from
     parent: Reference,
-    // Current state of the reference
-    state: RefState,
+    // Whether the reference has returned the token. This is the only part of
+    // the state of a reference that is stored: whether it is borrowing follows
+    // from where the token is (see state_of), and storing it as well would
+    // only allow the two to disagree.
+    dead: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -31,6 +35,14 @@ pub struct TokenMachine {
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct Reference(u32);
 
+impl Reference {
+    // For replaying traces, which identify references by the IDs the machine
+    // will give them.
+    pub fn from_id(id: u32) -> Self {
+        Reference(id)
+    }
+}
+
 impl TokenMachine {
     // In the initial state of the machine, there is a single reference
     // (borrowing from itself) holding the token.
@@ -44,7 +56,7 @@ impl TokenMachine {
                 // This means we don't require an Option to distinguish whether a
                 // reference has a parent or not.
                 parent: initial_ref,
-                state: RefState::Active,
+                dead: false,
             },
         );
 
@@ -68,8 +80,8 @@ impl TokenMachine {
         self.ref_info.insert(
             new_ref,
             RefInfo {
-                state: RefState::Active,
                 parent,
+                dead: false,
             },
         );
 
@@ -87,10 +99,9 @@ impl TokenMachine {
             panic!("Parent needs to have the token in order to lend it to a child");
         }
 
-        match target_info.state {
-            RefState::Active => {}
-            RefState::Dead => panic!("Target cannot be dead"),
-        };
+        if target_info.dead {
+            panic!("Target cannot be dead");
+        }
 
         self.current_owner = target;
     }
@@ -104,10 +115,37 @@ impl TokenMachine {
 
         // You die when you return the token, meaning you can never receive it
         // again.
-        self.ref_info.get_mut(&source).unwrap().state = RefState::Dead;
+        self.ref_info.get_mut(&source).unwrap().dead = true;
         self.current_owner = target;
     }
 
+    // The reference that currently holds the token.
+    pub fn current_owner(&self) -> Reference {
+        self.current_owner
+    }
+
+    // The token only ever moves from a parent to a child and back, so the
+    // references that have received it and not returned it yet are exactly
+    // the current owner and its ancestors: each of them lent the token to the
+    // next one down the chain.
+    pub fn state_of(&self, source: Reference) -> RefState {
+        if self.ref_info[&source].dead {
+            return RefState::Dead;
+        }
+
+        let mut current = self.current_owner;
+        loop {
+            if current == source {
+                return RefState::Borrowing;
+            }
+            let parent = self.ref_info[&current].parent;
+            if parent == current {
+                return RefState::Created;
+            }
+            current = parent;
+        }
+    }
+
     // Use the token to perform a memory access. This requires the reference
     // [source] to be the current owner of the token.
     pub fn use_token(&mut self, source: Reference) {
@@ -1,6 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+use crate::ids::{IdAllocator, Sequential, SharedAllocator};
+use crate::profiling::{self, Counter};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum RefState {
     // This state means you've never held a tokens.
     Created,
@@ -16,19 +21,22 @@ pub enum RefState {
 // flag on the accesses indicating interior mutability? That would allow you to
 // "cast away" interior mutability before using the reference, though. Probably
 // safest to require changing the reference kind to involve a retagging.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum RefKind {
     SharedReadOnly,
     SharedReadWrite,
     Unique,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct RefInfo {
     kind: RefKind,
     state: RefState,
     // The reference this reference was derived from
     parent: Reference,
+    // The initial reference of the tree this reference belongs to. Token
+    // pieces can never move between trees.
+    root: Reference,
     // How many token pieces this reference has
     num_tokens: u32,
     // Into how many pieces has this reference fragmented its part of a token?
@@ -37,68 +45,784 @@ pub struct RefInfo {
     num_splits: u32,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum TokenExclusivity {
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TokenExclusivity {
     Shared,
     Exclusive,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum TokenPermissions {
     ReadOnly,
     ReadWrite,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct TokenInfo(TokenExclusivity, TokenPermissions);
+// The part of its tree's token a reference holds, as returned by
+// get_token_info.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TokenInfo {
+    // Whether the reference holds the only piece of the token.
+    pub exclusivity: TokenExclusivity,
+    // The permissions of the token, which are the same for all pieces.
+    pub perms: TokenPermissions,
+    // The number of pieces the reference holds itself, not counting pieces it
+    // has lent out.
+    pub pieces_held: u32,
+    // The number of pieces the token is split into, in total.
+    pub pieces_in_tree: u32,
+    // See outstanding_splits.
+    pub outstanding_splits: u32,
+    // The number of pieces held by other references that could write with
+    // them if the token is read-write (see TreeInfo::writer_pieces).
+    pub other_writer_pieces: u32,
+}
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum AccessKind {
     Read,
     Write,
 }
 
-#[derive(Debug, Clone)]
-pub struct TokenMachine {
-    ref_count: u32,
-    // Invariant: token_count should be equal to the sum of all values in
-    // RefInfo.num_tokens.
+// The rule that allowed an access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AccessRule {
+    // A shared read-only or unique reference read while holding the token
+    // exclusively.
+    ReadExclusive,
+    // A shared read-only or unique reference read with a piece of a token
+    // that is shared, but read-only, so that no one can write.
+    ReadSharedReadOnly,
+    // A shared read-only or unique reference read with a piece of a shared
+    // read-write token, none of whose other pieces are held by a reference
+    // that could write with it.
+    ReadNoWriters,
+    // A shared read-write reference read, which it can do with any token.
+    ReadAnyToken,
+    // A shared read-write reference wrote with a (possibly shared) read-write
+    // token.
+    WriteReadWrite,
+    // A unique reference wrote with an exclusive read-write token.
+    WriteExclusive,
+}
+
+// What a successful access was justified by: the token the reference held at
+// the time and the rule that allowed the access with it. This lets a harness
+// check not only that an access is allowed, but also why.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct AccessReceipt {
+    pub source: Reference,
+    pub ref_kind: RefKind,
+    pub access_kind: AccessKind,
+    pub exclusivity: TokenExclusivity,
+    pub perms: TokenPermissions,
+    pub rule: AccessRule,
+    // The time of the access, as returned by time() right after it.
+    pub time: u64,
+}
+
+// Every way in which the machine can reject an operation. The Display
+// messages are the ones the machine used to panic with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MachineError {
+    UnknownReference(Reference),
+    MutableFromImmutable,
+    LendWithoutToken,
+    TargetAlreadyBorrowing,
+    TargetDead,
+    ReturnWithoutToken,
+    ReturnPartialToken,
+    ReturnFromRoot,
+    DupWithoutToken,
+    DupReadWriteToken,
+    MergeWithoutPieces,
+    ReclaimWithoutToken,
+    ReclaimPiecesOutsideSubtree,
+    ReclaimPendingSplits,
+    SetPermsWithoutToken,
+    SetPermsNotExclusive,
+    AccessWithoutToken,
+    SharedReadOnlyReadWithWriters,
+    SharedReadOnlyWrite,
+    // Carries the change that made the token read-only, if there was one.
+    SharedReadWriteWriteNeedsReadWrite(Option<PermChange>),
+    UniqueReadWithWriters,
+    UniqueWriteNeedsExclusive,
+    ConstantWrite,
+    RetWithoutCall,
+    // An argument is passed other than right after call or another argument.
+    ArgumentOutsideCall,
+    // A reference created during a call still holds a piece of a token of a
+    // tree the call did not create when the call returns.
+    RetEscapingReference(Reference),
+    // An operation during a call involves a reference that was neither passed
+    // to the call nor created during it.
+    CallForeignReference(Reference),
+}
+
+impl MachineError {
+    // The name of the variant, without the data it carries. Used to tell
+    // kinds of errors apart, e.g. when collecting an example of each.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MachineError::UnknownReference(_) => "UnknownReference",
+            MachineError::MutableFromImmutable => "MutableFromImmutable",
+            MachineError::LendWithoutToken => "LendWithoutToken",
+            MachineError::TargetAlreadyBorrowing => "TargetAlreadyBorrowing",
+            MachineError::TargetDead => "TargetDead",
+            MachineError::ReturnWithoutToken => "ReturnWithoutToken",
+            MachineError::ReturnPartialToken => "ReturnPartialToken",
+            MachineError::ReturnFromRoot => "ReturnFromRoot",
+            MachineError::DupWithoutToken => "DupWithoutToken",
+            MachineError::DupReadWriteToken => "DupReadWriteToken",
+            MachineError::MergeWithoutPieces => "MergeWithoutPieces",
+            MachineError::ReclaimWithoutToken => "ReclaimWithoutToken",
+            MachineError::ReclaimPiecesOutsideSubtree => "ReclaimPiecesOutsideSubtree",
+            MachineError::ReclaimPendingSplits => "ReclaimPendingSplits",
+            MachineError::SetPermsWithoutToken => "SetPermsWithoutToken",
+            MachineError::SetPermsNotExclusive => "SetPermsNotExclusive",
+            MachineError::AccessWithoutToken => "AccessWithoutToken",
+            MachineError::SharedReadOnlyReadWithWriters => "SharedReadOnlyReadWithWriters",
+            MachineError::SharedReadOnlyWrite => "SharedReadOnlyWrite",
+            MachineError::SharedReadWriteWriteNeedsReadWrite(_) => {
+                "SharedReadWriteWriteNeedsReadWrite"
+            }
+            MachineError::UniqueReadWithWriters => "UniqueReadWithWriters",
+            MachineError::UniqueWriteNeedsExclusive => "UniqueWriteNeedsExclusive",
+            MachineError::ConstantWrite => "ConstantWrite",
+            MachineError::RetWithoutCall => "RetWithoutCall",
+            MachineError::ArgumentOutsideCall => "ArgumentOutsideCall",
+            MachineError::RetEscapingReference(_) => "RetEscapingReference",
+            MachineError::CallForeignReference(_) => "CallForeignReference",
+        }
+    }
+}
+
+// The names of all variants of MachineError.
+pub const ERROR_NAMES: &[&str] = &[
+    "UnknownReference",
+    "MutableFromImmutable",
+    "LendWithoutToken",
+    "TargetAlreadyBorrowing",
+    "TargetDead",
+    "ReturnWithoutToken",
+    "ReturnPartialToken",
+    "ReturnFromRoot",
+    "DupWithoutToken",
+    "DupReadWriteToken",
+    "MergeWithoutPieces",
+    "ReclaimWithoutToken",
+    "ReclaimPiecesOutsideSubtree",
+    "ReclaimPendingSplits",
+    "SetPermsWithoutToken",
+    "SetPermsNotExclusive",
+    "AccessWithoutToken",
+    "SharedReadOnlyReadWithWriters",
+    "SharedReadOnlyWrite",
+    "SharedReadWriteWriteNeedsReadWrite",
+    "UniqueReadWithWriters",
+    "UniqueWriteNeedsExclusive",
+    "ConstantWrite",
+    "RetWithoutCall",
+    "ArgumentOutsideCall",
+    "RetEscapingReference",
+    "CallForeignReference",
+];
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            MachineError::UnknownReference(r) => {
+                return write!(f, "Reference {} does not exist", r);
+            }
+            MachineError::MutableFromImmutable => {
+                "Cannot create mutable reference from immutable reference"
+            }
+            MachineError::LendWithoutToken => "Need to have a token to lend one out",
+            MachineError::TargetAlreadyBorrowing => "Target has already received a token before",
+            MachineError::TargetDead => "Target cannot be dead",
+            MachineError::ReturnWithoutToken => "Cannot give back a token if you don't have one",
+            MachineError::ReturnPartialToken => {
+                "Can only give back the entire token and not just some piece of it"
+            }
+            MachineError::ReturnFromRoot => {
+                "The initial reference has no parent to give its token back to"
+            }
+            MachineError::DupWithoutToken => "Cannot duplicate a token if you do not have a token",
+            MachineError::DupReadWriteToken => {
+                "Token has to be made read-only before it can be duplicated"
+            }
+            MachineError::MergeWithoutPieces => "Can only merge tokens if you have more than one",
+            MachineError::ReclaimWithoutToken => "Need to have a token to reclaim exclusivity",
+            MachineError::ReclaimPiecesOutsideSubtree => {
+                "Cannot reclaim exclusivity while references outside your subtree hold pieces"
+            }
+            MachineError::ReclaimPendingSplits => {
+                "Cannot reclaim exclusivity while a descendant has split its token"
+            }
+            MachineError::SetPermsWithoutToken => "have to own token to change its state",
+            MachineError::SetPermsNotExclusive => {
+                "Need to have exclusive ownership of the token to change its state"
+            }
+            MachineError::AccessWithoutToken => "Cannot read/write without a token",
+            MachineError::SharedReadOnlyReadWithWriters => {
+                "Cannot read with shared read-only reference if there are writers"
+            }
+            MachineError::SharedReadOnlyWrite => "Cannot write with read-only reference",
+            MachineError::SharedReadWriteWriteNeedsReadWrite(Some(change)) => {
+                return write!(
+                    f,
+                    "Writing using SharedRW requires read-write token ({})",
+                    change
+                );
+            }
+            MachineError::SharedReadWriteWriteNeedsReadWrite(None) => {
+                "Writing using SharedRW requires read-write token"
+            }
+            MachineError::UniqueReadWithWriters => {
+                "Cannot read with unique reference if there are writers"
+            }
+            MachineError::UniqueWriteNeedsExclusive => {
+                "Writing with unique reference requires exclusive read-write access"
+            }
+            MachineError::ConstantWrite => "Constants are read-only and cannot be written",
+            MachineError::RetWithoutCall => "Cannot return from a call outside of any call",
+            MachineError::ArgumentOutsideCall => {
+                "Arguments can only be passed right after entering a call"
+            }
+            MachineError::RetEscapingReference(r) => {
+                return write!(
+                    f,
+                    "Reference {} was created during the call and still holds a token when it returns",
+                    r
+                );
+            }
+            MachineError::CallForeignReference(r) => {
+                return write!(
+                    f,
+                    "Reference {} was not passed to the call or created during it",
+                    r
+                );
+            }
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for MachineError {}
+
+// A single step of the machine. Having operations as data allows sequences of
+// them to be generated and replayed, for instance by the planner.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Operation {
+    NewRoot,
+    NewConstRoot,
+    CreateRef(Reference, RefKind),
+    Borrow(Reference),
+    Return(Reference),
+    Dup(Reference),
+    Merge(Reference),
+    SetPerms(Reference, TokenPermissions),
+    Use(Reference, AccessKind),
+    ReclaimExclusive(Reference),
+    // Enter a function, passing it the given reference (see Frame).
+    Call(Reference),
+    // Pass a further reference to the call just entered.
+    Arg(Reference),
+    Ret,
+}
+
+// What happens to the permissions of a token when it is duplicated. Without a
+// restriction, duplicating a read-write token makes it shared but keeps it
+// read-write, which lets two SharedReadWrite references write while both hold
+// a piece.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DupRule {
+    // Duplication leaves the permissions alone.
+    Unrestricted,
+    // Duplicating a read-write token turns it into a read-only token.
+    CapToReadOnly,
+    // Only read-only tokens can be duplicated, so the permissions have to be
+    // lowered explicitly using set_token_perms first.
+    RequireReadOnly,
+}
+
+// Whether a reference that has split its token can give it back piece by
+// piece.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ReturnRule {
+    // The entire token has to be given back at once, so all pieces have to be
+    // merged first.
+    Strict,
+    // Individual pieces can be given back. The reference dies once it gives
+    // back its last piece.
+    Partial,
+}
+
+// Rule variants of the machine, so that the consequences of different choices
+// can be compared.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct MachineConfig {
+    pub dup_rule: DupRule,
+    pub return_rule: ReturnRule,
+    pub root: RootConfig,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            dup_rule: DupRule::Unrestricted,
+            return_rule: ReturnRule::Strict,
+            root: RootConfig::default(),
+        }
+    }
+}
+
+// The state every new tree starts out in (except for constants, see
+// new_const_root). By default, the root is a unique reference holding an
+// exclusive read-write token, like a local variable. Other starting points
+// model memory the program did not allocate itself, e.g. a SharedReadWrite
+// root for a raw pointer received through FFI, whose token may already be
+// split because other code holds on to the same memory.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RootConfig {
+    pub kind: RefKind,
+    pub perms: TokenPermissions,
+    // The number of pieces the root's token is split into from the start.
+    // The root holds all of them, as if it had already duplicated its token
+    // pieces - 1 times. Must be at least 1 and at most MAX_ROOT_PIECES.
+    pub pieces: u32,
+}
+
+// The most pieces a root can start out with. Every piece is tracked on its
+// own, and configurations come from trace headers and requests to tbm serve,
+// so the number has to be bounded.
+pub const MAX_ROOT_PIECES: u32 = 4096;
+
+impl Default for RootConfig {
+    fn default() -> Self {
+        RootConfig {
+            kind: RefKind::Unique,
+            perms: TokenPermissions::ReadWrite,
+            pieces: 1,
+        }
+    }
+}
+
+// A record of a reference changing the permissions of the token of its tree,
+// either explicitly or as a side effect of duplicating it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PermChange {
+    // The reference that performed the change.
+    pub by: Reference,
+    // The root of the tree whose token changed.
+    pub root: Reference,
+    // The machine time (see TokenMachine::time) at which the change happened.
+    pub at: u64,
+    pub from: TokenPermissions,
+    pub to: TokenPermissions,
+}
+
+impl fmt::Display for PermChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} changed the token from {:?} to {:?} at time {}",
+            self.by, self.from, self.to, self.at
+        )
+    }
+}
+
+// Why a reference has the kind it has. References get their kind when they
+// are created, so for now this is all there is to a reference's kind history;
+// a cast between kinds would add a justification of its own.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum KindJustification {
+    // The reference is the root of a tree, with the kind from the
+    // configuration, or Unique for constants (see add_root).
+    Root {
+        constant: bool,
+    },
+    // The reference was derived from [parent], which had kind [parent_kind]
+    // at the time.
+    Derived {
+        parent: Reference,
+        parent_kind: RefKind,
+    },
+}
+
+// A record of a reference getting a kind.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct KindChange {
+    pub reference: Reference,
+    // The machine time at which the reference got the kind.
+    pub at: u64,
+    pub kind: RefKind,
+    pub justification: KindJustification,
+}
+
+impl fmt::Display for KindChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} became {:?} at time {}",
+            self.reference, self.kind, self.at
+        )?;
+        match self.justification {
+            KindJustification::Root { constant: false } => write!(f, " as a root"),
+            KindJustification::Root { constant: true } => write!(f, " as a constant root"),
+            KindJustification::Derived {
+                parent,
+                parent_kind,
+            } => write!(f, ", derived from {} ({:?})", parent, parent_kind),
+        }
+    }
+}
+
+// Every root reference has its own token, which can be split up and lent out
+// within its tree independently of the tokens of other trees. This models
+// distinct local variables of a program.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct TreeInfo {
+    // Invariant: token_count should be equal to the sum of RefInfo.num_tokens
+    // over all references in this tree.
     token_count: u32,
-    ref_info: HashMap<Reference, RefInfo>,
+    // Invariant: writer_pieces should be equal to the number of pieces held
+    // by SharedReadWrite references in this tree. These are the only pieces
+    // that can be written with while the token is shared (a unique reference
+    // needs the token exclusively), so the other pieces are only readers.
+    writer_pieces: u32,
     token_perms: TokenPermissions,
+    // Constants (promoted constants and static items) have a token that is
+    // read-only forever, and is always considered shared, because any part
+    // of the program may be reading it at any time.
+    constant: bool,
+    // Set for the tree of a call's local once the call returns (see ret). Its
+    // references stay around, dead, but it is no longer one of the roots.
+    deallocated: bool,
+}
+
+// Every piece of a token has an identity of its own, so that a particular
+// piece can be followed through the tree: from the reference that created it,
+// through every reference that lent it on, until it is merged away again.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct PieceId(u32);
+
+impl PieceId {
+    pub fn id(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for PieceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "p{}", self.0)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PieceInfo {
+    // The root of the tree whose token this is a piece of.
+    pub root: Reference,
+    // The reference that created the piece: the root for the first piece of
+    // a tree, otherwise the reference that split it off with dup_token.
+    pub creator: Reference,
+    // The reference currently holding the piece.
+    pub holder: Reference,
+    // The machine time at which the holder received (or created) the piece.
+    // A reference always passes on or merges away the piece it received
+    // last, so that a piece it lent out and got back is the first to go.
+    pub received: u64,
+}
+
+impl Operation {
+    // The references an operation refers to.
+    pub fn references(self) -> Vec<Reference> {
+        match self {
+            Operation::NewRoot | Operation::NewConstRoot | Operation::Ret => vec![],
+            Operation::CreateRef(r, _)
+            | Operation::Borrow(r)
+            | Operation::Return(r)
+            | Operation::Dup(r)
+            | Operation::Merge(r)
+            | Operation::SetPerms(r, _)
+            | Operation::Use(r, _)
+            | Operation::ReclaimExclusive(r)
+            | Operation::Call(r)
+            | Operation::Arg(r) => vec![r],
+        }
+    }
+
+    // The same operation, with every reference it refers to replaced by [f].
+    pub fn map_references<F: Fn(Reference) -> Reference>(self, f: F) -> Operation {
+        match self {
+            Operation::NewRoot | Operation::NewConstRoot | Operation::Ret => self,
+            Operation::CreateRef(r, kind) => Operation::CreateRef(f(r), kind),
+            Operation::Borrow(r) => Operation::Borrow(f(r)),
+            Operation::Return(r) => Operation::Return(f(r)),
+            Operation::Dup(r) => Operation::Dup(f(r)),
+            Operation::Merge(r) => Operation::Merge(f(r)),
+            Operation::SetPerms(r, perms) => Operation::SetPerms(f(r), perms),
+            Operation::Use(r, access) => Operation::Use(f(r), access),
+            Operation::ReclaimExclusive(r) => Operation::ReclaimExclusive(f(r)),
+            Operation::Call(r) => Operation::Call(f(r)),
+            Operation::Arg(r) => Operation::Arg(f(r)),
+        }
+    }
+}
+
+// A function call in progress. A call is entered with one argument, and
+// further arguments are passed with Arg right after it; they have to be
+// references the caller can use. The callee can only use its arguments and
+// the references it creates itself, and when it returns, every reborrow it
+// made has to have given its token back, so that the token ends up with the
+// arguments again. The references created during the call are dead
+// afterwards. Trees created during the call are the callee's own (locals):
+// they are exempt from giving tokens back, and are deallocated as a whole at
+// the return, tokens and all. Calls nest, and a nested call only sees what
+// its own caller passes it.
+//
+// This is not a protector: nothing stops the caller's other references from
+// being used after the call, it only keeps the callee's reborrows from
+// outliving it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Frame {
+    // In the order they were passed.
+    pub arguments: Vec<Reference>,
+    // In order of creation.
+    pub created: Vec<Reference>,
+    // Whether arguments can still be passed, i.e. nothing else happened since
+    // the call was entered.
+    pub open: bool,
+}
+
+impl Frame {
+    // Whether [r] can be used during this call.
+    pub fn can_use(&self, r: Reference) -> bool {
+        self.arguments.contains(&r) || self.created.contains(&r)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TokenMachine {
+    config: MachineConfig,
+    // The number of operations performed successfully so far.
+    time: u64,
+    // The number of references created so far.
+    ref_count: u32,
+    ids: SharedAllocator,
+    ref_info: HashMap<Reference, RefInfo>,
+    // Indexed by the root reference of each tree.
+    trees: HashMap<Reference, TreeInfo>,
+    // Every permission change so far, in order.
+    perm_changes: Vec<PermChange>,
+    // Every reference getting a kind so far, in order.
+    kind_changes: Vec<KindChange>,
+    // Every piece of every token that currently exists. Pieces are created
+    // along with a tree and by dup_token, and destroyed by merge_token.
+    pieces: BTreeMap<PieceId, PieceInfo>,
+    // The number of pieces created so far, used to number new ones. IDs of
+    // merged pieces are not reused.
+    piece_count: u32,
+    // The calls in progress, innermost last.
+    frames: Vec<Frame>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub struct Reference(u32);
 
+impl Reference {
+    // References are normally handed out by the machine. Constructing one by
+    // hand is meant for reading traces, where references are identified by
+    // the IDs the machine will give them.
+    pub fn from_id(id: u32) -> Self {
+        Reference(id)
+    }
+
+    pub fn id(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "r{}", self.0)
+    }
+}
+
+// HashMap does not implement Hash, so hash the reference table in a canonical
+// (sorted) order. This allows sets of machine states to be kept, e.g. to avoid
+// revisiting states during a search.
+impl Hash for TokenMachine {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.config.hash(state);
+        self.time.hash(state);
+        self.ref_count.hash(state);
+        // The ID allocator is left out: its description is a string, and
+        // hashing it for every state would slow down searches for no gain.
+
+        let mut entries: Vec<_> = self.ref_info.iter().collect();
+        entries.sort_by_key(|(r, _)| **r);
+        entries.hash(state);
+
+        let mut trees: Vec<_> = self.trees.iter().collect();
+        trees.sort_by_key(|(r, _)| **r);
+        trees.hash(state);
+
+        self.perm_changes.hash(state);
+        self.kind_changes.hash(state);
+        self.pieces.hash(state);
+        self.piece_count.hash(state);
+        self.frames.hash(state);
+    }
+}
+
+// Written out by hand so that clones can be counted when profiling.
+impl Clone for TokenMachine {
+    fn clone(&self) -> Self {
+        profiling::count(Counter::Clones);
+
+        TokenMachine {
+            config: self.config,
+            time: self.time,
+            ref_count: self.ref_count,
+            ids: self.ids.clone(),
+            ref_info: self.ref_info.clone(),
+            trees: self.trees.clone(),
+            perm_changes: self.perm_changes.clone(),
+            kind_changes: self.kind_changes.clone(),
+            pieces: self.pieces.clone(),
+            piece_count: self.piece_count,
+            frames: self.frames.clone(),
+        }
+    }
+}
+
 impl TokenMachine {
     pub fn init() -> (Reference, Self) {
-        let initial_ref = Reference(0);
+        TokenMachine::init_with(MachineConfig::default())
+    }
+
+    // A machine with a single tree, whose root starts out as [config.root]
+    // says.
+    pub fn init_with(config: MachineConfig) -> (Reference, Self) {
+        let mut machine = TokenMachine::init_empty_with(config);
+        let initial_ref = machine.new_root();
+
+        (initial_ref, machine)
+    }
+
+    // A machine without any references. Use new_root to add trees to it.
+    pub fn init_empty() -> Self {
+        TokenMachine::init_empty_with(MachineConfig::default())
+    }
+
+    pub fn init_empty_with(config: MachineConfig) -> Self {
+        TokenMachine {
+            config,
+            time: 0,
+            ref_count: 0,
+            ids: SharedAllocator::new(Sequential),
+            ref_info: HashMap::new(),
+            trees: HashMap::new(),
+            perm_changes: Vec::new(),
+            kind_changes: Vec::new(),
+            pieces: BTreeMap::new(),
+            piece_count: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    // Replace the way IDs are picked for references created from now on.
+    pub fn set_id_allocator<A: IdAllocator + 'static>(&mut self, ids: A) {
+        self.ids = SharedAllocator::new(ids);
+    }
 
-        let mut ref_info = HashMap::new();
-        ref_info.insert(
-            initial_ref,
+    fn allocate_id(&mut self, key: Option<&str>) -> Reference {
+        let mut attempt = 0;
+        let new_ref = loop {
+            profiling::count(Counter::MapLookups);
+            let candidate = Reference(self.ids.allocate(key, self.ref_count, attempt));
+            if !self.ref_info.contains_key(&candidate) {
+                break candidate;
+            }
+            attempt += 1;
+        };
+        self.ref_count += 1;
+        if let Some(frame) = self.frames.last_mut() {
+            frame.created.push(new_ref);
+        }
+
+        new_ref
+    }
+
+    // Add a new tree to the machine, consisting of a single reference holding
+    // the token of the tree, in the state given by the configuration (an
+    // exclusive read-write token by default, see RootConfig).
+    pub fn new_root(&mut self) -> Reference {
+        self.new_root_keyed(None)
+    }
+
+    // Like new_root, but passes [key] to the ID allocator.
+    pub fn new_root_keyed(&mut self, key: Option<&str>) -> Reference {
+        self.add_root(key, false)
+    }
+
+    // Add a new tree for a constant, such as a promoted constant or a static
+    // item. Its token is read-only and shared, and any attempt to write
+    // through a reference in the tree is rejected with ConstantWrite.
+    pub fn new_const_root(&mut self) -> Reference {
+        self.add_root(None, true)
+    }
+
+    fn add_root(&mut self, key: Option<&str>, constant: bool) -> Reference {
+        let new_ref = self.allocate_id(key);
+        let root = if constant {
+            RootConfig {
+                perms: TokenPermissions::ReadOnly,
+                ..RootConfig::default()
+            }
+        } else {
+            self.config.root
+        };
+        assert!(root.pieces >= 1, "a root needs at least one token piece");
+
+        self.ref_info.insert(
+            new_ref,
             RefInfo {
-                kind: RefKind::Unique,
+                kind: root.kind,
                 state: RefState::Borrowing,
-                num_tokens: 1,
-                num_splits: 0,
+                num_tokens: root.pieces,
+                num_splits: root.pieces - 1,
                 // Initial reference borrows from itself: this simplifies the code since
                 // we don't have to consider two cases, one where a reference has a
                 // parent and one where it doesn't.
-                parent: initial_ref,
+                parent: new_ref,
+                root: new_ref,
             },
         );
-
-        (
-            initial_ref,
-            TokenMachine {
-                ref_count: 1,
-                token_count: 1,
-                ref_info,
-                token_perms: TokenPermissions::ReadWrite,
+        self.trees.insert(
+            new_ref,
+            TreeInfo {
+                token_count: root.pieces,
+                writer_pieces: 0,
+                token_perms: root.perms,
+                constant,
+                deallocated: false,
             },
-        )
+        );
+        for _ in 0..root.pieces {
+            self.create_piece(new_ref);
+        }
+        self.kind_changes.push(KindChange {
+            reference: new_ref,
+            at: self.time,
+            kind: root.kind,
+            justification: KindJustification::Root { constant },
+        });
+
+        self.time += 1;
+
+        new_ref
     }
 
     // Initially tried to do reference without tracking the parent (instead
@@ -109,17 +833,29 @@ impl TokenMachine {
     // to Y and return the token back to X for the read). This is made
     // impossible if you force X to return its token to the common ancestor
     // before being able to lend to Y.
-    pub fn create_ref(&mut self, parent: Reference, kind: RefKind) -> Reference {
-        let parent_info = self.ref_info[&parent];
+    pub fn create_ref(
+        &mut self,
+        parent: Reference,
+        kind: RefKind,
+    ) -> Result<Reference, MachineError> {
+        self.create_ref_keyed(parent, kind, None)
+    }
+
+    // Like create_ref, but passes [key] to the ID allocator.
+    pub fn create_ref_keyed(
+        &mut self,
+        parent: Reference,
+        kind: RefKind,
+        key: Option<&str>,
+    ) -> Result<Reference, MachineError> {
+        let parent_info = self.info(parent);
         if parent_info.kind == RefKind::SharedReadOnly && kind != RefKind::SharedReadOnly {
             // Prevent read-only reference from spawning mutable references and
             // using them to mutate.
-            panic!("Cannot create mutable reference from immutable reference");
+            return Err(MachineError::MutableFromImmutable);
         }
 
-        let id = self.ref_count;
-        self.ref_count += 1;
-        let new_ref = Reference(id);
+        let new_ref = self.allocate_id(key);
 
         self.ref_info.insert(
             new_ref,
@@ -127,22 +863,34 @@ impl TokenMachine {
                 kind,
                 state: RefState::Created,
                 parent,
+                root: parent_info.root,
                 num_tokens: 0,
                 num_splits: 0,
             },
         );
+        self.kind_changes.push(KindChange {
+            reference: new_ref,
+            at: self.time,
+            kind,
+            justification: KindJustification::Derived {
+                parent,
+                parent_kind: parent_info.kind,
+            },
+        });
 
-        new_ref
+        self.time += 1;
+
+        Ok(new_ref)
     }
 
-    pub fn borrow_token(&mut self, target: Reference) {
-        let target_info = self.ref_info[&target];
+    pub fn borrow_token(&mut self, target: Reference) -> Result<(), MachineError> {
+        let target_info = self.info(target);
         let source = target_info.parent;
-        let source_info = self.ref_info[&source];
+        let source_info = self.info(source);
 
         // Source must own a token to lend one out
         if source_info.num_tokens == 0 {
-            panic!("Need to have a token to lend one out");
+            return Err(MachineError::LendWithoutToken);
         }
 
         // Target must be ready to receive a token.
@@ -155,79 +903,368 @@ impl TokenMachine {
 
                 // Need to increment num_splits when you do so, in order to make
                 // sure that all such tokens get sent back.
-                panic!("Target has already received a token before")
+                return Err(MachineError::TargetAlreadyBorrowing);
             }
-            RefState::Dead { .. } => panic!("Target cannot be dead"),
+            RefState::Dead => return Err(MachineError::TargetDead),
         };
 
-        self.ref_info.get_mut(&source).unwrap().num_tokens -= 1;
-        self.ref_info.get_mut(&target).unwrap().num_tokens += 1;
+        self.transfer_piece(source, target);
+
+        self.info_mut(target).state = RefState::Borrowing;
 
-        self.ref_info.get_mut(&target).unwrap().state = RefState::Borrowing;
+        self.time += 1;
+
+        Ok(())
     }
 
-    pub fn return_token(&mut self, source: Reference) {
-        let source_info = self.ref_info[&source];
+    // Like every operation, this checks everything that can go wrong before
+    // changing anything, so that a rejected return leaves the machine as it
+    // was (see apply).
+    pub fn return_token(&mut self, source: Reference) -> Result<(), MachineError> {
+        let source_info = self.info(source);
 
         if source_info.num_tokens == 0 {
-            panic!("Cannot give back a token if you don't have one");
+            return Err(MachineError::ReturnWithoutToken);
         }
 
+        let target = source_info.parent;
+
         if source_info.num_splits > 0 {
-            panic!("Can only give back the entire token and not just some piece of it");
+            match self.config.return_rule {
+                ReturnRule::Strict => return Err(MachineError::ReturnPartialToken),
+                ReturnRule::Partial => return self.return_piece(source),
+            }
         }
 
         assert!(source_info.num_tokens == 1);
 
-        let target = source_info.parent;
+        // The initial reference borrows from itself, so returning its token
+        // would kill it while it still holds the token.
+        if target == source {
+            return Err(MachineError::ReturnFromRoot);
+        }
 
-        self.ref_info.get_mut(&source).unwrap().num_tokens -= 1;
-        self.ref_info.get_mut(&target).unwrap().num_tokens += 1;
+        self.transfer_piece(source, target);
 
-        self.ref_info.get_mut(&source).unwrap().state = RefState::Dead;
+        self.info_mut(source).state = RefState::Dead;
+
+        self.time += 1;
+
+        Ok(())
     }
 
-    pub fn dup_token(&mut self, source: Reference) {
-        let source_info = self.ref_info[&source];
+    // Give back a single piece of a token that [source] has split. The piece
+    // is one that [source] no longer has to merge, so it owes one split less,
+    // whereas its parent now holds a piece that it has to merge itself before
+    // it can give back its own token. [source] stays alive, since it still
+    // holds (or has lent out) the rest of the token.
+    fn return_piece(&mut self, source: Reference) -> Result<(), MachineError> {
+        let target = self.info(source).parent;
+
+        if target == source {
+            return Err(MachineError::ReturnFromRoot);
+        }
+
+        self.transfer_piece(source, target);
+
+        self.info_mut(source).num_splits -= 1;
+        self.info_mut(target).num_splits += 1;
+
+        self.time += 1;
+
+        Ok(())
+    }
+
+    // Move a single token piece from [source] to [target]. All movement of
+    // pieces between references goes through here. Pieces only ever move
+    // between a reference and its parent, which are always in the same tree,
+    // so a piece can never end up in a different tree than the one whose
+    // token it is part of.
+    fn transfer_piece(&mut self, source: Reference, target: Reference) {
+        debug_assert_eq!(self.info(source).root, self.info(target).root);
+
+        let piece = self.latest_piece(source);
+        let time = self.time;
+        let piece_info = self.pieces.get_mut(&piece).unwrap();
+        piece_info.holder = target;
+        piece_info.received = time;
+
+        let root = self.info(source).root;
+        if self.is_writer(source) {
+            self.tree_mut(root).writer_pieces -= 1;
+        }
+        if self.is_writer(target) {
+            self.tree_mut(root).writer_pieces += 1;
+        }
+
+        self.info_mut(source).num_tokens -= 1;
+        self.info_mut(target).num_tokens += 1;
+    }
+
+    // Whether the pieces [r] holds count as writer pieces of its tree.
+    fn is_writer(&self, r: Reference) -> bool {
+        self.info(r).kind == RefKind::SharedReadWrite
+    }
+
+    // Register a new piece, held by the reference that creates it.
+    fn create_piece(&mut self, creator: Reference) -> PieceId {
+        let piece = PieceId(self.piece_count);
+        self.piece_count += 1;
+        if self.is_writer(creator) {
+            let root = self.info(creator).root;
+            self.tree_mut(root).writer_pieces += 1;
+        }
+        self.pieces.insert(
+            piece,
+            PieceInfo {
+                root: self.info(creator).root,
+                creator,
+                holder: creator,
+                received: self.time,
+            },
+        );
+        piece
+    }
+
+    // The piece [holder] received last, which is the one it gives up next.
+    fn latest_piece(&self, holder: Reference) -> PieceId {
+        self.pieces
+            .iter()
+            .filter(|(_, info)| info.holder == holder)
+            .max_by_key(|(piece, info)| (info.received, **piece))
+            .map(|(piece, _)| *piece)
+            .expect("reference holding a token has no pieces")
+    }
+
+    pub fn dup_token(&mut self, source: Reference) -> Result<(), MachineError> {
+        let source_info = self.info(source);
 
         if source_info.num_tokens == 0 {
-            panic!("Cannot duplicate a token if you do not have a token");
+            return Err(MachineError::DupWithoutToken);
         }
 
-        let source_info = self.ref_info.get_mut(&source).unwrap();
+        let tree_info = self.tree(source_info.root);
+        let token_perms = match (self.config.dup_rule, tree_info.token_perms) {
+            (_, TokenPermissions::ReadOnly) | (DupRule::Unrestricted, _) => tree_info.token_perms,
+            (DupRule::CapToReadOnly, TokenPermissions::ReadWrite) => TokenPermissions::ReadOnly,
+            (DupRule::RequireReadOnly, TokenPermissions::ReadWrite) => {
+                return Err(MachineError::DupReadWriteToken);
+            }
+        };
+
+        self.record_perm_change(source, token_perms);
+
+        let source_info = self.info_mut(source);
         source_info.num_tokens += 1;
         source_info.num_splits += 1;
-        self.token_count += 1;
+        let root = source_info.root;
+
+        let tree_info = self.tree_mut(root);
+        tree_info.token_count += 1;
+        tree_info.token_perms = token_perms;
+        self.create_piece(source);
+
+        self.time += 1;
+
+        Ok(())
     }
 
-    pub fn merge_token(&mut self, source: Reference) {
-        let source_info = self.ref_info[&source];
+    pub fn merge_token(&mut self, source: Reference) -> Result<(), MachineError> {
+        let source_info = self.info(source);
 
         if source_info.num_tokens <= 1 {
-            panic!("Can only merge tokens if you have more than one");
+            return Err(MachineError::MergeWithoutPieces);
+        }
+        assert!(source_info.num_splits > 0);
+
+        let piece = self.latest_piece(source);
+        self.pieces.remove(&piece);
+        if self.is_writer(source) {
+            let root = self.info(source).root;
+            self.tree_mut(root).writer_pieces -= 1;
         }
 
-        let source_info = self.ref_info.get_mut(&source).unwrap();
+        let source_info = self.info_mut(source);
         source_info.num_tokens -= 1;
         source_info.num_splits -= 1;
-        self.token_count -= 1;
+        let root = source_info.root;
+        self.tree_mut(root).token_count -= 1;
+
+        self.time += 1;
+
+        Ok(())
+    }
+
+    // Whether [descendant] was derived (directly or indirectly) from
+    // [ancestor]. A reference is not its own descendant.
+    pub fn is_descendant(&self, descendant: Reference, ancestor: Reference) -> bool {
+        let mut current = descendant;
+        loop {
+            let parent = self.info(current).parent;
+            if parent == current {
+                return false;
+            }
+            if parent == ancestor {
+                return true;
+            }
+            current = parent;
+        }
     }
 
-    pub fn set_token_perms(&mut self, source: Reference, token_perms: TokenPermissions) {
+    // Force all descendants of [source] that are borrowing to return their
+    // token, and merge the pieces that come back, so that [source] ends up
+    // holding the token of its tree exclusively. This models a unique
+    // reference reasserting itself after a phase in which it was shared with
+    // references derived from it.
+    //
+    // This is only allowed if every other piece of the token is held within
+    // the subtree of [source], and none of the borrowing descendants have
+    // split their token: a split means the descendant is in the middle of
+    // something that it has to finish itself. Inside a call, it is also only
+    // allowed if the callee can use every borrowing descendant (see Frame),
+    // since it could not make the others return either. The whole operation
+    // counts as a single step.
+    pub fn reclaim_exclusive(&mut self, source: Reference) -> Result<(), MachineError> {
+        let source_info = self.info(source);
+
+        if source_info.num_tokens == 0 {
+            return Err(MachineError::ReclaimWithoutToken);
+        }
+
+        let tree: Vec<(Reference, RefInfo)> = self
+            .ref_info
+            .iter()
+            .filter(|(_, info)| info.root == source_info.root)
+            .map(|(r, info)| (*r, *info))
+            .collect();
+
+        let mut borrowing_descendants = Vec::new();
+        for &(r, info) in &tree {
+            if r == source {
+                continue;
+            }
+
+            let is_descendant = self.is_descendant(r, source);
+            if info.num_tokens > 0 && !is_descendant {
+                return Err(MachineError::ReclaimPiecesOutsideSubtree);
+            }
+
+            if is_descendant && info.state == RefState::Borrowing {
+                if info.num_splits > 0 {
+                    return Err(MachineError::ReclaimPendingSplits);
+                }
+                borrowing_descendants.push(r);
+            }
+        }
+
+        if let Some(frame) = self.frames.last() {
+            if let Some(&r) = borrowing_descendants.iter().find(|&&r| !frame.can_use(r)) {
+                return Err(MachineError::CallForeignReference(r));
+            }
+        }
+
+        // Without splits below [source], the borrowing descendants form chains
+        // that each hold a single piece. Returning from the deepest reference
+        // upwards moves every piece back to [source].
+        let depth = |r: Reference| {
+            let mut depth = 0;
+            let mut current = r;
+            while current != source {
+                current = self.info(current).parent;
+                depth += 1;
+            }
+            depth
+        };
+        borrowing_descendants.sort_by_key(|&r| std::cmp::Reverse(depth(r)));
+
+        // The returns and merges are performed on a staged copy that only
+        // replaces this machine once all of them went through, so that
+        // reclaiming either happens in full or not at all.
+        let mut staged = self.clone();
+        for r in borrowing_descendants {
+            staged.return_token(r)?;
+        }
+        while staged.info(source).num_tokens > 1 {
+            staged.merge_token(source)?;
+        }
+        staged.time = self.time + 1;
+        *self = staged;
+
+        Ok(())
+    }
+
+    pub fn set_token_perms(
+        &mut self,
+        source: Reference,
+        token_perms: TokenPermissions,
+    ) -> Result<(), MachineError> {
+        let root = self.info(source).root;
+        if self.tree(root).constant && token_perms == TokenPermissions::ReadWrite {
+            return Err(MachineError::ConstantWrite);
+        }
+
         // Changing the state of the token requires exclusive ownership of it.
         let token_info = self
             .get_token_info(source)
-            .expect("have to own token to change its state");
+            .ok_or(MachineError::SetPermsWithoutToken)?;
 
-        if token_info.0 != TokenExclusivity::Exclusive {
-            panic!("Need to have exclusive ownership of the token to change its state");
+        if token_info.exclusivity != TokenExclusivity::Exclusive {
+            return Err(MachineError::SetPermsNotExclusive);
         }
 
-        self.token_perms = token_perms;
+        self.record_perm_change(source, token_perms);
+
+        self.tree_mut(root).token_perms = token_perms;
+
+        self.time += 1;
+
+        Ok(())
     }
 
-    fn get_token_info(&self, source: Reference) -> Option<TokenInfo> {
-        let source_info = self.ref_info[&source];
+    // All lookups of reference and tree information go through these, so
+    // that they can be counted when profiling.
+    fn info(&self, r: Reference) -> RefInfo {
+        profiling::count(Counter::MapLookups);
+        self.ref_info[&r]
+    }
+
+    fn info_mut(&mut self, r: Reference) -> &mut RefInfo {
+        profiling::count(Counter::MapLookups);
+        self.ref_info.get_mut(&r).unwrap()
+    }
+
+    fn tree(&self, root: Reference) -> TreeInfo {
+        profiling::count(Counter::MapLookups);
+        self.trees[&root]
+    }
+
+    fn tree_mut(&mut self, root: Reference) -> &mut TreeInfo {
+        profiling::count(Counter::MapLookups);
+        self.trees.get_mut(&root).unwrap()
+    }
+
+    // Remember that [source] is about to set the permissions of its token to
+    // [to]. Setting the permissions to what they already are is not a change.
+    fn record_perm_change(&mut self, source: Reference, to: TokenPermissions) {
+        let root = self.info(source).root;
+        let from = self.tree(root).token_perms;
+
+        if from != to {
+            self.perm_changes.push(PermChange {
+                by: source,
+                root,
+                at: self.time,
+                from,
+                to,
+            });
+        }
+    }
+
+    // The part of the token [source] currently holds, or None if it holds no
+    // piece of it (because it has not borrowed yet, has lent out everything it
+    // had, or is dead).
+    pub fn get_token_info(&self, source: Reference) -> Option<TokenInfo> {
+        let source_info = self.info(source);
 
         if source_info.num_tokens == 0 {
             return None;
@@ -237,76 +1274,763 @@ impl TokenMachine {
         // you gave your token back entirely.
         assert!(source_info.state != RefState::Dead);
 
-        let exclusivity = if self.token_count == 1 {
+        let tree_info = self.tree(source_info.root);
+
+        let exclusivity = if tree_info.token_count == 1 && !tree_info.constant {
             TokenExclusivity::Exclusive
         } else {
             TokenExclusivity::Shared
         };
 
-        let perms = self.token_perms;
+        let perms = tree_info.token_perms;
 
-        Some(TokenInfo(exclusivity, perms))
+        let own_writer_pieces = if self.is_writer(source) {
+            source_info.num_tokens
+        } else {
+            0
+        };
+
+        Some(TokenInfo {
+            exclusivity,
+            perms,
+            pieces_held: source_info.num_tokens,
+            pieces_in_tree: tree_info.token_count,
+            outstanding_splits: source_info.num_splits,
+            other_writer_pieces: tree_info.writer_pieces - own_writer_pieces,
+        })
     }
 
     // Not keeping track of the type of reference doesn't work for the second
     // optimization in the SB paper. This is because that optimization would not
     // be allowed for a mutable reference.
-    pub fn use_token(&mut self, source: Reference, access_kind: AccessKind) {
+    pub fn use_token(
+        &mut self,
+        source: Reference,
+        access_kind: AccessKind,
+    ) -> Result<AccessReceipt, MachineError> {
+        // Checked first, so that every write to a constant gets the same error
+        // regardless of the reference it goes through.
+        if access_kind == AccessKind::Write && self.is_constant(source) {
+            return Err(MachineError::ConstantWrite);
+        }
+
         let token_info = self
             .get_token_info(source)
-            .expect("Cannot read/write without a token");
+            .ok_or(MachineError::AccessWithoutToken)?;
+        let ref_kind = self.info(source).kind;
+        // Reading can be done if there are no writers. That is certainly the
+        // case with an exclusive or a read-only token, but also with a shared
+        // read-write token if none of the other pieces are held by references
+        // that could write with them: several readers on their own do not
+        // conflict.
+        let read_rule = if token_info.exclusivity == TokenExclusivity::Exclusive {
+            Some(AccessRule::ReadExclusive)
+        } else if token_info.perms == TokenPermissions::ReadOnly {
+            Some(AccessRule::ReadSharedReadOnly)
+        } else if token_info.other_writer_pieces == 0 {
+            Some(AccessRule::ReadNoWriters)
+        } else {
+            None
+        };
 
-        match self.ref_info[&source].kind {
-            RefKind::SharedReadOnly => {
-                match access_kind {
-                    AccessKind::Read => {
-                        // Reading can be done if there are no writers, so you either need a shared read-only token or an exclusive token.
-                        if !(token_info
-                            == TokenInfo(TokenExclusivity::Shared, TokenPermissions::ReadOnly)
-                            || token_info.0 == TokenExclusivity::Exclusive)
-                        {
-                            panic!(
-                                "Cannot read with shared read-only reference if there are writers"
-                            );
-                        }
-                    }
-                    AccessKind::Write => panic!("Cannot write with read-only reference"),
-                }
-            }
+        let rule = match ref_kind {
+            RefKind::SharedReadOnly => match access_kind {
+                AccessKind::Read => read_rule.ok_or(MachineError::SharedReadOnlyReadWithWriters)?,
+                AccessKind::Write => return Err(MachineError::SharedReadOnlyWrite),
+            },
             RefKind::SharedReadWrite => {
                 match access_kind {
                     // Can read with any kind of token, shared/exclusive and
                     // read-only or read-write.
-                    AccessKind::Read => {}
+                    AccessKind::Read => AccessRule::ReadAnyToken,
                     AccessKind::Write => {
                         // Writing requires (shared/exclusive) read-write token
-                        if !(token_info.1 == TokenPermissions::ReadWrite) {
-                            panic!("Writing using SharedRW requires read-write token");
+                        if !(token_info.perms == TokenPermissions::ReadWrite) {
+                            return Err(MachineError::SharedReadWriteWriteNeedsReadWrite(
+                                self.last_perm_change(source),
+                            ));
                         }
+                        AccessRule::WriteReadWrite
                     }
                 }
             }
             RefKind::Unique => {
                 match access_kind {
-                    AccessKind::Read => {
-                        // Reading can be done if there are no writers, so you either need a shared read-only token or an exclusive token.
-                        if !(token_info
-                            == TokenInfo(TokenExclusivity::Shared, TokenPermissions::ReadOnly)
-                            || token_info.0 == TokenExclusivity::Exclusive)
-                        {
-                            panic!("Cannot read with unique reference if there are writers");
-                        }
-                    }
+                    AccessKind::Read => read_rule.ok_or(MachineError::UniqueReadWithWriters)?,
                     AccessKind::Write => {
                         // Writing requires exclusive read-write access.
-                        if !(token_info
-                            == TokenInfo(TokenExclusivity::Exclusive, TokenPermissions::ReadWrite))
+                        if !((token_info.exclusivity, token_info.perms)
+                            == (TokenExclusivity::Exclusive, TokenPermissions::ReadWrite))
                         {
-                            panic!("Writing with unique reference requires exclusive read-write access");
+                            return Err(MachineError::UniqueWriteNeedsExclusive);
                         }
+                        AccessRule::WriteExclusive
                     }
                 }
             }
+        };
+
+        self.time += 1;
+
+        Ok(AccessReceipt {
+            source,
+            ref_kind,
+            access_kind,
+            exclusivity: token_info.exclusivity,
+            perms: token_info.perms,
+            rule,
+            time: self.time,
+        })
+    }
+
+    // Perform a single operation. Only creating a reference produces a result,
+    // which is the newly created reference.
+    //
+    // Unlike the individual operations, this checks that the references
+    // involved exist, since operations often come from outside (e.g. from a
+    // trace file).
+    //
+    // Errors are recoverable: a rejected operation has no effect at all, so
+    // the caller may carry on with the machine as if the operation had never
+    // been attempted (replay_all, the planner and the Rc model all do). This
+    // is checked by properties::check_rejections_recoverable. Embedders that
+    // would rather stop at the first rejection can use guard::GuardedMachine.
+    // A panic, on the other hand, means that the machine's own bookkeeping
+    // went wrong, and the machine must not be used afterwards.
+    pub fn apply(&mut self, op: Operation) -> Result<Option<Reference>, MachineError> {
+        if let Some(r) = op
+            .references()
+            .into_iter()
+            .find(|r| !self.ref_info.contains_key(r))
+        {
+            return Err(MachineError::UnknownReference(r));
+        }
+        if let Operation::Arg(argument) = op {
+            self.pass(argument)?;
+            return Ok(None);
+        }
+        if let Some(frame) = self.frames.last() {
+            if let Some(r) = op.references().into_iter().find(|&r| !frame.can_use(r)) {
+                return Err(MachineError::CallForeignReference(r));
+            }
+        }
+
+        let created = match op {
+            Operation::NewRoot => Some(self.new_root()),
+            Operation::NewConstRoot => Some(self.new_const_root()),
+            Operation::CreateRef(parent, kind) => Some(self.create_ref(parent, kind)?),
+            Operation::Borrow(target) => {
+                self.borrow_token(target)?;
+                None
+            }
+            Operation::Return(source) => {
+                self.return_token(source)?;
+                None
+            }
+            Operation::Dup(source) => {
+                self.dup_token(source)?;
+                None
+            }
+            Operation::Merge(source) => {
+                self.merge_token(source)?;
+                None
+            }
+            Operation::SetPerms(source, perms) => {
+                self.set_token_perms(source, perms)?;
+                None
+            }
+            Operation::Use(source, access_kind) => {
+                self.use_token(source, access_kind)?;
+                None
+            }
+            Operation::ReclaimExclusive(source) => {
+                self.reclaim_exclusive(source)?;
+                None
+            }
+            Operation::Call(argument) => {
+                self.call(argument);
+                None
+            }
+            Operation::Arg(_) => unreachable!("handled above"),
+            Operation::Ret => {
+                self.ret()?;
+                None
+            }
+        };
+        if !matches!(op, Operation::Call(_)) {
+            if let Some(frame) = self.frames.last_mut() {
+                frame.open = false;
+            }
+        }
+
+        self.check_invariants_if_enabled();
+
+        Ok(created)
+    }
+
+    // With the check-invariants feature, panic if an invariant is broken.
+    // Checking them all is quadratic in the size of the state, which is too
+    // slow to do after every operation by default, even in debug builds;
+    // properties::check_conservation, the fuzzer and the simulator check them
+    // as they go instead.
+    fn check_invariants_if_enabled(&self) {
+        if cfg!(feature = "check-invariants") {
+            if let Err(msg) = self.check_invariants() {
+                panic!("invariant broken: {}", msg);
+            }
+        }
+    }
+
+    // Enter a function, passing it [argument] (see Frame).
+    pub fn call(&mut self, argument: Reference) {
+        if let Some(caller) = self.frames.last_mut() {
+            caller.open = false;
+        }
+        self.frames.push(Frame {
+            arguments: vec![argument],
+            created: Vec::new(),
+            open: true,
+        });
+
+        self.time += 1;
+    }
+
+    // Pass [argument] to the call just entered, as well. It has to be a
+    // reference the caller can use.
+    pub fn pass(&mut self, argument: Reference) -> Result<(), MachineError> {
+        let (caller, callee) = match self.frames.split_last_mut() {
+            Some((callee, caller)) if callee.open => (caller.last(), callee),
+            _ => return Err(MachineError::ArgumentOutsideCall),
+        };
+        if caller.is_some_and(|caller| !caller.can_use(argument)) {
+            return Err(MachineError::CallForeignReference(argument));
+        }
+        if !callee.arguments.contains(&argument) {
+            callee.arguments.push(argument);
+        }
+
+        self.time += 1;
+
+        Ok(())
+    }
+
+    // Return from the innermost call. Every reference created during the call
+    // dies, which is only allowed once none of them holds a token, unless it
+    // belongs to a tree the call created. Those trees are deallocated.
+    pub fn ret(&mut self) -> Result<(), MachineError> {
+        let frame = self.frames.last().ok_or(MachineError::RetWithoutCall)?;
+        let (local, borrowed): (Vec<_>, Vec<_>) = frame
+            .created
+            .iter()
+            .copied()
+            .partition(|&r| frame.created.contains(&self.info(r).root));
+
+        if let Some(&r) = borrowed.iter().find(|&&r| self.info(r).num_tokens > 0) {
+            return Err(MachineError::RetEscapingReference(r));
+        }
+
+        self.frames.pop();
+        for r in borrowed {
+            self.info_mut(r).state = RefState::Dead;
+        }
+        let roots: Vec<_> = local
+            .into_iter()
+            .filter(|&r| self.info(r).root == r)
+            .collect();
+        for root in roots {
+            self.deallocate(root);
+        }
+
+        self.time += 1;
+
+        Ok(())
+    }
+
+    // Remove the token of the tree of [root] and kill all of its references.
+    // This is only done to trees created during a call, whose references are
+    // all created during the call as well.
+    fn deallocate(&mut self, root: Reference) {
+        self.pieces.retain(|_, piece| piece.root != root);
+        let tree = self.tree_mut(root);
+        tree.token_count = 0;
+        tree.writer_pieces = 0;
+        tree.deallocated = true;
+        for info in self.ref_info.values_mut().filter(|info| info.root == root) {
+            info.state = RefState::Dead;
+            info.num_tokens = 0;
+            info.num_splits = 0;
+        }
+    }
+
+    // Functional counterpart of apply: leaves this machine untouched and
+    // returns the state after performing [op]. On failure, the error is
+    // returned together with the (unchanged) original state, so that
+    // exploration code can carry on from there. Like apply, it also returns
+    // the reference created by the operation, if any.
+    pub fn step(
+        &self,
+        op: Operation,
+    ) -> Result<(TokenMachine, Option<Reference>), (MachineError, &Self)> {
+        let mut next = self.clone();
+        match next.apply(op) {
+            Ok(created) => Ok((next, created)),
+            Err(err) => Err((err, self)),
+        }
+    }
+
+    // Perform every operation of [ops] in turn, stopping at the first one that
+    // is rejected.
+    pub fn step_all(&self, ops: &[Operation]) -> Result<TokenMachine, (MachineError, &Self)> {
+        let mut next = self.clone();
+        for &op in ops {
+            if let Err(err) = next.apply(op) {
+                return Err((err, self));
+            }
+        }
+        Ok(next)
+    }
+
+    // All references that have been created so far, sorted by ID. With the
+    // default allocator (ids::Sequential) that is the order of creation, but
+    // not in general.
+    pub fn references(&self) -> Vec<Reference> {
+        let mut refs: Vec<_> = self.ref_info.keys().copied().collect();
+        refs.sort();
+        refs
+    }
+
+    // The number of operations performed successfully so far.
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    // Every permission change in any tree, in the order in which they
+    // happened.
+    pub fn perm_changes(&self) -> &[PermChange] {
+        &self.perm_changes
+    }
+
+    // The permission changes of the token of the tree [source] belongs to.
+    pub fn perm_history(&self, source: Reference) -> Vec<PermChange> {
+        let root = self.info(source).root;
+        self.perm_changes
+            .iter()
+            .filter(|change| change.root == root)
+            .copied()
+            .collect()
+    }
+
+    // The most recent permission change of the token of the tree [source]
+    // belongs to, i.e. the one responsible for its current permissions.
+    pub fn last_perm_change(&self, source: Reference) -> Option<PermChange> {
+        self.perm_history(source).last().copied()
+    }
+
+    // How [source] came to have its kind: the kind changes of its ancestors,
+    // from the root of its tree down, followed by its own. This is the chain
+    // to show when a reference is rejected for being derived from a
+    // reference of the wrong kind (e.g. MutableFromImmutable).
+    pub fn kind_history(&self, source: Reference) -> Vec<KindChange> {
+        let mut chain = vec![source];
+        let mut current = source;
+        while self.info(current).parent != current {
+            current = self.info(current).parent;
+            chain.push(current);
+        }
+
+        self.kind_changes
+            .iter()
+            .filter(|change| chain.contains(&change.reference))
+            .copied()
+            .collect()
+    }
+
+    // The kind history explaining why [op] was rejected with [err], for errors
+    // that are about the kind of a reference.
+    pub fn kind_diagnostic(&self, op: Operation, err: MachineError) -> Option<Vec<KindChange>> {
+        match (op, err) {
+            (Operation::CreateRef(parent, _), MachineError::MutableFromImmutable) => {
+                Some(self.kind_history(parent))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn config(&self) -> &MachineConfig {
+        &self.config
+    }
+
+    // The root references of all trees, sorted by ID as for references(),
+    // leaving out the trees that have been deallocated.
+    pub fn roots(&self) -> Vec<Reference> {
+        let mut roots: Vec<_> = self
+            .trees
+            .iter()
+            .filter(|(_, tree)| !tree.deallocated)
+            .map(|(&root, _)| root)
+            .collect();
+        roots.sort();
+        roots
+    }
+
+    // Whether [source] belongs to the tree of a constant.
+    pub fn is_constant(&self, source: Reference) -> bool {
+        self.tree(self.info(source).root).constant
+    }
+
+    // The reference [source] was derived from. Roots are their own parent.
+    pub fn parent_of(&self, source: Reference) -> Reference {
+        self.info(source).parent
+    }
+
+    // The root of the tree [source] belongs to.
+    pub fn root_of(&self, source: Reference) -> Reference {
+        self.info(source).root
+    }
+
+    // The number of pieces the token of the tree [source] belongs to is
+    // currently split into.
+    pub fn token_count(&self, source: Reference) -> u32 {
+        self.tree(self.info(source).root).token_count
+    }
+
+    pub fn kind_of(&self, source: Reference) -> RefKind {
+        self.info(source).kind
+    }
+
+    pub fn state_of(&self, source: Reference) -> RefState {
+        self.info(source).state
+    }
+
+    // The permissions of the token of the tree [source] belongs to.
+    pub fn token_perms(&self, source: Reference) -> TokenPermissions {
+        self.tree(self.info(source).root).token_perms
+    }
+
+    // Every piece of every token that currently exists, in order of creation.
+    pub fn pieces(&self) -> Vec<(PieceId, PieceInfo)> {
+        self.pieces.iter().map(|(p, info)| (*p, *info)).collect()
+    }
+
+    // The registry entry of [piece], or None if it has been merged away.
+    pub fn piece_info(&self, piece: PieceId) -> Option<PieceInfo> {
+        self.pieces.get(&piece).copied()
+    }
+
+    // The pieces [source] currently holds, in order of creation.
+    pub fn pieces_held_by(&self, source: Reference) -> Vec<PieceId> {
+        self.pieces
+            .iter()
+            .filter(|(_, info)| info.holder == source)
+            .map(|(p, _)| *p)
+            .collect()
+    }
+
+    // The calls in progress, innermost last.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    // How far [source] is from the root of its tree. Roots have depth 0.
+    pub fn depth_of(&self, source: Reference) -> usize {
+        let mut depth = 0;
+        let mut current = source;
+        loop {
+            let parent = self.info(current).parent;
+            if parent == current {
+                return depth;
+            }
+            depth += 1;
+            current = parent;
+        }
+    }
+
+    // How many times [source] has split its token without merging the pieces
+    // back together. A reference has to get this back to zero before it can
+    // return its token.
+    pub fn outstanding_splits(&self, source: Reference) -> u32 {
+        self.info(source).num_splits
+    }
+
+    // Check that the token pieces are accounted for. dup_token and merge_token
+    // only adjust counters, so this is the only place where it is checked that
+    // every piece created by a split is either still held by the splitting
+    // reference, lent out to one of its children, or has been merged back.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        for (root, tree_info) in &self.trees {
+            let total: u32 = self
+                .ref_info
+                .values()
+                .filter(|info| info.root == *root)
+                .map(|info| info.num_tokens)
+                .sum();
+            if tree_info.deallocated && tree_info.token_count != 0 {
+                return Err(format!("deallocated tree {:?} still has a token", root));
+            }
+            if tree_info.constant && tree_info.token_perms != TokenPermissions::ReadOnly {
+                return Err(format!("constant tree {:?} has a writable token", root));
+            }
+            if total != tree_info.token_count {
+                return Err(format!(
+                    "token_count of tree {:?} is {} but its references hold {} pieces",
+                    root, tree_info.token_count, total
+                ));
+            }
+            let writers: u32 = self
+                .ref_info
+                .values()
+                .filter(|info| info.root == *root && info.kind == RefKind::SharedReadWrite)
+                .map(|info| info.num_tokens)
+                .sum();
+            if writers != tree_info.writer_pieces {
+                return Err(format!(
+                    "writer_pieces of tree {:?} is {} but its shared read-write references hold {} pieces",
+                    root, tree_info.writer_pieces, writers
+                ));
+            }
+            let registered = self.pieces.values().filter(|p| p.root == *root).count() as u32;
+            if registered != tree_info.token_count {
+                return Err(format!(
+                    "token_count of tree {:?} is {} but {} of its pieces are registered",
+                    root, tree_info.token_count, registered
+                ));
+            }
+        }
+
+        for (piece, piece_info) in &self.pieces {
+            if self.info(piece_info.holder).root != piece_info.root {
+                return Err(format!(
+                    "{} of tree {:?} is held by {:?} in another tree",
+                    piece, piece_info.root, piece_info.holder
+                ));
+            }
+        }
+
+        for (r, info) in &self.ref_info {
+            match info.state {
+                RefState::Created | RefState::Dead => {
+                    if info.num_tokens != 0 || info.num_splits != 0 {
+                        return Err(format!(
+                            "{:?} is {:?} but has {} pieces and {} splits",
+                            r, info.state, info.num_tokens, info.num_splits
+                        ));
+                    }
+                }
+                RefState::Borrowing => {
+                    let held = self.pieces.values().filter(|p| p.holder == *r).count() as u32;
+                    if held != info.num_tokens {
+                        return Err(format!(
+                            "{:?} holds {} pieces but the registry lists {}",
+                            r, info.num_tokens, held
+                        ));
+                    }
+
+                    // Every child that is still borrowing holds exactly one
+                    // piece that came from this reference.
+                    let lent = self
+                        .ref_info
+                        .iter()
+                        .filter(|(child, child_info)| {
+                            *child != r
+                                && child_info.parent == *r
+                                && child_info.root == info.root
+                                && child_info.state == RefState::Borrowing
+                        })
+                        .count() as u32;
+
+                    if info.num_tokens + lent != info.num_splits + 1 {
+                        return Err(format!(
+                            "{:?} holds {} pieces and lent out {}, but split {} times",
+                            r, info.num_tokens, lent, info.num_splits
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::ids::ContentHash;
+    use crate::trace::{self, Trace, Verdict};
+
+    // Every trace of corpus/errors, which between them reject an operation
+    // with every kind of error, as the machine before the rejected operation,
+    // that operation and the error.
+    fn rejections() -> Vec<(String, TokenMachine, Operation, MachineError)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/errors");
+        let mut rejections = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let trace = Trace::load(&path).unwrap();
+            let config = trace.config().unwrap().unwrap_or_default();
+            let (step, error) = match trace::replay(config, &trace).0 {
+                Verdict::Rejected(step, error) => (step, error),
+                Verdict::Accepted => panic!("{} is accepted", path.display()),
+            };
+            let before = TokenMachine::init_empty_with(config)
+                .step_all(&trace.ops[..step])
+                .unwrap();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            rejections.push((name, before, trace.ops[step], error));
+        }
+        assert!(!rejections.is_empty());
+        rejections
+    }
+
+    #[test]
+    fn rejected_operations_leave_the_machine_unchanged() {
+        for (name, machine, op, error) in rejections() {
+            let mut applied = machine.clone();
+            assert_eq!(applied.apply(op), Err(error), "{}", name);
+            assert_eq!(applied, machine, "{}: apply changed the state", name);
+
+            match machine.step(op) {
+                Err((stepped_error, unchanged)) => {
+                    assert_eq!(stepped_error, error, "{}", name);
+                    assert_eq!(*unchanged, machine, "{}: step changed the state", name);
+                }
+                Ok(_) => panic!("{}: step accepted {}", name, op),
+            }
+        }
+    }
+
+    #[test]
+    fn every_error_has_a_corpus_case() {
+        let mut found: Vec<_> = rejections()
+            .into_iter()
+            .map(|(_, _, _, error)| error.name())
+            .collect();
+        found.sort_unstable();
+        found.dedup();
+        let mut names = ERROR_NAMES.to_vec();
+        names.sort_unstable();
+        assert_eq!(found, names);
+    }
+
+    // The machine after running [text], a trace without a config header.
+    fn run(text: &str) -> Result<TokenMachine, MachineError> {
+        let trace = Trace::parse(text).unwrap();
+        TokenMachine::init_empty_with(MachineConfig::default())
+            .step_all(&trace.ops)
+            .map_err(|(error, _)| error)
+    }
+
+    #[test]
+    fn calls_take_several_arguments() {
+        let machine =
+            run("r0 = root\nr1 = root\ncall r0\narg r1\nuse r0 read\nuse r1 read\n").unwrap();
+        assert_eq!(
+            machine.frames()[0].arguments,
+            vec![Reference(0), Reference(1)]
+        );
+
+        // Only right after the call, and only the caller's references.
+        assert_eq!(
+            run("r0 = root\nr1 = root\ncall r0\nuse r0 read\narg r1\n").unwrap_err(),
+            MachineError::ArgumentOutsideCall
+        );
+        assert_eq!(
+            run("r0 = root\nr1 = root\ncall r0\nr2 = create r0 shared_ro\narg r1\n").unwrap_err(),
+            MachineError::ArgumentOutsideCall
+        );
+        assert_eq!(
+            run("r0 = root\nr1 = root\nr2 = root\ncall r0\narg r1\ncall r1\narg r2\n").unwrap_err(),
+            MachineError::CallForeignReference(Reference(2))
+        );
+    }
+
+    #[test]
+    fn nested_calls_only_see_their_own_arguments() {
+        let text = "r0 = root\ncall r0\nr1 = create r0 shared_ro\nborrow r1\n\
+                    call r1\nr2 = create r1 shared_ro\n";
+        assert_eq!(
+            run(&format!("{}use r0 read\n", text)).unwrap_err(),
+            MachineError::CallForeignReference(Reference(0))
+        );
+        let machine = run(&format!("{}ret\nreturn r1\nret\nuse r0 read\n", text)).unwrap();
+        assert_eq!(machine.state_of(Reference(1)), RefState::Dead);
+        assert_eq!(machine.state_of(Reference(2)), RefState::Dead);
+        machine.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn a_call_only_reclaims_from_references_it_can_use() {
+        let text = "r0 = root\nr1 = create r0 shared_ro\nborrow r1\ndup r1\ncall r1\n\
+                    r2 = create r1 shared_ro\nborrow r2\nreclaim r1\n";
+        let machine = run(text).unwrap();
+        assert_eq!(machine.state_of(Reference(2)), RefState::Dead);
+        machine.check_invariants().unwrap();
+
+        let text = "r0 = root\nr1 = create r0 shared_ro\nborrow r1\ndup r1\n\
+                    r2 = create r1 shared_ro\nborrow r2\ncall r1\nreclaim r1\n";
+        assert_eq!(
+            run(text).unwrap_err(),
+            MachineError::CallForeignReference(Reference(2))
+        );
+    }
+
+    #[test]
+    fn locals_are_deallocated_at_the_return() {
+        let text = "r0 = root\ncall r0\nr1 = root\nr2 = create r1 unique\nborrow r2\n\
+                    call r2\nr3 = root\nret\nret\n";
+        let machine = run(text).unwrap();
+        for r in 1..=3 {
+            assert_eq!(machine.state_of(Reference(r)), RefState::Dead);
+            assert_eq!(machine.token_count(Reference(r)), 0);
+        }
+        assert_eq!(machine.token_count(Reference(0)), 1);
+        assert_eq!(machine.roots(), vec![Reference(0)]);
+        machine.check_invariants().unwrap();
+        assert_eq!(
+            run(&format!("{}use r1 read\n", text)).unwrap_err().name(),
+            "AccessWithoutToken"
+        );
+    }
+
+    #[test]
+    fn content_hash_ids_do_not_depend_on_earlier_references() {
+        let mut a = TokenMachine::init_empty_with(MachineConfig::default());
+        let mut b = a.clone();
+        a.set_id_allocator(ContentHash { namespace: 1 });
+        b.set_id_allocator(ContentHash { namespace: 1 });
+        let x = a.new_root_keyed(Some("main.rs:3"));
+        b.new_root();
+        b.new_root_keyed(Some("main.rs:2"));
+        assert_eq!(b.new_root_keyed(Some("main.rs:3")), x);
+
+        // A key used twice still gets a fresh ID.
+        let y = a
+            .create_ref_keyed(x, RefKind::SharedReadOnly, Some("main.rs:3"))
+            .unwrap();
+        assert_ne!(x, y);
+        a.check_invariants().unwrap();
+
+        // step reports the reference it created, wherever it ends up in
+        // references().
+        let (next, created) = a
+            .step(Operation::CreateRef(y, RefKind::SharedReadOnly))
+            .unwrap();
+        let created = created.unwrap();
+        assert!(!a.references().contains(&created));
+        assert!(next.references().contains(&created));
+        assert_eq!(next.parent_of(created), y);
+    }
+
+    #[test]
+    fn rejected_operations_can_be_retried_with_the_same_error() {
+        for (name, mut machine, op, error) in rejections() {
+            for _ in 0..3 {
+                assert_eq!(machine.apply(op), Err(error), "{}", name);
+            }
+            machine.check_invariants().unwrap();
         }
     }
 }
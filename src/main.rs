@@ -1,29 +1,93 @@
 #![allow(dead_code)]
+mod absint;
+mod batch;
+mod budget;
+mod cli;
+mod cost;
+mod coverage;
+mod diff;
+mod events;
+mod explore;
+mod fragments;
+mod fuzz;
+mod grammar;
+mod guard;
+mod ids;
+mod json;
+mod litmus;
+mod lockstep;
 mod machine;
 mod machine2;
+mod memory;
+mod meta;
+mod metrics;
+mod minimize;
+mod miri;
+mod mutate;
+mod orchestrate;
+mod planner;
+mod profiling;
+mod projection;
+mod properties;
+mod query;
+mod rc;
+mod repl;
+mod report;
+mod repro;
+mod rng;
+mod sb;
+mod script;
+mod serve;
+mod session;
+mod simulate;
+mod slice;
+mod sync;
+mod trace;
+mod triage;
+mod version;
 
 use machine2::{AccessKind, RefKind, TokenMachine};
 
-fn main() {
+fn demo() {
     let (r1, mut machine) = TokenMachine::init();
 
     println!("{:?}", machine);
-    let r2 = machine.create_ref(r1, RefKind::Unique);
+    let r2 = machine.create_ref(r1, RefKind::Unique).unwrap();
     println!("{:?}", machine);
-    let r3 = machine.create_ref(r1, RefKind::Unique);
+    let r3 = machine.create_ref(r1, RefKind::Unique).unwrap();
     println!("{:?}", machine);
-    machine.borrow_token(r2);
+    machine.borrow_token(r2).unwrap();
     println!("{:?}", machine);
-    machine.use_token(r2, AccessKind::Write);
+    machine.use_token(r2, AccessKind::Write).unwrap();
     println!("{:?}", machine);
-    machine.return_token(r2);
+    machine.return_token(r2).unwrap();
     println!("{:?}", machine);
-    machine.borrow_token(r3);
+    machine.borrow_token(r3).unwrap();
     println!("{:?}", machine);
-    machine.use_token(r3, AccessKind::Write);
+    machine.use_token(r3, AccessKind::Write).unwrap();
     println!("{:?}", machine);
-    machine.return_token(r3);
+    machine.return_token(r3).unwrap();
     println!("{:?}", machine);
-    machine.use_token(r1, AccessKind::Write);
+    machine.use_token(r1, AccessKind::Write).unwrap();
     println!("{:?}", machine);
 }
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let code = if args.is_empty() {
+        demo();
+        0
+    } else {
+        match cli::run(&args) {
+            Ok(code) => code,
+            Err(msg) => {
+                eprintln!("error: {}", msg);
+                2
+            }
+        }
+    };
+
+    profiling::dump();
+    std::process::exit(code);
+}
@@ -0,0 +1,95 @@
+// A simulation of the contents of memory alongside the machine, so that traces
+// can be analysed at the level of values rather than only of verdicts. Every
+// tree is a single memory location, every write stores a value of its own in
+// it, and every read observes the value of the last write before it (or the
+// initial value of the location). Which write a read observes is its
+// reads-from relation, as in weak memory models.
+//
+// Values are identified by the write that stored them, so two traces read the
+// same values exactly when their reads-from relations agree. That is what a
+// check like "could an optimized program read a different value?" compares.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::machine2::{AccessKind, MachineConfig, Operation, Reference, TokenMachine};
+use crate::trace::{Trace, Verdict};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct StoredBy {
+    // The step of the trace that performed the write.
+    pub step: usize,
+    pub writer: Reference,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ReadsFrom {
+    // The step of the trace that performed the read.
+    pub step: usize,
+    pub reader: Reference,
+    // The root of the tree that was read, which stands for its location.
+    pub location: Reference,
+    // The write whose value was read, or None for the initial value.
+    pub write: Option<StoredBy>,
+}
+
+impl fmt::Display for ReadsFrom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {}: {} reads {} from ",
+            self.step, self.reader, self.location
+        )?;
+        match self.write {
+            Some(write) => write!(f, "the write by {} at step {}", write.writer, write.step),
+            None => write!(f, "its initial value"),
+        }
+    }
+}
+
+// The contents of every location: the write that stored its current value.
+// Locations that have not been written hold their initial value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Memory {
+    contents: HashMap<Reference, StoredBy>,
+}
+
+impl Memory {
+    pub fn load(&self, location: Reference) -> Option<StoredBy> {
+        self.contents.get(&location).copied()
+    }
+
+    pub fn store(&mut self, location: Reference, write: StoredBy) {
+        self.contents.insert(location, write);
+    }
+}
+
+// Replay [trace], recording which write every accepted read observes. Rejected
+// accesses don't touch memory, so replay stops at the first rejection as
+// usual. Returns the reads in order, with the verdict of the replay.
+pub fn reads_from(config: MachineConfig, trace: &Trace) -> (Vec<ReadsFrom>, Verdict) {
+    let mut machine = TokenMachine::init_empty_with(config);
+    let mut memory = Memory::default();
+    let mut reads = Vec::new();
+
+    for (step, &op) in trace.ops.iter().enumerate() {
+        if let Err(err) = machine.apply(op) {
+            return (reads, Verdict::Rejected(step, err));
+        }
+
+        if let Operation::Use(r, access_kind) = op {
+            let location = machine.root_of(r);
+            match access_kind {
+                AccessKind::Read => reads.push(ReadsFrom {
+                    step,
+                    reader: r,
+                    location,
+                    write: memory.load(location),
+                }),
+                AccessKind::Write => memory.store(location, StoredBy { step, writer: r }),
+            }
+        }
+    }
+
+    (reads, Verdict::Accepted)
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use crate::machine2::Reference;
+
+// Data that a frontend attaches to references, such as the source variable,
+// type or lifetime a reference corresponds to. This is kept in a side table
+// instead of in RefInfo, so that the machine does not have to know about the
+// kind of data and does not need to become generic over it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaTable<M> {
+    entries: HashMap<Reference, M>,
+}
+
+impl<M> Default for MetaTable<M> {
+    fn default() -> Self {
+        MetaTable {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<M: Default + Clone> MetaTable<M> {
+    pub fn new() -> Self {
+        MetaTable::default()
+    }
+
+    pub fn set(&mut self, r: Reference, meta: M) {
+        self.entries.insert(r, meta);
+    }
+
+    pub fn get(&self, r: Reference) -> Option<&M> {
+        self.entries.get(&r)
+    }
+
+    // The metadata of [r], which is created using Default if nothing has been
+    // attached yet.
+    pub fn get_mut(&mut self, r: Reference) -> &mut M {
+        self.entries.entry(r).or_default()
+    }
+
+    // The metadata of [r], or the default if nothing has been attached.
+    pub fn get_or_default(&self, r: Reference) -> M {
+        self.entries.get(&r).cloned().unwrap_or_default()
+    }
+
+    // Give [child] a copy of the metadata of [parent]. Useful for frontends
+    // where a derived reference starts out describing the same thing as the
+    // reference it was derived from.
+    pub fn inherit(&mut self, parent: Reference, child: Reference) {
+        let meta = self.get_or_default(parent);
+        self.entries.insert(child, meta);
+    }
+
+    pub fn remove(&mut self, r: Reference) -> Option<M> {
+        self.entries.remove(&r)
+    }
+}
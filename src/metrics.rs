@@ -0,0 +1,107 @@
+// Numeric series describing the machine state after every step of a trace, for
+// plotting how long traces evolve and comparing them across configurations.
+
+use std::fmt::Write;
+
+use crate::machine2::{MachineConfig, RefState, TokenMachine, TokenPermissions};
+use crate::trace::{Trace, Verdict};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct StepMetrics {
+    // The number of operations applied so far; 0 is the empty machine.
+    pub step: usize,
+    pub refs: usize,
+    // References that have not returned their token yet.
+    pub live_refs: usize,
+    // The depth of the deepest live reference, over all trees.
+    pub max_depth: usize,
+    pub trees: usize,
+    // Token pieces, summed over all trees.
+    pub pieces: u32,
+    // Trees whose token is held in one piece, and trees whose token is split.
+    pub exclusive_trees: usize,
+    pub shared_trees: usize,
+    pub read_only_trees: usize,
+}
+
+const COLUMNS: &[&str] = &[
+    "step",
+    "refs",
+    "live_refs",
+    "max_depth",
+    "trees",
+    "pieces",
+    "exclusive_trees",
+    "shared_trees",
+    "read_only_trees",
+];
+
+impl StepMetrics {
+    pub fn of(step: usize, machine: &TokenMachine) -> Self {
+        let refs = machine.references();
+        let live: Vec<_> = refs
+            .iter()
+            .copied()
+            .filter(|&r| machine.state_of(r) != RefState::Dead)
+            .collect();
+        let roots = machine.roots();
+        let pieces: Vec<_> = roots.iter().map(|&r| machine.token_count(r)).collect();
+
+        StepMetrics {
+            step,
+            refs: refs.len(),
+            live_refs: live.len(),
+            max_depth: live.iter().map(|&r| machine.depth_of(r)).max().unwrap_or(0),
+            trees: roots.len(),
+            pieces: pieces.iter().sum(),
+            exclusive_trees: pieces.iter().filter(|&&n| n == 1).count(),
+            shared_trees: pieces.iter().filter(|&&n| n > 1).count(),
+            read_only_trees: roots
+                .iter()
+                .filter(|&&r| machine.token_perms(r) == TokenPermissions::ReadOnly)
+                .count(),
+        }
+    }
+
+    fn values(&self) -> [u64; 9] {
+        [
+            self.step as u64,
+            self.refs as u64,
+            self.live_refs as u64,
+            self.max_depth as u64,
+            self.trees as u64,
+            u64::from(self.pieces),
+            self.exclusive_trees as u64,
+            self.shared_trees as u64,
+            self.read_only_trees as u64,
+        ]
+    }
+}
+
+// The metrics of the empty machine and after every step of [trace] that is
+// accepted. The series stops at the first rejected operation.
+pub fn series(config: MachineConfig, trace: &Trace) -> (Vec<StepMetrics>, Verdict) {
+    let mut machine = TokenMachine::init_empty_with(config);
+    let mut rows = vec![StepMetrics::of(0, &machine)];
+
+    for (i, &op) in trace.ops.iter().enumerate() {
+        if let Err(err) = machine.apply(op) {
+            return (rows, Verdict::Rejected(i, err));
+        }
+        rows.push(StepMetrics::of(i + 1, &machine));
+    }
+
+    (rows, Verdict::Accepted)
+}
+
+pub fn to_csv(rows: &[StepMetrics]) -> String {
+    let mut out = COLUMNS.join(",");
+    out.push('\n');
+
+    for row in rows {
+        let values: Vec<_> = row.values().iter().map(|v| v.to_string()).collect();
+        writeln!(out, "{}", values.join(",")).unwrap();
+    }
+
+    out
+}
@@ -0,0 +1,61 @@
+use crate::mutate;
+use crate::trace::Trace;
+
+// Shrink [trace] by deleting operations for as long as [still_fails] keeps
+// holding. Larger chunks are tried first, halving the chunk size whenever no
+// chunk of the current size can be removed. The result is 1-minimal: removing
+// any single operation makes [still_fails] false.
+pub fn minimize<F>(trace: &Trace, mut still_fails: F) -> Trace
+where
+    F: FnMut(&Trace) -> bool,
+{
+    let mut current = trace.clone();
+    let mut chunk = (current.ops.len() / 2).max(1);
+
+    loop {
+        let mut removed_any = false;
+        let mut start = 0;
+
+        while start < current.ops.len() {
+            let end = (start + chunk).min(current.ops.len());
+            let mut candidate = current.clone();
+            candidate.ops.drain(start..end);
+
+            if still_fails(&candidate) {
+                current = candidate;
+                removed_any = true;
+            } else {
+                start += chunk;
+            }
+        }
+
+        if chunk == 1 && !removed_any {
+            return current;
+        }
+        if !removed_any {
+            chunk = (chunk / 2).max(1);
+        }
+    }
+}
+
+// Shrink [trace] further than minimize can on its own, by alternating it with
+// deleting whole subtrees of references (see mutate.rs) until neither makes
+// progress. The result is 1-minimal, and no subtree can be deleted from it.
+pub fn shrink<F>(trace: &Trace, mut still_fails: F) -> Trace
+where
+    F: FnMut(&Trace) -> bool,
+{
+    let mut current = minimize(trace, &mut still_fails);
+
+    loop {
+        let smaller = mutate::shrinking_mutations(&current)
+            .into_iter()
+            .map(|mutation| mutation.apply(&current))
+            .find(|candidate| candidate.ops.len() < current.ops.len() && still_fails(candidate));
+
+        match smaller {
+            Some(smaller) => current = minimize(&smaller, &mut still_fails),
+            None => return current,
+        }
+    }
+}
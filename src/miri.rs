@@ -0,0 +1,207 @@
+// Comparison of the verdicts of the machine with those of Miri over a corpus
+// of generated programs. The corpus consists of:
+//
+// - a directory of Rust programs, <name>.rs,
+// - a results file with Miri's verdict for each program, one "<name>
+//   <verdict>" line per program, where the verdict is "ok" if Miri ran the
+//   program without reporting undefined behavior and "ub" if it did,
+// - a directory of traces corresponding to the programs, <name>.tbm.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::machine2::MachineConfig;
+use crate::trace::{self, ParseError, Trace, Verdict};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MiriVerdict {
+    Ok,
+    UndefinedBehavior,
+}
+
+pub fn parse_results(text: &str) -> Result<BTreeMap<String, MiriVerdict>, ParseError> {
+    let mut results = BTreeMap::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |message| ParseError::at_line(i + 1, message);
+        let (name, verdict) = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [name, verdict] => (name.to_string(), *verdict),
+            _ => {
+                return Err(error(format!(
+                    "expected '<name> <verdict>', found '{}'",
+                    line
+                )))
+            }
+        };
+        let verdict = match verdict {
+            "ok" | "pass" => MiriVerdict::Ok,
+            "ub" | "fail" => MiriVerdict::UndefinedBehavior,
+            _ => return Err(error(format!("expected ok or ub, found '{}'", verdict))),
+        };
+
+        results.insert(name, verdict);
+    }
+
+    Ok(results)
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub name: String,
+    pub program: PathBuf,
+    pub trace: PathBuf,
+    pub miri: MiriVerdict,
+    pub model: Verdict,
+}
+
+impl Case {
+    pub fn model_accepts(&self) -> bool {
+        self.model == Verdict::Accepted
+    }
+
+    pub fn miri_accepts(&self) -> bool {
+        self.miri == MiriVerdict::Ok
+    }
+
+    pub fn divergent(&self) -> bool {
+        self.model_accepts() != self.miri_accepts()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Comparison {
+    pub cases: Vec<Case>,
+    // Programs with a Miri verdict that could not be compared, and why.
+    pub skipped: Vec<(String, String)>,
+}
+
+impl Comparison {
+    // The number of cases in a cell of the confusion matrix.
+    pub fn count(&self, model_accepts: bool, miri_accepts: bool) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| {
+                case.model_accepts() == model_accepts && case.miri_accepts() == miri_accepts
+            })
+            .count()
+    }
+
+    pub fn divergent(&self) -> Vec<&Case> {
+        self.cases.iter().filter(|case| case.divergent()).collect()
+    }
+
+    // A Markdown report with the confusion matrix and a list of divergent
+    // cases linking to their program and trace. Paths are made relative to
+    // [base] where possible, so that the links work from the report's
+    // directory.
+    pub fn render_markdown(&self, base: &Path) -> String {
+        let link = |path: &Path| {
+            let shown = path.strip_prefix(base).unwrap_or(path);
+            format!("[{}]({})", shown.display(), shown.display())
+        };
+
+        let mut out = String::new();
+        writeln!(out, "# Model vs. Miri\n").unwrap();
+        writeln!(out, "| | Miri accepts | Miri rejects |").unwrap();
+        writeln!(out, "|---|---|---|").unwrap();
+        writeln!(
+            out,
+            "| **model accepts** | {} | {} |",
+            self.count(true, true),
+            self.count(true, false)
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "| **model rejects** | {} | {} |",
+            self.count(false, true),
+            self.count(false, false)
+        )
+        .unwrap();
+
+        writeln!(out, "\n## Divergent cases\n").unwrap();
+        for case in self.divergent() {
+            writeln!(
+                out,
+                "- `{}`: model {}, Miri {} ({}, {})",
+                case.name,
+                case.model,
+                if case.miri_accepts() { "ok" } else { "ub" },
+                link(&case.program),
+                link(&case.trace)
+            )
+            .unwrap();
+        }
+
+        if !self.skipped.is_empty() {
+            writeln!(out, "\n## Skipped\n").unwrap();
+            for (name, reason) in &self.skipped {
+                writeln!(out, "- `{}`: {}", name, reason).unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+// Replay the trace of every program that has a Miri verdict. Traces are
+// replayed with the configuration from their header, or [config] if they
+// don't have one.
+pub fn compare(
+    programs: &Path,
+    results: &Path,
+    traces: &Path,
+    config: MachineConfig,
+) -> Result<Comparison, String> {
+    let results_text =
+        fs::read_to_string(results).map_err(|e| format!("{}: {}", results.display(), e))?;
+    let results =
+        parse_results(&results_text).map_err(|e| format!("{}: {}", results.display(), e))?;
+
+    let mut comparison = Comparison::default();
+
+    for (name, miri) in results {
+        let program = programs.join(format!("{}.rs", name));
+        let trace_path = traces.join(format!("{}.tbm", name));
+
+        if !program.exists() {
+            comparison
+                .skipped
+                .push((name, format!("{} does not exist", program.display())));
+            continue;
+        }
+
+        let trace = match Trace::load(&trace_path) {
+            Ok(trace) => trace,
+            Err(msg) => {
+                comparison.skipped.push((name, msg));
+                continue;
+            }
+        };
+        let trace_config = match trace.config() {
+            Ok(trace_config) => trace_config.unwrap_or(config),
+            Err(msg) => {
+                comparison.skipped.push((name, msg));
+                continue;
+            }
+        };
+
+        let (model, _) = trace::replay(trace_config, &trace);
+        comparison.cases.push(Case {
+            name,
+            program,
+            trace: trace_path,
+            miri,
+            model,
+        });
+    }
+
+    Ok(comparison)
+}
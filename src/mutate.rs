@@ -0,0 +1,143 @@
+// Mutations of traces that respect their structure, unlike deleting arbitrary
+// operations. The fuzzer uses them to vary traces it has generated, and the
+// minimizer uses subtree deletion to shrink traces that plain deletion gets
+// stuck on: deleting the creation of a reference on its own leaves the
+// operations on it (and on everything created after it, whose IDs shift)
+// pointing at the wrong references.
+
+use std::collections::HashSet;
+
+use crate::machine2::{AccessKind, Operation, RefKind, Reference};
+use crate::rng::Rng;
+use crate::trace::{self, Trace};
+
+const REF_KINDS: [RefKind; 3] = [
+    RefKind::SharedReadOnly,
+    RefKind::SharedReadWrite,
+    RefKind::Unique,
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Mutation {
+    // Remove a reference, everything derived from it and every operation
+    // involving any of them, renumbering the references created later.
+    DeleteSubtree(Reference),
+    // Change the kind of the reference created by the operation at an index.
+    ReplaceKind(usize, RefKind),
+    // Turn the read at an index into a write or vice versa.
+    SwapAccess(usize),
+}
+
+// The references created by [trace], in order.
+fn created(trace: &Trace) -> Vec<(usize, Reference)> {
+    let mut next_id = 0;
+    let mut refs = Vec::new();
+    for (i, &op) in trace.ops.iter().enumerate() {
+        if trace::creates_reference(op) {
+            refs.push((i, Reference::from_id(next_id)));
+            next_id += 1;
+        }
+    }
+    refs
+}
+
+fn delete_subtree(trace: &Trace, target: Reference) -> Trace {
+    let created = created(trace);
+    let mut removed = HashSet::new();
+    let mut removed_ops = HashSet::new();
+
+    for &(i, r) in &created {
+        let doomed = match trace.ops[i] {
+            Operation::CreateRef(parent, _) => r == target || removed.contains(&parent),
+            _ => r == target,
+        };
+        if doomed {
+            removed.insert(r);
+            removed_ops.insert(i);
+        }
+    }
+
+    let renumber = |r: Reference| {
+        let shift = removed.iter().filter(|gone| gone.id() < r.id()).count() as u32;
+        Reference::from_id(r.id() - shift)
+    };
+
+    let ops = trace
+        .ops
+        .iter()
+        .enumerate()
+        .filter(|(i, op)| {
+            !removed_ops.contains(i) && !op.references().iter().any(|r| removed.contains(r))
+        })
+        .map(|(_, &op)| op.map_references(renumber))
+        .collect();
+
+    Trace {
+        headers: trace.headers.clone(),
+        ops,
+    }
+}
+
+impl Mutation {
+    pub fn apply(&self, trace: &Trace) -> Trace {
+        match *self {
+            Mutation::DeleteSubtree(r) => delete_subtree(trace, r),
+            Mutation::ReplaceKind(i, kind) => {
+                let mut mutant = trace.clone();
+                if let Operation::CreateRef(parent, _) = mutant.ops[i] {
+                    mutant.ops[i] = Operation::CreateRef(parent, kind);
+                }
+                mutant
+            }
+            Mutation::SwapAccess(i) => {
+                let mut mutant = trace.clone();
+                if let Operation::Use(r, access) = mutant.ops[i] {
+                    let swapped = match access {
+                        AccessKind::Read => AccessKind::Write,
+                        AccessKind::Write => AccessKind::Read,
+                    };
+                    mutant.ops[i] = Operation::Use(r, swapped);
+                }
+                mutant
+            }
+        }
+    }
+}
+
+// The subtree deletions that apply to [trace], latest reference first. These
+// are the mutations that make a trace shorter.
+pub fn shrinking_mutations(trace: &Trace) -> Vec<Mutation> {
+    created(trace)
+        .into_iter()
+        .rev()
+        .map(|(_, r)| Mutation::DeleteSubtree(r))
+        .collect()
+}
+
+// Every mutation that applies to [trace].
+pub fn mutations(trace: &Trace) -> Vec<Mutation> {
+    let mut result = shrinking_mutations(trace);
+
+    for (i, &op) in trace.ops.iter().enumerate() {
+        match op {
+            Operation::CreateRef(_, kind) => {
+                for &other in REF_KINDS.iter().filter(|&&other| other != kind) {
+                    result.push(Mutation::ReplaceKind(i, other));
+                }
+            }
+            Operation::Use(..) => result.push(Mutation::SwapAccess(i)),
+            _ => {}
+        }
+    }
+
+    result
+}
+
+pub fn random_mutation(rng: &mut Rng, trace: &Trace) -> Option<Mutation> {
+    let candidates = mutations(trace);
+    if candidates.is_empty() {
+        None
+    } else {
+        Some(*rng.choose(&candidates))
+    }
+}
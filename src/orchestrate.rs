@@ -0,0 +1,340 @@
+// Long-running differential fuzzing. Every job is a configuration of the
+// token machine together with a model to compare it with, and gets a worker
+// thread of its own that keeps generating traces (see fuzz.rs) and replaying
+// them on both models. Traces on which the models disagree are minimized and
+// pushed onto a queue shared by all workers, which drops divergences that
+// some worker already found. The orchestrator drains the queue into the
+// output directory and rewrites a summary of every job at a fixed interval,
+// so that a run can be left going and looked at whenever.
+
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fuzz::{self, FuzzOptions};
+use crate::lockstep;
+use crate::machine2::{MachineConfig, Operation};
+use crate::minimize;
+use crate::rng::Rng;
+use crate::trace::{self, SimpleVerdict, Trace, Verdict};
+use crate::triage;
+
+// The model the token machine is compared with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ModelPair {
+    // The Stacked Borrows model of sb.rs, in lockstep.
+    StackedBorrows,
+    // The simple model of machine.rs, which only understands traces with a
+    // single root.
+    Simple,
+}
+
+impl fmt::Display for ModelPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelPair::StackedBorrows => write!(f, "sb"),
+            ModelPair::Simple => write!(f, "simple"),
+        }
+    }
+}
+
+pub fn parse_model_pair(text: &str) -> Result<ModelPair, String> {
+    match text {
+        "sb" => Ok(ModelPair::StackedBorrows),
+        "simple" => Ok(ModelPair::Simple),
+        _ => Err(format!("unknown model '{}', expected sb or simple", text)),
+    }
+}
+
+// The step at which the models disagree on [trace] and a description of the
+// disagreement, or None if they agree (or the trace can't be compared).
+pub fn diverges(config: MachineConfig, pair: ModelPair, trace: &Trace) -> Option<(usize, String)> {
+    match pair {
+        ModelPair::StackedBorrows => {
+            let lockstep = lockstep::lockstep(config, trace);
+            let step = &lockstep.steps[lockstep.divergence?];
+            let describe = |accepted: bool| if accepted { "accepts" } else { "rejects" };
+            Some((
+                step.index,
+                format!(
+                    "token machine {}, Stacked Borrows {} '{}'",
+                    describe(step.token.is_ok()),
+                    describe(step.sb.is_ok()),
+                    step.op
+                ),
+            ))
+        }
+        ModelPair::Simple => {
+            let roots = trace
+                .ops
+                .iter()
+                .filter(|op| matches!(op, Operation::NewRoot | Operation::NewConstRoot))
+                .count();
+            if roots != 1 {
+                return None;
+            }
+
+            let (verdict, _) = trace::replay(config, trace);
+            let (simple, _) = trace::replay_simple(trace);
+            match (verdict, simple) {
+                (Verdict::Accepted, SimpleVerdict::Accepted { .. }) => None,
+                (Verdict::Rejected(step, _), SimpleVerdict::Rejected(simple_step, _))
+                    if step == simple_step =>
+                {
+                    None
+                }
+                (verdict, simple) => {
+                    let step = match (&verdict, &simple) {
+                        (Verdict::Rejected(step, _), SimpleVerdict::Rejected(other, _)) => {
+                            *step.min(other)
+                        }
+                        (Verdict::Rejected(step, _), _) | (_, SimpleVerdict::Rejected(step, _)) => {
+                            *step
+                        }
+                        _ => unreachable!(),
+                    };
+                    Some((
+                        step,
+                        format!("token machine {}, simple model {}", verdict, simple),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Job {
+    pub config: MachineConfig,
+    pub pair: ModelPair,
+}
+
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} vs {}",
+            trace::format_config_inline(&self.config),
+            self.pair
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub job: Job,
+    pub seed: u64,
+    pub hash: u64,
+    pub minimized: Trace,
+    pub step: usize,
+    pub description: String,
+}
+
+impl Divergence {
+    // The minimized trace, with headers saying how to reproduce it.
+    pub fn to_trace(&self) -> Trace {
+        let mut trace = self.minimized.clone();
+        trace.set_config(&self.job.config);
+        trace.set_header("model-pair", self.job.pair.to_string());
+        trace.set_header("seed", self.seed.to_string());
+        trace.set_header(
+            "diverges",
+            format!("at {}: {}", self.step, self.description),
+        );
+        trace
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JobStats {
+    pub runs: u64,
+    pub divergences: u64,
+    // Divergences another run (of any job) had already found.
+    pub duplicates: u64,
+}
+
+// What the workers share: the divergences found but not yet written out, and
+// the hashes of every divergence found so far.
+#[derive(Debug, Default)]
+struct Queue {
+    pending: Vec<Divergence>,
+    seen: HashSet<(ModelPair, u64)>,
+    stats: Vec<JobStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OrchestratorOptions {
+    pub jobs: Vec<Job>,
+    pub max_len: usize,
+    pub max_refs: usize,
+    pub seed: u64,
+    // How long to run for, and how many runs every worker does at most.
+    pub duration: Duration,
+    pub runs: Option<u64>,
+    // How often the queue is drained and the summary rewritten.
+    pub interval: Duration,
+    pub out: PathBuf,
+}
+
+fn worker(index: usize, options: &OrchestratorOptions, queue: &Mutex<Queue>, stop: &AtomicBool) {
+    let job = options.jobs[index];
+    let fuzz_options = FuzzOptions {
+        config: job.config,
+        max_len: options.max_len,
+        max_refs: options.max_refs,
+        mutations: 0,
+    };
+
+    let mut run = 0;
+    while !stop.load(Ordering::Relaxed) && options.runs.is_none_or(|runs| run < runs) {
+        // Interleave the seeds of the workers, so that every seed is used by
+        // exactly one of them and the seed of a divergence is enough to find
+        // it again.
+        let seed = options
+            .seed
+            .wrapping_add(run * options.jobs.len() as u64 + index as u64);
+        run += 1;
+
+        let trace = fuzz::generate(&mut Rng::new(seed), &fuzz_options);
+        let found = diverges(job.config, job.pair, &trace).map(|_| {
+            let minimized = minimize::shrink(&trace, |candidate| {
+                diverges(job.config, job.pair, candidate).is_some()
+            });
+            let (step, description) = diverges(job.config, job.pair, &minimized).unwrap();
+            Divergence {
+                job,
+                seed,
+                hash: triage::canonical_hash(&job.config, &minimized),
+                minimized,
+                step,
+                description,
+            }
+        });
+
+        let mut queue = queue.lock().unwrap();
+        queue.stats[index].runs += 1;
+        if let Some(divergence) = found {
+            if queue.seen.insert((job.pair, divergence.hash)) {
+                queue.stats[index].divergences += 1;
+                queue.pending.push(divergence);
+            } else {
+                queue.stats[index].duplicates += 1;
+            }
+        }
+    }
+}
+
+fn summary(options: &OrchestratorOptions, stats: &[JobStats], elapsed: Duration) -> String {
+    let mut out = String::new();
+    writeln!(out, "running for {}s", elapsed.as_secs()).unwrap();
+    writeln!(
+        out,
+        "{:<60} {:>9} {:>11} {:>10}",
+        "job", "runs", "divergences", "duplicates"
+    )
+    .unwrap();
+    for (job, stats) in options.jobs.iter().zip(stats) {
+        writeln!(
+            out,
+            "{:<60} {:>9} {:>11} {:>10}",
+            job.to_string(),
+            stats.runs,
+            stats.divergences,
+            stats.duplicates
+        )
+        .unwrap();
+    }
+    let total: u64 = stats.iter().map(|stats| stats.divergences).sum();
+    writeln!(out, "{} distinct divergences", total).unwrap();
+    out
+}
+
+// Write out the divergences found since the last time, and the summary.
+// Returns the new divergences.
+fn drain(
+    options: &OrchestratorOptions,
+    queue: &Mutex<Queue>,
+    start: Instant,
+) -> Result<Vec<Divergence>, String> {
+    let (pending, stats) = {
+        let mut queue = queue.lock().unwrap();
+        (std::mem::take(&mut queue.pending), queue.stats.clone())
+    };
+
+    let out = &options.out;
+    for divergence in &pending {
+        let path = out.join(format!(
+            "{}-{}.tbm",
+            divergence.job.pair,
+            triage::format_hash(divergence.hash)
+        ));
+        divergence.to_trace().save(&path)?;
+    }
+
+    let path = summary_path(out);
+    fs::write(&path, summary(options, &stats, start.elapsed()))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(pending)
+}
+
+// Run every job until the duration is up or all workers have done their runs,
+// calling [found] with the new divergences every interval. Returns the final
+// statistics of every job.
+pub fn orchestrate<F>(options: &OrchestratorOptions, mut found: F) -> Result<Vec<JobStats>, String>
+where
+    F: FnMut(&[Divergence]),
+{
+    fs::create_dir_all(&options.out).map_err(|e| format!("{}: {}", options.out.display(), e))?;
+
+    let queue = Arc::new(Mutex::new(Queue {
+        stats: vec![JobStats::default(); options.jobs.len()],
+        ..Queue::default()
+    }));
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+
+    let workers: Vec<_> = (0..options.jobs.len())
+        .map(|index| {
+            let options = options.clone();
+            let queue = Arc::clone(&queue);
+            let stop = Arc::clone(&stop);
+            // Workers catch the panics of the machines they drive, which
+            // shouldn't clutter the output either.
+            thread::spawn(move || fuzz::quietly(|| worker(index, &options, &queue, &stop)))
+        })
+        .collect();
+
+    // Check on the workers every so often rather than sleeping for the whole
+    // interval, so that runs that end early don't wait for the next drain.
+    let mut result = Ok(());
+    let mut last_drain = Instant::now();
+    while result.is_ok()
+        && start.elapsed() < options.duration
+        && !workers.iter().all(|worker| worker.is_finished())
+    {
+        thread::sleep(Duration::from_millis(50));
+        if last_drain.elapsed() >= options.interval {
+            result = drain(options, &queue, start).map(|new| found(&new));
+            last_drain = Instant::now();
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    result?;
+    found(&drain(options, &queue, start)?);
+    let stats = queue.lock().unwrap().stats.clone();
+    Ok(stats)
+}
+
+pub fn summary_path(out: &Path) -> PathBuf {
+    out.join("summary.txt")
+}
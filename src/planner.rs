@@ -0,0 +1,179 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::budget::{Budget, Exhausted, Meter};
+use crate::machine2::{AccessKind, Operation, Reference, TokenMachine};
+use crate::profiling::{self, Counter};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanResult {
+    // Performing these operations (possibly none at all) from the current
+    // state makes the access legal, and there is no shorter sequence that does.
+    Found(Vec<Operation>),
+    // Every state reachable through returns, merges and borrows was explored
+    // and none of them allow the access.
+    Impossible,
+    // No plan was found within the bound, but a longer one might exist.
+    BoundReached,
+    // The search was cut short before it found a plan or showed that none
+    // exists within the bound.
+    OutOfBudget(Exhausted),
+}
+
+// The moves the planner is allowed to make. Creating references, duplicating
+// tokens or changing permissions would change what the program does, whereas
+// returns, merges and borrows only move existing token pieces around.
+fn candidate_moves(machine: &TokenMachine) -> Vec<Operation> {
+    let mut moves = Vec::new();
+
+    for r in machine.references() {
+        moves.push(Operation::Return(r));
+        moves.push(Operation::Merge(r));
+        moves.push(Operation::Borrow(r));
+    }
+
+    moves
+}
+
+fn access_allowed(machine: &TokenMachine, target: Reference, access_kind: AccessKind) -> bool {
+    machine.step(Operation::Use(target, access_kind)).is_ok()
+}
+
+// Breadth-first search for the shortest sequence of at most [max_len]
+// operations after which [target] can perform an access of kind
+// [access_kind].
+//
+// Each of the candidate moves makes progress that can never be undone (a
+// borrow moves a reference out of Created, a return kills it and a merge
+// decreases the number of token pieces), so the search space is finite and an
+// exhausted search proves that no plan exists.
+pub fn plan_access(
+    machine: &TokenMachine,
+    target: Reference,
+    access_kind: AccessKind,
+    max_len: usize,
+) -> PlanResult {
+    plan_access_within(machine, target, access_kind, Budget::depth(max_len))
+}
+
+// Like plan_access, with the maximum length of the plan given by the depth of
+// [budget], and giving up once the rest of it runs out.
+pub fn plan_access_within(
+    machine: &TokenMachine,
+    target: Reference,
+    access_kind: AccessKind,
+    budget: Budget,
+) -> PlanResult {
+    let max_len = budget.max_depth;
+    let mut meter = Meter::start(budget);
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    let mut bound_reached = false;
+
+    visited.insert(machine.clone());
+    queue.push_back((machine.clone(), Vec::new()));
+
+    while let Some((state, plan)) = queue.pop_front() {
+        if !meter.visit() {
+            break;
+        }
+        profiling::count(Counter::StatesExpanded);
+
+        if access_allowed(&state, target, access_kind) {
+            return PlanResult::Found(plan);
+        }
+
+        for op in candidate_moves(&state) {
+            let next = match state.step(op) {
+                Ok((next, _)) => next,
+                Err(_) => continue,
+            };
+
+            if visited.contains(&next) {
+                profiling::count(Counter::DedupHits);
+                continue;
+            }
+
+            if plan.len() == max_len {
+                bound_reached = true;
+                continue;
+            }
+
+            let mut next_plan = plan.clone();
+            next_plan.push(op);
+            visited.insert(next.clone());
+            queue.push_back((next, next_plan));
+        }
+    }
+
+    if let Some(exhausted) = meter.exhausted() {
+        PlanResult::OutOfBudget(exhausted)
+    } else if bound_reached {
+        PlanResult::BoundReached
+    } else {
+        PlanResult::Impossible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine2::MachineConfig;
+    use crate::trace::Trace;
+
+    fn replay(text: &str) -> TokenMachine {
+        let mut machine = TokenMachine::init_empty_with(MachineConfig::default());
+        for op in Trace::parse(text).unwrap().ops {
+            machine.apply(op).unwrap();
+        }
+        machine
+    }
+
+    // r1 holds the token, which has to go back to r0 before r2 can borrow it.
+    const HELD_BY_SIBLING: &str =
+        "r0 = root\nr1 = create r0 unique\nborrow r1\nr2 = create r0 unique\n";
+
+    #[test]
+    fn plans_are_as_short_as_possible() {
+        let machine = replay(HELD_BY_SIBLING);
+        let (r1, r2) = (Reference::from_id(1), Reference::from_id(2));
+
+        assert_eq!(
+            plan_access(&machine, r2, AccessKind::Write, 4),
+            PlanResult::Found(vec![Operation::Return(r1), Operation::Borrow(r2)])
+        );
+        assert_eq!(
+            plan_access(&machine, r2, AccessKind::Write, 1),
+            PlanResult::BoundReached
+        );
+        assert_eq!(
+            plan_access(&machine, r1, AccessKind::Write, 4),
+            PlanResult::Found(Vec::new())
+        );
+    }
+
+    #[test]
+    fn shared_read_only_references_cannot_be_planned_into_writing() {
+        let machine = replay("r0 = root\nr1 = create r0 shared_ro\n");
+        let r1 = Reference::from_id(1);
+
+        assert_eq!(
+            plan_access(&machine, r1, AccessKind::Write, 10),
+            PlanResult::Impossible
+        );
+        assert!(matches!(
+            plan_access(&machine, r1, AccessKind::Read, 10),
+            PlanResult::Found(plan) if plan.len() == 1
+        ));
+    }
+
+    #[test]
+    fn planning_gives_up_when_the_budget_runs_out() {
+        let machine = replay(HELD_BY_SIBLING);
+        let budget = Budget::depth(4).with_states(2);
+
+        assert_eq!(
+            plan_access_within(&machine, Reference::from_id(2), AccessKind::Write, budget),
+            PlanResult::OutOfBudget(Exhausted::States)
+        );
+    }
+}
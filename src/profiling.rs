@@ -0,0 +1,61 @@
+// Counters for finding out where exploration spends its time. They are only
+// maintained when the crate is built with the "profiling" feature; otherwise
+// counting compiles to nothing.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Counter {
+    MapLookups,
+    Clones,
+    StatesExpanded,
+    DedupHits,
+}
+
+const COUNTERS: [Counter; 4] = [
+    Counter::MapLookups,
+    Counter::Clones,
+    Counter::StatesExpanded,
+    Counter::DedupHits,
+];
+
+#[cfg(feature = "profiling")]
+mod enabled {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::Counter;
+
+    static VALUES: [AtomicU64; 4] = [
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+    ];
+
+    pub fn count(counter: Counter) {
+        VALUES[counter as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: Counter) -> u64 {
+        VALUES[counter as usize].load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "profiling")]
+pub use enabled::{count, get};
+
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub fn count(_counter: Counter) {}
+
+#[cfg(not(feature = "profiling"))]
+pub fn get(_counter: Counter) -> u64 {
+    0
+}
+
+// Print all counters to stderr. Does nothing unless profiling is enabled.
+pub fn dump() {
+    if cfg!(feature = "profiling") {
+        for &counter in &COUNTERS {
+            eprintln!("{:?}: {}", counter, get(counter));
+        }
+    }
+}
@@ -0,0 +1,333 @@
+// Projecting the token machine onto the simple model of machine.rs, which has
+// a single token that is never split: it can only be lent to a child and
+// returned to the parent, and only its current owner can use it. As long as
+// the token of a tree is in one piece, the piece accounting of machine2 should
+// mean exactly that, so the analysis below replays a trace on both and checks
+// after every step that the holder of the only piece is the simple model's
+// current owner, and that every reference is in the same state in both.
+//
+// The first step after which that no longer holds is where the projection
+// breaks, and the feature of machine2 responsible for it says which part of
+// the multi-piece accounting the simple model has no counterpart for. Only the
+// tree of the first root is projected; the simple model has no other trees.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::explore;
+use crate::fuzz;
+use crate::machine;
+use crate::machine2::{self, MachineConfig, Operation, Reference, TokenMachine};
+use crate::trace::Trace;
+
+// The features of machine2 that the simple model does not have.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feature {
+    // The root is configured to start with more than one piece.
+    RootPieces,
+    // Splitting the token into pieces.
+    Dup,
+    // Merging pieces back together.
+    Merge,
+    // Changing the permissions of the token.
+    Perms,
+    // Taking the token back from descendants without them returning it.
+    Reclaim,
+    // Giving back a single piece of a split token (ReturnRule::Partial).
+    PartialReturn,
+    // Call frames, which kill the references created during a call.
+    Calls,
+    // Lending, returning, creating or using, which both models have. The
+    // projection breaking on one of these means the models really disagree.
+    Ownership,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Feature::RootPieces => "root pieces",
+            Feature::Dup => "dup",
+            Feature::Merge => "merge",
+            Feature::Perms => "token permissions",
+            Feature::Reclaim => "reclaim",
+            Feature::PartialReturn => "partial return",
+            Feature::Calls => "call frames",
+            Feature::Ownership => "ownership",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The feature [op] exercises, given the state before it.
+fn feature_of(machine: &TokenMachine, op: Operation) -> Feature {
+    match op {
+        Operation::Dup(_) => Feature::Dup,
+        Operation::Merge(_) => Feature::Merge,
+        Operation::SetPerms(..) => Feature::Perms,
+        Operation::ReclaimExclusive(_) => Feature::Reclaim,
+        Operation::Return(r) if machine.outstanding_splits(r) > 0 => Feature::PartialReturn,
+        Operation::Call(_) | Operation::Arg(_) | Operation::Ret => Feature::Calls,
+        Operation::NewRoot
+        | Operation::NewConstRoot
+        | Operation::CreateRef(..)
+        | Operation::Borrow(_)
+        | Operation::Return(_)
+        | Operation::Use(..) => Feature::Ownership,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Break {
+    pub step: usize,
+    pub op: Operation,
+    pub feature: Feature,
+    pub reason: String,
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {}: '{}' breaks the projection ({}): {}",
+            self.step, self.op, self.feature, self.reason
+        )
+    }
+}
+
+// The outcome of projecting a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Projection {
+    // Every step could be projected, up to the end of the trace or up to the
+    // first operation machine2 rejected (at the given step).
+    Holds { rejected_at: Option<usize> },
+    Broken(Break),
+}
+
+impl fmt::Display for Projection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Projection::Holds { rejected_at: None } => write!(f, "the projection holds"),
+            Projection::Holds {
+                rejected_at: Some(step),
+            } => write!(
+                f,
+                "the projection holds up to step {}, which is rejected",
+                step
+            ),
+            Projection::Broken(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+// Both models, started from a single root.
+struct Projector {
+    machine: TokenMachine,
+    root: Reference,
+    simple: machine::TokenMachine,
+    // The simple model's name for every reference of the projected tree.
+    refs: HashMap<Reference, machine::Reference>,
+}
+
+fn same_state(state: machine2::RefState, simple: machine::RefState) -> bool {
+    matches!(
+        (state, simple),
+        (machine2::RefState::Created, machine::RefState::Created)
+            | (machine2::RefState::Borrowing, machine::RefState::Borrowing)
+            | (machine2::RefState::Dead, machine::RefState::Dead)
+    )
+}
+
+impl Projector {
+    fn new(config: MachineConfig) -> Self {
+        let (root, machine) = TokenMachine::init_with(config);
+        let (simple_root, simple) = machine::TokenMachine::init();
+        let mut refs = HashMap::new();
+        refs.insert(root, simple_root);
+
+        Projector {
+            machine,
+            root,
+            simple,
+            refs,
+        }
+    }
+
+    // Why the current states don't correspond, if they don't.
+    fn mismatch(&self) -> Option<String> {
+        let pieces = self.machine.token_count(self.root);
+        if pieces != 1 {
+            return Some(format!("the token is split into {} pieces", pieces));
+        }
+
+        let owner = self.simple.current_owner();
+        for (&r, &simple_r) in &self.refs {
+            let state = self.machine.state_of(r);
+            let simple_state = self.simple.state_of(simple_r);
+            if !same_state(state, simple_state) {
+                return Some(format!(
+                    "{} is {:?}, but {:?} in the simple model",
+                    r, state, simple_state
+                ));
+            }
+
+            let holds = self
+                .machine
+                .get_token_info(r)
+                .is_some_and(|info| info.pieces_held > 0);
+            if holds != (simple_r == owner) {
+                return Some(if holds {
+                    format!("{} holds the token, but not in the simple model", r)
+                } else {
+                    format!(
+                        "{} does not hold the token, but does in the simple model",
+                        r
+                    )
+                });
+            }
+        }
+
+        None
+    }
+
+    // Perform [op] on the simple model, for operations on the projected tree
+    // that it has a counterpart for. The simple model panics on illegal
+    // operations, which is reported as an error.
+    fn simulate(&mut self, op: Operation, created: Option<Reference>) -> Result<(), String> {
+        let refs = &mut self.refs;
+        let simple = &mut self.simple;
+
+        fuzz::quietly(|| {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                match op {
+                    Operation::CreateRef(parent, _) => {
+                        let new_ref = simple.create_ref(refs[&parent]);
+                        refs.insert(created.unwrap(), new_ref);
+                    }
+                    Operation::Borrow(r) => simple.borrow_token(refs[&r]),
+                    Operation::Return(r) => {
+                        if simple.current_owner() != refs[&r] {
+                            return Err(format!(
+                                "{} does not hold the token in the simple model",
+                                r
+                            ));
+                        }
+                        simple.return_token();
+                    }
+                    Operation::Use(r, _) => simple.use_token(refs[&r]),
+                    _ => {}
+                }
+                Ok(())
+            }))
+            .unwrap_or_else(|payload| Err(fuzz::panic_message(&*payload)))
+        })
+    }
+
+    // Perform step [step] of a trace. Returns Ok(false) if machine2 rejected
+    // it, in which case the projection is not taken any further.
+    fn step(&mut self, step: usize, op: Operation) -> Result<bool, Break> {
+        // Operations on other trees can't break the projection.
+        let projected = op.references().iter().all(|r| self.refs.contains_key(r));
+        let feature = if projected {
+            feature_of(&self.machine, op)
+        } else {
+            Feature::Ownership
+        };
+
+        let created = match self.machine.apply(op) {
+            Ok(created) => created,
+            Err(_) => return Ok(false),
+        };
+        let broken = |reason| Break {
+            step,
+            op,
+            feature,
+            reason,
+        };
+
+        if projected && !op.references().is_empty() {
+            self.simulate(op, created).map_err(broken)?;
+        }
+        match self.mismatch() {
+            Some(reason) => Err(broken(reason)),
+            None => Ok(true),
+        }
+    }
+}
+
+// Project the operations [ops], which start from a machine with a single root.
+pub fn project_ops(config: MachineConfig, ops: &[Operation]) -> Projection {
+    let mut projector = Projector::new(config);
+    if let Some(reason) = projector.mismatch() {
+        return Projection::Broken(Break {
+            step: 0,
+            op: Operation::NewRoot,
+            feature: Feature::RootPieces,
+            reason,
+        });
+    }
+
+    for (step, &op) in ops.iter().enumerate() {
+        match projector.step(step, op) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Projection::Holds {
+                    rejected_at: Some(step),
+                }
+            }
+            Err(b) => return Projection::Broken(b),
+        }
+    }
+
+    Projection::Holds { rejected_at: None }
+}
+
+// Project [trace], which has to start by creating the root to project (as
+// for replay_simple). Steps are numbered as in the trace.
+pub fn project(config: MachineConfig, trace: &Trace) -> Result<Projection, String> {
+    match trace.ops.first() {
+        Some(Operation::NewRoot) => {}
+        _ => return Err("the trace has to start with a root to project".to_string()),
+    }
+
+    Ok(match project_ops(config, &trace.ops[1..]) {
+        Projection::Holds { rejected_at } => Projection::Holds {
+            rejected_at: rejected_at.map(|step| step + 1),
+        },
+        Projection::Broken(b) if b.op == Operation::NewRoot => Projection::Broken(b),
+        Projection::Broken(b) => Projection::Broken(Break {
+            step: b.step + 1,
+            ..b
+        }),
+    })
+}
+
+// How often each feature breaks the projection among all traces of at most
+// [depth] operations from a single root, counting every trace whose last
+// operation is the one that breaks it, with the shortest such trace.
+pub fn census(
+    config: MachineConfig,
+    depth: usize,
+    max_refs: usize,
+) -> BTreeMap<Feature, (usize, Vec<Operation>)> {
+    let mut breaks: BTreeMap<Feature, (usize, Vec<Operation>)> = BTreeMap::new();
+    let (_, machine) = TokenMachine::init_with(config);
+
+    explore::for_each_trace(&machine, depth, max_refs, &mut |ops, _| {
+        if let Projection::Broken(b) = project_ops(config, ops) {
+            let last = match b.feature {
+                Feature::RootPieces => ops.is_empty(),
+                _ => b.step + 1 == ops.len(),
+            };
+            if last {
+                let entry = breaks.entry(b.feature).or_insert((0, ops.to_vec()));
+                entry.0 += 1;
+                if ops.len() < entry.1.len() {
+                    entry.1 = ops.to_vec();
+                }
+            }
+        }
+    });
+
+    breaks
+}
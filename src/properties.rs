@@ -0,0 +1,278 @@
+use crate::budget::{Budget, SearchReport};
+use crate::explore;
+use crate::machine2::{MachineConfig, Operation, TokenMachine};
+use crate::minimize;
+use crate::trace::Trace;
+
+// A trace that violates a property, together with a description of what went
+// wrong.
+pub type Counterexample = (Vec<Operation>, String);
+
+// Check that token pieces are conserved along every trace of at most [depth]
+// operations: the machine's own accounting must hold after every step, and
+// since a root starts out with a single piece and every dup adds exactly one
+// piece and one split (and every merge removes one of each), the outstanding
+// splits of all references in a tree together must account for every piece
+// of its token beyond the first. Returns the number of traces that were checked.
+pub fn check_conservation(
+    config: MachineConfig,
+    depth: usize,
+    max_refs: usize,
+) -> Result<usize, Counterexample> {
+    let (_, machine) = TokenMachine::init_with(config);
+    let mut checked = 0;
+    let mut counterexample = None;
+
+    explore::for_each_trace(&machine, depth, max_refs, &mut |trace, state| {
+        if counterexample.is_some() {
+            return;
+        }
+        checked += 1;
+
+        if let Err(msg) = state.check_invariants() {
+            counterexample = Some((trace.to_vec(), msg));
+            return;
+        }
+
+        for root in state.roots() {
+            let splits: u32 = state
+                .references()
+                .into_iter()
+                .filter(|&r| state.root_of(r) == root)
+                .map(|r| state.outstanding_splits(r))
+                .sum();
+            let pieces = state.token_count(root);
+            if splits + 1 != pieces {
+                counterexample = Some((
+                    trace.to_vec(),
+                    format!(
+                        "{} outstanding splits in tree {:?} but {} pieces",
+                        splits, root, pieces
+                    ),
+                ));
+                return;
+            }
+        }
+    });
+
+    match counterexample {
+        Some(counterexample) => Err(counterexample),
+        None => Ok(checked),
+    }
+}
+
+// Check the error contract of the machine (see TokenMachine::apply) along
+// every trace of at most [depth] operations: every operation that is rejected
+// must leave the state exactly as it was, and attempting it again must be
+// rejected with the same error. Together this means that errors are final for
+// a given state, yet the machine can still be used after one. Returns the
+// number of rejected operations that were checked.
+pub fn check_rejections_recoverable(
+    config: MachineConfig,
+    depth: usize,
+    max_refs: usize,
+) -> Result<usize, Counterexample> {
+    let (_, machine) = TokenMachine::init_with(config);
+    let mut checked = 0;
+    let mut counterexample = None;
+
+    explore::for_each_trace(&machine, depth, max_refs, &mut |trace, state| {
+        if counterexample.is_some() {
+            return;
+        }
+
+        for op in explore::candidate_operations(state, max_refs) {
+            let mut attempt = state.clone();
+            let error = match attempt.apply(op) {
+                Ok(_) => continue,
+                Err(error) => error,
+            };
+            checked += 1;
+
+            let mut extended = trace.to_vec();
+            extended.push(op);
+
+            if attempt != *state {
+                counterexample = Some((
+                    extended,
+                    format!("rejected operation {:?} changed the state", op),
+                ));
+                return;
+            }
+
+            match attempt.apply(op) {
+                Err(again) if again == error => {}
+                again => {
+                    counterexample = Some((
+                        extended,
+                        format!(
+                            "operation {:?} was rejected with {} but then gave {:?}",
+                            op, error, again
+                        ),
+                    ));
+                    return;
+                }
+            }
+        }
+    });
+
+    match counterexample {
+        Some(counterexample) => Err(counterexample),
+        None => Ok(checked),
+    }
+}
+
+// Check that [property] holds after every trace of at most [depth] operations,
+// starting from a machine with a single root, under the default configuration
+// and with at most 4 references. On failure the returned counterexample is
+// minimized.
+pub fn check_property<F>(depth: usize, property: F) -> Result<usize, Counterexample>
+where
+    F: FnMut(&[Operation], &TokenMachine) -> bool,
+{
+    check_property_with(MachineConfig::default(), depth, 4, property)
+}
+
+pub fn check_property_with<F>(
+    config: MachineConfig,
+    depth: usize,
+    max_refs: usize,
+    property: F,
+) -> Result<usize, Counterexample>
+where
+    F: FnMut(&[Operation], &TokenMachine) -> bool,
+{
+    check_property_within(config, Budget::depth(depth), max_refs, property)
+        .map(|report| report.states)
+}
+
+// Like check_property_with, but only checks as many traces as [budget]
+// allows. If the budget runs out before a violation is found, the report says
+// so: the property has then only been checked for part of the traces.
+pub fn check_property_within<F>(
+    config: MachineConfig,
+    budget: Budget,
+    max_refs: usize,
+    mut property: F,
+) -> Result<SearchReport, Counterexample>
+where
+    F: FnMut(&[Operation], &TokenMachine) -> bool,
+{
+    let (_, machine) = TokenMachine::init_with(config);
+    let mut violation = None;
+
+    let report = explore::for_each_trace_within(&machine, budget, max_refs, &mut |trace, state| {
+        if violation.is_some() {
+            return;
+        }
+
+        if !property(trace, state) {
+            violation = Some(trace.to_vec());
+        }
+    });
+
+    let trace = match violation {
+        Some(trace) => trace,
+        None => return Ok(report),
+    };
+
+    // Removing operations renumbers the references created after them, so
+    // most candidates are simply rejected; only keep those that are accepted
+    // in full and still violate the property.
+    let minimized = minimize::shrink(&Trace::new(trace), |candidate| {
+        match machine.step_all(&candidate.ops) {
+            Ok(state) => !property(&candidate.ops, &state),
+            Err(_) => false,
+        }
+    });
+    let len = minimized.ops.len();
+
+    Err((
+        minimized.ops,
+        format!("property does not hold after {} operations", len),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coverage;
+    use crate::machine2::{AccessKind, RefKind, Reference, RootConfig};
+
+    #[test]
+    fn pieces_are_conserved_under_every_configuration() {
+        let mut configs = coverage::all_configs();
+        configs.push(MachineConfig {
+            root: RootConfig {
+                pieces: 3,
+                ..RootConfig::default()
+            },
+            ..MachineConfig::default()
+        });
+        for config in configs {
+            let checked = check_conservation(config, 4, 3)
+                .unwrap_or_else(|(trace, msg)| panic!("{:?}: {} after {:?}", config, msg, trace));
+            assert!(checked > 1, "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn rejections_are_recoverable_under_every_configuration() {
+        for config in coverage::all_configs() {
+            let checked = check_rejections_recoverable(config, 3, 3)
+                .unwrap_or_else(|(trace, msg)| panic!("{:?}: {} after {:?}", config, msg, trace));
+            assert!(checked > 0, "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn a_property_that_holds_is_checked_on_every_trace() {
+        let checked = check_property(4, |_, state| state.check_invariants().is_ok()).unwrap();
+        assert!(checked > 1);
+    }
+
+    #[test]
+    fn a_unique_writer_is_the_only_writer_of_its_tree() {
+        let can_write = |s: &TokenMachine, r| s.step(Operation::Use(r, AccessKind::Write)).is_ok();
+        check_property(4, |_, s| {
+            s.references().into_iter().all(|u| {
+                s.kind_of(u) != RefKind::Unique
+                    || !can_write(s, u)
+                    || s.references()
+                        .into_iter()
+                        .all(|r| r == u || s.root_of(r) != s.root_of(u) || !can_write(s, r))
+            })
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn shared_read_write_references_are_a_minimal_counterexample_to_a_single_writer() {
+        // Writers of the first tree, since those of separate trees don't
+        // exclude each other.
+        let writers = |state: &TokenMachine| {
+            state
+                .references()
+                .into_iter()
+                .filter(|&r| state.root_of(r) == Reference::from_id(0))
+                .filter(|&r| state.step(Operation::Use(r, AccessKind::Write)).is_ok())
+                .count()
+        };
+        let (trace, _) = check_property_with(MachineConfig::default(), 5, 3, |_, state| {
+            writers(state) <= 1
+        })
+        .unwrap_err();
+
+        let (_, machine) = TokenMachine::init();
+        let state = machine.step_all(&trace).unwrap();
+        assert!(writers(&state) > 1, "{:?}", trace);
+        // Dropping any operation loses the counterexample.
+        for i in 0..trace.len() {
+            let mut shorter = trace.clone();
+            shorter.remove(i);
+            if let Ok(state) = machine.step_all(&shorter) {
+                assert!(writers(&state) <= 1, "{:?} is not minimal", trace);
+            }
+        }
+    }
+}
@@ -0,0 +1,652 @@
+// A small query language over replayed traces, for investigations across the
+// corpus that would otherwise take a bit of bespoke Rust each. A query picks
+// rows and filters them:
+//
+//     query      = ( "steps" | "refs" ) [ "where" condition ] ;
+//     condition  = conjunct { "or" conjunct } ;
+//     conjunct   = negation { "and" negation } ;
+//     negation   = "not" negation | "(" condition ")" | comparison ;
+//     comparison = field ( "=" | "!=" | "<" | "<=" | ">" | ">=" ) value ;
+//
+// "steps" has a row for every event of the log and every reference there is
+// after it, so its fields describe the step (step, op, outcome, time, frames)
+// and the reference as it is after the step (see STEP_FIELDS). "refs" has a
+// row for every reference, describing it at the end of the trace and counting
+// what was done to it along the way (see REF_FIELDS). For example:
+//
+//     steps where kind = shared_ro and perms = readwrite and pieces > 0
+//     refs where state = dead and uses = 0
+//
+// Values are numbers, true or false, or words such as shared_ro; only numbers
+// can be ordered. Traces are replayed up to their first rejected operation,
+// which is the last event of the log.
+
+use std::fmt;
+
+use crate::events::{Event, EventLog};
+use crate::machine2::{AccessKind, MachineConfig, Operation, Reference, TokenMachine};
+use crate::trace::{self, Trace};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Subject {
+    Steps,
+    Refs,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Word(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Word(w) => write!(f, "{}", w),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Type {
+    Int,
+    Bool,
+    // A word out of the given ones, or any word if there are none.
+    Word(&'static [&'static str]),
+}
+
+const KINDS: &[&str] = &["shared_ro", "shared_rw", "unique"];
+const STATES: &[&str] = &["created", "borrowing", "dead"];
+const PERMS: &[&str] = &["readonly", "readwrite"];
+const OPS: &[&str] = &[
+    "root",
+    "const_root",
+    "create",
+    "borrow",
+    "return",
+    "dup",
+    "merge",
+    "perms",
+    "use",
+    "reclaim",
+    "call",
+    "arg",
+    "ret",
+];
+
+// The fields describing a reference, in rows of either subject.
+const REFERENCE_FIELDS: &[(&str, Type)] = &[
+    ("ref", Type::Int),
+    ("parent", Type::Int),
+    ("root", Type::Int),
+    ("depth", Type::Int),
+    ("kind", Type::Word(KINDS)),
+    ("state", Type::Word(STATES)),
+    ("perms", Type::Word(PERMS)),
+    ("pieces", Type::Int),
+    ("splits", Type::Int),
+];
+
+// The fields of "steps" rows besides those of REFERENCE_FIELDS. [involved]
+// says whether the step's operation names the reference or created it, and
+// [outcome] is "accepted" or the name of the error.
+const STEP_FIELDS: &[(&str, Type)] = &[
+    ("step", Type::Int),
+    ("op", Type::Word(OPS)),
+    ("outcome", Type::Word(&[])),
+    ("involved", Type::Bool),
+    ("time", Type::Int),
+    ("frames", Type::Int),
+];
+
+// The fields of "refs" rows besides those of REFERENCE_FIELDS. [created] is
+// the step that created the reference; the others count the accepted
+// operations naming it, except for [rejected].
+const REF_FIELDS: &[(&str, Type)] = &[
+    ("created", Type::Int),
+    ("uses", Type::Int),
+    ("reads", Type::Int),
+    ("writes", Type::Int),
+    ("borrows", Type::Int),
+    ("returns", Type::Int),
+    ("dups", Type::Int),
+    ("merges", Type::Int),
+    ("calls", Type::Int),
+    ("rejected", Type::Int),
+];
+
+fn field_type(subject: Subject, name: &str) -> Option<Type> {
+    let own = match subject {
+        Subject::Steps => STEP_FIELDS,
+        Subject::Refs => REF_FIELDS,
+    };
+    REFERENCE_FIELDS
+        .iter()
+        .chain(own)
+        .find(|(field, _)| *field == name)
+        .map(|(_, ty)| *ty)
+}
+
+fn field_names(subject: Subject) -> Vec<&'static str> {
+    let own = match subject {
+        Subject::Steps => STEP_FIELDS,
+        Subject::Refs => REF_FIELDS,
+    };
+    REFERENCE_FIELDS
+        .iter()
+        .chain(own)
+        .map(|(field, _)| *field)
+        .collect()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn holds(self, left: &Value, right: &Value) -> bool {
+        match (self, left, right) {
+            (Comparison::Eq, _, _) => left == right,
+            (Comparison::Ne, _, _) => left != right,
+            (Comparison::Lt, Value::Int(l), Value::Int(r)) => l < r,
+            (Comparison::Le, Value::Int(l), Value::Int(r)) => l <= r,
+            (Comparison::Gt, Value::Int(l), Value::Int(r)) => l > r,
+            (Comparison::Ge, Value::Int(l), Value::Int(r)) => l >= r,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Compare(String, Comparison, Value),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+// A row: the values of the fields of its subject.
+type Row = Vec<(&'static str, Value)>;
+
+impl Condition {
+    fn holds(&self, row: &Row) -> bool {
+        match self {
+            Condition::Compare(field, comparison, value) => row
+                .iter()
+                .find(|(name, _)| name == field)
+                .is_some_and(|(_, actual)| comparison.holds(actual, value)),
+            Condition::Not(c) => !c.holds(row),
+            Condition::And(a, b) => a.holds(row) && b.holds(row),
+            Condition::Or(a, b) => a.holds(row) || b.holds(row),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub subject: Subject,
+    pub condition: Option<Condition>,
+}
+
+// A row a query selected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    // The step and its operation, for "steps" queries.
+    pub step: Option<(usize, Operation)>,
+    pub reference: Reference,
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.step {
+            Some((step, op)) => write!(f, "step {} ({}): {}", step, op, self.reference),
+            None => write!(f, "{}", self.reference),
+        }
+    }
+}
+
+// Split a query into words, numbers, operators and parentheses.
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | '=' => tokens.push(c.to_string()),
+            '!' | '<' | '>' => {
+                let mut token = c.to_string();
+                if chars.peek() == Some(&'=') {
+                    token.push(chars.next().unwrap());
+                } else if c == '!' {
+                    return Err("expected '=' after '!'".to_string());
+                }
+                tokens.push(token);
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '-' => {
+                let mut token = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if !(next.is_ascii_alphanumeric() || next == '_' || next == '-') {
+                        break;
+                    }
+                    token.push(next);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+            _ => return Err(format!("unexpected character '{}'", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    subject: Subject,
+    tokens: Vec<String>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.at).map(String::as_str)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<&str, String> {
+        let token = self
+            .tokens
+            .get(self.at)
+            .ok_or_else(|| format!("expected {}, found the end of the query", expected))?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.peek() == Some(token) {
+            self.at += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let mut condition = self.conjunct()?;
+        while self.eat("or") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.conjunct()?));
+        }
+        Ok(condition)
+    }
+
+    fn conjunct(&mut self) -> Result<Condition, String> {
+        let mut condition = self.negation()?;
+        while self.eat("and") {
+            condition = Condition::And(Box::new(condition), Box::new(self.negation()?));
+        }
+        Ok(condition)
+    }
+
+    fn negation(&mut self) -> Result<Condition, String> {
+        if self.eat("not") {
+            return Ok(Condition::Not(Box::new(self.negation()?)));
+        }
+        if self.eat("(") {
+            let condition = self.condition()?;
+            if !self.eat(")") {
+                return Err(format!(
+                    "expected ')', found {}",
+                    self.peek()
+                        .map_or("the end of the query".to_string(), |t| format!("'{}'", t))
+                ));
+            }
+            return Ok(condition);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        let subject = self.subject;
+        let field = self.next("a field")?.to_string();
+        let ty = field_type(subject, &field).ok_or_else(|| {
+            format!(
+                "unknown field '{}', expected one of {}",
+                field,
+                field_names(subject).join(", ")
+            )
+        })?;
+
+        let comparison = match self.next("a comparison")? {
+            "=" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            other => return Err(format!("expected a comparison, found '{}'", other)),
+        };
+        let ordered = !matches!(comparison, Comparison::Eq | Comparison::Ne);
+        if ordered && ty != Type::Int {
+            return Err(format!("'{}' is not a number and cannot be ordered", field));
+        }
+
+        let word = self.next("a value")?;
+        let value = match ty {
+            Type::Int => word.parse().map(Value::Int).ok(),
+            Type::Bool => match word {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            Type::Word(words) if words.is_empty() || words.contains(&word) => {
+                Some(Value::Word(word.to_string()))
+            }
+            Type::Word(_) => None,
+        };
+        let value = value.ok_or_else(|| {
+            let expected = match ty {
+                Type::Int => "a number".to_string(),
+                Type::Bool => "true or false".to_string(),
+                Type::Word(words) => words.join(" or "),
+            };
+            format!("'{}' is {}, found '{}'", field, expected, word)
+        })?;
+
+        Ok(Condition::Compare(field, comparison, value))
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query, String> {
+        let tokens = tokenize(text)?;
+        let subject = match tokens.first().map(String::as_str) {
+            Some("steps") => Subject::Steps,
+            Some("refs") => Subject::Refs,
+            Some(other) => return Err(format!("expected steps or refs, found '{}'", other)),
+            None => return Err("the query is empty".to_string()),
+        };
+        let mut parser = Parser {
+            subject,
+            tokens,
+            at: 1,
+        };
+
+        let condition = match parser.peek() {
+            None => None,
+            Some("where") => {
+                parser.at += 1;
+                Some(parser.condition()?)
+            }
+            Some(other) => return Err(format!("expected where, found '{}'", other)),
+        };
+        if let Some(extra) = parser.peek() {
+            return Err(format!("unexpected '{}' after the condition", extra));
+        }
+
+        Ok(Query { subject, condition })
+    }
+
+    fn selects(&self, row: &Row) -> bool {
+        self.condition.as_ref().is_none_or(|c| c.holds(row))
+    }
+
+    // The rows of [trace], replayed under [config], that the query selects.
+    pub fn run(&self, config: MachineConfig, trace: &Trace) -> Vec<Match> {
+        let mut machine = TokenMachine::init_empty_with(config);
+        let mut log = EventLog::new();
+        let mut matches = Vec::new();
+
+        for &op in &trace.ops {
+            let outcome = machine.apply(op);
+            let seq = log.record(op, outcome, None) as usize;
+            if self.subject == Subject::Steps {
+                let event = &log.events()[seq];
+                for r in machine.references() {
+                    if self.selects(&step_row(&machine, event, r)) {
+                        matches.push(Match {
+                            step: Some((seq, op)),
+                            reference: r,
+                        });
+                    }
+                }
+            }
+            if outcome.is_err() {
+                break;
+            }
+        }
+
+        if self.subject == Subject::Refs {
+            for r in machine.references() {
+                if self.selects(&ref_row(&machine, log.events(), r)) {
+                    matches.push(Match {
+                        step: None,
+                        reference: r,
+                    });
+                }
+            }
+        }
+        matches
+    }
+}
+
+fn reference_row(machine: &TokenMachine, r: Reference) -> Row {
+    let id = |r: Reference| Value::Int(r.id() as i64);
+    let pieces = machine.get_token_info(r).map_or(0, |info| info.pieces_held);
+    vec![
+        ("ref", id(r)),
+        ("parent", id(machine.parent_of(r))),
+        ("root", id(machine.root_of(r))),
+        ("depth", Value::Int(machine.depth_of(r) as i64)),
+        (
+            "kind",
+            Value::Word(trace::kind_name(machine.kind_of(r)).to_string()),
+        ),
+        (
+            "state",
+            Value::Word(format!("{:?}", machine.state_of(r)).to_lowercase()),
+        ),
+        (
+            "perms",
+            Value::Word(trace::perms_name(machine.token_perms(r)).to_string()),
+        ),
+        ("pieces", Value::Int(pieces as i64)),
+        ("splits", Value::Int(machine.outstanding_splits(r) as i64)),
+    ]
+}
+
+// The name of [op] as written in traces.
+fn op_name(op: Operation) -> String {
+    op.to_string()
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn involves(event: &Event, r: Reference) -> bool {
+    event.op.references().contains(&r) || event.outcome == Ok(Some(r))
+}
+
+fn step_row(machine: &TokenMachine, event: &Event, r: Reference) -> Row {
+    let outcome = match event.outcome {
+        Ok(_) => "accepted",
+        Err(err) => err.name(),
+    };
+    let mut row = reference_row(machine, r);
+    row.extend(vec![
+        ("step", Value::Int(event.seq as i64)),
+        ("op", Value::Word(op_name(event.op))),
+        ("outcome", Value::Word(outcome.to_string())),
+        ("involved", Value::Bool(involves(event, r))),
+        ("time", Value::Int(machine.time() as i64)),
+        ("frames", Value::Int(machine.frames().len() as i64)),
+    ]);
+    row
+}
+
+fn ref_row(machine: &TokenMachine, events: &[Event], r: Reference) -> Row {
+    let accepted = |matches: &dyn Fn(Operation) -> bool| {
+        let count = events
+            .iter()
+            .filter(|e| e.outcome.is_ok() && e.op.references().contains(&r) && matches(e.op))
+            .count();
+        Value::Int(count as i64)
+    };
+    let created = events
+        .iter()
+        .find(|e| e.outcome == Ok(Some(r)))
+        .map_or(-1, |e| e.seq as i64);
+    let rejected = events
+        .iter()
+        .filter(|e| e.outcome.is_err() && e.op.references().contains(&r))
+        .count();
+
+    let mut row = reference_row(machine, r);
+    row.extend(vec![
+        ("created", Value::Int(created)),
+        ("uses", accepted(&|op| matches!(op, Operation::Use(..)))),
+        (
+            "reads",
+            accepted(&|op| matches!(op, Operation::Use(_, AccessKind::Read))),
+        ),
+        (
+            "writes",
+            accepted(&|op| matches!(op, Operation::Use(_, AccessKind::Write))),
+        ),
+        (
+            "borrows",
+            accepted(&|op| matches!(op, Operation::Borrow(_))),
+        ),
+        (
+            "returns",
+            accepted(&|op| matches!(op, Operation::Return(_))),
+        ),
+        ("dups", accepted(&|op| matches!(op, Operation::Dup(_)))),
+        ("merges", accepted(&|op| matches!(op, Operation::Merge(_)))),
+        (
+            "calls",
+            accepted(&|op| matches!(op, Operation::Call(_) | Operation::Arg(_))),
+        ),
+        ("rejected", Value::Int(rejected as i64)),
+    ]);
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(field: &str, comparison: Comparison, value: Value) -> Box<Condition> {
+        Box::new(Condition::Compare(field.to_string(), comparison, value))
+    }
+
+    fn condition(text: &str) -> Condition {
+        Query::parse(text).unwrap().condition.unwrap()
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_which_binds_tighter_than_or() {
+        let ref1 = || compare("ref", Comparison::Eq, Value::Int(1));
+        let unique = || compare("kind", Comparison::Eq, Value::Word("unique".to_string()));
+        let step0 = || compare("step", Comparison::Eq, Value::Int(0));
+
+        assert_eq!(
+            condition("steps where not ref = 1 and kind = unique or step = 0"),
+            Condition::Or(
+                Box::new(Condition::And(Box::new(Condition::Not(ref1())), unique())),
+                step0()
+            )
+        );
+        assert_eq!(
+            condition("steps where step = 0 or ref = 1 and not kind = unique"),
+            Condition::Or(
+                step0(),
+                Box::new(Condition::And(ref1(), Box::new(Condition::Not(unique()))))
+            )
+        );
+    }
+
+    #[test]
+    fn parentheses_group_conditions() {
+        let uses = || compare("uses", Comparison::Gt, Value::Int(0));
+        let either = || {
+            Box::new(Condition::Or(
+                compare("ref", Comparison::Eq, Value::Int(1)),
+                compare("ref", Comparison::Eq, Value::Int(2)),
+            ))
+        };
+
+        assert_eq!(
+            condition("refs where not (ref = 1 or ref = 2) and uses > 0"),
+            Condition::And(Box::new(Condition::Not(either())), uses())
+        );
+        assert_eq!(
+            condition("refs where ((ref = 1 or ref = 2)) and uses > 0"),
+            Condition::And(either(), uses())
+        );
+        assert!(Query::parse("refs where (ref = 1").is_err());
+    }
+
+    #[test]
+    fn bad_conditions_are_rejected() {
+        let error = |text: &str| Query::parse(text).unwrap_err();
+
+        assert_eq!(
+            error("refs where kind < unique"),
+            "'kind' is not a number and cannot be ordered"
+        );
+        assert!(error("refs where colour = red").starts_with("unknown field 'colour'"));
+        // Step fields only exist for steps.
+        assert!(error("refs where step = 0").starts_with("unknown field 'step'"));
+        assert_eq!(
+            error("refs where state = alive"),
+            "'state' is created or borrowing or dead, found 'alive'"
+        );
+    }
+
+    // The write through r1 is rejected, which ends the replay.
+    const TRACE: &str = "r0 = root\nr1 = create r0 shared_ro\nborrow r1\n\
+                         use r1 read\nuse r1 read\nuse r1 write\n";
+
+    fn run(text: &str) -> Vec<Match> {
+        let trace = Trace::parse(TRACE).unwrap();
+        Query::parse(text)
+            .unwrap()
+            .run(MachineConfig::default(), &trace)
+    }
+
+    #[test]
+    fn steps_queries_select_a_reference_after_a_step() {
+        let matches = run("steps where involved = true and outcome != accepted");
+        let r1 = Reference::from_id(1);
+        assert_eq!(
+            matches,
+            vec![Match {
+                step: Some((5, Operation::Use(r1, AccessKind::Write))),
+                reference: r1,
+            }]
+        );
+    }
+
+    #[test]
+    fn refs_queries_count_what_was_done_to_a_reference() {
+        let refs = |text| {
+            run(text)
+                .into_iter()
+                .map(|m| m.reference.id())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(refs("refs where reads = 2 and rejected = 1"), vec![1]);
+        assert_eq!(refs("refs where created = 0 and borrows = 0"), vec![0]);
+        assert_eq!(refs("refs"), vec![0, 1]);
+    }
+}
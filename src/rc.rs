@@ -0,0 +1,330 @@
+// A model of Rc<RefCell<T>> on top of the token machine, to see how dynamic
+// borrow checking relates to the token discipline.
+//
+// Each cell is the root of its own tree. Rc and Weak handles are only counted
+// here and do not hold tokens themselves; guards returned by borrow and
+// borrow_mut are references derived from the cell's root:
+//
+// - borrow() lowers the token to read-only (for the first shared guard),
+//   splits off a piece and lends it to a new shared read-only reference;
+// - borrow_mut() lends the whole token to a new unique reference;
+// - releasing a guard returns its piece and merges it back, restoring the
+//   token to read-write once the last shared guard is gone.
+//
+// When RefCell's own check fails (where Rust would panic with "already
+// borrowed"), the error records what the token machine would have said if the
+// borrow had gone ahead and the guard had been used, so that double-borrow
+// panics can be lined up with token conflicts.
+
+use std::fmt;
+
+use crate::machine2::{
+    AccessKind, MachineError, Operation, RefKind, Reference, TokenMachine, TokenPermissions,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Handle(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HandleKind {
+    Strong,
+    Weak,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RcError {
+    // The handle has been dropped already.
+    HandleDropped,
+    // A weak handle was upgraded after the last strong handle was dropped.
+    CellDropped,
+    // A cell was borrowed through a weak handle without upgrading it.
+    WeakAccess,
+    // RefCell::borrow while mutably borrowed. [token] is the error the token
+    // machine would have reported, or None if it would have allowed the
+    // borrow and a read through it.
+    AlreadyMutablyBorrowed { token: Option<MachineError> },
+    // RefCell::borrow_mut while borrowed. [token] is the error the token
+    // machine would have reported, or None if it would have allowed the
+    // borrow and a write through it.
+    AlreadyBorrowed { token: Option<MachineError> },
+    // The guard has been released already.
+    GuardReleased,
+    Machine(MachineError),
+}
+
+impl fmt::Display for RcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (msg, token) = match self {
+            RcError::HandleDropped => return write!(f, "Handle has been dropped"),
+            RcError::CellDropped => return write!(f, "Cannot upgrade: no strong handles left"),
+            RcError::WeakAccess => return write!(f, "Weak handles have to be upgraded first"),
+            RcError::GuardReleased => return write!(f, "Guard has been released"),
+            RcError::Machine(err) => return write!(f, "{}", err),
+            RcError::AlreadyMutablyBorrowed { token } => ("already mutably borrowed", token),
+            RcError::AlreadyBorrowed { token } => ("already borrowed", token),
+        };
+        match token {
+            Some(err) => write!(f, "{} (token machine: {})", msg, err),
+            None => write!(f, "{} (token machine would allow it)", msg),
+        }
+    }
+}
+
+impl From<MachineError> for RcError {
+    fn from(err: MachineError) -> Self {
+        RcError::Machine(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Cell {
+    root: Reference,
+    strong: usize,
+    weak: usize,
+    shared_guards: Vec<Reference>,
+    mutable_guard: Option<Reference>,
+}
+
+#[derive(Debug, Clone)]
+struct HandleInfo {
+    cell: usize,
+    kind: HandleKind,
+    alive: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RcWorld {
+    machine: TokenMachine,
+    cells: Vec<Cell>,
+    handles: Vec<HandleInfo>,
+}
+
+impl Default for RcWorld {
+    fn default() -> Self {
+        RcWorld::new(TokenMachine::init_empty())
+    }
+}
+
+impl RcWorld {
+    // Cells are added to [machine] as new trees.
+    pub fn new(machine: TokenMachine) -> Self {
+        RcWorld {
+            machine,
+            cells: Vec::new(),
+            handles: Vec::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &TokenMachine {
+        &self.machine
+    }
+
+    fn add_handle(&mut self, cell: usize, kind: HandleKind) -> Handle {
+        match kind {
+            HandleKind::Strong => self.cells[cell].strong += 1,
+            HandleKind::Weak => self.cells[cell].weak += 1,
+        }
+        self.handles.push(HandleInfo {
+            cell,
+            kind,
+            alive: true,
+        });
+        Handle(self.handles.len() - 1)
+    }
+
+    fn handle(&self, handle: Handle) -> Result<&HandleInfo, RcError> {
+        match self.handles.get(handle.0) {
+            Some(info) if info.alive => Ok(info),
+            _ => Err(RcError::HandleDropped),
+        }
+    }
+
+    fn strong_cell(&self, handle: Handle) -> Result<usize, RcError> {
+        let info = self.handle(handle)?;
+        if info.kind == HandleKind::Weak {
+            return Err(RcError::WeakAccess);
+        }
+        Ok(info.cell)
+    }
+
+    // Rc::new(RefCell::new(..)).
+    pub fn new_cell(&mut self) -> Handle {
+        let root = self.machine.new_root();
+        self.cells.push(Cell {
+            root,
+            strong: 0,
+            weak: 0,
+            shared_guards: Vec::new(),
+            mutable_guard: None,
+        });
+        self.add_handle(self.cells.len() - 1, HandleKind::Strong)
+    }
+
+    // Rc::clone or Weak::clone.
+    pub fn clone_handle(&mut self, handle: Handle) -> Result<Handle, RcError> {
+        let info = self.handle(handle)?.clone();
+        Ok(self.add_handle(info.cell, info.kind))
+    }
+
+    pub fn downgrade(&mut self, handle: Handle) -> Result<Handle, RcError> {
+        let cell = self.strong_cell(handle)?;
+        Ok(self.add_handle(cell, HandleKind::Weak))
+    }
+
+    pub fn upgrade(&mut self, handle: Handle) -> Result<Handle, RcError> {
+        let cell = self.handle(handle)?.cell;
+        if self.cells[cell].strong == 0 {
+            return Err(RcError::CellDropped);
+        }
+        Ok(self.add_handle(cell, HandleKind::Strong))
+    }
+
+    pub fn drop_handle(&mut self, handle: Handle) -> Result<(), RcError> {
+        let info = self.handle(handle)?.clone();
+        let cell = &mut self.cells[info.cell];
+        match info.kind {
+            HandleKind::Strong => cell.strong -= 1,
+            HandleKind::Weak => cell.weak -= 1,
+        }
+        self.handles[handle.0].alive = false;
+        Ok(())
+    }
+
+    pub fn strong_count(&self, handle: Handle) -> Result<usize, RcError> {
+        Ok(self.cells[self.handle(handle)?.cell].strong)
+    }
+
+    pub fn weak_count(&self, handle: Handle) -> Result<usize, RcError> {
+        Ok(self.cells[self.handle(handle)?.cell].weak)
+    }
+
+    // The token operations for a new guard on [cell], performed on [machine].
+    fn lend_guard(
+        machine: &mut TokenMachine,
+        cell: &Cell,
+        kind: RefKind,
+    ) -> Result<Reference, MachineError> {
+        if kind == RefKind::SharedReadOnly {
+            if cell.shared_guards.is_empty() {
+                machine.apply(Operation::SetPerms(cell.root, TokenPermissions::ReadOnly))?;
+            }
+            // Keep a piece at the root for the next guard.
+            machine.apply(Operation::Dup(cell.root))?;
+        }
+        let guard = machine.create_ref(cell.root, kind)?;
+        machine.apply(Operation::Borrow(guard))?;
+        Ok(guard)
+    }
+
+    // What the token machine says about a guard of [kind] on [cell] that is
+    // used for [access] right away, ignoring RefCell's check.
+    fn unchecked(&self, cell: &Cell, kind: RefKind, access: AccessKind) -> Option<MachineError> {
+        let mut machine = self.machine.clone();
+        Self::lend_guard(&mut machine, cell, kind)
+            .and_then(|guard| machine.apply(Operation::Use(guard, access)))
+            .err()
+    }
+
+    // RefCell::borrow. The guard is the reference that reads go through.
+    pub fn borrow(&mut self, handle: Handle) -> Result<Reference, RcError> {
+        let index = self.strong_cell(handle)?;
+        let cell = &self.cells[index];
+
+        if cell.mutable_guard.is_some() {
+            let token = self.unchecked(cell, RefKind::SharedReadOnly, AccessKind::Read);
+            return Err(RcError::AlreadyMutablyBorrowed { token });
+        }
+
+        let mut machine = self.machine.clone();
+        let guard = Self::lend_guard(&mut machine, cell, RefKind::SharedReadOnly)?;
+        self.machine = machine;
+        self.cells[index].shared_guards.push(guard);
+        Ok(guard)
+    }
+
+    // RefCell::borrow_mut.
+    pub fn borrow_mut(&mut self, handle: Handle) -> Result<Reference, RcError> {
+        let index = self.strong_cell(handle)?;
+        let cell = &self.cells[index];
+
+        if cell.mutable_guard.is_some() || !cell.shared_guards.is_empty() {
+            let token = self.unchecked(cell, RefKind::Unique, AccessKind::Write);
+            return Err(RcError::AlreadyBorrowed { token });
+        }
+
+        let mut machine = self.machine.clone();
+        let guard = Self::lend_guard(&mut machine, cell, RefKind::Unique)?;
+        self.machine = machine;
+        self.cells[index].mutable_guard = Some(guard);
+        Ok(guard)
+    }
+
+    // The index of the cell [guard] is a live guard of.
+    fn guarded_cell(&self, guard: Reference) -> Result<usize, RcError> {
+        self.cells
+            .iter()
+            .position(|cell| {
+                cell.mutable_guard == Some(guard) || cell.shared_guards.contains(&guard)
+            })
+            .ok_or(RcError::GuardReleased)
+    }
+
+    // Access the cell's contents through a guard.
+    pub fn access(&mut self, guard: Reference, access: AccessKind) -> Result<(), RcError> {
+        self.guarded_cell(guard)?;
+        self.machine.apply(Operation::Use(guard, access))?;
+        Ok(())
+    }
+
+    // Drop a guard.
+    pub fn release(&mut self, guard: Reference) -> Result<(), RcError> {
+        let index = self.guarded_cell(guard)?;
+        let cell = &self.cells[index];
+        let shared = cell.mutable_guard != Some(guard);
+
+        let mut machine = self.machine.clone();
+        machine.apply(Operation::Return(guard))?;
+        if shared {
+            machine.apply(Operation::Merge(cell.root))?;
+            if cell.shared_guards.len() == 1 {
+                machine.apply(Operation::SetPerms(cell.root, TokenPermissions::ReadWrite))?;
+            }
+        }
+        self.machine = machine;
+
+        let cell = &mut self.cells[index];
+        if shared {
+            cell.shared_guards.retain(|&g| g != guard);
+        } else {
+            cell.mutable_guard = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_live_guards_give_access() {
+        let mut world = RcWorld::default();
+        let cell = world.new_cell();
+        let guard = world.borrow_mut(cell).unwrap();
+        world.access(guard, AccessKind::Write).unwrap();
+        world.release(guard).unwrap();
+
+        let before = world.machine().clone();
+        assert_eq!(
+            world.access(guard, AccessKind::Read),
+            Err(RcError::GuardReleased)
+        );
+        // The cell itself is not a guard either.
+        let root = world.machine().roots()[0];
+        assert_eq!(
+            world.access(root, AccessKind::Read),
+            Err(RcError::GuardReleased)
+        );
+        assert_eq!(*world.machine(), before);
+    }
+}
@@ -0,0 +1,409 @@
+// An interactive session with the machine. Every line is an operation in the
+// syntax of trace files (see trace.rs), which is performed right away, or one
+// of the commands below. A rejected operation poisons the machine (see
+// guard.rs), so that nothing else happens until the user either recovers to
+// the state before it or resets the session.
+//
+// A session can be forked, under another configuration if need be, to try
+// something out and switch back; the machines not in use are kept in a
+// SessionManager.
+//
+// Independently of the session, a trace can be stepped through the token
+// machine and the Stacked Borrows model side by side (see lockstep.rs), to
+// look at both states on the way to the step where they disagree.
+
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::fragments;
+use crate::guard::{GuardError, GuardedMachine, Snapshot};
+use crate::lockstep::Stepper;
+use crate::machine2::{MachineConfig, Operation, TokenMachine};
+use crate::session::{self, SessionManager};
+use crate::trace::{self, Trace};
+
+const HELP: &str = "\
+operations are written as in trace files, e.g. 'root', 'create r0 unique',
+'borrow r1', 'use r1 write', 'call r1', 'ret' or
+'expand reborrow_and_return r0'
+
+commands:
+    :state          print the state of the machine
+    :recover        go back to the state before the rejected operation
+    :reset          start over with an empty machine (saving any recording)
+    :record FILE    record the session, to be saved to FILE on :stop
+    :fork NAME [key=value ...]
+                    copy the session to a new one called NAME and switch to
+                    it; with settings, its operations are replayed under the
+                    changed configuration
+    :switch NAME    continue in another session
+    :sessions       list the sessions
+    :drop NAME      remove a session other than the current one
+    :lockstep FILE  step the trace in FILE through the token machine and the
+                    Stacked Borrows model side by side
+    :next [N]       perform the next N (1 by default) operations of that
+                    trace on both models, stopping where they disagree
+    :stop           save the recording
+    :help           print this message
+    :quit           end the session (saving any recording)";
+
+// A recording in progress. The saved trace contains every accepted operation
+// of the session, including those before :record, since the later operations
+// refer to the references they created; the header says where the recording
+// started.
+struct Recording {
+    path: PathBuf,
+    start: usize,
+}
+
+pub struct Session {
+    // The name of the current session, and the others.
+    name: String,
+    others: SessionManager,
+    machine: GuardedMachine,
+    // The state before the last operation, to recover to if it was rejected.
+    before: Option<Snapshot>,
+    // The accepted operations so far.
+    ops: Vec<Operation>,
+    recording: Option<Recording>,
+    // The trace being stepped with :next, if any.
+    lockstep: Option<Stepper>,
+}
+
+impl Session {
+    pub fn new(config: MachineConfig) -> Self {
+        Session {
+            name: "main".to_string(),
+            others: SessionManager::new(),
+            machine: GuardedMachine::new(TokenMachine::init_empty_with(config)),
+            before: None,
+            ops: Vec::new(),
+            recording: None,
+            lockstep: None,
+        }
+    }
+
+    pub fn machine(&self) -> &TokenMachine {
+        self.machine.machine()
+    }
+
+    // Handle a single line of input, returning what to print and whether the
+    // session is over.
+    pub fn handle(&mut self, line: &str) -> (String, bool) {
+        let line = line.trim();
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            None => (String::new(), false),
+            Some(":quit") => (self.stop().unwrap_or_default(), true),
+            Some(":help") => (HELP.to_string(), false),
+            Some(":state") => (format!("{:?}", self.machine()), false),
+            Some(":recover") => (self.recover(), false),
+            Some(":reset") => {
+                let mut out = self.stop().unwrap_or_default();
+                self.machine.reset();
+                self.ops.clear();
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str("reset");
+                (out, false)
+            }
+            Some(":record") => match words.next() {
+                Some(path) => (self.record(PathBuf::from(path)), false),
+                None => ("usage: :record FILE".to_string(), false),
+            },
+            Some(":fork") => match words.next() {
+                Some(name) => (self.fork(name, words.collect()), false),
+                None => ("usage: :fork NAME [key=value ...]".to_string(), false),
+            },
+            Some(":switch") => match words.next() {
+                Some(name) => (self.switch(name), false),
+                None => ("usage: :switch NAME".to_string(), false),
+            },
+            Some(":sessions") => (self.sessions(), false),
+            Some(":drop") => match words.next() {
+                Some(name) if name == self.name => {
+                    ("cannot drop the current session".to_string(), false)
+                }
+                Some(name) => match self.others.destroy(name) {
+                    Ok(_) => (format!("dropped '{}'", name), false),
+                    Err(err) => (format!("error: {}", err), false),
+                },
+                None => ("usage: :drop NAME".to_string(), false),
+            },
+            Some(":lockstep") => match words.next() {
+                Some(path) => (self.start_lockstep(path), false),
+                None => ("usage: :lockstep FILE".to_string(), false),
+            },
+            Some(":next") => match words.next().map(str::parse).unwrap_or(Ok(1)) {
+                Ok(count) => (self.next_steps(count), false),
+                Err(_) => ("usage: :next [N]".to_string(), false),
+            },
+            Some(":stop") => (
+                self.stop().unwrap_or_else(|| "not recording".to_string()),
+                false,
+            ),
+            Some(command) if command.starts_with(':') => {
+                (format!("unknown command '{}' (try :help)", command), false)
+            }
+            Some(_) => (self.operations(line), false),
+        }
+    }
+
+    fn recover(&mut self) -> String {
+        match (self.machine.poison(), self.before.take()) {
+            (Some((op, _)), Some(before)) => {
+                self.machine.recover_to(before);
+                format!("recovered to the state before '{}'", op)
+            }
+            _ => "nothing to recover from".to_string(),
+        }
+    }
+
+    // The current session as the session manager keeps them.
+    fn current(&self) -> session::Session {
+        session::Session::replay(*self.machine().config(), &self.ops)
+            .expect("the accepted operations are accepted again")
+    }
+
+    // Continue in [session], called [name], ending any recording.
+    fn enter(&mut self, name: &str, session: session::Session) -> String {
+        let mut out = self.stop().map(|saved| saved + "\n").unwrap_or_default();
+        let mut machine = GuardedMachine::new(TokenMachine::init_empty_with(*session.config()));
+        for &op in session.ops() {
+            machine
+                .apply(op)
+                .expect("the accepted operations are accepted again");
+        }
+
+        self.machine = machine;
+        self.ops = session.ops().to_vec();
+        self.before = None;
+        self.name = name.to_string();
+        out.push_str(&format!("now in '{}'", name));
+        out
+    }
+
+    fn fork(&mut self, name: &str, settings: Vec<&str>) -> String {
+        if self.machine.poison().is_some() {
+            return "cannot fork a poisoned machine (:recover first)".to_string();
+        }
+        let mut config = *self.machine().config();
+        for setting in settings {
+            let result = match setting.split_once('=') {
+                Some((key, value)) => trace::apply_config_setting(&mut config, key, value),
+                None => Err(format!("expected key=value, found '{}'", setting)),
+            };
+            if let Err(msg) = result {
+                return format!("error: {}", msg);
+            }
+        }
+
+        let current = self.name.clone();
+        if name == current {
+            return format!("error: there already is a session '{}'", name);
+        }
+        self.others.insert(&current, self.current()).unwrap();
+        match self.others.fork(&current, name, Some(config)) {
+            Ok(_) => {
+                let forked = self.others.destroy(name).unwrap();
+                self.enter(name, forked)
+            }
+            Err(err) => {
+                self.others.destroy(&current).unwrap();
+                format!("error: {}", err)
+            }
+        }
+    }
+
+    fn switch(&mut self, name: &str) -> String {
+        if name == self.name {
+            return format!("already in '{}'", name);
+        }
+        if self.machine.poison().is_some() {
+            return "cannot leave a poisoned machine (:recover first)".to_string();
+        }
+        let target = match self.others.destroy(name) {
+            Ok(target) => target,
+            Err(err) => return format!("error: {}", err),
+        };
+        let current = self.name.clone();
+        self.others.insert(&current, self.current()).unwrap();
+        self.enter(name, target)
+    }
+
+    fn sessions(&self) -> String {
+        let current = self.current();
+        let mut sessions: Vec<_> = self.others.list().collect();
+        sessions.push((&self.name, &current));
+        sessions.sort_by_key(|(name, _)| *name);
+
+        let lines: Vec<_> = sessions
+            .into_iter()
+            .map(|(name, session)| {
+                format!(
+                    "{} {} ({}, {} operations)",
+                    if name == self.name { "*" } else { " " },
+                    name,
+                    trace::format_config_inline(session.config()),
+                    session.ops().len()
+                )
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    fn start_lockstep(&mut self, path: &str) -> String {
+        let trace = match Trace::load(Path::new(path)) {
+            Ok(trace) => trace,
+            Err(msg) => return format!("error: {}", msg),
+        };
+        let config = match trace.config() {
+            Ok(config) => config.unwrap_or_default(),
+            Err(msg) => return format!("error: {}", msg),
+        };
+        self.lockstep = Some(Stepper::new(config, &trace));
+        format!(
+            "stepping {} operations of {} (:next to perform them)",
+            trace.ops.len(),
+            path
+        )
+    }
+
+    fn next_steps(&mut self, count: usize) -> String {
+        let stepper = match &mut self.lockstep {
+            Some(stepper) => stepper,
+            None => return "no trace to step (:lockstep FILE first)".to_string(),
+        };
+
+        let mut out = Vec::new();
+        for _ in 0..count {
+            match stepper.step() {
+                Some(step) => {
+                    let agrees = step.agrees();
+                    out.push(step.to_string());
+                    if !agrees {
+                        out.push(format!("the models disagree on step {}", step.index));
+                        break;
+                    }
+                }
+                None => {
+                    out.push("no more operations".to_string());
+                    break;
+                }
+            }
+        }
+        out.join("\n")
+    }
+
+    fn record(&mut self, path: PathBuf) -> String {
+        let mut out = String::new();
+        if let Some(saved) = self.stop() {
+            out.push_str(&saved);
+            out.push('\n');
+        }
+        out.push_str(&format!("recording to {}", path.display()));
+        self.recording = Some(Recording {
+            path,
+            start: self.ops.len(),
+        });
+        out
+    }
+
+    // Save the recording in progress, if there is one.
+    fn stop(&mut self) -> Option<String> {
+        let recording = self.recording.take()?;
+
+        // Saving a recording while the machine is poisoned keeps the rejected
+        // operation at the end of the trace, since the rejection is often what
+        // made the session worth keeping.
+        let mut trace = Trace::new(self.ops.clone());
+        trace.set_config(self.machine().config());
+        trace.set_header("recorded-from", recording.start.to_string());
+        if let Some((op, err)) = self.machine.poison() {
+            trace.set_header("expect", format!("{} at {}", err.name(), trace.ops.len()));
+            trace.ops.push(op);
+        }
+
+        Some(match trace.save(&recording.path) {
+            Ok(()) => format!(
+                "saved {} operations to {}",
+                trace.ops.len(),
+                recording.path.display()
+            ),
+            Err(msg) => format!("error: {}", msg),
+        })
+    }
+
+    fn operations(&mut self, line: &str) -> String {
+        let line = match line.split_once('=') {
+            Some((_, op)) => op.trim(),
+            None => line,
+        };
+
+        // Fragments number the references they create the way traces do, in
+        // order of creation, which is how the REPL's machines pick IDs.
+        let created = self.machine().references().len() as u32;
+        let ops = match line.split_once(char::is_whitespace) {
+            Some(("expand", fragment)) => {
+                let words: Vec<_> = fragment.split_whitespace().collect();
+                fragments::expand(&words, created)
+            }
+            _ => trace::parse_operation(line).map(|op| vec![op]),
+        };
+        let ops = match ops {
+            Ok(ops) => ops,
+            Err(msg) => return format!("error: {}", msg),
+        };
+
+        let mut out = Vec::new();
+        for op in ops {
+            self.before = self.machine.snapshot().or_else(|| self.before.take());
+            match self.machine.apply(op) {
+                Ok(Some(r)) => out.push(format!("{} = {}", r, op)),
+                Ok(None) => out.push(format!("ok: {}", op)),
+                Err(GuardError::Rejected(err)) => {
+                    out.push(format!("rejected: {}: {} (:recover to continue)", op, err));
+                    for change in self.machine().kind_diagnostic(op, err).unwrap_or_default() {
+                        out.push(format!("  {}", change));
+                    }
+                    break;
+                }
+                Err(err) => {
+                    out.push(format!("error: {}", err));
+                    break;
+                }
+            }
+            self.ops.push(op);
+        }
+        out.join("\n")
+    }
+}
+
+// Run a session reading from [input] until it ends, printing a prompt to
+// [output] before every line.
+pub fn run<R: BufRead, W: Write>(config: MachineConfig, input: R, mut output: W) -> io::Result<()> {
+    let mut session = Session::new(config);
+    let mut lines = input.lines();
+
+    loop {
+        write!(output, "tbm> ")?;
+        output.flush()?;
+
+        let (out, done) = match lines.next() {
+            Some(line) => session.handle(&line?),
+            None => {
+                // End the prompt line when the input runs out.
+                writeln!(output)?;
+                (session.stop().unwrap_or_default(), true)
+            }
+        };
+        if !out.is_empty() {
+            writeln!(output, "{}", out)?;
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,268 @@
+// A report on the semantics given by one configuration of the machine: which
+// of the guarantees below hold along every short trace, and which litmus
+// tests (see litmus.rs) get the expected outcome. Reports for different
+// configurations can be put side by side to see what a rule toggle changes.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::json;
+use crate::litmus;
+use crate::machine2::{AccessKind, MachineConfig, Operation, RefKind, Reference, TokenMachine};
+use crate::miri::MiriVerdict;
+use crate::properties::{self, Counterexample};
+use crate::trace::{self, Verdict};
+
+type Check = fn(MachineConfig, usize, usize) -> Result<usize, Counterexample>;
+
+// Guarantees, each with a check that is run with the depth and maximum number
+// of references of the report.
+const GUARANTEES: &[(&str, Check)] = &[
+    ("token pieces are conserved", properties::check_conservation),
+    (
+        "rejected operations have no effect",
+        properties::check_rejections_recoverable,
+    ),
+    (
+        "at most one reference per tree can write",
+        at_most_one_writer,
+    ),
+    (
+        "unique references only write with the only piece",
+        unique_writes_exclusive,
+    ),
+];
+
+// The references that could write in [state].
+fn writers(state: &TokenMachine) -> Vec<Reference> {
+    state
+        .references()
+        .into_iter()
+        .filter(|&r| state.step(Operation::Use(r, AccessKind::Write)).is_ok())
+        .collect()
+}
+
+fn at_most_one_writer(
+    config: MachineConfig,
+    depth: usize,
+    max_refs: usize,
+) -> Result<usize, Counterexample> {
+    properties::check_property_with(config, depth, max_refs, |_, state| {
+        let writers = writers(state);
+        state.roots().into_iter().all(|root| {
+            writers
+                .iter()
+                .filter(|&&r| state.root_of(r) == root)
+                .count()
+                <= 1
+        })
+    })
+}
+
+fn unique_writes_exclusive(
+    config: MachineConfig,
+    depth: usize,
+    max_refs: usize,
+) -> Result<usize, Counterexample> {
+    properties::check_property_with(config, depth, max_refs, |_, state| {
+        writers(state)
+            .into_iter()
+            .filter(|&r| state.kind_of(r) == RefKind::Unique)
+            .all(|r| state.token_count(r) == 1)
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct GuaranteeResult {
+    pub name: &'static str,
+    // The number of traces checked, or a counterexample.
+    pub result: Result<usize, Counterexample>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LitmusOutcome {
+    pub name: String,
+    pub expected: MiriVerdict,
+    pub model: Verdict,
+}
+
+impl LitmusOutcome {
+    pub fn passes(&self) -> bool {
+        (self.model == Verdict::Accepted) == (self.expected == MiriVerdict::Ok)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SemanticsReport {
+    pub config: MachineConfig,
+    pub depth: usize,
+    pub max_refs: usize,
+    pub guarantees: Vec<GuaranteeResult>,
+    pub litmus: Vec<LitmusOutcome>,
+    // Litmus tests that could not be imported, and why.
+    pub unsupported: Vec<(String, String)>,
+}
+
+fn describe_counterexample((ops, msg): &Counterexample) -> String {
+    let ops: Vec<_> = ops.iter().map(|op| op.to_string()).collect();
+    format!("{} ({})", msg, ops.join("; "))
+}
+
+fn expected_name(expected: MiriVerdict) -> &'static str {
+    match expected {
+        MiriVerdict::Ok => "ok",
+        MiriVerdict::UndefinedBehavior => "ub",
+    }
+}
+
+impl SemanticsReport {
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{{").unwrap();
+        writeln!(
+            out,
+            "  \"config\": {},",
+            json::string(&trace::format_config_inline(&self.config))
+        )
+        .unwrap();
+        writeln!(out, "  \"depth\": {},", self.depth).unwrap();
+        writeln!(out, "  \"max_refs\": {},", self.max_refs).unwrap();
+
+        let guarantees: Vec<_> = self
+            .guarantees
+            .iter()
+            .map(|g| match &g.result {
+                Ok(traces) => format!(
+                    "    {{ \"name\": {}, \"holds\": true, \"traces\": {} }}",
+                    json::string(g.name),
+                    traces
+                ),
+                Err(counterexample) => format!(
+                    "    {{ \"name\": {}, \"holds\": false, \"counterexample\": {} }}",
+                    json::string(g.name),
+                    json::string(&describe_counterexample(counterexample))
+                ),
+            })
+            .collect();
+        writeln!(out, "  \"guarantees\": [\n{}\n  ],", guarantees.join(",\n")).unwrap();
+
+        let litmus: Vec<_> = self
+            .litmus
+            .iter()
+            .map(|test| {
+                format!(
+                    "    {{ \"name\": {}, \"expected\": \"{}\", \"model\": {}, \"passes\": {} }}",
+                    json::string(&test.name),
+                    expected_name(test.expected),
+                    json::string(&test.model.to_string()),
+                    test.passes()
+                )
+            })
+            .collect();
+        writeln!(out, "  \"litmus\": [\n{}\n  ],", litmus.join(",\n")).unwrap();
+
+        let unsupported: Vec<_> = self
+            .unsupported
+            .iter()
+            .map(|(path, reason)| {
+                format!(
+                    "    {{ \"path\": {}, \"reason\": {} }}",
+                    json::string(path),
+                    json::string(reason)
+                )
+            })
+            .collect();
+        writeln!(
+            out,
+            "  \"unsupported\": [\n{}\n  ]",
+            unsupported.join(",\n")
+        )
+        .unwrap();
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    pub fn render_table(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "configuration: {} (traces of up to {} operations, {} references)",
+            trace::format_config_inline(&self.config),
+            self.depth,
+            self.max_refs
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "\nguarantee                                          holds"
+        )
+        .unwrap();
+        for g in &self.guarantees {
+            let holds = match &g.result {
+                Ok(_) => "yes".to_string(),
+                Err(counterexample) => format!("no: {}", describe_counterexample(counterexample)),
+            };
+            writeln!(out, "{:<50} {}", g.name, holds).unwrap();
+        }
+
+        writeln!(out, "\nlitmus test                        expected  passes").unwrap();
+        for test in &self.litmus {
+            writeln!(
+                out,
+                "{:<34} {:<9} {}",
+                test.name,
+                expected_name(test.expected),
+                if test.passes() {
+                    "yes".to_string()
+                } else {
+                    format!("no (model: {})", test.model)
+                }
+            )
+            .unwrap();
+        }
+        let passed = self.litmus.iter().filter(|test| test.passes()).count();
+        writeln!(out, "{} of {} litmus tests pass", passed, self.litmus.len()).unwrap();
+        for (path, reason) in &self.unsupported {
+            writeln!(out, "unsupported: {}: {}", path, reason).unwrap();
+        }
+
+        out
+    }
+}
+
+// Check every guarantee along the traces of at most [depth] operations and
+// [max_refs] references, and run the litmus tests in [litmus_dir].
+pub fn generate(
+    config: MachineConfig,
+    depth: usize,
+    max_refs: usize,
+    litmus_dir: &Path,
+) -> Result<SemanticsReport, String> {
+    let guarantees = GUARANTEES
+        .iter()
+        .map(|&(name, check)| GuaranteeResult {
+            name,
+            result: check(config, depth, max_refs),
+        })
+        .collect();
+
+    let (tests, unsupported) = litmus::import_all(config, litmus_dir)?;
+    let litmus = tests
+        .into_iter()
+        .map(|test| LitmusOutcome {
+            model: trace::replay(config, &test.trace).0,
+            name: test.name,
+            expected: test.expected,
+        })
+        .collect();
+
+    Ok(SemanticsReport {
+        config,
+        depth,
+        max_refs,
+        guarantees,
+        litmus,
+        unsupported,
+    })
+}
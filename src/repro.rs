@@ -0,0 +1,150 @@
+// Reproducer bundles for fuzz failures. A bundle is a directory containing:
+//
+//     seed           the seed the failing trace was generated from
+//     options        the remaining fuzzing options the trace was generated with
+//     config         the machine configuration (see trace::format_config)
+//     trace.tbm      the failing trace
+//     minimized.tbm  the minimized failing trace
+//     verdict        what the fuzzer expected and what actually happened
+//     version        the version of the rules the bundle was made with
+
+use std::fs;
+use std::path::Path;
+
+use crate::fuzz::{self, FuzzFailure, FuzzOptions};
+use crate::rng::Rng;
+use crate::trace::{self, Trace};
+use crate::version;
+
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub seed: u64,
+    pub options: FuzzOptions,
+    pub trace: Trace,
+    pub minimized: Trace,
+    pub expected: String,
+    pub actual: String,
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+impl Bundle {
+    pub fn from_failure(options: FuzzOptions, failure: &FuzzFailure) -> Self {
+        Bundle {
+            seed: failure.seed,
+            options,
+            trace: failure.trace.clone(),
+            minimized: failure.minimized.clone(),
+            expected: failure.failure.expected.clone(),
+            actual: failure.failure.actual.clone(),
+        }
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<(), String> {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+        write_file(&dir.join("seed"), &format!("{}\n", self.seed))?;
+        write_file(
+            &dir.join("options"),
+            &format!(
+                "max_len = {}\nmax_refs = {}\nmutations = {}\n",
+                self.options.max_len, self.options.max_refs, self.options.mutations
+            ),
+        )?;
+        write_file(
+            &dir.join("config"),
+            &trace::format_config(&self.options.config),
+        )?;
+        self.trace.save(&dir.join("trace.tbm"))?;
+        self.minimized.save(&dir.join("minimized.tbm"))?;
+        write_file(
+            &dir.join("verdict"),
+            &format!("expected: {}\nactual: {}\n", self.expected, self.actual),
+        )?;
+        write_file(
+            &dir.join("version"),
+            &format!("{}\n", version::SEMANTICS_VERSION),
+        )
+    }
+
+    // Bundles made with an older version of the rules are read with their
+    // traces migrated, but the recorded verdict is left as it was.
+    pub fn read(dir: &Path) -> Result<Self, String> {
+        let version_path = dir.join("version");
+        if version_path.exists() {
+            version::parse_version(&read_file(&version_path)?)
+                .and_then(version::check_supported)
+                .map_err(|e| format!("{}: {}", version_path.display(), e))?;
+        }
+
+        let seed = read_file(&dir.join("seed"))?
+            .trim()
+            .parse()
+            .map_err(|e| format!("{}: {}", dir.join("seed").display(), e))?;
+        let config = trace::parse_config(&read_file(&dir.join("config"))?)
+            .map_err(|e| format!("{}: {}", dir.join("config").display(), e))?;
+
+        let options_text = read_file(&dir.join("options"))?;
+        let option = |name: &str| -> Result<usize, String> {
+            options_text
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(key, _)| key.trim() == name)
+                .and_then(|(_, value)| value.trim().parse().ok())
+                .ok_or_else(|| format!("{}: missing '{}'", dir.join("options").display(), name))
+        };
+        let options = FuzzOptions {
+            config,
+            max_len: option("max_len")?,
+            max_refs: option("max_refs")?,
+            // Bundles written before mutations existed don't mention them.
+            mutations: option("mutations").unwrap_or(0),
+        };
+        let trace = Trace::load(&dir.join("trace.tbm"))?;
+        let minimized = Trace::load(&dir.join("minimized.tbm"))?;
+
+        let verdict = read_file(&dir.join("verdict"))?;
+        let field = |name: &str| {
+            verdict
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(|value| value.trim().to_string())
+                .ok_or_else(|| format!("{}: missing '{}'", dir.join("verdict").display(), name))
+        };
+
+        Ok(Bundle {
+            seed,
+            options,
+            trace,
+            minimized,
+            expected: field("expected:")?,
+            actual: field("actual:")?,
+        })
+    }
+
+    // Whether generating a trace from the recorded seed and options still
+    // yields the recorded trace. If not, the generator has changed since the
+    // bundle was written and the seed is of no further use.
+    pub fn seed_regenerates_trace(&self) -> bool {
+        fuzz::quietly(|| fuzz::generate(&mut Rng::new(self.seed), &self.options)).ops
+            == self.trace.ops
+    }
+
+    // Replay the bundle and report the failure (if any) that happens for both
+    // the full and the minimized trace.
+    pub fn reproduce(&self) -> Vec<(&'static str, Option<fuzz::Failure>)> {
+        let config = self.options.config;
+        fuzz::quietly(|| {
+            vec![
+                ("trace.tbm", fuzz::check(config, &self.trace)),
+                ("minimized.tbm", fuzz::check(config, &self.minimized)),
+            ]
+        })
+    }
+}
@@ -0,0 +1,187 @@
+// A small Stacked Borrows model to compare the token machine against. It is
+// deliberately simplified: every tree is a single memory location with its
+// own borrow stack, there are no protectors, and a write pops every item
+// above the one granting it (real Stacked Borrows keeps adjacent
+// SharedReadWrite items). References get the same IDs as in the token
+// machine, so traces can be replayed on both.
+//
+// Operations that only move tokens around (borrow, return, dup, merge, perms,
+// reclaim) have no counterpart here and are always accepted.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::machine2::{AccessKind, Operation, RefKind, Reference};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Permission {
+    Unique,
+    SharedReadWrite,
+    SharedReadOnly,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Item {
+    pub tag: Reference,
+    pub perm: Permission,
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let perm = match self.perm {
+            Permission::Unique => "Unique",
+            Permission::SharedReadWrite => "SharedRW",
+            Permission::SharedReadOnly => "SharedRO",
+        };
+        write!(f, "{}({})", perm, self.tag)
+    }
+}
+
+// Undefined behavior, as detected by the model.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SbError {
+    UnknownTag(Reference),
+    // No item in the stack grants the access to the tag.
+    NoGrantingItem(Reference, AccessKind),
+}
+
+impl fmt::Display for SbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SbError::UnknownTag(tag) => write!(f, "Unknown tag {}", tag),
+            SbError::NoGrantingItem(tag, access) => write!(
+                f,
+                "No item granting {:?} access to tag {} in the borrow stack",
+                access, tag
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SbError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StackedBorrows {
+    next_id: u32,
+    // Indexed by the root of each tree, with the bottom of the stack first.
+    stacks: HashMap<Reference, Vec<Item>>,
+    root_of: HashMap<Reference, Reference>,
+}
+
+fn grants(perm: Permission, access: AccessKind) -> bool {
+    match access {
+        AccessKind::Read => true,
+        AccessKind::Write => perm != Permission::SharedReadOnly,
+    }
+}
+
+impl StackedBorrows {
+    pub fn new() -> Self {
+        StackedBorrows::default()
+    }
+
+    fn fresh(&mut self) -> Reference {
+        let r = Reference::from_id(self.next_id);
+        self.next_id += 1;
+        r
+    }
+
+    fn new_allocation(&mut self, perm: Permission) -> Reference {
+        let root = self.fresh();
+        self.root_of.insert(root, root);
+        self.stacks.insert(root, vec![Item { tag: root, perm }]);
+        root
+    }
+
+    fn stack_of(&mut self, tag: Reference) -> Result<&mut Vec<Item>, SbError> {
+        let root = *self.root_of.get(&tag).ok_or(SbError::UnknownTag(tag))?;
+        Ok(self.stacks.get_mut(&root).unwrap())
+    }
+
+    // Perform an access through [tag]. Reads pop the Unique items above the
+    // granting item, writes pop all items above it.
+    pub fn access(&mut self, tag: Reference, access: AccessKind) -> Result<(), SbError> {
+        let stack = self.stack_of(tag)?;
+        let granting = stack
+            .iter()
+            .rposition(|item| item.tag == tag && grants(item.perm, access))
+            .ok_or(SbError::NoGrantingItem(tag, access))?;
+
+        match access {
+            AccessKind::Read => {
+                let mut i = granting + 1;
+                while i < stack.len() {
+                    if stack[i].perm == Permission::Unique {
+                        stack.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            AccessKind::Write => stack.truncate(granting + 1),
+        }
+        Ok(())
+    }
+
+    // Derive a new reference from [parent]. Creating a shared read-only
+    // reference counts as a read through the parent, the other kinds as a
+    // write.
+    pub fn retag(&mut self, parent: Reference, kind: RefKind) -> Result<Reference, SbError> {
+        let (access, perm) = match kind {
+            RefKind::SharedReadOnly => (AccessKind::Read, Permission::SharedReadOnly),
+            RefKind::SharedReadWrite => (AccessKind::Write, Permission::SharedReadWrite),
+            RefKind::Unique => (AccessKind::Write, Permission::Unique),
+        };
+        self.access(parent, access)?;
+
+        let tag = self.fresh();
+        self.stack_of(parent)?.push(Item { tag, perm });
+        let root = self.root_of[&parent];
+        self.root_of.insert(tag, root);
+        Ok(tag)
+    }
+
+    pub fn apply(&mut self, op: Operation) -> Result<Option<Reference>, SbError> {
+        match op {
+            Operation::NewRoot => Ok(Some(self.new_allocation(Permission::Unique))),
+            Operation::NewConstRoot => Ok(Some(self.new_allocation(Permission::SharedReadOnly))),
+            Operation::CreateRef(parent, kind) => self.retag(parent, kind).map(Some),
+            Operation::Use(r, access) => self.access(r, access).map(|()| None),
+            Operation::Borrow(_)
+            | Operation::Return(_)
+            | Operation::Dup(_)
+            | Operation::Merge(_)
+            | Operation::SetPerms(..)
+            | Operation::ReclaimExclusive(_)
+            | Operation::Call(_)
+            | Operation::Arg(_)
+            | Operation::Ret => Ok(None),
+        }
+    }
+
+    // The borrow stack of the tree [tag] belongs to, bottom first.
+    pub fn stack(&self, tag: Reference) -> Option<&[Item]> {
+        let root = self.root_of.get(&tag)?;
+        self.stacks.get(root).map(Vec::as_slice)
+    }
+
+    // The roots of all allocations, in order of creation.
+    pub fn roots(&self) -> Vec<Reference> {
+        let mut roots: Vec<_> = self.stacks.keys().copied().collect();
+        roots.sort();
+        roots
+    }
+}
+
+impl fmt::Display for StackedBorrows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, root) in self.roots().into_iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            let items: Vec<_> = self.stacks[&root].iter().map(Item::to_string).collect();
+            write!(f, "[{}]", items.join(" "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+// Exploration strategies, properties and trace post-processors written as
+// Rhai scripts and loaded at runtime, so that trying a variation doesn't take
+// recompiling the crate. The engine is only built in with the "scripting"
+// feature; without it, loading a script fails with an error saying so.
+//
+// A script defines any of these functions:
+//
+//     fn property(ops, state)   checked after every short trace, like the
+//                               closures passed to check_property; returns
+//                               whether the state is fine
+//     fn priority(op, state)    steers a walk: of the operations the machine
+//                               would accept, the one with the highest
+//                               priority is taken (ties are broken at
+//                               random), and negative priorities are never
+//                               taken
+//     fn process(ops)           rewrites a trace, returning the new operations
+//
+// Operations are passed as strings in trace syntax (e.g. "borrow r1") and the
+// state as a map, see state_fields.
+
+use crate::budget::{Budget, SearchReport};
+use crate::explore;
+use crate::machine2::{MachineConfig, Operation, Reference, TokenMachine};
+use crate::properties::{self, Counterexample};
+use crate::rng::Rng;
+use crate::trace::{self, Trace};
+
+// A value of the state passed to scripts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+    Map(Vec<(&'static str, Value)>),
+}
+
+// The state of [machine] as scripts see it: "time", the number of "frames",
+// the ids of the "roots", and for every reference in "refs" its "id",
+// "parent", "root", "kind", "state", the number of "pieces" it holds and its
+// outstanding "splits".
+pub fn state_fields(machine: &TokenMachine) -> Value {
+    let id = |r: Reference| Value::Int(r.id() as i64);
+    let refs = machine
+        .references()
+        .into_iter()
+        .map(|r| {
+            let pieces = machine.get_token_info(r).map_or(0, |info| info.pieces_held);
+            Value::Map(vec![
+                ("id", id(r)),
+                ("parent", id(machine.parent_of(r))),
+                ("root", id(machine.root_of(r))),
+                (
+                    "kind",
+                    Value::Str(trace::kind_name(machine.kind_of(r)).to_string()),
+                ),
+                (
+                    "state",
+                    Value::Str(format!("{:?}", machine.state_of(r)).to_lowercase()),
+                ),
+                ("pieces", Value::Int(pieces as i64)),
+                ("splits", Value::Int(machine.outstanding_splits(r) as i64)),
+            ])
+        })
+        .collect();
+
+    Value::Map(vec![
+        ("time", Value::Int(machine.time() as i64)),
+        ("frames", Value::Int(machine.frames().len() as i64)),
+        (
+            "roots",
+            Value::List(machine.roots().into_iter().map(id).collect()),
+        ),
+        ("refs", Value::List(refs)),
+    ])
+}
+
+fn op_strings(ops: &[Operation]) -> Vec<String> {
+    ops.iter().map(|op| op.to_string()).collect()
+}
+
+#[cfg(feature = "scripting")]
+mod enabled {
+    use std::fs;
+    use std::path::Path;
+
+    use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+    use super::Value;
+
+    pub struct Script {
+        engine: Engine,
+        ast: AST,
+    }
+
+    fn to_dynamic(value: &Value) -> Dynamic {
+        match value {
+            Value::Int(i) => Dynamic::from(*i),
+            Value::Str(s) => Dynamic::from(s.clone()),
+            Value::List(values) => Dynamic::from(values.iter().map(to_dynamic).collect::<Array>()),
+            Value::Map(fields) => {
+                let mut map = Map::new();
+                for (key, value) in fields {
+                    map.insert((*key).into(), to_dynamic(value));
+                }
+                Dynamic::from(map)
+            }
+        }
+    }
+
+    fn strings(values: &[String]) -> Dynamic {
+        Dynamic::from(
+            values
+                .iter()
+                .map(|s| Dynamic::from(s.clone()))
+                .collect::<Array>(),
+        )
+    }
+
+    impl Script {
+        pub fn load(path: &Path) -> Result<Script, String> {
+            let text =
+                fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let engine = Engine::new();
+            let ast = engine
+                .compile(&text)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(Script { engine, ast })
+        }
+
+        pub fn defines(&self, name: &str) -> bool {
+            self.ast.iter_functions().any(|f| f.name == name)
+        }
+
+        fn call(&self, name: &str, args: Vec<Dynamic>) -> Result<Dynamic, String> {
+            let mut scope = Scope::new();
+            self.engine
+                .call_fn(&mut scope, &self.ast, name, args)
+                .map_err(|e| format!("{}: {}", name, e))
+        }
+
+        pub fn property(&self, ops: &[String], state: &Value) -> Result<bool, String> {
+            self.call("property", vec![strings(ops), to_dynamic(state)])?
+                .as_bool()
+                .map_err(|ty| format!("property returned a {} instead of a bool", ty))
+        }
+
+        pub fn priority(&self, op: &str, state: &Value) -> Result<i64, String> {
+            let op = Dynamic::from(op.to_string());
+            self.call("priority", vec![op, to_dynamic(state)])?
+                .as_int()
+                .map_err(|ty| format!("priority returned a {} instead of an integer", ty))
+        }
+
+        pub fn process(&self, ops: &[String]) -> Result<Vec<String>, String> {
+            self.call("process", vec![strings(ops)])?
+                .into_array()
+                .map_err(|ty| format!("process returned a {} instead of an array", ty))?
+                .into_iter()
+                .map(|op| {
+                    op.into_string()
+                        .map_err(|ty| format!("process returned a {} instead of an operation", ty))
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(feature = "scripting")]
+pub use enabled::Script;
+
+#[cfg(not(feature = "scripting"))]
+pub enum Script {}
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    pub fn load(path: &std::path::Path) -> Result<Script, String> {
+        Err(format!(
+            "{}: tbm was built without scripting; rebuild with --features scripting",
+            path.display()
+        ))
+    }
+
+    pub fn defines(&self, _name: &str) -> bool {
+        match *self {}
+    }
+
+    pub fn property(&self, _ops: &[String], _state: &Value) -> Result<bool, String> {
+        match *self {}
+    }
+
+    pub fn priority(&self, _op: &str, _state: &Value) -> Result<i64, String> {
+        match *self {}
+    }
+
+    pub fn process(&self, _ops: &[String]) -> Result<Vec<String>, String> {
+        match *self {}
+    }
+}
+
+// Check the script's property after every trace [budget] allows. The outer
+// error is the script failing; the inner one a trace violating the property.
+pub fn check_property(
+    script: &Script,
+    config: MachineConfig,
+    budget: Budget,
+    max_refs: usize,
+) -> Result<Result<SearchReport, Counterexample>, String> {
+    let mut error = None;
+    let result = properties::check_property_within(config, budget, max_refs, |ops, state| {
+        if error.is_some() {
+            return true;
+        }
+        script
+            .property(&op_strings(ops), &state_fields(state))
+            .unwrap_or_else(|e| {
+                error = Some(e);
+                true
+            })
+    });
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(result),
+    }
+}
+
+// Walk from a single root for at most [steps] operations, taking the accepted
+// candidate the script gives the highest priority. The walk ends early if
+// every candidate is rejected or has a negative priority.
+pub fn guided_walk(
+    script: &Script,
+    config: MachineConfig,
+    steps: usize,
+    max_refs: usize,
+    seed: u64,
+) -> Result<Trace, String> {
+    let mut rng = Rng::new(seed);
+    let (_, mut machine) = TokenMachine::init_with(config);
+    let mut ops = vec![Operation::NewRoot];
+
+    for _ in 0..steps {
+        let state = state_fields(&machine);
+        let mut best: Vec<Operation> = Vec::new();
+        let mut best_priority = 0;
+
+        for op in explore::candidate_operations(&machine, max_refs) {
+            if machine.step(op).is_err() {
+                continue;
+            }
+            let priority = script.priority(&op.to_string(), &state)?;
+            if priority < 0 || (!best.is_empty() && priority < best_priority) {
+                continue;
+            }
+            if best.is_empty() || priority > best_priority {
+                best.clear();
+                best_priority = priority;
+            }
+            best.push(op);
+        }
+
+        if best.is_empty() {
+            break;
+        }
+        let op = best[rng.below(best.len())];
+        machine.apply(op).map_err(|e| e.to_string())?;
+        ops.push(op);
+    }
+
+    Ok(Trace::new(ops))
+}
+
+// [trace] with its operations replaced by what the script's process function
+// makes of them. The headers are kept.
+pub fn post_process(script: &Script, trace: &Trace) -> Result<Trace, String> {
+    let ops = script
+        .process(&op_strings(&trace.ops))?
+        .iter()
+        .map(|op| trace::parse_operation(op))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut processed = trace.clone();
+    processed.ops = ops;
+    Ok(processed)
+}
@@ -0,0 +1,518 @@
+// The machine as a service, for frontends and notebooks that would rather
+// not link against the crate. tbm serve listens for HTTP requests with JSON
+// bodies and keeps its machines in a SessionManager:
+//
+//     POST /init      {"config": "dup_rule=...", "name": "a"}  -> {"session": "a"}
+//     POST /fork      {"session": "a", "config": "..."}        -> {"session": "s1"}
+//     POST /apply     {"session": "a", "op": "root"}           -> {"outcome": "ok", "created": "r0"}
+//                     {"session": "a", "ops": [...]}           -> {"outcomes": [...]}
+//     POST /query     {"session": "a"}                         -> {"time": 1, "frames": 0, "trees": [...]}
+//     POST /snapshot  {"session": "a"}                         -> {"trace": "#! version 2\n..."}
+//     POST /restore   {"trace": "..."}                         -> {"session": "s2"}
+//     POST /list      {}                                       -> {"sessions": [...]}
+//     POST /close     {"session": "a"}                         -> {}
+//
+// Names are optional wherever a session is created; without one, the session
+// gets a fresh name. A fork without a configuration is an exact copy, and one
+// with a configuration replays the operations of the session under it.
+//
+// Rejected operations have no effect, as for TokenMachine::apply, and are
+// answered with "outcome": "rejected", the name of the error and its message;
+// the rest of a list of operations is still applied. A list with an operation
+// that doesn't parse is refused as a whole. The trees are those of
+// json::trees. A snapshot is the accepted operations as a trace, which is how
+// states are saved everywhere else, so it can be replayed by tbm itself and
+// is migrated like any saved trace when it is restored.
+//
+// A server keeps at most Limits::max_sessions sessions, and a session records
+// at most Limits::max_ops operations. Requests that would open another session
+// are answered with 429, and those that would record more operations (or
+// restore a longer trace) with 413; neither has any effect.
+//
+// Bodies are parsed with serde_json, which also bounds how deeply they can
+// nest. Requests are handled one at a time and every connection is closed after
+// its response, which is all a handful of clients needs. The server sends no
+// CORS headers and refuses requests that carry an Origin header, which
+// browsers add to the requests of web pages, so that a page the user happens
+// to visit cannot drive it.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::json;
+use crate::machine2::{MachineConfig, Operation};
+use crate::session::{Session, SessionError, SessionManager};
+use crate::trace::{self, Trace};
+use crate::version;
+
+// How much a server holds on to, so that its clients cannot make it use
+// unbounded memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    // The most sessions open at once.
+    pub max_sessions: usize,
+    // The most operations a session can record.
+    pub max_ops: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_sessions: 256,
+            max_ops: 100_000,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Server {
+    sessions: SessionManager,
+    limits: Limits,
+}
+
+// An HTTP status and a JSON body.
+pub type Response = (u16, String);
+
+fn error(status: u16, message: &str) -> Response {
+    (status, format!("{{\"error\":{}}}", json::string(message)))
+}
+
+fn session_error(err: SessionError) -> Response {
+    match err {
+        SessionError::Unknown(_) => error(404, &err.to_string()),
+        _ => error(400, &err.to_string()),
+    }
+}
+
+fn parse_op(op: &Value) -> Result<Operation, String> {
+    let text = op.as_str().ok_or("operations have to be strings")?;
+    trace::parse_operation(text)
+}
+
+fn apply_one(session: &mut Session, op: Operation) -> String {
+    match session.apply(op) {
+        Ok(created) => {
+            let created = created.map_or("null".to_string(), |r| json::string(&r.to_string()));
+            format!("{{\"outcome\":\"ok\",\"created\":{}}}", created)
+        }
+        Err(err) => format!(
+            "{{\"outcome\":\"rejected\",\"error\":{},\"message\":{}}}",
+            json::string(err.name()),
+            json::string(&err.to_string())
+        ),
+    }
+}
+
+// The configuration a request asks for, if it asks for one.
+fn requested_config(body: &Value) -> Result<Option<MachineConfig>, Response> {
+    body.get("config")
+        .and_then(Value::as_str)
+        .map(|text| trace::parse_config_inline(text).map_err(|msg| error(400, &msg)))
+        .transpose()
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Server {
+            limits,
+            ..Server::default()
+        }
+    }
+
+    // Refuse to open another session if there are as many as allowed.
+    fn check_sessions(&self) -> Result<(), Response> {
+        let max = self.limits.max_sessions;
+        if self.sessions.list().count() >= max {
+            return Err(error(
+                429,
+                &format!("at most {} sessions can be open at once", max),
+            ));
+        }
+        Ok(())
+    }
+
+    // Refuse to let a session record more than [count] operations if that is
+    // more than allowed.
+    fn check_ops(&self, count: usize) -> Result<(), Response> {
+        let max = self.limits.max_ops;
+        if count > max {
+            return Err(error(
+                413,
+                &format!("sessions can record at most {} operations", max),
+            ));
+        }
+        Ok(())
+    }
+
+    // The name requested for a new session, or a fresh one.
+    fn new_name(&self, body: &Value) -> String {
+        match body.get("name").and_then(Value::as_str) {
+            Some(name) => name.to_string(),
+            None => self.sessions.fresh_name("s"),
+        }
+    }
+
+    fn opened(name: &str) -> Response {
+        (200, format!("{{\"session\":{}}}", json::string(name)))
+    }
+
+    fn name(body: &Value) -> Result<&str, Response> {
+        body.get("session")
+            .and_then(Value::as_str)
+            .ok_or_else(|| error(400, "missing session"))
+    }
+
+    fn session(&mut self, body: &Value) -> Result<&mut Session, Response> {
+        let name = Server::name(body)?;
+        self.sessions.get_mut(name).map_err(session_error)
+    }
+
+    // Answer a request for [path] with the JSON [body].
+    pub fn handle(&mut self, method: &str, path: &str, body: &str) -> Response {
+        if method != "POST" {
+            return error(405, "every endpoint expects POST");
+        }
+        let body = if body.trim().is_empty() {
+            Value::Object(serde_json::Map::new())
+        } else {
+            match serde_json::from_str(body) {
+                Ok(body) => body,
+                Err(msg) => return error(400, &format!("invalid JSON: {}", msg)),
+            }
+        };
+
+        match self.route(path, &body) {
+            Ok(response) | Err(response) => response,
+        }
+    }
+
+    fn route(&mut self, path: &str, body: &Value) -> Result<Response, Response> {
+        let bad_request = |msg: String| error(400, &msg);
+
+        match path {
+            "/init" => {
+                self.check_sessions()?;
+                let config = requested_config(body)?.unwrap_or_default();
+                let name = self.new_name(body);
+                self.sessions.create(&name, config).map_err(session_error)?;
+                Ok(Server::opened(&name))
+            }
+            "/fork" => {
+                self.check_sessions()?;
+                let from = Server::name(body)?;
+                let config = requested_config(body)?;
+                let name = self.new_name(body);
+                self.sessions
+                    .fork(from, &name, config)
+                    .map_err(session_error)?;
+                Ok(Server::opened(&name))
+            }
+            "/apply" => {
+                // Every operation is parsed before any is applied, so that a
+                // request with a bad one, or too many, has no effect.
+                let recorded = self.session(body)?.ops().len();
+                if let Some(op) = body.get("op") {
+                    let op = parse_op(op).map_err(bad_request)?;
+                    self.check_ops(recorded + 1)?;
+                    return Ok((200, apply_one(self.session(body)?, op)));
+                }
+                let ops = body
+                    .get("ops")
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| bad_request("expected op or ops".to_string()))?
+                    .iter()
+                    .map(parse_op)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(bad_request)?;
+                self.check_ops(recorded + ops.len())?;
+                let session = self.session(body)?;
+                let outcomes: Vec<_> = ops.into_iter().map(|op| apply_one(session, op)).collect();
+                Ok((200, format!("{{\"outcomes\":[{}]}}", outcomes.join(","))))
+            }
+            "/query" => {
+                let machine = self.session(body)?.machine();
+                let mut out = String::new();
+                write!(
+                    out,
+                    "{{\"time\":{},\"frames\":{},\"trees\":{}}}",
+                    machine.time(),
+                    machine.frames().len(),
+                    json::trees(machine)
+                )
+                .unwrap();
+                Ok((200, out))
+            }
+            "/snapshot" => {
+                let mut trace = self.session(body)?.trace();
+                version::stamp(&mut trace);
+                Ok((
+                    200,
+                    format!("{{\"trace\":{}}}", json::string(&trace.to_string())),
+                ))
+            }
+            "/restore" => {
+                self.check_sessions()?;
+                let text = body
+                    .get("trace")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| bad_request("missing trace".to_string()))?;
+                let mut trace = Trace::parse(text).map_err(|e| bad_request(e.to_string()))?;
+                version::migrate(&mut trace).map_err(bad_request)?;
+                self.check_ops(trace.ops.len())?;
+                let config = trace.config().map_err(bad_request)?.unwrap_or_default();
+                let session = Session::replay(config, &trace.ops).map_err(session_error)?;
+                let name = self.new_name(body);
+                self.sessions
+                    .insert(&name, session)
+                    .map_err(session_error)?;
+                Ok(Server::opened(&name))
+            }
+            "/list" => {
+                let sessions: Vec<_> = self
+                    .sessions
+                    .list()
+                    .map(|(name, session)| {
+                        format!(
+                            "{{\"name\":{},\"config\":{},\"operations\":{}}}",
+                            json::string(name),
+                            json::string(&trace::format_config_inline(session.config())),
+                            session.ops().len()
+                        )
+                    })
+                    .collect();
+                Ok((200, format!("{{\"sessions\":[{}]}}", sessions.join(","))))
+            }
+            "/close" => {
+                let name = Server::name(body)?;
+                self.sessions.destroy(name).map_err(session_error)?;
+                Ok((200, "{}".to_string()))
+            }
+            _ => Err(error(404, &format!("no endpoint {}", path))),
+        }
+    }
+}
+
+// The largest request body, and the most bytes of a request line and its
+// headers, that are read.
+const MAX_BODY: usize = 1 << 20;
+const MAX_HEAD: u64 = 64 << 10;
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        403 => "Forbidden",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        _ => "Error",
+    }
+}
+
+// Read one request from [stream] and write the response.
+fn serve_connection(server: &mut Server, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD + MAX_BODY as u64));
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut words = request_line.split_whitespace();
+    let (method, path) = (
+        words.next().unwrap_or("").to_string(),
+        words.next().unwrap_or("").to_string(),
+    );
+
+    let (mut length, mut cross_origin) = (0, false);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+            cross_origin |= name.trim().eq_ignore_ascii_case("origin");
+        }
+    }
+    let (status, body) = if cross_origin {
+        error(403, "requests from web pages are not accepted")
+    } else if length > MAX_BODY {
+        error(
+            413,
+            &format!("request bodies can be at most {} bytes", MAX_BODY),
+        )
+    } else {
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        server.handle(&method, &path, &String::from_utf8_lossy(&body))
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// Serve requests on [address], within [limits], until the process is
+// stopped.
+pub fn serve(address: &str, limits: Limits) -> Result<(), String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    let mut server = Server::with_limits(limits);
+
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| serve_connection(&mut server, stream));
+        if let Err(e) = result {
+            eprintln!("connection failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send [request] to a server on a fresh port and return the response.
+    fn roundtrip(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(&mut Server::new(), stream).unwrap();
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handle.join().unwrap();
+        response
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused() {
+        let response = roundtrip("POST /init HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+    }
+
+    #[test]
+    fn requests_from_web_pages_are_refused() {
+        let response = roundtrip(
+            "POST /init HTTP/1.1\r\nOrigin: http://example.com\r\nContent-Length: 2\r\n\r\n{}",
+        );
+        assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+        assert!(!response.contains("Access-Control"), "{}", response);
+    }
+
+    #[test]
+    fn bodies_are_read_up_to_their_length() {
+        let body = r#"{"name": "a"}"#;
+        let response = roundtrip(&format!(
+            "POST /init HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.ends_with(r#"{"session":"a"}"#), "{}", response);
+    }
+
+    #[test]
+    fn a_bad_operation_rejects_the_whole_request() {
+        let mut server = Server::new();
+        server.handle("POST", "/init", r#"{"name": "a"}"#);
+        let (status, _) = server.handle(
+            "POST",
+            "/apply",
+            r#"{"session": "a", "ops": ["root", "create r0 unique", "frobnicate"]}"#,
+        );
+        assert_eq!(status, 400);
+        let (_, list) = server.handle("POST", "/list", "");
+        assert!(list.contains(r#""operations":0"#), "{}", list);
+
+        let (status, response) = server.handle(
+            "POST",
+            "/apply",
+            r#"{"session": "a", "ops": ["root", "create r0 unique"]}"#,
+        );
+        assert_eq!(status, 200);
+        assert!(response.contains(r#""created":"r1""#), "{}", response);
+    }
+
+    #[test]
+    fn sessions_and_their_operations_are_limited() {
+        let mut server = Server::with_limits(Limits {
+            max_sessions: 2,
+            max_ops: 3,
+        });
+        server.handle("POST", "/init", r#"{"name": "a"}"#);
+        server.handle("POST", "/fork", r#"{"session": "a", "name": "b"}"#);
+        let (status, _) = server.handle("POST", "/init", "");
+        assert_eq!(status, 429);
+        let (status, _) = server.handle("POST", "/restore", r#"{"trace": "r0 = root\n"}"#);
+        assert_eq!(status, 429);
+        server.handle("POST", "/close", r#"{"session": "b"}"#);
+
+        let (status, _) = server.handle(
+            "POST",
+            "/apply",
+            r#"{"session": "a", "ops": ["root", "root", "root", "root"]}"#,
+        );
+        assert_eq!(status, 413);
+        let (status, _) = server.handle(
+            "POST",
+            "/apply",
+            r#"{"session": "a", "ops": ["root", "root", "root"]}"#,
+        );
+        assert_eq!(status, 200);
+        let (status, response) =
+            server.handle("POST", "/apply", r#"{"session": "a", "op": "root"}"#);
+        assert_eq!(status, 413);
+        assert!(response.contains("at most 3 operations"), "{}", response);
+
+        let (status, _) = server.handle(
+            "POST",
+            "/restore",
+            r#"{"trace": "r0 = root\nr1 = root\nr2 = root\nr3 = root\n"}"#,
+        );
+        assert_eq!(status, 413);
+        let (_, list) = server.handle("POST", "/list", "");
+        assert!(list.contains(r#""operations":3"#), "{}", list);
+        assert!(!list.contains(r#""name":"s1""#), "{}", list);
+    }
+
+    #[test]
+    fn deeply_nested_bodies_are_rejected() {
+        let mut server = Server::new();
+        let body = "[".repeat(200_000);
+        let (status, response) = server.handle("POST", "/init", &body);
+        assert_eq!(status, 400);
+        assert!(response.contains("invalid JSON"), "{}", response);
+    }
+
+    #[test]
+    fn escapes_with_surrogate_pairs_are_decoded() {
+        let mut server = Server::new();
+        let (status, response) = server.handle("POST", "/init", r#"{"name": "\ud83d\ude00"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(
+            response,
+            format!("{{\"session\":{}}}", json::string("\u{1f600}"))
+        );
+    }
+}
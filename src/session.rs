@@ -0,0 +1,232 @@
+// Many named machines at once. A session is a machine together with the
+// configuration it runs under and the operations it accepted, so that it can
+// be forked: either as is, or under another configuration by replaying its
+// operations, which is how "the same trace under two configurations" is set
+// up. The REPL, the HTTP server and tbm compare keep their machines in a
+// SessionManager rather than each keeping their own.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::events::Outcome;
+use crate::machine2::{MachineConfig, MachineError, Operation, TokenMachine};
+use crate::trace::Trace;
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    config: MachineConfig,
+    machine: TokenMachine,
+    // The accepted operations, which lead to [machine] from an empty one.
+    ops: Vec<Operation>,
+}
+
+impl Session {
+    pub fn new(config: MachineConfig) -> Self {
+        Session {
+            config,
+            machine: TokenMachine::init_empty_with(config),
+            ops: Vec::new(),
+        }
+    }
+
+    // Perform [ops] on an empty machine under [config]. Fails with the index
+    // of the first operation that is rejected.
+    pub fn replay(config: MachineConfig, ops: &[Operation]) -> Result<Self, SessionError> {
+        let mut session = Session::new(config);
+        for (step, &op) in ops.iter().enumerate() {
+            session
+                .apply(op)
+                .map_err(|error| SessionError::Replay { step, op, error })?;
+        }
+        Ok(session)
+    }
+
+    pub fn config(&self) -> &MachineConfig {
+        &self.config
+    }
+
+    pub fn machine(&self) -> &TokenMachine {
+        &self.machine
+    }
+
+    pub fn ops(&self) -> &[Operation] {
+        &self.ops
+    }
+
+    // Apply [op], remembering it if it is accepted. A rejected operation has
+    // no effect, as for TokenMachine::apply.
+    pub fn apply(&mut self, op: Operation) -> Outcome {
+        let outcome = self.machine.apply(op);
+        if outcome.is_ok() {
+            self.ops.push(op);
+        }
+        outcome
+    }
+
+    // A copy of this session, under [config] if given. A different
+    // configuration may reject some of the operations that led here.
+    pub fn fork(&self, config: Option<MachineConfig>) -> Result<Session, SessionError> {
+        match config {
+            Some(config) if config != self.config => Session::replay(config, &self.ops),
+            _ => Ok(self.clone()),
+        }
+    }
+
+    // The accepted operations as a trace with the configuration as header.
+    pub fn trace(&self) -> Trace {
+        let mut trace = Trace::new(self.ops.clone());
+        trace.set_config(&self.config);
+        trace
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    Exists(String),
+    Unknown(String),
+    // Forking under another configuration rejected operation [step].
+    Replay {
+        step: usize,
+        op: Operation,
+        error: MachineError,
+    },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Exists(name) => write!(f, "there already is a session '{}'", name),
+            SessionError::Unknown(name) => write!(f, "there is no session '{}'", name),
+            SessionError::Replay { step, op, error } => write!(
+                f,
+                "the new configuration rejects step {} ('{}'): {}",
+                step, op, error
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionManager {
+    sessions: BTreeMap<String, Session>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        SessionManager::default()
+    }
+
+    // Add [session] under [name], which must not be taken yet.
+    pub fn insert(&mut self, name: &str, session: Session) -> Result<&mut Session, SessionError> {
+        if self.sessions.contains_key(name) {
+            return Err(SessionError::Exists(name.to_string()));
+        }
+        Ok(self.sessions.entry(name.to_string()).or_insert(session))
+    }
+
+    // Start a session with an empty machine.
+    pub fn create(
+        &mut self,
+        name: &str,
+        config: MachineConfig,
+    ) -> Result<&mut Session, SessionError> {
+        self.insert(name, Session::new(config))
+    }
+
+    // Copy session [from] to [to], under [config] if given.
+    pub fn fork(
+        &mut self,
+        from: &str,
+        to: &str,
+        config: Option<MachineConfig>,
+    ) -> Result<&mut Session, SessionError> {
+        let forked = self.get(from)?.fork(config)?;
+        self.insert(to, forked)
+    }
+
+    // Remove a session, returning it.
+    pub fn destroy(&mut self, name: &str) -> Result<Session, SessionError> {
+        self.sessions
+            .remove(name)
+            .ok_or_else(|| SessionError::Unknown(name.to_string()))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Session, SessionError> {
+        self.sessions
+            .get(name)
+            .ok_or_else(|| SessionError::Unknown(name.to_string()))
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Result<&mut Session, SessionError> {
+        self.sessions
+            .get_mut(name)
+            .ok_or_else(|| SessionError::Unknown(name.to_string()))
+    }
+
+    // Every session, in order of name.
+    pub fn list(&self) -> impl Iterator<Item = (&str, &Session)> {
+        self.sessions
+            .iter()
+            .map(|(name, session)| (name.as_str(), session))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    // The first name of the form [prefix]N that is not taken.
+    pub fn fresh_name(&self, prefix: &str) -> String {
+        (1..)
+            .map(|n| format!("{}{}", prefix, n))
+            .find(|name| !self.sessions.contains_key(name))
+            .unwrap()
+    }
+
+    // Apply [op] to every session.
+    pub fn apply_all(&mut self, op: Operation) -> Vec<(String, Outcome)> {
+        self.sessions
+            .iter_mut()
+            .map(|(name, session)| (name.clone(), session.apply(op)))
+            .collect()
+    }
+}
+
+// Whether two outcomes are the same verdict: both accepted, or both rejected
+// with the same kind of error.
+fn agree(a: &Outcome, b: &Outcome) -> bool {
+    match (a, b) {
+        (Ok(_), Ok(_)) => true,
+        (Err(a), Err(b)) => a.name() == b.name(),
+        _ => false,
+    }
+}
+
+// Apply [trace] to every session of [manager] in step, up to the first step
+// on which they don't all agree. Returns that step and every session's
+// outcome of it. If they all reject the same operation, they agree on the
+// whole trace, since replay stops there as well.
+pub fn first_disagreement(
+    manager: &mut SessionManager,
+    trace: &Trace,
+) -> Option<(usize, Vec<(String, Outcome)>)> {
+    for (step, &op) in trace.ops.iter().enumerate() {
+        let outcomes = manager.apply_all(op);
+        let first = &outcomes.first()?.1;
+        if !outcomes.iter().all(|(_, outcome)| agree(first, outcome)) {
+            return Some((step, outcomes));
+        }
+        if first.is_err() {
+            return None;
+        }
+    }
+    None
+}
+
+// How a session dealt with an operation, for printing.
+pub fn describe_outcome(outcome: &Outcome) -> String {
+    match outcome {
+        Ok(Some(r)) => format!("created {}", r),
+        Ok(None) => "accepted".to_string(),
+        Err(err) => format!("rejected ({}): {}", err.name(), err),
+    }
+}
@@ -0,0 +1,246 @@
+// Long random walks over the states of the machine. Exhaustive exploration
+// (explore.rs) only reaches short traces, and fuzzing (fuzz.rs) checks the
+// invariants after every step of traces of a few dozen operations, which is
+// too slow to go much further. A walk only takes operations the machine
+// accepts, for as many steps as the budget allows, and checks the invariants
+// every so often instead. Along the way it samples the shape of the state,
+// so that bookkeeping that keeps growing (e.g. pieces that are never merged,
+// or logs that are never trimmed) shows up as well as outright corruption.
+//
+// The number of references is bounded as usual. A walk that gets stuck
+// (every candidate is rejected) or reaches the maximum walk length starts over
+// from a fresh machine.
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::budget::{Budget, Meter, SearchReport};
+use crate::explore;
+use crate::fuzz;
+use crate::machine2::{MachineConfig, Operation, RefState, TokenMachine};
+use crate::rng::Rng;
+use crate::trace::Trace;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SimulateOptions {
+    pub config: MachineConfig,
+    // The total number of steps is the state budget; the depth is the
+    // maximum length of a single walk.
+    pub budget: Budget,
+    pub max_refs: usize,
+    // Check the invariants and sample the state every this many steps.
+    pub sample_every: u64,
+}
+
+// The size of the parts of a state that could grow without bound.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Shape {
+    pub refs: usize,
+    pub live_refs: usize,
+    pub pieces: usize,
+    pub perm_changes: usize,
+    pub frames: usize,
+}
+
+impl Shape {
+    pub fn of(machine: &TokenMachine) -> Self {
+        let refs = machine.references();
+        Shape {
+            live_refs: refs
+                .iter()
+                .filter(|&&r| machine.state_of(r) != RefState::Dead)
+                .count(),
+            refs: refs.len(),
+            pieces: machine.pieces().len(),
+            perm_changes: machine.perm_changes().len(),
+            frames: machine.frames().len(),
+        }
+    }
+
+    // The names and values of every measurement, in a fixed order.
+    pub fn fields(&self) -> [(&'static str, usize); 5] {
+        [
+            ("refs", self.refs),
+            ("live refs", self.live_refs),
+            ("pieces", self.pieces),
+            ("perm changes", self.perm_changes),
+            ("frames", self.frames),
+        ]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sample {
+    // The total number of steps taken when the sample was taken.
+    pub step: u64,
+    // How many steps the current walk had taken.
+    pub walk_step: usize,
+    pub shape: Shape,
+}
+
+// The machine panicking or breaking its invariants during a walk.
+#[derive(Debug, Clone)]
+pub struct WalkFailure {
+    // The walk so far, which ends in the failure. The failure was only found
+    // at the next sample, so it may have happened well before the end.
+    pub trace: Trace,
+    pub message: String,
+    // The walk minimized while still failing fuzz::check, if it does.
+    pub minimized: Option<Trace>,
+}
+
+impl fmt::Display for WalkFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "after {} operations: {}",
+            self.trace.ops.len(),
+            self.message
+        )?;
+        if let Some(minimized) = &self.minimized {
+            write!(f, " (minimized to {} operations)", minimized.ops.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub steps: u64,
+    pub walks: u64,
+    // Walks that started over because every candidate was rejected.
+    pub stuck: u64,
+    pub samples: Vec<Sample>,
+    pub failure: Option<WalkFailure>,
+    pub report: SearchReport,
+}
+
+fn failure(config: MachineConfig, ops: &[Operation], message: String) -> WalkFailure {
+    let trace = Trace::new(ops.to_vec());
+    let minimized =
+        fuzz::check(config, &trace).map(|found| fuzz::minimize_failure(config, &trace, &found));
+
+    WalkFailure {
+        trace,
+        message,
+        minimized,
+    }
+}
+
+// A fresh machine, and the walk that leads to it.
+fn start(config: MachineConfig) -> (TokenMachine, Vec<Operation>) {
+    let mut machine = TokenMachine::init_empty_with(config);
+    machine.apply(Operation::NewRoot).unwrap();
+    (machine, vec![Operation::NewRoot])
+}
+
+// Walk until the budget runs out or the machine fails, starting from [seed].
+pub fn simulate(options: &SimulateOptions, seed: u64) -> Simulation {
+    let config = options.config;
+    let mut rng = Rng::new(seed);
+    let mut meter = Meter::start(options.budget);
+    let mut samples = Vec::new();
+    let (mut steps, mut walks, mut stuck) = (0, 1, 0);
+    let (mut machine, mut ops) = start(config);
+
+    let failure = fuzz::quietly(|| {
+        while meter.visit() {
+            let candidates = explore::candidate_operations(&machine, options.max_refs);
+            let mut accepted = false;
+
+            // Most candidates are rejected, so try a few at random before
+            // going through all of them in a random order, which only leaves
+            // the walk stuck if every one of them is rejected.
+            let mut order: Vec<_> = (0..32).map(|_| rng.below(candidates.len())).collect();
+            let start_at = rng.below(candidates.len());
+            order.extend((0..candidates.len()).map(|i| (start_at + i) % candidates.len()));
+
+            for i in order {
+                let op = candidates[i];
+                match panic::catch_unwind(AssertUnwindSafe(|| machine.apply(op))) {
+                    Ok(Ok(_)) => {
+                        ops.push(op);
+                        accepted = true;
+                        break;
+                    }
+                    Ok(Err(_)) => {}
+                    Err(payload) => {
+                        ops.push(op);
+                        let msg = format!("panicked: {}", fuzz::panic_message(&*payload));
+                        return Some(failure(config, &ops, msg));
+                    }
+                }
+            }
+            steps += 1;
+
+            if steps % options.sample_every == 0 {
+                if let Err(msg) = machine.check_invariants() {
+                    return Some(failure(config, &ops, msg));
+                }
+                samples.push(Sample {
+                    step: steps,
+                    walk_step: ops.len() - 1,
+                    shape: Shape::of(&machine),
+                });
+            }
+
+            if !accepted || ops.len() > options.budget.max_depth {
+                if !accepted {
+                    stuck += 1;
+                }
+                walks += 1;
+                (machine, ops) = start(config);
+            }
+        }
+
+        None
+    });
+
+    Simulation {
+        steps,
+        walks,
+        stuck,
+        samples,
+        failure,
+        report: meter.report(),
+    }
+}
+
+// The smallest, mean and largest value of every measurement over [samples],
+// and whether it was larger in the second half of walks of at most
+// [max_depth] steps than in the first half, which is what unbounded growth
+// looks like.
+pub fn summarize(
+    samples: &[Sample],
+    max_depth: usize,
+) -> Vec<(&'static str, usize, f64, usize, bool)> {
+    let fields = Shape::default().fields();
+
+    (0..fields.len())
+        .map(|i| {
+            let value = |sample: &Sample| sample.shape.fields()[i].1;
+            let values: Vec<_> = samples.iter().map(value).collect();
+            let min = values.iter().copied().min().unwrap_or(0);
+            let max = values.iter().copied().max().unwrap_or(0);
+            let mean = if values.is_empty() {
+                0.0
+            } else {
+                values.iter().sum::<usize>() as f64 / values.len() as f64
+            };
+
+            let half_max = |late: bool| {
+                samples
+                    .iter()
+                    .filter(|sample| (sample.walk_step > max_depth / 2) == late)
+                    .map(value)
+                    .max()
+            };
+            let growing = match (half_max(false), half_max(true)) {
+                (Some(early), Some(late)) => late > early,
+                _ => false,
+            };
+
+            (fields[i].0, min, mean, max, growing)
+        })
+        .collect()
+}
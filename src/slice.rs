@@ -0,0 +1,328 @@
+// Explaining the verdict of a single operation of a trace by the operations
+// it actually depends on. While a trace is replayed, every event in the log is
+// annotated with its footprint: the parts of the state the operation looked
+// at, and the parts it changed (which are observed by comparing the state
+// before and after it, rather than predicted). The explanation of an
+// operation is then the backward slice from it: every earlier operation that
+// changed something it looked at, and, transitively, everything those looked
+// at. Unlike minimize.rs, which tries deleting operations and replays the
+// result every time, this takes a single replay.
+//
+// The slice is renumbered so that it is a trace of its own, and checked by
+// replaying it: if the footprints missed a dependency, the operation would get
+// a different verdict, and the whole prefix is returned instead.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::events::{EventLog, Outcome};
+use crate::machine2::{
+    Frame, MachineConfig, Operation, RefKind, RefState, Reference, TokenMachine, TokenPermissions,
+};
+use crate::trace::{self, Trace};
+
+// A part of the state an operation can look at or change.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Resource {
+    // The kind, state and pieces of a reference (and its existence).
+    Ref(Reference),
+    // The number of pieces, the permissions and the writers of the token of
+    // the tree with the given root.
+    Token(Reference),
+    // The calls in progress.
+    Frames,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Footprint {
+    pub reads: BTreeSet<Resource>,
+    pub writes: BTreeSet<Resource>,
+}
+
+// What the rules can look at when performing [op]. This errs on the side of
+// too much, since a missed read makes for a wrong explanation.
+fn reads(machine: &TokenMachine, op: Operation) -> BTreeSet<Resource> {
+    let mut res: BTreeSet<_> = op.references().into_iter().map(Resource::Ref).collect();
+    let refs = machine.references();
+    let exists = |r: Reference| refs.binary_search(&r).is_ok();
+
+    if !machine.frames().is_empty() {
+        res.insert(Resource::Frames);
+    }
+    match op {
+        Operation::NewRoot | Operation::NewConstRoot | Operation::Call(_) => {}
+        Operation::Arg(_) => {
+            res.insert(Resource::Frames);
+        }
+        Operation::Ret => {
+            res.insert(Resource::Frames);
+            if let Some(frame) = machine.frames().last() {
+                res.extend(frame.created.iter().copied().map(Resource::Ref));
+                // Deallocating a local tree takes its token.
+                for &r in &frame.created {
+                    if exists(r) && machine.root_of(r) == r {
+                        res.insert(Resource::Token(r));
+                    }
+                }
+            }
+        }
+        Operation::CreateRef(..) => {}
+        Operation::Borrow(r) | Operation::Return(r) if exists(r) => {
+            res.insert(Resource::Ref(machine.parent_of(r)));
+            res.insert(Resource::Token(machine.root_of(r)));
+        }
+        Operation::ReclaimExclusive(r) if exists(r) => {
+            let root = machine.root_of(r);
+            res.insert(Resource::Token(root));
+            res.extend(
+                refs.iter()
+                    .filter(|&&other| machine.root_of(other) == root)
+                    .map(|&other| Resource::Ref(other)),
+            );
+        }
+        Operation::Dup(r)
+        | Operation::Merge(r)
+        | Operation::SetPerms(r, _)
+        | Operation::Use(r, _)
+            if exists(r) =>
+        {
+            res.insert(Resource::Token(machine.root_of(r)));
+        }
+        _ => {}
+    }
+
+    res
+}
+
+// Everything about a reference or a token that can make a difference to a
+// later verdict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Observed {
+    refs: HashMap<Reference, (RefKind, RefState, u32, u32)>,
+    tokens: HashMap<Reference, (u32, TokenPermissions, u32)>,
+    frames: Vec<Frame>,
+}
+
+fn observe(machine: &TokenMachine) -> Observed {
+    let refs = machine.references();
+    let held = |r: Reference| machine.get_token_info(r).map_or(0, |info| info.pieces_held);
+
+    Observed {
+        refs: refs
+            .iter()
+            .map(|&r| {
+                let info = (
+                    machine.kind_of(r),
+                    machine.state_of(r),
+                    held(r),
+                    machine.outstanding_splits(r),
+                );
+                (r, info)
+            })
+            .collect(),
+        tokens: machine
+            .roots()
+            .into_iter()
+            .map(|root| {
+                let writers = refs
+                    .iter()
+                    .filter(|&&r| {
+                        machine.root_of(r) == root && machine.kind_of(r) == RefKind::SharedReadWrite
+                    })
+                    .map(|&r| held(r))
+                    .sum();
+                let perms = machine.token_perms(root);
+                (root, (machine.token_count(root), perms, writers))
+            })
+            .collect(),
+        frames: machine.frames().to_vec(),
+    }
+}
+
+fn writes(before: &Observed, after: &Observed) -> BTreeSet<Resource> {
+    let mut res = BTreeSet::new();
+    for (r, info) in &after.refs {
+        if before.refs.get(r) != Some(info) {
+            res.insert(Resource::Ref(*r));
+        }
+    }
+    for (root, info) in &after.tokens {
+        if before.tokens.get(root) != Some(info) {
+            res.insert(Resource::Token(*root));
+        }
+    }
+    if before.frames != after.frames {
+        res.insert(Resource::Frames);
+    }
+    res
+}
+
+// A trace replayed up to (and including) its first rejected operation, with
+// the footprint of every event.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub config: MachineConfig,
+    pub log: EventLog,
+    pub footprints: Vec<Footprint>,
+}
+
+// Perform [op] on [machine], returning its outcome and its footprint. This is
+// also the dependency model of cost.rs.
+pub(crate) fn apply_observed(machine: &mut TokenMachine, op: Operation) -> (Outcome, Footprint) {
+    let reads = reads(machine, op);
+    let before = observe(machine);
+    let outcome = machine.apply(op);
+    let writes = writes(&before, &observe(machine));
+    (outcome, Footprint { reads, writes })
+}
+
+pub fn record(config: MachineConfig, trace: &Trace) -> Recorded {
+    let mut machine = TokenMachine::init_empty_with(config);
+    let mut log = EventLog::new();
+    let mut footprints = Vec::new();
+
+    for &op in &trace.ops {
+        let (outcome, footprint) = apply_observed(&mut machine, op);
+
+        log.record(op, outcome, None);
+        footprints.push(footprint);
+        if outcome.is_err() {
+            break;
+        }
+    }
+
+    Recorded {
+        config,
+        log,
+        footprints,
+    }
+}
+
+// Outcomes count as the same verdict if both are accepted or both are
+// rejected with the same kind of error.
+fn same_verdict(a: &Outcome, b: &Outcome) -> bool {
+    match (a, b) {
+        (Ok(_), Ok(_)) => true,
+        (Err(a), Err(b)) => a.name() == b.name(),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    // The indices of the operations of the original trace that were kept, in
+    // order; the last one is the operation being explained.
+    pub steps: Vec<usize>,
+    // The kept operations, as they are in the original trace.
+    pub ops: Vec<Operation>,
+    // The kept operations as a trace of their own, with references
+    // renumbered.
+    pub trace: Trace,
+    pub outcome: Outcome,
+    // Whether the slice reproduced the verdict. If not, [steps] is the whole
+    // prefix up to the operation.
+    pub sliced: bool,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = match &self.outcome {
+            Ok(_) => "accepted".to_string(),
+            Err(err) => format!("rejected: {}", err),
+        };
+        write!(
+            f,
+            "step {} is {}, which depends on {} earlier operations",
+            self.steps.last().unwrap(),
+            verdict,
+            self.steps.len() - 1
+        )?;
+        for (&step, op) in self.steps.iter().zip(&self.ops) {
+            write!(f, "\n{:>4}  {}", step, op)?;
+        }
+        if !self.sliced {
+            write!(
+                f,
+                "\n(the slice did not reproduce the verdict; this is the whole prefix)"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The operations of [steps], with references renumbered to the IDs replay
+// gives them when only these operations are performed.
+fn renumber(ops: &[Operation], steps: &[usize]) -> Trace {
+    let mut ids = HashMap::new();
+    let (mut created, mut count) = (0, 0);
+    let mut kept = Vec::new();
+
+    for (step, &op) in ops.iter().enumerate() {
+        let keep = steps.contains(&step);
+        if keep {
+            // References a kept operation uses were created by kept
+            // operations, unless the trace uses references that don't exist.
+            kept.push(op.map_references(|r| ids.get(&r).copied().unwrap_or(r)));
+        }
+        if trace::creates_reference(op) {
+            if keep {
+                ids.insert(Reference::from_id(created), Reference::from_id(count));
+                count += 1;
+            }
+            created += 1;
+        }
+    }
+
+    Trace::new(kept)
+}
+
+impl Recorded {
+    // Explain the verdict of operation [step] by the smallest set of earlier
+    // operations it depends on.
+    pub fn explain_minimal(&self, step: usize) -> Result<Explanation, String> {
+        let events = self.log.events();
+        let event = events.get(step).ok_or_else(|| {
+            format!(
+                "step {} is not performed: the trace stops after step {}",
+                step,
+                events.len().saturating_sub(1)
+            )
+        })?;
+        let ops: Vec<_> = events.iter().map(|event| event.op).collect();
+
+        let mut needed = self.footprints[step].reads.clone();
+        let mut steps = vec![step];
+        for earlier in (0..step).rev() {
+            let footprint = &self.footprints[earlier];
+            if footprint.writes.iter().any(|w| needed.contains(w)) {
+                steps.push(earlier);
+                needed.extend(footprint.reads.iter().copied());
+            }
+        }
+        steps.reverse();
+
+        let trace = renumber(&ops, &steps);
+        let (last, prefix) = trace.ops.split_last().unwrap();
+        let (_, mut machine) = trace::replay(self.config, &Trace::new(prefix.to_vec()));
+        let outcome = machine.apply(*last);
+
+        if same_verdict(&outcome, &event.outcome) {
+            Ok(Explanation {
+                ops: steps.iter().map(|&step| ops[step]).collect(),
+                steps,
+                trace,
+                outcome,
+                sliced: true,
+            })
+        } else {
+            let steps: Vec<_> = (0..=step).collect();
+            Ok(Explanation {
+                ops: ops[..=step].to_vec(),
+                trace: Trace::new(ops[..=step].to_vec()),
+                steps,
+                outcome: event.outcome,
+                sliced: false,
+            })
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::events::{Event, EventLog, Outcome};
+use crate::machine2::{Operation, TokenMachine};
+
+#[derive(Debug)]
+struct Shared {
+    machine: TokenMachine,
+    log: EventLog,
+}
+
+// A handle to a machine that can be shared between threads. Operations
+// submitted through any of the handles are applied one at a time, and the
+// event log records them in exactly the order in which they were applied.
+#[derive(Debug, Clone)]
+pub struct SyncTokenMachine {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl SyncTokenMachine {
+    pub fn new(machine: TokenMachine) -> Self {
+        SyncTokenMachine {
+            shared: Arc::new(Mutex::new(Shared {
+                machine,
+                log: EventLog::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared
+            .lock()
+            .expect("a thread panicked while applying an operation")
+    }
+
+    // Apply [op] and record it in the event log. Returns the outcome of the
+    // operation together with its sequence number in the log.
+    pub fn apply(&self, op: Operation) -> (u64, Outcome) {
+        let mut shared = self.lock();
+        let changes_before = shared.machine.perm_changes().len();
+        let outcome = shared.machine.apply(op);
+        let perm_change = shared.machine.perm_changes().get(changes_before).copied();
+        let seq = shared.log.record(op, outcome, perm_change);
+        (seq, outcome)
+    }
+
+    // A copy of the current state of the machine.
+    pub fn snapshot(&self) -> TokenMachine {
+        self.lock().machine.clone()
+    }
+
+    // A copy of all events recorded so far.
+    pub fn events(&self) -> Vec<Event> {
+        self.lock().log.events().to_vec()
+    }
+
+    // Run [f] on the machine without any other operation being applied
+    // concurrently.
+    pub fn with_machine<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&TokenMachine) -> R,
+    {
+        f(&self.lock().machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::machine2::{AccessKind, MachineConfig, RefKind};
+
+    #[test]
+    fn the_log_replays_to_the_shared_state() {
+        let machine =
+            SyncTokenMachine::new(TokenMachine::init_empty_with(MachineConfig::default()));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let machine = machine.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        let root = machine.apply(Operation::NewRoot).1.unwrap().unwrap();
+                        let op = Operation::CreateRef(root, RefKind::Unique);
+                        let child = machine.apply(op).1.unwrap().unwrap();
+                        machine.apply(Operation::Borrow(child)).1.unwrap();
+                        machine
+                            .apply(Operation::Use(child, AccessKind::Write))
+                            .1
+                            .unwrap();
+                        // Rejected: the root lent its token out.
+                        let op = Operation::Use(root, AccessKind::Write);
+                        assert!(machine.apply(op).1.is_err());
+                        machine.apply(Operation::Return(child)).1.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let events = machine.events();
+        assert_eq!(events.len(), 4 * 25 * 6);
+        let mut replayed = TokenMachine::init_empty_with(MachineConfig::default());
+        for (i, event) in events.iter().enumerate() {
+            assert_eq!(event.seq, i as u64);
+            assert_eq!(replayed.apply(event.op), event.outcome);
+        }
+        assert_eq!(replayed, machine.snapshot());
+        assert_eq!(machine.with_machine(|m| m.roots().len()), 100);
+    }
+}
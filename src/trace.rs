@@ -0,0 +1,578 @@
+// Text format for traces and machine configurations.
+//
+// A trace is a sequence of operations, one per line (see grammar.rs for the
+// exact grammar), that is replayed on an
+// empty machine. References are written as r0, r1, ... after the IDs the
+// machine gives them, which are handed out in order of creation. Operations
+// that create a reference can be preceded by a binding that states the ID the
+// new reference will get, which is checked when parsing:
+//
+//     # comments run until the end of the line
+//     r0 = root
+//     r1 = const_root   # a constant, whose token is read-only forever
+//     r2 = create r0 unique
+//     borrow r2
+//     use r2 write
+//     return r2
+//     dup r0
+//     merge r0
+//     perms r0 readonly
+//     reclaim r0
+//     call r1
+//     ret
+//
+// A line "expand <fragment> <arguments>" is replaced by the operations of one
+// of the fragments in fragments.rs, e.g. "expand fan_out r0 3".
+//
+// Lines starting with "#!" are headers that describe the trace rather than
+// being part of it, e.g. the configuration the trace is meant to be replayed
+// with ("#! config dup_rule=cap_to_read_only return_rule=strict") or the
+// version of the rules it was made with ("#! version 1", see version.rs).
+//
+// A configuration file consists of "key = value" lines, e.g. "dup_rule =
+// cap_to_read_only". Keys that are left out keep their default value.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use crate::fuzz;
+use crate::grammar;
+use crate::machine;
+use crate::machine2::{
+    AccessKind, DupRule, MachineConfig, MachineError, Operation, RefKind, Reference, ReturnRule,
+    RootConfig, TokenMachine, TokenPermissions, MAX_ROOT_PIECES,
+};
+use crate::version;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    // Line and column numbers start at 1. Errors about a line as a whole have
+    // no column.
+    pub line: usize,
+    pub column: Option<usize>,
+    // What would have been accepted at the column, if that can be said.
+    pub expected: Vec<String>,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn at_line(line: usize, message: String) -> Self {
+        ParseError {
+            line,
+            column: None,
+            expected: Vec::new(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "line {}, column {}: {}", self.line, column, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Trace {
+    // Header keys and values, in the order they appear in the file.
+    pub headers: Vec<(String, String)>,
+    pub ops: Vec<Operation>,
+}
+
+pub fn kind_name(kind: RefKind) -> &'static str {
+    match kind {
+        RefKind::SharedReadOnly => "shared_ro",
+        RefKind::SharedReadWrite => "shared_rw",
+        RefKind::Unique => "unique",
+    }
+}
+
+pub fn access_name(access_kind: AccessKind) -> &'static str {
+    match access_kind {
+        AccessKind::Read => "read",
+        AccessKind::Write => "write",
+    }
+}
+
+pub fn perms_name(perms: TokenPermissions) -> &'static str {
+    match perms {
+        TokenPermissions::ReadOnly => "readonly",
+        TokenPermissions::ReadWrite => "readwrite",
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Operation::NewRoot => write!(f, "root"),
+            Operation::NewConstRoot => write!(f, "const_root"),
+            Operation::CreateRef(parent, kind) => {
+                write!(f, "create {} {}", parent, kind_name(kind))
+            }
+            Operation::Borrow(r) => write!(f, "borrow {}", r),
+            Operation::Return(r) => write!(f, "return {}", r),
+            Operation::Dup(r) => write!(f, "dup {}", r),
+            Operation::Merge(r) => write!(f, "merge {}", r),
+            Operation::SetPerms(r, perms) => write!(f, "perms {} {}", r, perms_name(perms)),
+            Operation::Use(r, access_kind) => write!(f, "use {} {}", r, access_name(access_kind)),
+            Operation::ReclaimExclusive(r) => write!(f, "reclaim {}", r),
+            Operation::Call(r) => write!(f, "call {}", r),
+            Operation::Arg(r) => write!(f, "arg {}", r),
+            Operation::Ret => write!(f, "ret"),
+        }
+    }
+}
+
+pub fn creates_reference(op: Operation) -> bool {
+    matches!(
+        op,
+        Operation::NewRoot | Operation::NewConstRoot | Operation::CreateRef(..)
+    )
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.headers {
+            writeln!(f, "#! {} {}", key, value)?;
+        }
+
+        let mut next_id = 0;
+        for op in &self.ops {
+            if creates_reference(*op) {
+                write!(f, "{} = ", Reference::from_id(next_id))?;
+                next_id += 1;
+            }
+            writeln!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_reference(word: &str) -> Result<Reference, String> {
+    grammar::reference(word)
+        .ok_or_else(|| format!("expected a reference like r0, found '{}'", word))
+}
+
+pub fn parse_kind(word: &str) -> Result<RefKind, String> {
+    match word {
+        "shared_ro" => Ok(RefKind::SharedReadOnly),
+        "shared_rw" => Ok(RefKind::SharedReadWrite),
+        "unique" => Ok(RefKind::Unique),
+        _ => Err(format!(
+            "expected shared_ro, shared_rw or unique, found '{}'",
+            word
+        )),
+    }
+}
+
+pub fn parse_access(word: &str) -> Result<AccessKind, String> {
+    match word {
+        "read" => Ok(AccessKind::Read),
+        "write" => Ok(AccessKind::Write),
+        _ => Err(format!("expected read or write, found '{}'", word)),
+    }
+}
+
+fn parse_perms(word: &str) -> Result<TokenPermissions, String> {
+    match word {
+        "readonly" => Ok(TokenPermissions::ReadOnly),
+        "readwrite" => Ok(TokenPermissions::ReadWrite),
+        _ => Err(format!("expected readonly or readwrite, found '{}'", word)),
+    }
+}
+
+// Parse a single operation, as written in a trace but without a binding.
+pub fn parse_operation(text: &str) -> Result<Operation, String> {
+    grammar::parse_operation(text).map_err(|err| err.message)
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+impl Trace {
+    pub fn new(ops: Vec<Operation>) -> Self {
+        Trace {
+            headers: Vec::new(),
+            ops,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Trace, ParseError> {
+        grammar::parse(text)
+    }
+
+    // The value of the first header called [key].
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Set the header [key], replacing an existing header of that name.
+    pub fn set_header(&mut self, key: &str, value: String) {
+        match self.headers.iter_mut().find(|(k, _)| k == key) {
+            Some(header) => header.1 = value,
+            None => self.headers.push((key.to_string(), value)),
+        }
+    }
+
+    // The configuration given by the "config" header, if there is one.
+    pub fn config(&self) -> Result<Option<MachineConfig>, String> {
+        match self.header("config") {
+            Some(settings) => parse_config_inline(settings).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_config(&mut self, config: &MachineConfig) {
+        self.set_header("config", format_config_inline(config));
+    }
+
+    // Load a trace and migrate it to the current version of the rules.
+    pub fn load(path: &Path) -> Result<Trace, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut trace = Trace::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        version::migrate(&mut trace).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(trace)
+    }
+
+    // Save a trace, recording the current version of the rules if it doesn't
+    // say which version it was made with.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut trace = self.clone();
+        version::stamp(&mut trace);
+        fs::write(path, trace.to_string()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// The result of replaying a trace.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Verdict {
+    Accepted,
+    // The index of the first rejected operation and the reason.
+    Rejected(usize, MachineError),
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Accepted => write!(f, "accepted"),
+            Verdict::Rejected(step, err) => write!(f, "rejected at step {}: {}", step, err),
+        }
+    }
+}
+
+// Replay [trace] on an empty machine, stopping at the first rejected
+// operation. Returns the state the machine ended up in.
+pub fn replay(config: MachineConfig, trace: &Trace) -> (Verdict, TokenMachine) {
+    let mut machine = TokenMachine::init_empty_with(config);
+
+    for (i, &op) in trace.ops.iter().enumerate() {
+        if let Err(err) = machine.apply(op) {
+            return (Verdict::Rejected(i, err), machine);
+        }
+    }
+
+    (Verdict::Accepted, machine)
+}
+
+// A rejected operation found by replay_all.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Violation {
+    pub step: usize,
+    pub op: Operation,
+    pub error: MachineError,
+    // Whether the operation involves a reference affected by an earlier
+    // violation, in which case it may only have been rejected because of it.
+    pub tainted: bool,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {}: {}: {}", self.step, self.op, self.error)?;
+        if self.tainted {
+            write!(f, " (tainted by an earlier violation)")?;
+        }
+        Ok(())
+    }
+}
+
+// Replay [trace] on an empty machine, skipping rejected operations instead of
+// stopping at the first one, and return every violation.
+//
+// The references involved in a skipped operation are tainted, and so is
+// everything derived from them later. A skipped operation that should have
+// created a reference creates a fresh root in its place instead, so that the
+// references created after it still get the IDs the trace expects; the
+// placeholder is tainted as well.
+pub fn replay_all(config: MachineConfig, trace: &Trace) -> (Vec<Violation>, TokenMachine) {
+    let mut machine = TokenMachine::init_empty_with(config);
+    let mut violations = Vec::new();
+    let mut tainted = HashSet::new();
+
+    for (step, &op) in trace.ops.iter().enumerate() {
+        let refs = op.references();
+        let involves_tainted = refs.iter().any(|r| tainted.contains(r));
+
+        match machine.apply(op) {
+            Ok(Some(created)) if involves_tainted => {
+                tainted.insert(created);
+            }
+            Ok(_) => {}
+            Err(error) => {
+                violations.push(Violation {
+                    step,
+                    op,
+                    error,
+                    tainted: involves_tainted,
+                });
+                tainted.extend(refs);
+                if creates_reference(op) {
+                    tainted.insert(machine.new_root());
+                }
+            }
+        }
+    }
+
+    (violations, machine)
+}
+
+// The result of replaying a trace on the simple model of machine.rs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimpleVerdict {
+    // The number of operations that were skipped because the simple model
+    // has no counterpart for them.
+    Accepted { ignored: usize },
+    // The index of the first rejected operation and the reason.
+    Rejected(usize, String),
+}
+
+impl fmt::Display for SimpleVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimpleVerdict::Accepted { ignored: 0 } => write!(f, "accepted"),
+            SimpleVerdict::Accepted { ignored } => {
+                write!(f, "accepted ({} operations ignored)", ignored)
+            }
+            SimpleVerdict::Rejected(step, msg) => write!(f, "rejected at step {}: {}", step, msg),
+        }
+    }
+}
+
+// Replay [trace] on the simple model, which has a single token that is never
+// split and no permissions. Reference kinds and access kinds are ignored, and
+// so are dup, merge, perms and reclaim. The trace has to start by creating
+// the one root the simple model has.
+//
+// The simple model panics on illegal operations, so the panics are caught and
+// reported as rejections. Returns the machine unless the trace does not start
+// with a root.
+pub fn replay_simple(trace: &Trace) -> (SimpleVerdict, Option<machine::TokenMachine>) {
+    let (_, mut machine) = match trace.ops.first() {
+        Some(Operation::NewRoot) => machine::TokenMachine::init(),
+        _ => {
+            let msg = "the simple model needs the trace to start with a root".to_string();
+            return (SimpleVerdict::Rejected(0, msg), None);
+        }
+    };
+    let simple_ref = |r: Reference| machine::Reference::from_id(r.id());
+    let mut ignored = 0;
+
+    for (i, &op) in trace.ops.iter().enumerate().skip(1) {
+        let result = fuzz::quietly(|| {
+            panic::catch_unwind(AssertUnwindSafe(|| match op {
+                Operation::NewRoot | Operation::NewConstRoot => {
+                    Err("the simple model has only one root".to_string())
+                }
+                Operation::CreateRef(parent, _) => {
+                    machine.create_ref(simple_ref(parent));
+                    Ok(())
+                }
+                Operation::Borrow(r) => {
+                    machine.borrow_token(simple_ref(r));
+                    Ok(())
+                }
+                // The simple model always returns the token of its current
+                // owner, so check that that is the reference the trace means.
+                Operation::Return(r) => {
+                    if machine.current_owner() != simple_ref(r) {
+                        return Err(format!("{} does not hold the token", r));
+                    }
+                    machine.return_token();
+                    Ok(())
+                }
+                Operation::Use(r, _) => {
+                    machine.use_token(simple_ref(r));
+                    Ok(())
+                }
+                Operation::Dup(_)
+                | Operation::Merge(_)
+                | Operation::SetPerms(..)
+                | Operation::ReclaimExclusive(_)
+                | Operation::Call(_)
+                | Operation::Arg(_)
+                | Operation::Ret => {
+                    ignored += 1;
+                    Ok(())
+                }
+            }))
+        });
+
+        let msg = match result {
+            Ok(Ok(())) => continue,
+            Ok(Err(msg)) => msg,
+            Err(payload) => fuzz::panic_message(&*payload),
+        };
+        return (SimpleVerdict::Rejected(i, msg), Some(machine));
+    }
+
+    (SimpleVerdict::Accepted { ignored }, Some(machine))
+}
+
+// Settings of the initial root are only included when they differ from the
+// default, so that the configuration of traces that don't use them reads the
+// same as before they existed.
+fn config_settings(config: &MachineConfig) -> Vec<(&'static str, String)> {
+    let dup_rule = match config.dup_rule {
+        DupRule::Unrestricted => "unrestricted",
+        DupRule::CapToReadOnly => "cap_to_read_only",
+        DupRule::RequireReadOnly => "require_read_only",
+    };
+    let return_rule = match config.return_rule {
+        ReturnRule::Strict => "strict",
+        ReturnRule::Partial => "partial",
+    };
+
+    let mut settings = vec![
+        ("dup_rule", dup_rule.to_string()),
+        ("return_rule", return_rule.to_string()),
+    ];
+
+    let root = config.root;
+    let default = RootConfig::default();
+    if root.kind != default.kind {
+        settings.push(("root_kind", kind_name(root.kind).to_string()));
+    }
+    if root.perms != default.perms {
+        settings.push(("root_perms", perms_name(root.perms).to_string()));
+    }
+    if root.pieces != default.pieces {
+        settings.push(("root_pieces", root.pieces.to_string()));
+    }
+
+    settings
+}
+
+pub fn format_config(config: &MachineConfig) -> String {
+    config_settings(config)
+        .into_iter()
+        .map(|(key, value)| format!("{} = {}\n", key, value))
+        .collect()
+}
+
+// The configuration on a single line, as used in trace headers and command
+// line flags: "dup_rule=unrestricted return_rule=strict".
+pub fn format_config_inline(config: &MachineConfig) -> String {
+    config_settings(config)
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn parse_config_inline(text: &str) -> Result<MachineConfig, String> {
+    let mut config = MachineConfig::default();
+    for setting in text.split_whitespace() {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=value, found '{}'", setting))?;
+        apply_config_setting(&mut config, key, value)?;
+    }
+    Ok(config)
+}
+
+// Change a single setting of [config], e.g. from a command line flag.
+pub fn apply_config_setting(
+    config: &mut MachineConfig,
+    key: &str,
+    value: &str,
+) -> Result<(), String> {
+    match (key, value) {
+        ("dup_rule", "unrestricted") => config.dup_rule = DupRule::Unrestricted,
+        ("dup_rule", "cap_to_read_only") => config.dup_rule = DupRule::CapToReadOnly,
+        ("dup_rule", "require_read_only") => config.dup_rule = DupRule::RequireReadOnly,
+        ("return_rule", "strict") => config.return_rule = ReturnRule::Strict,
+        ("return_rule", "partial") => config.return_rule = ReturnRule::Partial,
+        ("root_kind", kind) => config.root.kind = parse_kind(kind)?,
+        ("root_perms", perms) => config.root.perms = parse_perms(perms)?,
+        ("root_pieces", pieces) => {
+            config.root.pieces = match pieces.parse() {
+                Ok(pieces) if (1..=MAX_ROOT_PIECES).contains(&pieces) => pieces,
+                _ => {
+                    return Err(format!(
+                        "expected a number of pieces from 1 to {}, found '{}'",
+                        MAX_ROOT_PIECES, pieces
+                    ))
+                }
+            }
+        }
+        _ => return Err(format!("unknown setting {} = {}", key, value)),
+    }
+
+    Ok(())
+}
+
+pub fn parse_config(text: &str) -> Result<MachineConfig, ParseError> {
+    let mut config = MachineConfig::default();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let result = match line.find('=') {
+            Some(eq) => apply_config_setting(&mut config, line[..eq].trim(), line[eq + 1..].trim()),
+            None => Err(format!("expected 'key = value', found '{}'", line)),
+        };
+        result.map_err(|message| ParseError::at_line(i + 1, message))?;
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_pieces_are_bounded() {
+        let mut config = MachineConfig::default();
+        assert!(apply_config_setting(&mut config, "root_pieces", "4000000000").is_err());
+        assert!(apply_config_setting(&mut config, "root_pieces", "0").is_err());
+        assert!(parse_config_inline("root_pieces=4097").is_err());
+
+        apply_config_setting(&mut config, "root_pieces", "4096").unwrap();
+        assert_eq!(config.root.pieces, MAX_ROOT_PIECES);
+    }
+
+    #[test]
+    fn a_config_header_with_too_many_pieces_is_an_error() {
+        let mut trace = Trace::new(vec![Operation::NewRoot]);
+        trace.set_header("config", "root_pieces=4000000000".to_string());
+        assert!(trace.config().is_err());
+    }
+}
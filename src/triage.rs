@@ -0,0 +1,161 @@
+// A persistent index of the failures fuzzing has found, so that later fuzzing
+// sessions don't keep reporting the same ones. Failures are identified by a
+// hash of their minimized trace and configuration, which is the same however
+// (and with whatever seed) the failure was found. The index is a text file
+// with one line per failure:
+//
+//     <hash> <status> <note>
+//
+// where the status is "new" for failures that have been found but not looked
+// at, and "known" for failures that have been triaged. Fuzzing only reports
+// failures that are not known yet.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ids::Fnv;
+use crate::machine2::MachineConfig;
+use crate::trace::{self, Trace};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    New,
+    Known,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::New => write!(f, "new"),
+            Status::Known => write!(f, "known"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub status: Status,
+    pub note: String,
+}
+
+// The hash identifying a failing trace. Headers are left out: only the
+// configuration and the operations determine what the trace does.
+pub fn canonical_hash(config: &MachineConfig, trace: &Trace) -> u64 {
+    let mut hash = Fnv::new();
+    hash.write(trace::format_config_inline(config).as_bytes());
+    for op in &trace.ops {
+        hash.write(b"\n");
+        hash.write(op.to_string().as_bytes());
+    }
+    hash.finish()
+}
+
+pub fn format_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn parse_hash(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text.trim(), 16)
+        .map_err(|_| format!("expected a hexadecimal trace hash, found '{}'", text.trim()))
+}
+
+#[derive(Debug, Clone)]
+pub struct Index {
+    path: PathBuf,
+    entries: BTreeMap<u64, Entry>,
+}
+
+impl Index {
+    // Load the index at [path]. A missing file is an empty index.
+    pub fn load(path: &Path) -> Result<Index, String> {
+        let mut entries = BTreeMap::new();
+
+        if path.exists() {
+            let text =
+                fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            for (i, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let error = |msg| format!("{}:{}: {}", path.display(), i + 1, msg);
+                let mut fields = line.splitn(3, ' ');
+                let hash = parse_hash(fields.next().unwrap()).map_err(error)?;
+                let status = match fields.next() {
+                    Some("new") => Status::New,
+                    Some("known") => Status::Known,
+                    other => {
+                        return Err(error(format!(
+                            "expected new or known, found '{}'",
+                            other.unwrap_or("")
+                        )))
+                    }
+                };
+                let note = fields.next().unwrap_or("").to_string();
+                entries.insert(hash, Entry { status, note });
+            }
+        }
+
+        Ok(Index {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+
+        let mut text = String::from("# tbm triage index: <hash> <new|known> <note>\n");
+        for (hash, entry) in &self.entries {
+            text.push_str(&format!(
+                "{} {} {}\n",
+                format_hash(*hash),
+                entry.status,
+                entry.note
+            ));
+        }
+        fs::write(&self.path, text).map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+
+    pub fn get(&self, hash: u64) -> Option<&Entry> {
+        self.entries.get(&hash)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (u64, &Entry)> {
+        self.entries.iter().map(|(hash, entry)| (*hash, entry))
+    }
+
+    // Record a failure that was just found, unless the index already has it.
+    // Returns whether it was new to the index.
+    pub fn record(&mut self, hash: u64, note: &str) -> bool {
+        if self.entries.contains_key(&hash) {
+            return false;
+        }
+        self.entries.insert(
+            hash,
+            Entry {
+                status: Status::New,
+                note: note.to_string(),
+            },
+        );
+        true
+    }
+
+    // Mark a failure as triaged, replacing its note if [note] is given.
+    // Failures can be marked before they were ever recorded.
+    pub fn mark(&mut self, hash: u64, note: Option<&str>) {
+        let entry = self.entries.entry(hash).or_insert_with(|| Entry {
+            status: Status::Known,
+            note: String::new(),
+        });
+        entry.status = Status::Known;
+        if let Some(note) = note {
+            entry.note = note.to_string();
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Versioning of the machine's rules, so that saved traces, corpora and
+// reproducer bundles stay interpretable as the rules evolve. Traces record the
+// version they were made with in a "#! version N" header.
+
+use crate::machine2::{AccessRule, Operation, TokenMachine};
+use crate::trace::Trace;
+
+// Bump this whenever a change to the rules makes some trace replay differently
+// (accepted where it used to be rejected or vice versa, or rejected with a
+// different error), and add a migration from the previous version below.
+//
+// Version 2: unique and shared read-only references can read with a piece of
+// a shared read-write token as long as no other piece is held by a shared
+// read-write reference (AccessRule::ReadNoWriters).
+pub const SEMANTICS_VERSION: u32 = 2;
+
+// Traces written before versions were recorded.
+const UNVERSIONED: u32 = 1;
+
+// Rewrites a trace made with version [from] so that it means the same under
+// version [from] + 1, or explains why that is not possible.
+struct Migration {
+    from: u32,
+    migrate: fn(&mut Trace) -> Result<(), String>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    migrate: reads_without_writers,
+}];
+
+// Traces only replay differently under version 2 if they contain a read that
+// is now allowed by ReadNoWriters, since version 1 rejected exactly those
+// reads (with UniqueReadWithWriters or SharedReadOnlyReadWithWriters). There
+// is no way to rewrite such a read so that it is still rejected.
+fn reads_without_writers(trace: &mut Trace) -> Result<(), String> {
+    let config = trace.config()?.unwrap_or_default();
+    let mut machine = TokenMachine::init_empty_with(config);
+
+    for (i, &op) in trace.ops.iter().enumerate() {
+        let mut before = machine.clone();
+        if machine.apply(op).is_err() {
+            return Ok(());
+        }
+
+        if let Operation::Use(r, access_kind) = op {
+            let rule = before.use_token(r, access_kind).map(|receipt| receipt.rule);
+            if rule == Ok(AccessRule::ReadNoWriters) {
+                return Err(format!(
+                    "step {} ({}) was rejected before, but reads without writers are now allowed",
+                    i, op
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn parse_version(text: &str) -> Result<u32, String> {
+    text.trim()
+        .parse()
+        .map_err(|_| format!("expected a version number, found '{}'", text.trim()))
+}
+
+// Whether artifacts made with [version] can be used at all. Versions from the
+// future cannot: there is no telling what their traces mean.
+pub fn check_supported(version: u32) -> Result<(), String> {
+    if version > SEMANTICS_VERSION {
+        return Err(format!(
+            "made with semantics version {}, but this is version {}",
+            version, SEMANTICS_VERSION
+        ));
+    }
+    Ok(())
+}
+
+pub fn version_of(trace: &Trace) -> Result<u32, String> {
+    match trace.header("version") {
+        Some(version) => parse_version(version),
+        None => Ok(UNVERSIONED),
+    }
+}
+
+// Bring [trace] up to the current version, refusing traces that are too new
+// or that cannot be migrated.
+pub fn migrate(trace: &mut Trace) -> Result<(), String> {
+    let mut version = version_of(trace)?;
+    check_supported(version)?;
+
+    while version < SEMANTICS_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or_else(|| format!("no migration from semantics version {}", version))?;
+        (migration.migrate)(trace)
+            .map_err(|e| format!("cannot migrate from semantics version {}: {}", version, e))?;
+        version += 1;
+    }
+
+    trace.set_header("version", version.to_string());
+    Ok(())
+}
+
+// Record the current version in [trace] unless it already has a version.
+pub fn stamp(trace: &mut Trace) {
+    if trace.header("version").is_none() {
+        trace
+            .headers
+            .insert(0, ("version".to_string(), SEMANTICS_VERSION.to_string()));
+    }
+}